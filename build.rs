@@ -0,0 +1,23 @@
+// Bakes the commit and build time into the binary for /version, see
+// src/version.rs. Builds outside a git checkout can set LIMAIL_GIT_COMMIT.
+use std::env;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    let commit = env::var("LIMAIL_GIT_COMMIT").ok().or_else(|| {
+        let output = Command::new("git").args(&["rev-parse", "--short=12", "HEAD"]).output().ok()?;
+        if output.status.success() {
+            Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+        } else {
+            None
+        }
+    });
+    let built_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+
+    println!("cargo:rustc-env=LIMAIL_GIT_COMMIT={}", commit.unwrap_or_else(|| String::from("unknown")));
+    println!("cargo:rustc-env=LIMAIL_BUILT_AT={}", built_at);
+    println!("cargo:rerun-if-env-changed=LIMAIL_GIT_COMMIT");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
@@ -0,0 +1,8 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate limail;
+
+// cargo +nightly fuzz run inbound_multipart -- -timeout=5
+fuzz_target!(|data: &[u8]| {
+    let _ = limail::multipart::parse_inbound_multipart(data);
+});
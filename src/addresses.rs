@@ -0,0 +1,137 @@
+use serde::{Serialize, Deserialize};
+
+use crate::mailgun::MailgunEmailReceived;
+use crate::viewer;
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplyToChoice {
+    ReplyTo,
+    From,
+    // Don't reply at all.
+    Neither,
+}
+
+// Who auto-replies on routes (exact, or a prefix ending in *) go to when
+// an email's Reply-To isn't its From, as ticketing systems set on purpose.
+// Without a matching rule, that's From.
+#[derive(Deserialize, Clone)]
+pub struct ReplyToRule {
+    pub route: String,
+    pub prefer: ReplyToChoice,
+}
+
+// One address from an address header: `"Some Name" <someone@example.com>`,
+// `someone@example.com` or `someone@example.com (Some Name)`.
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct Mailbox {
+    pub name: Option<String>,
+    pub address: String,
+}
+
+impl Mailbox {
+    pub fn is(&self, address: &str) -> bool {
+        self.address.eq_ignore_ascii_case(address)
+    }
+
+    // `Some Name (someone@example.com)`, since Slack takes <...> for a link.
+    pub fn display(&self) -> String {
+        match &self.name {
+            Some(name) => format!("{} ({})", name, self.address),
+            None => self.address.clone(),
+        }
+    }
+}
+
+// The first address of a From header, or the whole header when it doesn't
+// have one.
+pub fn sender(from: &str) -> Mailbox {
+    parse_list(from).into_iter().next().unwrap_or_else(|| Mailbox { name: None, address: String::from(from.trim()) })
+}
+
+// Splits an address list on the commas between mailboxes, not the ones in
+// quoted names, comments or angle brackets. Groups (`team: a@x, b@y;`) are
+// flattened, and anything without an @ is dropped.
+pub fn parse_list(list: &str) -> Vec<Mailbox> {
+    let mut pieces = Vec::new();
+    let mut piece = String::new();
+    let (mut quoted, mut escaped, mut comment, mut angle) = (false, false, 0, false);
+    for c in list.chars() {
+        if escaped {
+            escaped = false;
+            piece.push(c);
+            continue;
+        }
+        match c {
+            '\\' if quoted || comment > 0 => escaped = true,
+            '"' if comment == 0 => quoted = !quoted,
+            '(' if !quoted => comment += 1,
+            ')' if !quoted && comment > 0 => comment -= 1,
+            '<' if !quoted && comment == 0 => angle = true,
+            '>' if !quoted && comment == 0 => angle = false,
+            ',' | ';' if !quoted && comment == 0 && !angle => {
+                pieces.push(piece.split_off(0));
+                continue;
+            },
+            // The name of a group, not a mailbox of its own.
+            ':' if !quoted && comment == 0 && !angle => {
+                piece.clear();
+                continue;
+            },
+            _ => (),
+        }
+        piece.push(c);
+    }
+    pieces.push(piece);
+    pieces.iter().filter_map(|piece| parse_mailbox(piece)).collect()
+}
+
+fn parse_mailbox(piece: &str) -> Option<Mailbox> {
+    let piece = piece.trim();
+    let (name, address) = match (piece.rfind('<'), piece.rfind('>')) {
+        (Some(start), Some(end)) if start < end => (unquote(&piece[..start]), piece[start + 1..end].trim()),
+        _ => match (piece.find('('), piece.rfind(')')) {
+            (Some(start), Some(end)) if start < end => (unquote(&piece[start + 1..end]), piece[..start].trim()),
+            _ => (None, piece),
+        },
+    };
+    if !address.contains('@') || address.contains(char::is_whitespace) {
+        return None;
+    }
+    Some(Mailbox { name, address: String::from(address) })
+}
+
+fn unquote(name: &str) -> Option<String> {
+    let name = name.trim().trim_matches('"').replace("\\\"", "\"");
+    let name = name.trim();
+    if name.is_empty() {
+        None
+    } else {
+        Some(String::from(name))
+    }
+}
+
+// Everyone in the email's To (or Cc, ...) headers, as it was sent.
+pub fn header(email: &MailgunEmailReceived, name: &str) -> Vec<Mailbox> {
+    viewer::headers(&email.message_headers).iter()
+        .filter(|(header, _)| header.eq_ignore_ascii_case(name))
+        .flat_map(|(_, value)| parse_list(value))
+        .collect()
+}
+
+// The Reply-To, when it names anyone other than From.
+pub fn differing_reply_to(email: &MailgunEmailReceived, from: &[Mailbox]) -> Option<Vec<Mailbox>> {
+    let reply_to = header(email, "Reply-To");
+    if reply_to.is_empty() || reply_to.iter().all(|mailbox| from.iter().any(|sender| sender.is(&mailbox.address))) {
+        None
+    } else {
+        Some(reply_to)
+    }
+}
+
+pub fn join(mailboxes: &[Mailbox]) -> String {
+    mailboxes.iter()
+        .map(|mailbox| mailbox.address.clone())
+        .collect::<Vec<String>>()
+        .join(", ")
+}
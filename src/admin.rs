@@ -0,0 +1,832 @@
+use std::collections::BTreeMap;
+use std::error::Error as StdError;
+use std::fmt::{self, Display};
+use std::thread;
+use std::time::Duration;
+
+use chrono::Utc;
+use serde::{Serialize, Deserialize};
+use serde_json::json;
+use warp::Rejection;
+use warp::http::Response;
+use warp::http::header::{CONTENT_DISPOSITION, CONTENT_SECURITY_POLICY, CONTENT_TYPE};
+
+use crate::archive::Archive;
+use crate::viewer::{self, HtmlQuery};
+use crate::audit::{AuditLog, AuditQuery};
+use crate::maintenance::{Maintenance, MaintenanceState};
+use crate::auth::Principal;
+use crate::blocklist::Blocklist;
+use crate::domains::SendingDomains;
+use crate::fanout::{self, Call};
+use crate::flags::{Flags, RouteFlags};
+use crate::mailgun::{EmailTemplate, Mailgun};
+use crate::metrics::Metrics;
+use crate::copies::SentCopy;
+use crate::outbox::{Outbox, OutboxEntry, OutboxQuery};
+use crate::pipeline::{Action, Pipeline};
+use crate::reputation::SenderHistory;
+use crate::slack::{Slack, SlackError};
+use crate::store::StoreError;
+use crate::suppressions::SuppressionQuery;
+use crate::unsubscribe::{self, Unsubscribed};
+
+#[derive(Debug)]
+pub enum AdminError {
+    NotFound(String),
+    InMaintenance(String),
+}
+impl std::convert::From<AdminError> for Rejection {
+    fn from(err: AdminError) -> Rejection {
+        warp::reject::custom(err)
+    }
+}
+
+impl Display for AdminError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            AdminError::NotFound(s) => s,
+            AdminError::InMaintenance(s) => s,
+        })
+    }
+}
+impl StdError for AdminError {}
+
+#[derive(Serialize)]
+struct BlocklistResponse {
+    entries: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct BlocklistChangeResponse {
+    entry: String,
+    changed: bool,
+}
+
+#[derive(Deserialize)]
+pub struct ReplayRequest {
+    // Defaults to whatever the email's route asked for originally.
+    #[serde(default)]
+    action: Option<Action>,
+}
+
+#[derive(Deserialize)]
+pub struct SendRequest {
+    pub recipient: String,
+    pub template: String,
+    pub subject: String,
+    // The Message-ID of the email being answered, if there is one.
+    #[serde(default)]
+    pub in_reply_to: Option<String>,
+    // The route whose footer it gets, the usual one when missing.
+    #[serde(default)]
+    pub route: Option<String>,
+    // Only for canned replies, which answer someone who wrote in the way an
+    // auto-reply would.
+    #[serde(skip)]
+    pub list_unsubscribe: Option<String>,
+}
+
+#[derive(Serialize)]
+struct SendResponse {
+    recipient: String,
+    template: String,
+    message_id: String,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RedactMode {
+    // Removes the Slack messages outright.
+    Delete,
+    // Leaves a placeholder behind, so the thread still makes sense.
+    Redact,
+}
+
+impl Default for RedactMode {
+    fn default() -> RedactMode {
+        RedactMode::Delete
+    }
+}
+
+#[derive(Deserialize, Default)]
+pub struct RedactRequest {
+    #[serde(default)]
+    pub mode: RedactMode,
+}
+
+#[derive(Serialize)]
+pub struct RedactResponse {
+    pub id: String,
+    pub slack_messages: usize,
+    // Messages Slack refused to change, the archive is scrubbed regardless.
+    pub slack_failures: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct MaintenanceEnded {
+    #[serde(flatten)]
+    state: MaintenanceState,
+    delivering: usize,
+}
+
+#[derive(Serialize)]
+struct ReplayResponse {
+    id: String,
+    action: Action,
+    outcome: &'static str,
+}
+
+pub fn stats(_principal: Principal, metrics: Metrics) -> impl warp::Reply {
+    warp::reply::json(&metrics.counters())
+}
+
+pub fn list_blocklist(_principal: Principal, blocklist: Blocklist) -> impl warp::Reply {
+    warp::reply::json(&BlocklistResponse {
+        entries: blocklist.entries(),
+    })
+}
+
+#[derive(Deserialize, Default)]
+pub struct ExportQuery {
+    // csv, or JSON when missing.
+    #[serde(default)]
+    pub format: Option<String>,
+}
+
+// The opt-out list, as CSV for compliance audits with ?format=csv.
+pub fn list_unsubscribed(
+    _principal: Principal,
+    unsubscribed: Unsubscribed,
+    query: ExportQuery,
+) -> Result<impl warp::Reply, Rejection> {
+    let entries = unsubscribed.entries();
+    let response = match query.format.as_ref().map(|format| &format[..]) {
+        Some("csv") => Response::builder()
+            .header(CONTENT_TYPE, "text/csv; charset=utf-8")
+            .header(CONTENT_DISPOSITION, "attachment; filename=\"unsubscribed.csv\"")
+            .body(unsubscribe::csv(&entries)),
+        _ => Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(serde_json::to_string(&entries).unwrap_or_default()),
+    };
+    Ok(response.unwrap())
+}
+
+fn change_unsubscribed(
+    principal: &Principal,
+    audit: &AuditLog,
+    unsubscribed: &Unsubscribed,
+    unsubscribe: bool,
+    address: &str,
+) -> Result<bool, StoreError> {
+    let previous = unsubscribed.get(address);
+    let changed = if unsubscribe {
+        unsubscribed.add(address, &principal.name)?
+    } else {
+        unsubscribed.remove(address)?
+    };
+    if changed {
+        audit.record(
+            principal,
+            if unsubscribe { "unsubscribed.add" } else { "unsubscribed.remove" },
+            address,
+            serde_json::to_value(&previous).unwrap_or_default(),
+            serde_json::to_value(&unsubscribed.get(address)).unwrap_or_default(),
+        )?;
+    }
+    Ok(changed)
+}
+
+pub fn unsubscribe(
+    address: String,
+    principal: Principal,
+    audit: AuditLog,
+    unsubscribed: Unsubscribed,
+) -> Result<impl warp::Reply, Rejection> {
+    let changed = change_unsubscribed(&principal, &audit, &unsubscribed, true, &address)?;
+    Ok(warp::reply::json(&json!({ "address": address, "changed": changed })))
+}
+
+pub fn resubscribe(
+    address: String,
+    principal: Principal,
+    audit: AuditLog,
+    unsubscribed: Unsubscribed,
+) -> Result<impl warp::Reply, Rejection> {
+    let changed = change_unsubscribed(&principal, &audit, &unsubscribed, false, &address)?;
+    Ok(warp::reply::json(&json!({ "address": address, "changed": changed })))
+}
+
+// Shared by the admin API and the dashboard form so both end up in the audit log.
+pub fn change_blocklist(
+    principal: &Principal,
+    audit: &AuditLog,
+    blocklist: &Blocklist,
+    block: bool,
+    entry: &str,
+) -> Result<bool, StoreError> {
+    let previous = blocklist.contains(entry);
+    let changed = if block {
+        blocklist.add(entry)?
+    } else {
+        blocklist.remove(entry)?
+    };
+    if changed {
+        audit.record(
+            principal,
+            if block { "blocklist.add" } else { "blocklist.remove" },
+            entry,
+            json!({ "blocked": previous }),
+            json!({ "blocked": block }),
+        )?;
+    }
+    Ok(changed)
+}
+
+// The dashboard's routing rules, audited like the blocklist.
+pub fn set_route_flags(
+    principal: &Principal,
+    audit: &AuditLog,
+    flags: &Flags,
+    rule: RouteFlags,
+) -> Result<(), StoreError> {
+    let after = serde_json::to_value(&rule).unwrap_or_default();
+    let route = rule.route.clone();
+    let previous = flags.set(rule)?;
+    audit.record(
+        principal,
+        "route_flags.set",
+        &route,
+        serde_json::to_value(&previous).unwrap_or_default(),
+        after,
+    )
+}
+
+// Back to everything on for the route, unless a broader entry matches it.
+pub fn remove_route_flags(
+    principal: &Principal,
+    audit: &AuditLog,
+    flags: &Flags,
+    route: &str,
+) -> Result<bool, StoreError> {
+    match flags.remove(route)? {
+        Some(previous) => {
+            audit.record(
+                principal,
+                "route_flags.remove",
+                route,
+                serde_json::to_value(&previous).unwrap_or_default(),
+                serde_json::Value::Null,
+            )?;
+            Ok(true)
+        },
+        None => Ok(false),
+    }
+}
+
+pub fn block(
+    entry: String,
+    principal: Principal,
+    audit: AuditLog,
+    blocklist: Blocklist,
+) -> Result<impl warp::Reply, Rejection> {
+    let changed = change_blocklist(&principal, &audit, &blocklist, true, &entry)?;
+    Ok(warp::reply::json(&BlocklistChangeResponse { entry, changed }))
+}
+
+pub fn unblock(
+    entry: String,
+    principal: Principal,
+    audit: AuditLog,
+    blocklist: Blocklist,
+) -> Result<impl warp::Reply, Rejection> {
+    let changed = change_blocklist(&principal, &audit, &blocklist, false, &entry)?;
+    Ok(warp::reply::json(&BlocklistChangeResponse { entry, changed }))
+}
+
+pub fn audit_log(
+    _principal: Principal,
+    audit: AuditLog,
+    query: AuditQuery,
+) -> Result<impl warp::Reply, Rejection> {
+    Ok(warp::reply::json(&audit.query(&query)?))
+}
+
+pub fn archived_email(
+    id: String,
+    _principal: Principal,
+    archive: Archive,
+) -> Result<impl warp::Reply, Rejection> {
+    match archive.get(&id)? {
+        Some(archived) => Ok(warp::reply::json(&archived)),
+        None => Err(AdminError::NotFound(format!("No archived email {}", id)).into()),
+    }
+}
+
+fn refuse_in_maintenance(maintenance: &Maintenance) -> Result<(), Rejection> {
+    if maintenance.is_enabled() {
+        Err(AdminError::InMaintenance(String::from("limail is in maintenance, nothing is being sent")).into())
+    } else {
+        Ok(())
+    }
+}
+
+// Runs inline rather than through the queue so the caller gets the outcome.
+pub fn replay(
+    id: String,
+    principal: Principal,
+    audit: AuditLog,
+    archive: Archive,
+    pipeline: Pipeline,
+    request: ReplayRequest,
+) -> Result<impl warp::Reply, Rejection> {
+    refuse_in_maintenance(&pipeline.maintenance)?;
+    let archived = match archive.get(&id)? {
+        Some(archived) => archived,
+        None => return Err(AdminError::NotFound(format!("No archived email {}", id)).into()),
+    };
+    let action = request.action.unwrap_or_else(|| archived.job.action.clone());
+    let job = archived.job.replay(action, &principal.name);
+    audit.record(
+        &principal,
+        "email.replay",
+        &id,
+        json!({ "action": archived.job.action }),
+        json!({ "action": job.action }),
+    )?;
+    let outcome = pipeline.process(&job)?;
+    Ok(warp::reply::json(&ReplayResponse {
+        id,
+        action: job.action,
+        outcome: outcome.as_str(),
+    }))
+}
+
+// For when the rate limiter withheld a reply someone actually needed, so it
+// deliberately doesn't consult (or update) the limiter.
+pub fn send(
+    principal: Principal,
+    audit: AuditLog,
+    mailgun: Mailgun,
+    metrics: Metrics,
+    outbox: Outbox,
+    maintenance: Maintenance,
+    request: SendRequest,
+) -> Result<impl warp::Reply, Rejection> {
+    let message_id = send_manually(&principal, &audit, &mailgun, &metrics, &outbox, &maintenance, &request)?;
+    Ok(warp::reply::json(&SendResponse {
+        recipient: request.recipient,
+        template: request.template,
+        message_id,
+    }))
+}
+
+// Shared by /admin/send and the canned replies picked in Slack. Returns the
+// Message-ID Mailgun assigned.
+pub fn send_manually(
+    principal: &Principal,
+    audit: &AuditLog,
+    mailgun: &Mailgun,
+    metrics: &Metrics,
+    outbox: &Outbox,
+    maintenance: &Maintenance,
+    request: &SendRequest,
+) -> Result<String, Rejection> {
+    refuse_in_maintenance(maintenance)?;
+    let in_reply_to = request.in_reply_to.clone().unwrap_or_default();
+    let message_id = mailgun.send_email(&EmailTemplate {
+        recipient: request.recipient.clone(),
+        subject: request.subject.clone(),
+        template: request.template.clone(),
+        in_reply_to: in_reply_to.clone(),
+        references: in_reply_to,
+        variables: BTreeMap::new(),
+        idempotency_key: None,
+        route: request.route.clone(),
+        list_unsubscribe: request.list_unsubscribe.clone(),
+    })?;
+    metrics.incr("replies_sent_manually");
+    metrics.record_email(
+        &format!("manual/{}", request.template),
+        &request.recipient,
+        &request.subject,
+        "sent manually",
+    );
+    if let Err(e) = outbox.record(&OutboxEntry {
+        at: Utc::now(),
+        recipient: request.recipient.clone(),
+        template: request.template.clone(),
+        subject: request.subject.clone(),
+        message_id: message_id.clone(),
+        sent_by: Some(principal.name.clone()),
+        feedback_id: None,
+        job_id: None,
+    }) {
+        error!("Unable to record the email to {} in the outbox: {}", request.recipient, e);
+    }
+    audit.record(
+        principal,
+        "email.send",
+        &request.recipient,
+        serde_json::Value::Null,
+        json!({
+            "template": request.template,
+            "subject": request.subject,
+            "in_reply_to": request.in_reply_to,
+            "message_id": message_id,
+        }),
+    )?;
+    Ok(message_id)
+}
+
+#[derive(Deserialize)]
+pub struct NewTemplate {
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    // The Handlebars source.
+    pub template: String,
+    #[serde(default)]
+    pub tag: Option<String>,
+    #[serde(default)]
+    pub comment: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct TemplateUpdate {
+    pub template: String,
+    #[serde(default)]
+    pub tag: Option<String>,
+    #[serde(default)]
+    pub comment: Option<String>,
+}
+
+// Names the version after when and by whom, unless it has a tag already,
+// as Mailgun wants a tag unique to the template.
+fn version_form(principal: &Principal, template: &str, tag: &Option<String>, comment: &Option<String>) -> Vec<(&'static str, String)> {
+    // The dashboard's forms send the fields left empty.
+    let tag = tag.clone().filter(|tag| !tag.is_empty())
+        .unwrap_or_else(|| format!("{}-{}", Utc::now().format("%Y%m%d%H%M%S"), principal.name));
+    let comment = comment.clone().filter(|comment| !comment.is_empty())
+        .unwrap_or_else(|| format!("Through limail by {}", principal.name));
+    vec![
+        ("template", String::from(template)),
+        ("tag", tag),
+        ("comment", comment),
+        ("engine", String::from("handlebars")),
+    ]
+}
+
+pub fn active_content(template: &serde_json::Value) -> serde_json::Value {
+    template["template"]["version"]["template"].clone()
+}
+
+// Mailgun's stored templates, without the dashboard login.
+pub fn templates(_principal: Principal, mailgun: Mailgun) -> Result<impl warp::Reply, Rejection> {
+    Ok(warp::reply::json(&mailgun.list_templates()?))
+}
+
+pub fn template(name: String, _principal: Principal, mailgun: Mailgun) -> Result<impl warp::Reply, Rejection> {
+    match mailgun.get_template(&name)? {
+        Some(template) => Ok(warp::reply::json(&template)),
+        None => Err(AdminError::NotFound(format!("Mailgun has no template {}", name)).into()),
+    }
+}
+
+// Shared by the admin API and the dashboard's template forms, like
+// change_blocklist.
+pub fn save_new_template(
+    principal: &Principal,
+    audit: &AuditLog,
+    mailgun: &Mailgun,
+    request: &NewTemplate,
+) -> Result<serde_json::Value, Rejection> {
+    let mut form = version_form(principal, &request.template, &request.tag, &request.comment);
+    form.push(("name", request.name.clone()));
+    if let Some(description) = request.description.as_ref().filter(|description| !description.is_empty()) {
+        form.push(("description", description.clone()));
+    }
+    let created = mailgun.create_template(&form)?;
+    audit.record(
+        principal,
+        "template.create",
+        &request.name,
+        serde_json::Value::Null,
+        json!({ "template": request.template, "description": request.description }),
+    )?;
+    Ok(created)
+}
+
+// A new active version, the old ones stay in Mailgun to go back to.
+pub fn save_template_version(
+    principal: &Principal,
+    audit: &AuditLog,
+    mailgun: &Mailgun,
+    name: &str,
+    request: &TemplateUpdate,
+) -> Result<serde_json::Value, Rejection> {
+    let previous = match mailgun.get_template(name)? {
+        Some(template) => active_content(&template),
+        None => return Err(AdminError::NotFound(format!("Mailgun has no template {}", name)).into()),
+    };
+    let mut form = version_form(principal, &request.template, &request.tag, &request.comment);
+    form.push(("active", String::from("yes")));
+    let updated = match mailgun.add_template_version(name, &form)? {
+        Some(updated) => updated,
+        None => return Err(AdminError::NotFound(format!("Mailgun has no template {}", name)).into()),
+    };
+    audit.record(
+        principal,
+        "template.update",
+        name,
+        json!({ "template": previous }),
+        json!({ "template": request.template }),
+    )?;
+    Ok(updated)
+}
+
+pub fn create_template(
+    principal: Principal,
+    audit: AuditLog,
+    mailgun: Mailgun,
+    request: NewTemplate,
+) -> Result<impl warp::Reply, Rejection> {
+    Ok(warp::reply::json(&save_new_template(&principal, &audit, &mailgun, &request)?))
+}
+
+pub fn update_template(
+    name: String,
+    principal: Principal,
+    audit: AuditLog,
+    mailgun: Mailgun,
+    request: TemplateUpdate,
+) -> Result<impl warp::Reply, Rejection> {
+    Ok(warp::reply::json(&save_template_version(&principal, &audit, &mailgun, &name, &request)?))
+}
+
+pub fn outbox(
+    _principal: Principal,
+    outbox: Outbox,
+    query: OutboxQuery,
+) -> Result<impl warp::Reply, Rejection> {
+    Ok(warp::reply::json(&outbox.query(&query)?))
+}
+
+#[derive(Deserialize)]
+pub struct CopyQuery {
+    pub message_id: String,
+    #[serde(default)]
+    pub remote_images: bool,
+}
+
+fn sent_copy(outbox: &Outbox, message_id: &str) -> Result<SentCopy, Rejection> {
+    match outbox.copy(message_id)? {
+        Some(copy) => Ok(copy),
+        None => Err(AdminError::NotFound(format!("No copy of the email {} was kept", message_id)).into()),
+    }
+}
+
+pub fn outbox_copy(
+    _principal: Principal,
+    outbox: Outbox,
+    query: CopyQuery,
+) -> Result<impl warp::Reply, Rejection> {
+    Ok(warp::reply::json(&sent_copy(&outbox, &query.message_id)?))
+}
+
+pub fn outbox_copy_html(
+    _principal: Principal,
+    outbox: Outbox,
+    query: CopyQuery,
+) -> Result<impl warp::Reply, Rejection> {
+    let copy = sent_copy(&outbox, &query.message_id)?;
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "text/html; charset=utf-8")
+        .header(CONTENT_SECURITY_POLICY, viewer::content_security_policy(query.remote_images))
+        .body(viewer::render_sent(&copy, query.remote_images))
+        .unwrap())
+}
+
+pub fn archived_email_html(
+    id: String,
+    _principal: Principal,
+    archive: Archive,
+    query: HtmlQuery,
+) -> Result<impl warp::Reply, Rejection> {
+    let archived = match archive.get(&id)? {
+        Some(archived) => archived,
+        None => return Err(AdminError::NotFound(format!("No archived email {}", id)).into()),
+    };
+    match viewer::render_html(&archived, query.remote_images) {
+        Some(html) => Ok(Response::builder()
+            .header(CONTENT_TYPE, "text/html; charset=utf-8")
+            .header(CONTENT_SECURITY_POLICY, viewer::content_security_policy(query.remote_images))
+            .body(html)
+            .unwrap()),
+        None => Err(AdminError::NotFound(format!("Archived email {} has no HTML part", id)).into()),
+    }
+}
+
+// Shared by the admin API and the Slack slash command. Safe to repeat if
+// Slack failed part of the way through.
+pub fn redact_email(
+    principal: &Principal,
+    audit: &AuditLog,
+    archive: &Archive,
+    slack: &Slack,
+    id: &str,
+    mode: RedactMode,
+) -> Result<Option<RedactResponse>, StoreError> {
+    let archived = match archive.redact(id, &principal.name)? {
+        Some(archived) => archived,
+        None => return Ok(None),
+    };
+    let placeholder = format!("_Redacted by {}_", principal.name);
+    let calls: Vec<Call<Result<(), SlackError>>> = archived.slack_messages.iter()
+        .map(|message| {
+            let (slack, message, placeholder) = (slack.clone(), message.clone(), placeholder.clone());
+            Box::new(move || match mode {
+                RedactMode::Delete => slack.delete_message(&message.channel, &message.ts),
+                RedactMode::Redact => slack.update_message(&message.channel, &message.ts, &placeholder),
+            }) as Call<_>
+        })
+        .collect();
+    let results = fanout::join(calls, slack.timeout + Duration::from_secs(1));
+    let slack_failures: Vec<String> = archived.slack_messages.iter().zip(results)
+        .filter_map(|(message, result)| match result {
+            Some(Ok(())) => None,
+            Some(Err(e)) => Some(format!("{} {}: {}", message.channel, message.ts, e)),
+            None => Some(format!("{} {}: Slack didn't answer in time", message.channel, message.ts)),
+        })
+        .collect();
+    audit.record(
+        principal,
+        "email.redact",
+        id,
+        serde_json::Value::Null,
+        json!({
+            "mode": format!("{:?}", mode).to_lowercase(),
+            "slack_messages": archived.slack_messages.len(),
+            "slack_failures": slack_failures,
+        }),
+    )?;
+    Ok(Some(RedactResponse {
+        id: String::from(id),
+        slack_messages: archived.slack_messages.len(),
+        slack_failures,
+    }))
+}
+
+pub fn redact(
+    id: String,
+    principal: Principal,
+    audit: AuditLog,
+    archive: Archive,
+    slack: Slack,
+    request: RedactRequest,
+) -> Result<impl warp::Reply, Rejection> {
+    match redact_email(&principal, &audit, &archive, &slack, &id, request.mode)? {
+        Some(response) => Ok(warp::reply::json(&response)),
+        None => Err(AdminError::NotFound(format!("No archived email {}", id)).into()),
+    }
+}
+
+pub fn maintenance_status(_principal: Principal, maintenance: Maintenance) -> impl warp::Reply {
+    warp::reply::json(&maintenance.state())
+}
+
+pub fn start_maintenance(
+    principal: Principal,
+    audit: AuditLog,
+    maintenance: Maintenance,
+) -> Result<impl warp::Reply, Rejection> {
+    if maintenance.enable(&principal.name)? {
+        audit.record(&principal, "maintenance.start", "maintenance", json!({ "enabled": false }), json!({ "enabled": true }))?;
+    }
+    Ok(warp::reply::json(&maintenance.state()))
+}
+
+// Jobs held back while delivering inline go out in the background, in the
+// order they arrived. Queued jobs are picked up by the workers by themselves.
+pub fn end_maintenance(
+    principal: Principal,
+    audit: AuditLog,
+    archive: Archive,
+    pipeline: Pipeline,
+) -> Result<impl warp::Reply, Rejection> {
+    let was_enabled = pipeline.maintenance.is_enabled();
+    let held = pipeline.maintenance.disable()?;
+    if was_enabled {
+        audit.record(
+            &principal,
+            "maintenance.end",
+            "maintenance",
+            json!({ "enabled": true }),
+            json!({ "enabled": false, "held": held.len() }),
+        )?;
+    }
+    let held_count = held.len();
+    thread::spawn(move || {
+        for id in held {
+            match archive.get(&id) {
+                Ok(Some(archived)) => {
+                    if let Err(e) = pipeline.process(&archived.job) {
+                        error!("Held job {} failed: {}", id, e);
+                    }
+                },
+                Ok(None) => error!("Held job {} is missing from the archive", id),
+                Err(e) => error!("Unable to read held job {}: {}", id, e),
+            }
+        }
+    });
+    Ok(warp::reply::json(&MaintenanceEnded {
+        state: pipeline.maintenance.state(),
+        delivering: held_count,
+    }))
+}
+
+pub fn variants(_principal: Principal, pipeline: Pipeline) -> impl warp::Reply {
+    warp::reply::json(&pipeline.variants.stats())
+}
+
+pub fn feedback(_principal: Principal, pipeline: Pipeline) -> Result<impl warp::Reply, Rejection> {
+    match &pipeline.feedback {
+        Some(feedback) => Ok(warp::reply::json(&feedback.summary()?)),
+        None => Err(AdminError::NotFound(String::from("Feedback links aren't configured")).into()),
+    }
+}
+
+// The replies the rate limiter held back, newest first.
+pub fn suppressions(_principal: Principal, pipeline: Pipeline, query: SuppressionQuery) -> Result<impl warp::Reply, Rejection> {
+    Ok(warp::reply::json(&pipeline.suppressions.query(&query)?))
+}
+
+// How soon after an auto-reply the same senders write again, for choosing
+// TIME_BETWEEN_RESPONSES_MINUTES.
+pub fn suppressions_summary(_principal: Principal, pipeline: Pipeline, query: SuppressionQuery) -> Result<impl warp::Reply, Rejection> {
+    let auto_replies = pipeline.outbox.auto_replies_since(query.since)?;
+    let window_minutes = pipeline.last_response_log.time_between_responses.0;
+    Ok(warp::reply::json(&pipeline.suppressions.summary(&query, &auto_replies, window_minutes)?))
+}
+
+#[derive(Deserialize)]
+pub struct SenderQuery {
+    pub address: String,
+}
+
+#[derive(Serialize)]
+pub struct SenderReputation {
+    #[serde(flatten)]
+    history: SenderHistory,
+    score: i64,
+}
+
+pub fn sender(_principal: Principal, query: SenderQuery, pipeline: Pipeline) -> Result<impl warp::Reply, Rejection> {
+    let reputation = match &pipeline.reputation {
+        Some(reputation) => reputation,
+        None => return Err(AdminError::NotFound(String::from("Sender reputation isn't configured")).into()),
+    };
+    match reputation.get(&query.address) {
+        Some(history) => Ok(warp::reply::json(&SenderReputation {
+            score: history.score(),
+            history,
+        })),
+        None => Err(AdminError::NotFound(format!("Nothing received from {}", query.address)).into()),
+    }
+}
+
+fn sending_domains(mailgun: &Mailgun) -> Result<&SendingDomains, Rejection> {
+    mailgun.domains.as_ref()
+        .ok_or_else(|| AdminError::NotFound(String::from("Sending domains aren't configured")).into())
+}
+
+pub fn domains(_principal: Principal, mailgun: Mailgun) -> Result<impl warp::Reply, Rejection> {
+    Ok(warp::reply::json(&sending_domains(&mailgun)?.states()))
+}
+
+// Takes a domain out of rotation (or puts it back), e.g. while its
+// reputation recovers.
+pub fn pause_domain(
+    domain: String,
+    paused: bool,
+    principal: Principal,
+    audit: AuditLog,
+    mailgun: Mailgun,
+) -> Result<impl warp::Reply, Rejection> {
+    let domains = sending_domains(&mailgun)?;
+    let was_paused = domains.states().get(&domain).map_or(false, |state| state.paused);
+    if !domains.set_paused(&domain, paused) {
+        return Err(AdminError::NotFound(format!("{} isn't a sending domain", domain)).into());
+    }
+    if was_paused != paused {
+        audit.record(
+            &principal,
+            if paused { "domain.pause" } else { "domain.resume" },
+            &domain,
+            json!({ "paused": was_paused }),
+            json!({ "paused": paused }),
+        )?;
+    }
+    Ok(warp::reply::json(&domains.states().get(&domain)))
+}
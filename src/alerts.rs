@@ -0,0 +1,160 @@
+use std::collections::{BTreeSet, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Serialize, Deserialize};
+use serde_json::json;
+
+use crate::clock::Clock;
+use crate::metrics::Metrics;
+use crate::notices::Notices;
+use crate::pipeline::route_matches;
+
+// Nothing is ever looked at further back than the longest window, this just
+// bounds memory if a rule has a silly one.
+const EVENT_LIMIT: usize = 10000;
+
+fn default_outcome() -> String {
+    String::from("failed")
+}
+
+#[derive(Deserialize, Clone)]
+pub struct AlertRule {
+    pub name: String,
+    // An exact route like forward/slack/C0123, or a prefix ending in *. Every
+    // route when missing.
+    #[serde(default)]
+    pub route: Option<String>,
+    #[serde(default = "default_outcome")]
+    pub outcome: String,
+    // Fires once there are more than this many matching emails in the window.
+    pub threshold: usize,
+    pub window_minutes: i64,
+    // Slack channel to post to when the alert fires and resolves.
+    #[serde(default)]
+    pub channel: Option<String>,
+    // Whether /ready reports degraded while the alert is firing.
+    #[serde(default)]
+    pub degrade: bool,
+}
+
+impl AlertRule {
+    fn matches(&self, route: &str, outcome: &str) -> bool {
+        self.route.as_ref().map_or(true, |pattern| route_matches(pattern, route))
+            && self.outcome == outcome
+    }
+}
+
+struct Event {
+    at: DateTime<Utc>,
+    route: String,
+    outcome: String,
+}
+
+#[derive(Serialize)]
+pub struct Readiness {
+    pub status: &'static str,
+    pub alerts: Vec<String>,
+}
+
+// Watches the outcome of every processed email and fires the configured rules.
+#[derive(Clone)]
+pub struct Alerts {
+    rules: Arc<Vec<AlertRule>>,
+    events: Arc<Mutex<VecDeque<Event>>>,
+    firing: Arc<Mutex<BTreeSet<String>>>,
+    notices: Notices,
+    metrics: Metrics,
+    clock: Clock,
+}
+
+impl Alerts {
+    pub fn new(rules: Vec<AlertRule>, notices: Notices, metrics: Metrics) -> Alerts {
+        Alerts {
+            rules: Arc::new(rules),
+            events: Arc::new(Mutex::new(VecDeque::new())),
+            firing: Arc::new(Mutex::new(BTreeSet::new())),
+            notices,
+            metrics,
+            clock: Clock::system(),
+        }
+    }
+
+    pub fn with_clock(self, clock: Clock) -> Alerts {
+        Alerts {
+            clock,
+            ..self
+        }
+    }
+
+    pub fn observe(&self, route: &str, outcome: &str) {
+        if !self.rules.iter().any(|rule| rule.matches(route, outcome)) {
+            return;
+        }
+        {
+            let mut events = self.events.lock().unwrap();
+            if events.len() >= EVENT_LIMIT {
+                events.pop_front();
+            }
+            events.push_back(Event {
+                at: self.clock.now(),
+                route: String::from(route),
+                outcome: String::from(outcome),
+            });
+        }
+        self.evaluate();
+    }
+
+    // Also called from /ready, so alerts resolve even when no more email arrives.
+    pub fn evaluate(&self) {
+        let now = self.clock.now();
+        let mut events = self.events.lock().unwrap();
+        let longest = self.rules.iter().map(|rule| rule.window_minutes).max().unwrap_or(0);
+        while events.front().map_or(false, |event| now - event.at > Duration::minutes(longest)) {
+            events.pop_front();
+        }
+
+        let mut firing = self.firing.lock().unwrap();
+        for rule in self.rules.iter() {
+            let since = now - Duration::minutes(rule.window_minutes);
+            let count = events.iter()
+                .filter(|event| event.at >= since && rule.matches(&event.route, &event.outcome))
+                .count();
+            let was_firing = firing.contains(&rule.name);
+            if count > rule.threshold && !was_firing {
+                firing.insert(rule.name.clone());
+                self.metrics.incr("alerts_fired");
+                warn!("Alert {} fired: {} {} emails in {} minutes", rule.name, count, rule.outcome, rule.window_minutes);
+                self.notify(rule, self.notices.render("alert_fired", &json!({
+                    "name": rule.name,
+                    "count": count,
+                    "outcome": rule.outcome,
+                    "window_minutes": rule.window_minutes,
+                })));
+            } else if count <= rule.threshold && was_firing {
+                firing.remove(&rule.name);
+                info!("Alert {} resolved", rule.name);
+                self.notify(rule, self.notices.render("alert_resolved", &json!({ "name": rule.name })));
+            }
+        }
+    }
+
+    fn notify(&self, rule: &AlertRule, text: String) {
+        if let Some(channel) = &rule.channel {
+            self.notices.post(channel.clone(), text);
+        }
+    }
+
+    pub fn readiness(&self) -> Readiness {
+        self.evaluate();
+        let firing = self.firing.lock().unwrap();
+        let degrading: Vec<String> = self.rules.iter()
+            .filter(|rule| rule.degrade && firing.contains(&rule.name))
+            .map(|rule| rule.name.clone())
+            .collect();
+        Readiness {
+            status: if degrading.is_empty() { "ok" } else { "degraded" },
+            alerts: firing.iter().cloned().collect(),
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use serde::Deserialize;
+
+fn default_requests_per_minute() -> u64 {
+    60
+}
+
+fn default_burst() -> u64 {
+    30
+}
+
+// A token bucket per admin token: up to burst requests at once, refilled
+// at requests_per_minute. Counted per process.
+#[derive(Deserialize, Clone)]
+pub struct ApiLimitConfig {
+    #[serde(default = "default_requests_per_minute")]
+    pub requests_per_minute: u64,
+    #[serde(default = "default_burst")]
+    pub burst: u64,
+}
+
+// What the RateLimit-* headers report.
+#[derive(Clone, Copy, Debug)]
+pub struct Quota {
+    pub limit: u64,
+    pub remaining: u64,
+    // Until the bucket is full again.
+    pub reset_seconds: u64,
+    // Until the next request is allowed, 0 if it already is.
+    pub retry_after_seconds: u64,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Clone)]
+pub struct ApiLimiter {
+    config: Arc<ApiLimitConfig>,
+    buckets: Arc<Mutex<BTreeMap<String, Bucket>>>,
+}
+
+impl ApiLimiter {
+    pub fn new(config: ApiLimitConfig) -> ApiLimiter {
+        ApiLimiter {
+            config: Arc::new(config),
+            buckets: Arc::new(Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    fn per_second(&self) -> f64 {
+        self.config.requests_per_minute.max(1) as f64 / 60.0
+    }
+
+    fn quota(&self, tokens: f64) -> Quota {
+        let per_second = self.per_second();
+        let capacity = self.config.burst.max(1) as f64;
+        Quota {
+            limit: self.config.burst.max(1),
+            remaining: tokens.floor() as u64,
+            reset_seconds: ((capacity - tokens) / per_second).ceil() as u64,
+            retry_after_seconds: if tokens >= 1.0 { 0 } else { ((1.0 - tokens) / per_second).ceil() as u64 },
+        }
+    }
+
+    fn refill(&self, bucket: &mut Bucket) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.updated);
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_millis()) / 1000.0;
+        bucket.tokens = (bucket.tokens + elapsed * self.per_second()).min(self.config.burst.max(1) as f64);
+        bucket.updated = now;
+    }
+
+    // Takes a request out of name's bucket, or says how long to wait.
+    pub fn take(&self, name: &str) -> Result<Quota, Quota> {
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(String::from(name)).or_insert_with(|| Bucket {
+            tokens: self.config.burst.max(1) as f64,
+            updated: Instant::now(),
+        });
+        self.refill(bucket);
+        if bucket.tokens < 1.0 {
+            return Err(self.quota(bucket.tokens));
+        }
+        bucket.tokens -= 1.0;
+        Ok(self.quota(bucket.tokens))
+    }
+
+    // Where name stands, without using anything up.
+    pub fn peek(&self, name: &str) -> Quota {
+        let mut buckets = self.buckets.lock().unwrap();
+        match buckets.get_mut(name) {
+            Some(bucket) => {
+                self.refill(bucket);
+                self.quota(bucket.tokens)
+            },
+            None => self.quota(self.config.burst.max(1) as f64),
+        }
+    }
+}
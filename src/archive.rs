@@ -0,0 +1,394 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+
+use crate::encryption::{self, Sealer};
+use crate::multipart::Part;
+use crate::pipeline::{Action, Job};
+use crate::store::{self, StoreError};
+use crate::viewer;
+
+pub const REDACTED: &str = "[redacted]";
+
+// Headers that say nothing about the content, kept so threading still works.
+const REDACTION_KEEPS_HEADERS: &[&str] = &["message-id", "in-reply-to", "references", "date", "from"];
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ArchivedOutcome {
+    pub at: DateTime<Utc>,
+    pub action: Action,
+    pub outcome: String,
+    #[serde(default)]
+    pub replayed_by: Option<String>,
+}
+
+// The contents live next to the email, in <id>.attachments/<index>.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ArchivedAttachment {
+    pub filename: String,
+    pub content_type: Option<String>,
+    pub size: usize,
+}
+
+// Where a forward ended up, so it can be deleted again.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ArchivedSlackMessage {
+    pub channel: String,
+    pub ts: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Redaction {
+    pub at: DateTime<Utc>,
+    pub by: String,
+}
+
+// What Mailgun was answered once the email was processed, which answers
+// its retries too.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ArchivedResponse {
+    pub at: DateTime<Utc>,
+    pub body: Value,
+}
+
+// Someone reacted to the forward in Slack, see handling.rs.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Handled {
+    pub at: DateTime<Utc>,
+    pub by: String,
+}
+
+// Every verified inbound email, kept as the job it arrived as, along with
+// what happened each time it was processed.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ArchivedEmail {
+    pub job: Job,
+    #[serde(default)]
+    pub attachments: Vec<ArchivedAttachment>,
+    // Bytes of the webhook it arrived in, unknown for older emails.
+    #[serde(default)]
+    pub payload_size: Option<usize>,
+    #[serde(default)]
+    pub outcomes: Vec<ArchivedOutcome>,
+    #[serde(default)]
+    pub slack_messages: Vec<ArchivedSlackMessage>,
+    // The steps of delivering it that are done, so a retry picks up after
+    // them. Posting to Slack is done once it's in slack_messages.
+    #[serde(default)]
+    pub completed_steps: Vec<String>,
+    #[serde(default)]
+    pub response: Option<ArchivedResponse>,
+    #[serde(default)]
+    pub redacted: Option<Redaction>,
+    #[serde(default)]
+    pub handled: Option<Handled>,
+    #[serde(default)]
+    pub first_reaction: Option<Handled>,
+}
+
+#[derive(Clone)]
+pub struct Archive {
+    dir: PathBuf,
+    write_lock: Arc<Mutex<()>>,
+    sealer: Option<Sealer>,
+}
+
+impl Archive {
+    pub fn new(dir: PathBuf) -> Archive {
+        Archive {
+            dir,
+            write_lock: Arc::new(Mutex::new(())),
+            sealer: None,
+        }
+    }
+
+    // Emails and attachments are written encrypted from now on, see
+    // encryption.rs.
+    pub fn encrypted(self, sealer: Sealer) -> Archive {
+        Archive {
+            sealer: Some(sealer),
+            ..self
+        }
+    }
+
+    fn read_file(&self, path: &Path) -> Result<Option<Vec<u8>>, StoreError> {
+        let bytes = match store::read_bytes(path)? {
+            Some(bytes) => bytes,
+            None => return Ok(None),
+        };
+        match &self.sealer {
+            Some(sealer) => Ok(Some(sealer.open(bytes)?)),
+            None if encryption::is_sealed(&bytes) => {
+                Err(StoreError::IoError(format!("{} is encrypted but no archive keys are configured", path.display())))
+            },
+            None => Ok(Some(bytes)),
+        }
+    }
+
+    fn write_file(&self, path: &Path, bytes: &[u8]) -> Result<(), StoreError> {
+        match &self.sealer {
+            Some(sealer) => store::write_bytes(path, &sealer.seal(bytes)?),
+            None => store::write_bytes(path, bytes),
+        }
+    }
+
+    fn read(&self, path: &Path) -> Result<Option<ArchivedEmail>, StoreError> {
+        match self.read_file(path)? {
+            Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn write(&self, path: &Path, archived: &ArchivedEmail) -> Result<(), StoreError> {
+        self.write_file(path, &serde_json::to_vec_pretty(archived)?)
+    }
+
+    // Ids are hex digests, anything else can't be ours (and mustn't be
+    // allowed to wander around the filesystem).
+    fn path(&self, id: &str) -> Option<PathBuf> {
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        Some(self.dir.join(format!("{}.json", id)))
+    }
+
+    fn attachment_path(&self, id: &str, index: usize) -> Option<PathBuf> {
+        self.path(id)?;
+        Some(self.dir.join(format!("{}.attachments", id)).join(index.to_string()))
+    }
+
+    // Mailgun retries carry the same job id, keep what we already have and
+    // return it.
+    pub fn store(&self, job: &Job, attachments: &[Part], payload_size: usize) -> Result<Option<ArchivedEmail>, StoreError> {
+        let path = match self.path(&job.id) {
+            Some(path) => path,
+            None => return Err(StoreError::IoError(format!("Invalid archive id {}", job.id))),
+        };
+        let _guard = self.write_lock.lock().unwrap();
+        if let Some(archived) = self.read(&path)? {
+            return Ok(Some(archived));
+        }
+        let mut archived_attachments = Vec::new();
+        for (index, part) in attachments.iter().enumerate() {
+            if let Some(attachment_path) = self.attachment_path(&job.id, index) {
+                self.write_file(&attachment_path, &part.data)?;
+            }
+            archived_attachments.push(ArchivedAttachment {
+                filename: part.filename.clone().unwrap_or_else(|| part.name.clone()),
+                content_type: part.content_type.clone(),
+                size: part.data.len(),
+            });
+        }
+        self.write(&path, &ArchivedEmail {
+            job: job.clone(),
+            attachments: archived_attachments,
+            payload_size: Some(payload_size),
+            outcomes: Vec::new(),
+            slack_messages: Vec::new(),
+            completed_steps: Vec::new(),
+            response: None,
+            redacted: None,
+            handled: None,
+            first_reaction: None,
+        })?;
+        Ok(None)
+    }
+
+    pub fn attachment(&self, id: &str, index: usize) -> Result<Option<(ArchivedAttachment, Vec<u8>)>, StoreError> {
+        let attachment = match self.get(id)?.and_then(|archived| archived.attachments.get(index).cloned()) {
+            Some(attachment) => attachment,
+            None => return Ok(None),
+        };
+        match self.attachment_path(id, index) {
+            Some(path) => Ok(self.read_file(&path)?.map(|data| (attachment, data))),
+            None => Ok(None),
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Result<Option<ArchivedEmail>, StoreError> {
+        match self.path(id) {
+            Some(path) => self.read(&path),
+            None => Ok(None),
+        }
+    }
+
+    // Reads every archived email, so only for the occasional report.
+    pub fn received_since(&self, since: DateTime<Utc>) -> Result<Vec<ArchivedEmail>, StoreError> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(StoreError::IoError(format!("Unable to list {}: {}", self.dir.display(), e))),
+        };
+        let mut emails = Vec::new();
+        for entry in entries {
+            let path = match entry {
+                Ok(entry) => entry.path(),
+                Err(e) => return Err(StoreError::IoError(format!("Unable to list {}: {}", self.dir.display(), e))),
+            };
+            if path.extension().map_or(true, |extension| extension != "json") {
+                continue;
+            }
+            if let Some(archived) = self.read(&path)? {
+                if archived.job.received_at >= since {
+                    emails.push(archived);
+                }
+            }
+        }
+        Ok(emails)
+    }
+
+    pub fn record_outcome(&self, job: &Job, outcome: &str) -> Result<(), StoreError> {
+        let path = match self.path(&job.id) {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let _guard = self.write_lock.lock().unwrap();
+        // The worker may not share a disk with the frontend that archived it.
+        let mut archived: ArchivedEmail = match self.read(&path)? {
+            Some(archived) => archived,
+            None => return Ok(()),
+        };
+        archived.outcomes.push(ArchivedOutcome {
+            at: Utc::now(),
+            action: job.action.clone(),
+            outcome: String::from(outcome),
+            replayed_by: job.replayed_by.clone(),
+        });
+        self.write(&path, &archived)
+    }
+
+    pub fn record_slack_messages(&self, job: &Job, messages: Vec<ArchivedSlackMessage>) -> Result<(), StoreError> {
+        let path = match self.path(&job.id) {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let _guard = self.write_lock.lock().unwrap();
+        let mut archived: ArchivedEmail = match self.read(&path)? {
+            Some(archived) => archived,
+            None => return Ok(()),
+        };
+        archived.slack_messages.extend(messages);
+        self.write(&path, &archived)
+    }
+
+    pub fn record_step(&self, job: &Job, step: &str) -> Result<(), StoreError> {
+        self.update(&job.id, |archived| {
+            if !archived.completed_steps.iter().any(|done| done == step) {
+                archived.completed_steps.push(String::from(step));
+            }
+        })
+    }
+
+    pub fn record_response(&self, job: &Job, body: Value) -> Result<(), StoreError> {
+        self.update(&job.id, |archived| archived.response = Some(ArchivedResponse {
+            at: Utc::now(),
+            body,
+        }))
+    }
+
+    fn update(&self, id: &str, change: impl FnOnce(&mut ArchivedEmail)) -> Result<(), StoreError> {
+        let path = match self.path(id) {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let _guard = self.write_lock.lock().unwrap();
+        let mut archived: ArchivedEmail = match self.read(&path)? {
+            Some(archived) => archived,
+            None => return Ok(()),
+        };
+        change(&mut archived);
+        self.write(&path, &archived)
+    }
+
+    pub fn mark_handled(&self, id: &str, by: &str) -> Result<(), StoreError> {
+        self.update(id, |archived| archived.handled = Some(Handled {
+            at: Utc::now(),
+            by: String::from(by),
+        }))
+    }
+
+    pub fn record_first_reaction(&self, id: &str, by: &str) -> Result<(), StoreError> {
+        self.update(id, |archived| {
+            if archived.first_reaction.is_none() {
+                archived.first_reaction = Some(Handled {
+                    at: Utc::now(),
+                    by: String::from(by),
+                });
+            }
+        })
+    }
+
+    // Re-encrypts everything not yet sealed with the current key, including
+    // what was archived before encryption was turned on. Returns how many
+    // files were rewritten.
+    pub fn rekey(&self) -> Result<usize, StoreError> {
+        let sealer = match &self.sealer {
+            Some(sealer) => sealer,
+            None => return Err(StoreError::IoError(String::from("No archive keys are configured"))),
+        };
+        let list = |dir: &Path| -> Result<Vec<PathBuf>, StoreError> {
+            match fs::read_dir(dir) {
+                Ok(entries) => Ok(entries.filter_map(|entry| entry.ok().map(|entry| entry.path())).collect()),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+                Err(e) => Err(StoreError::IoError(format!("Unable to list {}: {}", dir.display(), e))),
+            }
+        };
+        let mut files = Vec::new();
+        for path in list(&self.dir)? {
+            if path.is_dir() {
+                files.extend(list(&path)?);
+            } else if path.extension().map_or(false, |extension| extension == "json") {
+                files.push(path);
+            }
+        }
+        let _guard = self.write_lock.lock().unwrap();
+        let mut rekeyed = 0;
+        for path in files {
+            let bytes = match store::read_bytes(&path)? {
+                Some(bytes) => bytes,
+                None => continue,
+            };
+            if sealer.is_current(&bytes) {
+                continue;
+            }
+            store::write_bytes(&path, &sealer.seal(&sealer.open(bytes)?)?)?;
+            rekeyed += 1;
+        }
+        Ok(rekeyed)
+    }
+
+    // Scrubs the subject, bodies, most headers and the attachments, keeping
+    // enough to show that the email existed and where it was forwarded.
+    pub fn redact(&self, id: &str, by: &str) -> Result<Option<ArchivedEmail>, StoreError> {
+        let path = match self.path(id) {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let _guard = self.write_lock.lock().unwrap();
+        let mut archived: ArchivedEmail = match self.read(&path)? {
+            Some(archived) => archived,
+            None => return Ok(None),
+        };
+        let email = &mut archived.job.email;
+        email.subject = String::from(REDACTED);
+        email.body_plain = String::from(REDACTED);
+        email.body_html = None;
+        let headers: Vec<(String, String)> = viewer::headers(&email.message_headers).into_iter()
+            .filter(|(name, _)| REDACTION_KEEPS_HEADERS.contains(&&name.to_lowercase()[..]))
+            .collect();
+        email.message_headers = serde_json::to_string(&headers)?;
+        store::remove_dir(&self.dir.join(format!("{}.attachments", id)))?;
+        archived.attachments.clear();
+        archived.redacted = Some(Redaction {
+            at: Utc::now(),
+            by: String::from(by),
+        });
+        self.write(&path, &archived)?;
+        Ok(Some(archived))
+    }
+}
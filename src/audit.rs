@@ -0,0 +1,84 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+
+use crate::auth::Principal;
+use crate::store::{self, StoreError};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AuditEntry {
+    pub at: DateTime<Utc>,
+    pub actor: String,
+    pub action: String,
+    pub target: String,
+    pub previous: Value,
+    pub new: Value,
+}
+
+#[derive(Deserialize, Default)]
+pub struct AuditQuery {
+    pub actor: Option<String>,
+    pub action: Option<String>,
+    pub target: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub limit: Option<usize>,
+}
+
+impl AuditQuery {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        self.actor.as_ref().map_or(true, |actor| actor == &entry.actor)
+            && self.action.as_ref().map_or(true, |action| action == &entry.action)
+            && self.target.as_ref().map_or(true, |target| target == &entry.target)
+            && self.since.map_or(true, |since| entry.at >= since)
+    }
+}
+
+// Append-only record of every change made through the dashboard or admin API.
+#[derive(Clone)]
+pub struct AuditLog {
+    path: PathBuf,
+    write_lock: Arc<Mutex<()>>,
+}
+
+impl AuditLog {
+    pub fn new(path: PathBuf) -> AuditLog {
+        AuditLog {
+            path,
+            write_lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    pub fn record(
+        &self,
+        principal: &Principal,
+        action: &str,
+        target: &str,
+        previous: Value,
+        new: Value,
+    ) -> Result<(), StoreError> {
+        let entry = AuditEntry {
+            at: Utc::now(),
+            actor: principal.name.clone(),
+            action: String::from(action),
+            target: String::from(target),
+            previous,
+            new,
+        };
+        info!("audit: {} {} {}", entry.actor, entry.action, entry.target);
+        let _guard = self.write_lock.lock().unwrap();
+        store::append_json_line(&self.path, &entry)
+    }
+
+    // Newest first.
+    pub fn query(&self, query: &AuditQuery) -> Result<Vec<AuditEntry>, StoreError> {
+        let entries: Vec<AuditEntry> = store::read_json_lines(&self.path)?;
+        Ok(entries.into_iter()
+            .rev()
+            .filter(|entry| query.matches(entry))
+            .take(query.limit.unwrap_or(100))
+            .collect())
+    }
+}
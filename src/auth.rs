@@ -0,0 +1,168 @@
+use std::error::Error as StdError;
+use std::fmt::{self, Display};
+use std::sync::Arc;
+
+use serde::Deserialize;
+use warp::{Filter, Rejection};
+
+use crate::apilimit::{ApiLimiter, Quota};
+use crate::config::TokenConfig;
+
+#[derive(Debug)]
+pub enum AuthError {
+    Unauthorized(String),
+    Forbidden(String),
+    RateLimited(String, Quota),
+}
+impl std::convert::From<AuthError> for Rejection {
+    fn from(err: AuthError) -> Rejection {
+        warp::reject::custom(err)
+    }
+}
+
+impl Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            AuthError::Unauthorized(s) => s,
+            AuthError::Forbidden(s) => s,
+            AuthError::RateLimited(s, _) => s,
+        })
+    }
+}
+impl StdError for AuthError {}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Scope {
+    ReadStats,
+    ManageBlocklist,
+    SendEmail,
+    Redact,
+    Maintenance,
+    ManageTemplates,
+    ManageRoutes,
+}
+
+impl Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Scope::ReadStats => "read-stats",
+            Scope::ManageBlocklist => "manage-blocklist",
+            Scope::SendEmail => "send-email",
+            Scope::Redact => "redact",
+            Scope::Maintenance => "maintenance",
+            Scope::ManageTemplates => "manage-templates",
+            Scope::ManageRoutes => "manage-routes",
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Principal {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+}
+
+#[derive(Clone)]
+pub struct Tokens {
+    tokens: Arc<Vec<TokenConfig>>,
+    limiter: Option<ApiLimiter>,
+}
+
+impl Tokens {
+    pub fn new(tokens: Vec<TokenConfig>) -> Tokens {
+        Tokens {
+            tokens: Arc::new(tokens),
+            limiter: None,
+        }
+    }
+
+    pub fn limited(self, limiter: ApiLimiter) -> Tokens {
+        Tokens {
+            limiter: Some(limiter),
+            ..self
+        }
+    }
+
+    // Every configured token is compared so the time taken doesn't depend on
+    // which one (if any) matched.
+    fn find(&self, presented: &str) -> Option<Principal> {
+        self.tokens.iter().fold(None, |found, token| {
+            if constant_time_eq(presented.as_bytes(), token.token.as_bytes()) && found.is_none() {
+                Some(Principal {
+                    name: token.name.clone(),
+                    scopes: token.scopes.clone(),
+                })
+            } else {
+                found
+            }
+        })
+    }
+}
+
+// Accepts both `Bearer <token>` (for scripts) and HTTP basic auth with the
+// token as the password (so a browser can prompt for it).
+fn presented_token(header: &str) -> Option<String> {
+    let mut parts = header.trim().splitn(2, ' ');
+    match (parts.next(), parts.next()) {
+        (Some(scheme), Some(value)) if scheme.eq_ignore_ascii_case("bearer") => {
+            Some(String::from(value.trim()))
+        },
+        (Some(scheme), Some(value)) if scheme.eq_ignore_ascii_case("basic") => {
+            let decoded = base64::decode(value.trim()).ok()?;
+            let decoded = String::from_utf8(decoded).ok()?;
+            decoded.splitn(2, ':').nth(1).map(String::from)
+        },
+        _ => None
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// Without any configured tokens the protected routes simply don't exist.
+pub fn authenticate(tokens: Tokens) -> impl Filter<Extract = (Principal,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| -> Result<Principal, Rejection> {
+            if tokens.tokens.is_empty() {
+                return Err(warp::reject::not_found());
+            }
+            let principal = header.as_ref()
+                .and_then(|h| presented_token(h))
+                .and_then(|presented| tokens.find(&presented))
+                .ok_or_else(|| AuthError::Unauthorized(String::from("Missing or invalid credentials")))?;
+            if let Some(limiter) = &tokens.limiter {
+                limiter.take(&principal.name).map_err(|quota| AuthError::RateLimited(
+                    format!("Token {} is over its rate limit, retry in {}s", principal.name, quota.retry_after_seconds),
+                    quota,
+                ))?;
+            }
+            Ok(principal)
+        })
+}
+
+// The quota of the token a request presented, once it's been handled, for
+// its RateLimit-* headers.
+pub fn quota(tokens: Tokens) -> impl Filter<Extract = (Option<Quota>,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .map(move |header: Option<String>| {
+            let limiter = tokens.limiter.as_ref()?;
+            let principal = tokens.find(&presented_token(&header?)?)?;
+            Some(limiter.peek(&principal.name))
+        })
+}
+
+pub fn require(tokens: Tokens, scope: Scope) -> impl Filter<Extract = (Principal,), Error = Rejection> + Clone {
+    authenticate(tokens)
+        .and_then(move |principal: Principal| -> Result<Principal, Rejection> {
+            if principal.scopes.contains(&scope) {
+                Ok(principal)
+            } else {
+                Err(AuthError::Forbidden(format!("Token {} lacks the {} scope", principal.name, scope)).into())
+            }
+        })
+}
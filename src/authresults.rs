@@ -0,0 +1,123 @@
+use serde::Deserialize;
+
+use crate::mailgun::MailgunEmailReceived;
+use crate::viewer;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Pass,
+    Fail,
+    // none, neutral, temperror or simply not checked.
+    Unknown,
+}
+
+impl Verdict {
+    fn parse(result: &str) -> Verdict {
+        match &result.trim().to_lowercase()[..] {
+            "pass" => Verdict::Pass,
+            "fail" | "softfail" | "hardfail" | "permerror" | "policy" => Verdict::Fail,
+            _ => Verdict::Unknown,
+        }
+    }
+
+    fn symbol(self) -> &'static str {
+        match self {
+            Verdict::Pass => "✅",
+            Verdict::Fail => "❌",
+            Verdict::Unknown => "➖",
+        }
+    }
+}
+
+// Anyone can put an Authentication-Results header in their email, so only
+// those added by one of trusted_authserv_ids (Mailgun's own, say
+// "mxa.mailgun.org", or the MX in front of it) are believed. Without any,
+// every email is unverified.
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct AuthResultsConfig {
+    pub trusted_authserv_ids: Vec<String>,
+}
+
+// Whether the sender's domain vouches for the email, so "from a partner
+// org" can be told apart from someone pretending to be one. `trusted` is
+// false when no trusted server checked it, and then all three are Unknown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuthResults {
+    pub spf: Verdict,
+    pub dkim: Verdict,
+    pub dmarc: Verdict,
+    pub trusted: bool,
+}
+
+// The server that added an Authentication-Results header (RFC 8601), the
+// `mx.example.org` of `mx.example.org 1; spf=pass ...`.
+fn authserv_id(header: &str) -> &str {
+    header.split(';').next().and_then(|id| id.split_whitespace().next()).unwrap_or("")
+}
+
+// `method=result` pairs from an Authentication-Results header, e.g.
+// `mx.example.org; spf=pass smtp.mailfrom=...; dkim=pass header.d=...`
+fn method_results(header: &str) -> Vec<(String, Verdict)> {
+    header.split(';')
+        .skip(1)
+        .filter_map(|clause| {
+            let mut words = clause.split_whitespace();
+            let result = words.next()?;
+            let mut pair = result.splitn(2, '=');
+            match (pair.next(), pair.next()) {
+                (Some(method), Some(result)) => Some((method.to_lowercase(), Verdict::parse(result))),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+impl AuthResults {
+    // Only the topmost Authentication-Results header from a trusted server,
+    // which is the one it added on the way in: whatever's below it came
+    // with the email, and headers aren't merged. Within it, a single
+    // failing signature doesn't fail DKIM if another one passed.
+    pub fn from_email(email: &MailgunEmailReceived, trusted_authserv_ids: &[String]) -> AuthResults {
+        let mut results = AuthResults {
+            spf: Verdict::Unknown,
+            dkim: Verdict::Unknown,
+            dmarc: Verdict::Unknown,
+            trusted: false,
+        };
+        let combine = |current: Verdict, new: Verdict| match (current, new) {
+            (Verdict::Pass, _) | (_, Verdict::Pass) => Verdict::Pass,
+            (Verdict::Fail, _) | (_, Verdict::Fail) => Verdict::Fail,
+            _ => Verdict::Unknown,
+        };
+        let headers = viewer::headers(&email.message_headers);
+        let header = headers.iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("authentication-results"))
+            .map(|(_, value)| value)
+            .find(|value| trusted_authserv_ids.iter().any(|id| id.eq_ignore_ascii_case(authserv_id(value))));
+        if let Some(header) = header {
+            results.trusted = true;
+            for (method, verdict) in method_results(header) {
+                match &method[..] {
+                    "spf" => results.spf = combine(results.spf, verdict),
+                    "dkim" => results.dkim = combine(results.dkim, verdict),
+                    "dmarc" => results.dmarc = combine(results.dmarc, verdict),
+                    _ => (),
+                }
+            }
+        }
+        results
+    }
+
+    pub fn summary(&self) -> String {
+        if !self.trusted {
+            return String::from("SPF/DKIM/DMARC unverified ⚠️");
+        }
+        format!(
+            "SPF {} DKIM {} DMARC {}",
+            self.spf.symbol(),
+            self.dkim.symbol(),
+            self.dmarc.symbol()
+        )
+    }
+}
@@ -0,0 +1,238 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+
+use crate::queue::QueueConfig;
+use crate::store::StoreError;
+use crate::version::Version;
+
+// What in DATA_DIR a new host needs to carry on where the old one left
+// off. Captures, copies and traces are for debugging and stay behind.
+const STATE: &[&str] = &[
+    "archive",
+    "audit.log",
+    "blocklist.json",
+    "bounces.log",
+    "delayed.json",
+    "feedback.log",
+    "maintenance.json",
+    "outbox.log",
+    "pending.json",
+    "reputation.json",
+    "suppressions.log",
+    "takeover.json",
+    "threads.json",
+    "unhandled.json",
+    "unsubscribed.json",
+    "variants.json",
+];
+
+// What workers sharing a queue keep in redis instead: the rate limiter,
+// the thread map, the maintenance flag, the replies waiting to be sent and
+// the send budget, whose counts keep their expiry so a restore doesn't
+// reset the caps mid-window. A single process keeps the rate limiter and
+// the budget in memory, which a restart loses anyway.
+const SHARED: &[&str] = &[
+    "limail:last-response:*",
+    "limail:thread:*",
+    "limail:maintenance",
+    "limail:delay:*",
+    "limail:budget:*",
+];
+
+const MANIFEST: &str = "limail-backup.json";
+const SHARED_FILE: &str = "shared.json";
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    created_at: DateTime<Utc>,
+    version: String,
+    files: Vec<String>,
+}
+
+// A redis key with the seconds it had left, None if it doesn't expire.
+#[derive(Serialize, Deserialize)]
+struct SharedEntry {
+    key: String,
+    value: String,
+    ttl_seconds: Option<i64>,
+}
+
+pub struct BackedUp {
+    pub files: Vec<String>,
+    pub shared: usize,
+}
+
+pub struct Restored {
+    pub created_at: DateTime<Utc>,
+    pub version: String,
+    pub files: Vec<String>,
+    pub shared: usize,
+    // Of shared, the ones written to redis, rather than having expired
+    // since the backup or being there already.
+    pub shared_restored: usize,
+}
+
+fn redis_error(e: redis::RedisError) -> StoreError {
+    StoreError::IoError(format!("Unable to reach redis: {}", e))
+}
+
+fn connect(queue: &QueueConfig) -> Result<redis::Connection, StoreError> {
+    redis::Client::open(&queue.redis_url[..])
+        .and_then(|client| client.get_connection())
+        .map_err(redis_error)
+}
+
+fn dump_shared(queue: &QueueConfig) -> Result<Vec<SharedEntry>, StoreError> {
+    let mut connection = connect(queue)?;
+    let mut keys: Vec<String> = Vec::new();
+    for pattern in SHARED {
+        let found: Vec<String> = redis::cmd("SCAN")
+            .cursor_arg(0)
+            .arg("MATCH")
+            .arg(*pattern)
+            .iter(&mut connection)
+            .map_err(redis_error)?
+            .collect();
+        keys.extend(found);
+    }
+    let mut entries = Vec::new();
+    for key in keys {
+        let value: Option<String> = redis::cmd("GET").arg(&key).query(&mut connection).map_err(redis_error)?;
+        let ttl: i64 = redis::cmd("TTL").arg(&key).query(&mut connection).map_err(redis_error)?;
+        // Expired since it was found.
+        if let Some(value) = value {
+            entries.push(SharedEntry {
+                key,
+                value,
+                ttl_seconds: Some(ttl).filter(|ttl| *ttl >= 0),
+            });
+        }
+    }
+    Ok(entries)
+}
+
+fn append<W: Write>(tarball: &mut tar::Builder<W>, name: &str, bytes: &[u8]) -> io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(bytes.len() as u64);
+    header.set_mode(0o600);
+    header.set_mtime(Utc::now().timestamp() as u64);
+    header.set_cksum();
+    tarball.append_data(&mut header, name, bytes)
+}
+
+// Writes DATA_DIR's state, and redis' when workers share a queue, to a
+// zstd compressed tarball at out. limail should be stopped first, so
+// nothing changes halfway through. Encrypted archives stay encrypted, the
+// new host needs the same [archive_encryption] keys to read them.
+pub fn backup(data_dir: &Path, queue: Option<&QueueConfig>, out: &Path) -> Result<BackedUp, StoreError> {
+    let shared = match queue {
+        Some(queue) => dump_shared(queue)?,
+        None => Vec::new(),
+    };
+    let files: Vec<String> = STATE.iter()
+        .filter(|name| data_dir.join(name).exists())
+        .map(|name| String::from(*name))
+        .collect();
+    let manifest = Manifest {
+        created_at: Utc::now(),
+        version: String::from(Version::current().version),
+        files: files.clone(),
+    };
+    let tmp_path = out.with_extension("tmp");
+    let mut tarball = tar::Builder::new(zstd::Encoder::new(File::create(&tmp_path)?, 0)?);
+    append(&mut tarball, MANIFEST, &serde_json::to_vec_pretty(&manifest)?)?;
+    append(&mut tarball, SHARED_FILE, &serde_json::to_vec_pretty(&shared)?)?;
+    for name in &files {
+        let path = data_dir.join(name);
+        if path.is_dir() {
+            tarball.append_dir_all(name, &path)?;
+        } else {
+            tarball.append_path_with_name(&path, name)?;
+        }
+    }
+    tarball.into_inner()?.finish()?.sync_all()?;
+    fs::rename(&tmp_path, out)?;
+    Ok(BackedUp { files, shared: shared.len() })
+}
+
+fn read_entry<R: Read>(entry: &mut tar::Entry<R>) -> Result<Vec<u8>, StoreError> {
+    let mut bytes = Vec::new();
+    entry.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+// Unpacks a backup into data_dir, which mustn't have any state of its own
+// yet, and puts the shared entries that haven't expired since back in
+// redis, leaving any it already has alone.
+pub fn restore(data_dir: &Path, queue: Option<&QueueConfig>, from: &Path) -> Result<Restored, StoreError> {
+    let existing: Vec<&str> = STATE.iter().cloned().filter(|name| data_dir.join(name).exists()).collect();
+    if !existing.is_empty() {
+        return Err(StoreError::IoError(format!(
+            "{} already has {}, move it aside to restore over it",
+            data_dir.display(),
+            existing.join(", ")
+        )));
+    }
+    fs::create_dir_all(data_dir)?;
+    let mut tarball = tar::Archive::new(zstd::Decoder::new(File::open(from)?)?);
+    let mut manifest: Option<Manifest> = None;
+    let mut shared: Vec<SharedEntry> = Vec::new();
+    for entry in tarball.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let name = path.components().next()
+            .and_then(|component| component.as_os_str().to_str())
+            .map(String::from)
+            .unwrap_or_default();
+        if manifest.is_none() && name != MANIFEST {
+            return Err(StoreError::JsonError(format!("{} isn't a limail backup, it doesn't start with {}", from.display(), MANIFEST)));
+        }
+        if name == MANIFEST {
+            manifest = Some(serde_json::from_slice(&read_entry(&mut entry)?)?);
+        } else if name == SHARED_FILE {
+            shared = serde_json::from_slice(&read_entry(&mut entry)?)?;
+        } else if STATE.contains(&&name[..]) {
+            entry.unpack_in(data_dir)?;
+        } else {
+            warn!("Skipping {} in {}, it isn't limail state", path.display(), from.display());
+        }
+    }
+    let manifest = manifest
+        .ok_or_else(|| StoreError::JsonError(format!("{} is empty", from.display())))?;
+    let mut shared_restored = 0;
+    match queue {
+        Some(queue) if !shared.is_empty() => {
+            let elapsed = (Utc::now() - manifest.created_at).num_seconds().max(0);
+            let mut connection = connect(queue)?;
+            for entry in &shared {
+                let mut set = redis::cmd("SET");
+                set.arg(&entry.key).arg(&entry.value).arg("NX");
+                if let Some(ttl) = entry.ttl_seconds {
+                    if ttl <= elapsed {
+                        continue;
+                    }
+                    set.arg("EX").arg(ttl - elapsed);
+                }
+                let written: Option<String> = set.query(&mut connection).map_err(redis_error)?;
+                if written.is_some() {
+                    shared_restored += 1;
+                }
+            }
+        },
+        None if !shared.is_empty() => {
+            warn!("Not restoring {} shared entries, there's no [queue] in LIMAIL_CONFIG", shared.len());
+        },
+        _ => (),
+    }
+    Ok(Restored {
+        created_at: manifest.created_at,
+        version: manifest.version,
+        files: manifest.files,
+        shared: shared.len(),
+        shared_restored,
+    })
+}
@@ -0,0 +1,86 @@
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use crate::store::{self, StoreError};
+
+// Senders we never reply to or forward. Entries are either a full address
+// (spammer@example.com) or a whole domain (@example.com).
+#[derive(Clone)]
+pub struct Blocklist {
+    path: PathBuf,
+    entries: Arc<RwLock<BTreeSet<String>>>,
+}
+
+// `from` usually looks like `Display Name <addr@example.com>`.
+pub fn address_of(from: &str) -> String {
+    let address = match (from.rfind('<'), from.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &from[start + 1..end],
+        _ => from,
+    };
+    address.trim().to_lowercase()
+}
+
+fn normalize_entry(entry: &str) -> Option<String> {
+    let entry = entry.trim().to_lowercase();
+    if entry.is_empty() || !entry.contains('@') {
+        None
+    } else {
+        Some(entry)
+    }
+}
+
+impl Blocklist {
+    pub fn load(path: PathBuf) -> Result<Blocklist, StoreError> {
+        let entries: BTreeSet<String> = store::read_json(&path)?.unwrap_or_default();
+        Ok(Blocklist {
+            path,
+            entries: Arc::new(RwLock::new(entries)),
+        })
+    }
+
+    pub fn is_blocked(&self, from: &str) -> bool {
+        let address = address_of(from);
+        let entries = self.entries.read().unwrap();
+        entries.contains(&address) || address.rfind('@')
+            .map(|at| entries.contains(&address[at..]))
+            .unwrap_or(false)
+    }
+
+    pub fn contains(&self, entry: &str) -> bool {
+        normalize_entry(entry)
+            .map(|entry| self.entries.read().unwrap().contains(&entry))
+            .unwrap_or(false)
+    }
+
+    pub fn entries(&self) -> Vec<String> {
+        self.entries.read().unwrap().iter().cloned().collect()
+    }
+
+    // Changes take effect immediately and are persisted before returning.
+    pub fn add(&self, entry: &str) -> Result<bool, StoreError> {
+        let entry = match normalize_entry(entry) {
+            Some(entry) => entry,
+            None => return Ok(false),
+        };
+        let mut entries = self.entries.write().unwrap();
+        let added = entries.insert(entry);
+        if added {
+            store::write_json(&self.path, &*entries)?;
+        }
+        Ok(added)
+    }
+
+    pub fn remove(&self, entry: &str) -> Result<bool, StoreError> {
+        let entry = match normalize_entry(entry) {
+            Some(entry) => entry,
+            None => return Ok(false),
+        };
+        let mut entries = self.entries.write().unwrap();
+        let removed = entries.remove(&entry);
+        if removed {
+            store::write_json(&self.path, &*entries)?;
+        }
+        Ok(removed)
+    }
+}
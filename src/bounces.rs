@@ -0,0 +1,274 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use serde::{Serialize, Deserialize};
+
+use crate::blocklist::address_of;
+use crate::mailgun::{self, MailgunError};
+use crate::outbox::Outbox;
+use crate::pipeline::route_matches;
+use crate::store::{self, StoreError};
+
+fn default_after() -> usize {
+    3
+}
+
+// Bounces of replies on route (exact, or a prefix ending in *) go to
+// address rather than to MAILGUN_FROM, with the recipient in it
+// (bounce+alice=example.org@lichess.org) to know whose reply bounced.
+// A Mailgun route forwarding address to /emails/bounces records them.
+#[derive(Deserialize, Clone)]
+pub struct EnvelopeSender {
+    pub route: String,
+    pub address: String,
+}
+
+// Stops auto-replying to addresses whose last `after` auto-replies all
+// bounced for good, as Mailgun's permanent failure webhook (pointed at
+// /mailgun/events) or the bounces sent to an envelope sender report them.
+// The email is still handled, and the suppressed notice says the address
+// looks undeliverable.
+#[derive(Deserialize, Clone)]
+pub struct BounceConfig {
+    #[serde(default = "default_after")]
+    pub after: usize,
+    #[serde(default)]
+    pub envelope_senders: Vec<EnvelopeSender>,
+}
+
+#[derive(Clone)]
+pub struct EnvelopeSenders {
+    senders: Arc<Vec<EnvelopeSender>>,
+}
+
+impl EnvelopeSenders {
+    pub fn new(senders: Vec<EnvelopeSender>) -> Option<EnvelopeSenders> {
+        if senders.is_empty() {
+            return None;
+        }
+        Some(EnvelopeSenders { senders: Arc::new(senders) })
+    }
+
+    // Mailgun takes the envelope sender (Return-Path) from the Sender header.
+    pub fn for_reply(&self, route: &str, recipient: &str) -> Option<String> {
+        let sender = self.senders.iter().find(|sender| route_matches(&sender.route, route))?;
+        let at = sender.address.rfind('@')?;
+        let (local, domain) = (&sender.address[..at], &sender.address[at..]);
+        Some(format!("{}+{}{}", local, address_of(recipient).replace('@', "="), domain))
+    }
+}
+
+// The recipient in an envelope sender, see for_reply.
+pub fn bounced_recipient(envelope_sender: &str) -> Option<String> {
+    let address = address_of(envelope_sender);
+    let local = &address[..address.rfind('@')?];
+    let tag = &local[local.find('+')? + 1..];
+    let at = tag.rfind('=')?;
+    Some(format!("{}@{}", &tag[..at], &tag[at + 1..]))
+}
+
+// A bounce (delivery status notification) Mailgun received for an envelope
+// sender, with the fields it forwards received emails with.
+pub struct BounceReport {
+    pub recipient: String,
+    pub body_plain: String,
+    pub timestamp: i64,
+    pub token: String,
+    pub signature: String,
+}
+
+impl BounceReport {
+    pub fn from_fields(mut fields: BTreeMap<String, String>) -> Result<BounceReport, MailgunError> {
+        let mut field = |name: &str| fields.remove(name).unwrap_or_default();
+        Ok(BounceReport {
+            recipient: field("recipient"),
+            body_plain: field("body-plain"),
+            timestamp: field("timestamp").parse()
+                .map_err(|_| MailgunError::HmacError(String::from("Bad timestamp")))?,
+            token: field("token"),
+            signature: field("signature"),
+        })
+    }
+
+    pub fn verify(&self, api_key: &str, now: i64) -> Result<(), MailgunError> {
+        mailgun::verify_signature(api_key, self.timestamp, &self.token, &self.signature)?;
+        mailgun::check_timestamp(self.timestamp, now)
+    }
+
+    fn line(&self, pattern: &str) -> Option<String> {
+        Regex::new(pattern).unwrap()
+            .captures(&self.body_plain)
+            .map(|captures| String::from(captures[1].trim()))
+    }
+
+    // Only delayed when the report says so with a 4.x.x status, as many
+    // bounces don't say at all.
+    fn temporary(&self) -> bool {
+        self.line(r"(?mi)^Status:\s*(\d)\.\d+\.\d+").map_or(false, |class| class == "4")
+    }
+
+    fn reason(&self) -> String {
+        self.line(r"(?mi)^Diagnostic-Code:\s*(?:smtp;)?(.+)$").unwrap_or_default()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct EventSignature {
+    pub timestamp: String,
+    pub token: String,
+    pub signature: String,
+}
+
+#[derive(Deserialize, Default)]
+pub struct EventHeaders {
+    #[serde(rename = "message-id", default)]
+    pub message_id: String,
+    #[serde(default)]
+    pub from: String,
+}
+
+#[derive(Deserialize, Default)]
+pub struct EventMessage {
+    #[serde(default)]
+    pub headers: EventHeaders,
+}
+
+#[derive(Deserialize, Default)]
+pub struct DeliveryStatus {
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub message: String,
+}
+
+#[derive(Deserialize)]
+pub struct EventData {
+    pub event: String,
+    #[serde(default)]
+    pub severity: Option<String>,
+    #[serde(default)]
+    pub recipient: String,
+    #[serde(default)]
+    pub reason: Option<String>,
+    #[serde(rename = "delivery-status", default)]
+    pub delivery_status: DeliveryStatus,
+    #[serde(default)]
+    pub message: EventMessage,
+}
+
+// What Mailgun posts to webhooks about the emails it sent.
+#[derive(Deserialize)]
+pub struct DeliveryEvent {
+    pub signature: EventSignature,
+    #[serde(rename = "event-data")]
+    pub event_data: EventData,
+}
+
+impl DeliveryEvent {
+    pub fn verify(&self, api_key: &str, now: i64) -> Result<(), MailgunError> {
+        let timestamp = self.signature.timestamp.parse()
+            .map_err(|_| MailgunError::HmacError(String::from("Bad timestamp")))?;
+        mailgun::verify_signature(api_key, timestamp, &self.signature.token, &self.signature.signature)?;
+        mailgun::check_timestamp(timestamp, now)
+    }
+
+    // Temporary failures are retried by Mailgun, and may yet be delivered.
+    pub fn bounce(&self) -> Option<Bounce> {
+        let data = &self.event_data;
+        if data.event != "failed" || data.severity.as_ref().map(|s| &s[..]) != Some("permanent") {
+            return None;
+        }
+        let reason = [&data.delivery_status.description, &data.delivery_status.message]
+            .iter()
+            .find(|text| !text.is_empty())
+            .map(|text| text.to_string())
+            .or_else(|| data.reason.clone())
+            .unwrap_or_default();
+        Some(Bounce {
+            at: Utc::now(),
+            recipient: data.recipient.clone(),
+            message_id: normalize(&data.message.headers.message_id),
+            reason,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Bounce {
+    pub at: DateTime<Utc>,
+    pub recipient: String,
+    pub message_id: String,
+    pub reason: String,
+}
+
+// Mailgun returns Message-IDs in angle brackets, and its events don't.
+fn normalize(message_id: &str) -> String {
+    String::from(message_id.trim().trim_start_matches('<').trim_end_matches('>'))
+}
+
+#[derive(Clone)]
+pub struct Bounces {
+    config: BounceConfig,
+    path: PathBuf,
+    write_lock: Arc<Mutex<()>>,
+}
+
+impl Bounces {
+    pub fn new(config: BounceConfig, path: PathBuf) -> Bounces {
+        Bounces {
+            config,
+            path,
+            write_lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    // The reply a bounce report is about: the last auto-reply to the
+    // recipient in its envelope sender.
+    pub fn from_report(&self, outbox: &Outbox, report: &BounceReport) -> Result<Option<Bounce>, StoreError> {
+        if report.temporary() {
+            return Ok(None);
+        }
+        let recipient = match bounced_recipient(&report.recipient) {
+            Some(recipient) => recipient,
+            None => return Ok(None),
+        };
+        Ok(outbox.auto_replies_to(&recipient, 1)?.into_iter().next().map(|reply| Bounce {
+            at: Utc::now(),
+            recipient,
+            message_id: normalize(&reply.message_id),
+            reason: report.reason(),
+        }))
+    }
+
+    pub fn record(&self, bounce: &Bounce) -> Result<(), StoreError> {
+        let _guard = self.write_lock.lock().unwrap();
+        store::append_json_line(&self.path, bounce)
+    }
+
+    // Why replies to recipient bounce, when the last few all did.
+    pub fn undeliverable(&self, outbox: &Outbox, recipient: &str) -> Result<Option<String>, StoreError> {
+        let after = self.config.after.max(1);
+        let replies = outbox.auto_replies_to(recipient, after)?;
+        if replies.len() < after {
+            return Ok(None);
+        }
+        let bounces: Vec<Bounce> = store::read_json_lines(&self.path)?;
+        let bounced: BTreeSet<String> = bounces.iter().map(|bounce| bounce.message_id.clone()).collect();
+        if !replies.iter().all(|reply| bounced.contains(&normalize(&reply.message_id))) {
+            return Ok(None);
+        }
+        let last = bounces.iter().rev()
+            .find(|bounce| bounce.message_id == normalize(&replies[0].message_id))
+            .map(|bounce| bounce.reason.clone())
+            .unwrap_or_default();
+        Ok(Some(format!(
+            "the last {} replies to {} bounced ({}), the address looks undeliverable",
+            after,
+            recipient,
+            if last.is_empty() { "no reason given" } else { &last[..] }
+        )))
+    }
+}
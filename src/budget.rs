@@ -0,0 +1,167 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use serde::Deserialize;
+
+use crate::clock::Clock;
+use crate::mailgun::MailgunError;
+use crate::metrics::Metrics;
+use crate::slack::{Slack, SlackMessage};
+
+fn default_warn_percent() -> u64 {
+    80
+}
+
+// A ceiling on how many emails we send, whatever happens upstream (a reply
+// loop, a spam flood). Emails over budget aren't sent. A warning goes to
+// channel once warn_percent of a budget is used, and again when it runs
+// out. Counted in redis when there is a queue, so it covers every worker.
+#[derive(Deserialize, Clone)]
+pub struct SendBudgetConfig {
+    #[serde(default)]
+    pub hourly: Option<u64>,
+    #[serde(default)]
+    pub daily: Option<u64>,
+    #[serde(default = "default_warn_percent")]
+    pub warn_percent: u64,
+    #[serde(default)]
+    pub channel: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct SendBudget {
+    config: Arc<SendBudgetConfig>,
+    counts: Arc<Mutex<BTreeMap<String, u64>>>,
+    shared: Option<redis::Client>,
+    slack: Slack,
+    metrics: Metrics,
+    clock: Clock,
+}
+
+impl SendBudget {
+    pub fn new(config: SendBudgetConfig, slack: Slack, metrics: Metrics) -> SendBudget {
+        SendBudget {
+            config: Arc::new(config),
+            counts: Arc::new(Mutex::new(BTreeMap::new())),
+            shared: None,
+            slack,
+            metrics,
+            clock: Clock::system(),
+        }
+    }
+
+    pub fn shared(self, client: redis::Client) -> SendBudget {
+        SendBudget {
+            shared: Some(client),
+            ..self
+        }
+    }
+
+    pub fn with_clock(self, clock: Clock) -> SendBudget {
+        SendBudget {
+            clock,
+            ..self
+        }
+    }
+
+    // Counts every email sent, so each count is seen by exactly one sender
+    // and each warning goes out once.
+    fn incr(&self, key: &str, seconds: usize) -> u64 {
+        if let Some(client) = &self.shared {
+            let shared: redis::RedisResult<(u64,)> = client.get_connection().and_then(|mut connection| {
+                redis::pipe()
+                    .cmd("INCR").arg(key)
+                    .cmd("EXPIRE").arg(key).arg(seconds).ignore()
+                    .query(&mut connection)
+            });
+            match shared {
+                Ok((count,)) => return count,
+                Err(e) => error!("Unable to reach the shared send budget, counting locally: {}", e),
+            }
+        }
+        let mut counts = self.counts.lock().unwrap();
+        // Only the current windows matter.
+        counts.retain(|existing, _| existing.len() != key.len() || existing[..] >= key[..]);
+        let count = counts.entry(String::from(key)).or_insert(0);
+        *count += 1;
+        *count
+    }
+
+    fn count(&self, key: &str) -> u64 {
+        if let Some(client) = &self.shared {
+            let shared: redis::RedisResult<Option<u64>> = client.get_connection()
+                .and_then(|mut connection| redis::cmd("GET").arg(key).query(&mut connection));
+            match shared {
+                Ok(count) => return count.unwrap_or(0),
+                Err(e) => error!("Unable to reach the shared send budget, counting locally: {}", e),
+            }
+        }
+        self.counts.lock().unwrap().get(key).cloned().unwrap_or(0)
+    }
+
+    // The configured ones, as (name, limit, key, seconds).
+    fn windows(&self) -> Vec<(&'static str, u64, String, usize)> {
+        let now = self.clock.now();
+        let windows = vec![
+            ("hourly", self.config.hourly, format!("limail:budget:{}", now.format("%Y%m%d%H")), 60 * 60),
+            ("daily", self.config.daily, format!("limail:budget:{}", now.format("%Y%m%d")), 24 * 60 * 60),
+        ];
+        windows.into_iter()
+            .filter_map(|(name, limit, key, seconds)| Some((name, limit?, key, seconds)))
+            .collect()
+    }
+
+    fn warn(&self, text: String) {
+        warn!("{}", text);
+        self.metrics.incr("send_budget_warnings");
+        let channel = match &self.config.channel {
+            Some(channel) => channel.clone(),
+            None => return,
+        };
+        let slack = self.slack.clone();
+        thread::spawn(move || {
+            let sent = slack.send_message(&SlackMessage {
+                channel,
+                text,
+                thread_ts: None,
+                as_user: true,
+                username: None,
+                icon_emoji: None,
+                icon_url: None,
+                blocks: None,
+                unfurl_links: None,
+                unfurl_media: None,
+            });
+            if let Err(e) = sent {
+                error!("Unable to post a send budget warning: {}", e);
+            }
+        });
+    }
+
+    // Before sending, refuses if there's none of the budget left.
+    pub fn check(&self) -> Result<(), MailgunError> {
+        for (name, limit, key, _) in self.windows() {
+            if self.count(&key) >= limit {
+                self.metrics.incr("emails_over_budget");
+                return Err(MailgunError::OverBudget(format!("The {} send budget of {} emails is used up", name, limit)));
+            }
+        }
+        Ok(())
+    }
+
+    // Takes one email out of the budget once it's sent, so the ones that
+    // failed don't count. Workers checking at the same moment can go a
+    // few over.
+    pub fn spend(&self) {
+        for (name, limit, key, seconds) in self.windows() {
+            let count = self.incr(&key, seconds);
+            let warn_at = (limit * self.config.warn_percent + 99) / 100;
+            if count == limit {
+                self.warn(format!(":rotating_light: The {} send budget of {} emails is used up, not sending any more", name, limit));
+            } else if count == warn_at && count < limit {
+                self.warn(format!(":warning: {} of the {} send budget of {} emails used", count, name, limit));
+            }
+        }
+    }
+}
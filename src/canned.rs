@@ -0,0 +1,65 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+pub const ACTION_ID: &str = "canned_reply";
+
+#[derive(Deserialize, Clone)]
+pub struct CannedReply {
+    // The Mailgun template sent to the original sender.
+    pub template: String,
+    // Shown in the menu instead of the template name.
+    #[serde(default)]
+    pub label: Option<String>,
+}
+
+// Slack forwards on these routes (exact, or a prefix ending in *) come with
+// a menu of replies a mod can send the original sender with one click. The
+// choice arrives at /slack/interactions, see server.rs.
+#[derive(Deserialize, Clone)]
+pub struct CannedReplies {
+    pub route: String,
+    pub replies: Vec<CannedReply>,
+}
+
+// The block id carries the job, so the interaction knows who to answer.
+pub fn block_id(job_id: &str) -> String {
+    format!("{}:{}", ACTION_ID, job_id)
+}
+
+pub fn job_id(block_id: &str) -> Option<&str> {
+    let prefix = format!("{}:", ACTION_ID);
+    if block_id.starts_with(&prefix) {
+        Some(&block_id[prefix.len()..])
+    } else {
+        None
+    }
+}
+
+// The menu at the end of a forward.
+pub fn menu(job_id: &str, replies: &[CannedReply]) -> Value {
+    let options: Vec<Value> = replies.iter()
+        .map(|reply| json!({
+            "text": {
+                "type": "plain_text",
+                "text": reply.label.as_ref().unwrap_or(&reply.template),
+            },
+            "value": reply.template,
+        }))
+        .collect();
+    json!({
+        "type": "actions",
+        "block_id": block_id(job_id),
+        "elements": [{
+            "type": "static_select",
+            "action_id": ACTION_ID,
+            "placeholder": { "type": "plain_text", "text": "Send a canned reply" },
+            "options": options,
+            "confirm": {
+                "title": { "type": "plain_text", "text": "Send this reply?" },
+                "text": { "type": "plain_text", "text": "It goes to the original sender straight away." },
+                "confirm": { "type": "plain_text", "text": "Send" },
+                "deny": { "type": "plain_text", "text": "Cancel" },
+            },
+        }],
+    })
+}
@@ -0,0 +1,120 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+
+use crate::clock::Clock;
+use crate::store::{self, StoreError};
+
+fn default_max_bytes() -> usize {
+    256 * 1024
+}
+
+fn default_retention_days() -> u64 {
+    7
+}
+
+fn default_dir() -> String {
+    String::from("captures")
+}
+
+// Webhook bodies limail couldn't parse are kept in dir (relative to
+// DATA_DIR), cut off at max_bytes, so the parsing bug can be reproduced.
+// The error Mailgun gets names the capture. They're someone's email, so
+// they're deleted after retention_days.
+#[derive(Deserialize, Clone)]
+pub struct CaptureConfig {
+    #[serde(default = "default_dir")]
+    pub dir: String,
+    #[serde(default = "default_max_bytes")]
+    pub max_bytes: usize,
+    #[serde(default = "default_retention_days")]
+    pub retention_days: u64,
+}
+
+#[derive(Serialize)]
+struct CaptureInfo<'a> {
+    at: DateTime<Utc>,
+    route: &'a str,
+    content_type: &'a str,
+    size: usize,
+    truncated: bool,
+    error: &'a str,
+}
+
+// Only what expiring needs from a CaptureInfo.
+#[derive(Deserialize)]
+struct Captured {
+    at: DateTime<Utc>,
+}
+
+#[derive(Clone)]
+pub struct Captures {
+    config: Option<CaptureConfig>,
+    dir: PathBuf,
+    clock: Clock,
+}
+
+impl Captures {
+    pub fn new(config: Option<CaptureConfig>, data_dir: &Path) -> Captures {
+        let dir = data_dir.join(config.as_ref().map(|c| &c.dir[..]).unwrap_or("captures"));
+        Captures { config, dir, clock: Clock::system() }
+    }
+
+    pub fn with_clock(self, clock: Clock) -> Captures {
+        Captures {
+            clock,
+            ..self
+        }
+    }
+
+    // <id>.body holds the body, <id>.json what's needed to replay it. The id
+    // comes from the body, so Mailgun's retries don't add more copies.
+    pub fn capture(&self, route: &str, content_type: &str, body: &[u8], error: &str) -> Option<String> {
+        let config = self.config.as_ref()?;
+        let id = hex::encode(&Sha256::digest(body)[..8]);
+        let kept = &body[..body.len().min(config.max_bytes)];
+        let info = CaptureInfo {
+            at: self.clock.now(),
+            route,
+            content_type,
+            size: body.len(),
+            truncated: kept.len() < body.len(),
+            error,
+        };
+        let written = store::write_bytes(&self.dir.join(format!("{}.body", id)), kept)
+            .and_then(|_| store::write_json(&self.dir.join(format!("{}.json", id)), &info));
+        if let Err(e) = written {
+            error!("Unable to capture the unparseable webhook for {}: {}", route, e);
+            return None;
+        }
+        if let Err(e) = self.expire(config.retention_days) {
+            error!("Unable to delete old captures: {}", e);
+        }
+        Some(id)
+    }
+
+    // Going by the time in <id>.json rather than the files' mtimes, which
+    // know nothing of the clock.
+    fn expire(&self, retention_days: u64) -> Result<(), StoreError> {
+        let cutoff = self.clock.now() - Duration::days(retention_days as i64);
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().map_or(true, |extension| extension != "json") {
+                continue;
+            }
+            match store::read_json::<Captured>(&path) {
+                Ok(Some(captured)) if captured.at < cutoff => (),
+                _ => continue,
+            }
+            let body = path.with_extension("body");
+            if body.exists() {
+                fs::remove_file(body)?;
+            }
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
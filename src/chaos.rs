@@ -0,0 +1,69 @@
+use std::thread;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+fn default_delay_ms() -> u64 {
+    2000
+}
+
+// Makes delay_percent of the calls wait delay_ms first, and fail_percent
+// of them fail without being made.
+#[derive(Deserialize, Clone)]
+pub struct FaultConfig {
+    #[serde(default)]
+    pub fail_percent: f64,
+    #[serde(default)]
+    pub delay_percent: f64,
+    #[serde(default = "default_delay_ms")]
+    pub delay_ms: u64,
+}
+
+// Faults injected into the calls to Mailgun and Slack, to see the retries,
+// alerts and deadlines at work on staging. Only acted on when built with
+// the chaos feature, and limail won't start with a [chaos] section
+// otherwise, rather than quietly ignore it.
+#[derive(Deserialize, Clone, Default)]
+pub struct ChaosConfig {
+    #[serde(default)]
+    pub mailgun: Option<FaultConfig>,
+    #[serde(default)]
+    pub slack: Option<FaultConfig>,
+}
+
+#[derive(Clone)]
+pub struct Chaos {
+    name: &'static str,
+    config: FaultConfig,
+}
+
+// Between 0 and 100.
+fn roll() -> f64 {
+    let mut bytes = [0u8; 4];
+    match openssl::rand::rand_bytes(&mut bytes) {
+        Ok(()) => f64::from(u32::from_le_bytes(bytes)) / f64::from(u32::max_value()) * 100.0,
+        Err(_) => 100.0,
+    }
+}
+
+impl Chaos {
+    pub fn new(name: &'static str, config: FaultConfig) -> Chaos {
+        Chaos { name, config }
+    }
+
+    // Err is the message of the injected failure.
+    pub fn inject(&self) -> Result<(), String> {
+        if !cfg!(feature = "chaos") {
+            return Ok(());
+        }
+        if roll() < self.config.delay_percent {
+            warn!("Chaos: delaying a {} call by {}ms", self.name, self.config.delay_ms);
+            thread::sleep(Duration::from_millis(self.config.delay_ms));
+        }
+        if roll() < self.config.fail_percent {
+            warn!("Chaos: failing a {} call", self.name);
+            return Err(format!("Injected {} failure", self.name));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,182 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use chrono::Utc;
+
+use serde_json::Value;
+
+use crate::archive::Archive;
+use crate::backup;
+use crate::config::Config;
+use crate::encryption::Sealer;
+use crate::simulate;
+use crate::templates::{TemplateContext, Templates};
+
+// `--name value` pairs, anything else is an error.
+fn parse_flags(args: &[String], known: &[&str]) -> Result<Vec<(String, String)>, String> {
+    let mut flags = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let name = arg.trim_start_matches("--");
+        if !arg.starts_with("--") || !known.contains(&name) {
+            return Err(format!("Unexpected argument {}", arg));
+        }
+        match args.next() {
+            Some(value) => flags.push((String::from(name), value.clone())),
+            None => return Err(format!("{} needs a value", arg)),
+        }
+    }
+    Ok(flags)
+}
+
+fn flag<'a>(flags: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    flags.iter().find(|(n, _)| n == name).map(|(_, v)| &v[..])
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1)
+}
+
+const RENDER_USAGE: &str = "Usage: limail render --template <name> --payload <sample.json> [--templates <dir>]";
+
+// Renders a local template against a sample inbound payload, so templates
+// can be worked on without sending test emails.
+pub fn render(args: &[String]) {
+    let flags = parse_flags(args, &["template", "payload", "templates"])
+        .unwrap_or_else(|e| fail(&format!("{}\n{}", e, RENDER_USAGE)));
+    let (template, payload_path) = match (flag(&flags, "template"), flag(&flags, "payload")) {
+        (Some(template), Some(payload)) => (template, payload),
+        _ => fail(RENDER_USAGE),
+    };
+    let dir = flag(&flags, "templates")
+        .map(String::from)
+        .or_else(|| env::var("TEMPLATE_DIR").ok())
+        .unwrap_or_else(|| String::from("templates"));
+
+    let payload: Value = fs::read_to_string(payload_path)
+        .map_err(|e| e.to_string())
+        .and_then(|contents| serde_json::from_str(&contents).map_err(|e| e.to_string()))
+        .unwrap_or_else(|e| fail(&format!("Unable to read payload {}: {}", payload_path, e)));
+
+    let context = TemplateContext::from_payload(&payload);
+    match Templates::new(PathBuf::from(dir)).render(template, &context) {
+        Ok(rendered) => {
+            println!("Subject: {}\n", rendered.subject);
+            println!("{}", rendered.body);
+        },
+        Err(e) => fail(&e.to_string()),
+    }
+}
+
+// Re-encrypts the archive with the first of archive_encryption's keys, so
+// the others can be retired.
+pub fn rekey_archive(args: &[String]) {
+    if !args.is_empty() {
+        fail("Usage: limail rekey-archive");
+    }
+    let config = Config::load();
+    let keys = config.archive_encryption
+        .unwrap_or_else(|| fail("No [archive_encryption] in LIMAIL_CONFIG"));
+    let sealer = Sealer::new(&keys).unwrap_or_else(|e| fail(&e));
+    let data_dir = PathBuf::from(env::var("DATA_DIR").unwrap_or_else(|_| String::from(".")));
+    match Archive::new(data_dir.join("archive")).encrypted(sealer).rekey() {
+        Ok(rekeyed) => println!("Re-encrypted {} files", rekeyed),
+        Err(e) => fail(&e.to_string()),
+    }
+}
+
+const BACKUP_USAGE: &str = "Usage: limail backup --out <state.tar.zst>";
+
+// Saves what DATA_DIR (and redis, with a [queue]) knows, for moving limail
+// to another host with `limail restore`. Stop limail first.
+pub fn backup(args: &[String]) {
+    let flags = parse_flags(args, &["out"])
+        .unwrap_or_else(|e| fail(&format!("{}\n{}", e, BACKUP_USAGE)));
+    let out = flag(&flags, "out").unwrap_or_else(|| fail(BACKUP_USAGE));
+    let config = Config::load();
+    let data_dir = PathBuf::from(env::var("DATA_DIR").unwrap_or_else(|_| String::from(".")));
+    match backup::backup(&data_dir, config.queue.as_ref(), Path::new(out)) {
+        Ok(backed_up) => println!(
+            "Backed up {} and {} shared entries to {}",
+            backed_up.files.join(", "),
+            backed_up.shared,
+            out
+        ),
+        Err(e) => fail(&e.to_string()),
+    }
+}
+
+const RESTORE_USAGE: &str = "Usage: limail restore --from <state.tar.zst>";
+
+// Restores a `limail backup` into an empty DATA_DIR, before limail is
+// started there.
+pub fn restore(args: &[String]) {
+    let flags = parse_flags(args, &["from"])
+        .unwrap_or_else(|e| fail(&format!("{}\n{}", e, RESTORE_USAGE)));
+    let from = flag(&flags, "from").unwrap_or_else(|| fail(RESTORE_USAGE));
+    let config = Config::load();
+    let data_dir = PathBuf::from(env::var("DATA_DIR").unwrap_or_else(|_| String::from(".")));
+    match backup::restore(&data_dir, config.queue.as_ref(), Path::new(from)) {
+        Ok(restored) => {
+            println!(
+                "Restored {} from the backup limail {} made at {}",
+                restored.files.join(", "),
+                restored.version,
+                restored.created_at.to_rfc3339()
+            );
+            println!("Restored {} of {} shared entries, the rest had expired or were already set", restored.shared_restored, restored.shared);
+        },
+        Err(e) => fail(&e.to_string()),
+    }
+}
+
+const SIMULATE_USAGE: &str = "Usage: limail simulate --since <7d|12h|30m> --rules <config.toml> [--window <minutes>]";
+
+// Replays archived emails through a candidate LIMAIL_CONFIG and lists the
+// ones it would have handled differently, so rule changes can be checked
+// before they're deployed. The time between responses is --window, or
+// TIME_BETWEEN_RESPONSES_MINUTES.
+pub fn simulate(args: &[String]) {
+    let flags = parse_flags(args, &["since", "rules", "window"])
+        .unwrap_or_else(|e| fail(&format!("{}\n{}", e, SIMULATE_USAGE)));
+    let (since, rules) = match (flag(&flags, "since"), flag(&flags, "rules")) {
+        (Some(since), Some(rules)) => (since, rules),
+        _ => fail(SIMULATE_USAGE),
+    };
+    let since = Utc::now() - simulate::parse_since(since).unwrap_or_else(|e| fail(&e));
+    let window = flag(&flags, "window").map(String::from).or_else(|| env::var("TIME_BETWEEN_RESPONSES_MINUTES").ok());
+    let time_between_responses = window.map(|window| window.trim().parse::<i64>()
+        .unwrap_or_else(|_| fail(&format!("Invalid time between responses {}, expected a number of minutes", window))));
+    let candidate = Config::from_file(rules).unwrap_or_else(|e| fail(&e));
+    let data_dir = PathBuf::from(env::var("DATA_DIR").unwrap_or_else(|_| String::from(".")));
+    let scratch_dir = env::temp_dir().join(format!("limail-simulate-{}", process::id()));
+    let simulation = simulate::run(&Config::load(), candidate, &data_dir, &scratch_dir, since, time_between_responses);
+    if let Err(e) = fs::remove_dir_all(&scratch_dir) {
+        eprintln!("Unable to remove {}: {}", scratch_dir.display(), e);
+    }
+    let simulation = simulation.unwrap_or_else(|e| fail(&e.to_string()));
+    for difference in &simulation.differences {
+        println!(
+            "{} {} {} from {}: {} -> {}",
+            difference.received_at.to_rfc3339(),
+            difference.id,
+            difference.route,
+            difference.from,
+            difference.actual,
+            difference.simulated
+        );
+    }
+    println!(
+        "Replayed {} emails, {} would be handled differently, skipped {} without an outcome",
+        simulation.replayed,
+        simulation.differences.len(),
+        simulation.skipped
+    );
+    match time_between_responses {
+        Some(minutes) => println!("Replied at most once every {} minutes. Sender history and variant counts start empty.", minutes),
+        None => println!("Not simulated: the time between responses. Sender history and variant counts start empty."),
+    }
+}
@@ -0,0 +1,74 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use chrono::{DateTime, Duration, Utc};
+
+#[derive(Clone)]
+enum Source {
+    // The system time, shifted by offset (zero unless LIMAIL_CLOCK is set).
+    System { offset: Duration },
+    // Stands still until it's set or advanced.
+    Manual(Arc<(Mutex<DateTime<Utc>>, Condvar)>),
+}
+
+// Where the rate limiter, the scheduled reports, delayed replies, SLA
+// reminders, budgets, cooldowns, retention and everything else keeping
+// time gets it from, so they can be run at another time than now:
+// LIMAIL_CLOCK starts the clock at a given time (on staging, say, to see
+// Monday's weekly report on a Thursday), and simulations and tests move a
+// manual clock themselves.
+#[derive(Clone)]
+pub struct Clock {
+    source: Source,
+}
+
+impl Clock {
+    pub fn system() -> Clock {
+        Clock { source: Source::System { offset: Duration::zero() } }
+    }
+
+    // Runs at the normal speed from `at`.
+    pub fn starting_at(at: DateTime<Utc>) -> Clock {
+        Clock { source: Source::System { offset: at - Utc::now() } }
+    }
+
+    pub fn manual(at: DateTime<Utc>) -> Clock {
+        Clock { source: Source::Manual(Arc::new((Mutex::new(at), Condvar::new()))) }
+    }
+
+    pub fn now(&self) -> DateTime<Utc> {
+        match &self.source {
+            Source::System { offset } => Utc::now() + *offset,
+            Source::Manual(manual) => *manual.0.lock().unwrap(),
+        }
+    }
+
+    // Only moves a manual clock, and wakes whoever is sleeping on it.
+    pub fn set(&self, at: DateTime<Utc>) {
+        match &self.source {
+            Source::System { .. } => warn!("Not setting the system clock to {}", at.to_rfc3339()),
+            Source::Manual(manual) => {
+                let (lock, condvar) = &**manual;
+                *lock.lock().unwrap() = at;
+                condvar.notify_all();
+            },
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.set(self.now() + by);
+    }
+
+    pub fn sleep_until(&self, due: DateTime<Utc>) {
+        match &self.source {
+            Source::System { .. } => thread::sleep((due - self.now()).to_std().unwrap_or_default()),
+            Source::Manual(manual) => {
+                let (lock, condvar) = &**manual;
+                let mut now = lock.lock().unwrap();
+                while *now < due {
+                    now = condvar.wait(now).unwrap();
+                }
+            },
+        }
+    }
+}
@@ -0,0 +1,189 @@
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use serde::{Serialize, Deserialize};
+use sha2::Sha256;
+
+use crate::admin::RedactMode;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Slack rejects replays older than this itself, and so do we.
+const MAX_AGE_SECONDS: i64 = 5 * 60;
+
+// The /limail slash command, the canned reply menu and the Events API. Requests are signed
+// with the app's signing secret; users restricts who may use them (Slack
+// user ids, everyone in the workspace when empty).
+#[derive(Deserialize, Clone)]
+pub struct SlashCommandConfig {
+    pub signing_secret: String,
+    #[serde(default)]
+    pub users: Vec<String>,
+}
+
+// The parts of Slack's slash command form we use.
+#[derive(Deserialize)]
+pub struct SlashCommand {
+    pub user_id: String,
+    pub user_name: String,
+    #[serde(default)]
+    pub text: String,
+}
+
+#[derive(Serialize)]
+pub struct SlashResponse {
+    pub response_type: &'static str,
+    pub text: String,
+}
+
+impl SlashResponse {
+    // Only shown to whoever ran the command.
+    pub fn ephemeral(text: String) -> SlashResponse {
+        SlashResponse {
+            response_type: "ephemeral",
+            text,
+        }
+    }
+}
+
+// Interactive components (the canned reply menu) post a form with the
+// event as JSON in `payload`, signed the same way as slash commands.
+#[derive(Deserialize)]
+pub struct InteractionForm {
+    pub payload: String,
+}
+
+#[derive(Deserialize)]
+pub struct InteractionUser {
+    pub id: String,
+    #[serde(default)]
+    pub username: String,
+}
+
+#[derive(Deserialize)]
+pub struct SelectedOption {
+    pub value: String,
+}
+
+#[derive(Deserialize)]
+pub struct InteractionAction {
+    pub action_id: String,
+    #[serde(default)]
+    pub block_id: String,
+    #[serde(default)]
+    pub selected_option: Option<SelectedOption>,
+}
+
+#[derive(Deserialize)]
+pub struct InteractionChannel {
+    pub id: String,
+}
+
+#[derive(Deserialize)]
+pub struct InteractionMessage {
+    pub ts: String,
+    #[serde(default)]
+    pub thread_ts: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct Interaction {
+    pub user: InteractionUser,
+    #[serde(default)]
+    pub actions: Vec<InteractionAction>,
+    #[serde(default)]
+    pub channel: Option<InteractionChannel>,
+    #[serde(default)]
+    pub message: Option<InteractionMessage>,
+}
+
+// An Events API callback (reactions, see handling.rs), or the one-off
+// url_verification challenge Slack sends when the URL is configured.
+#[derive(Deserialize)]
+pub struct EventEnvelope {
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default)]
+    pub challenge: Option<String>,
+    #[serde(default)]
+    pub event: Option<Event>,
+}
+
+#[derive(Deserialize)]
+pub struct Event {
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default)]
+    pub user: String,
+    #[serde(default)]
+    pub reaction: String,
+    #[serde(default)]
+    pub item: Option<EventItem>,
+}
+
+#[derive(Deserialize)]
+pub struct EventItem {
+    #[serde(default)]
+    pub channel: String,
+    #[serde(default)]
+    pub ts: String,
+}
+
+pub enum Command {
+    Redact { id: String, mode: RedactMode },
+    Help,
+}
+
+pub const USAGE: &str = "Usage: `/limail delete <limail id or link>` removes a forwarded email from Slack and scrubs the archived copy, `/limail redact <limail id or link>` leaves a placeholder instead.";
+
+impl SlashCommandConfig {
+    // See https://api.slack.com/authentication/verifying-requests-from-slack
+    pub fn verify(&self, timestamp: &str, signature: &str, body: &[u8]) -> bool {
+        match timestamp.parse::<i64>() {
+            Ok(at) if (Utc::now().timestamp() - at).abs() <= MAX_AGE_SECONDS => (),
+            _ => return false,
+        }
+        if !signature.starts_with("v0=") {
+            return false;
+        }
+        let signature = match hex::decode(&signature[3..]) {
+            Ok(signature) => signature,
+            Err(_) => return false,
+        };
+        let mut mac = HmacSha256::new_varkey(self.signing_secret.as_bytes()).expect("HMAC takes keys of any size");
+        mac.input(format!("v0:{}:", timestamp).as_bytes());
+        mac.input(body);
+        mac.verify(&signature).is_ok()
+    }
+
+    pub fn allows(&self, user_id: &str) -> bool {
+        self.users.is_empty() || self.users.iter().any(|user| user == user_id)
+    }
+}
+
+// Accepts the id limail posts with every forward, or a link to the archive
+// viewer (which Slack wraps in <...|label>).
+fn email_id(argument: &str) -> Option<String> {
+    let argument = argument.trim_start_matches('<').split(|c| c == '|' || c == '>').next()?;
+    let id = match argument.find("/archive/") {
+        Some(start) => argument[start + "/archive/".len()..].split(|c| c == '?' || c == '/').next()?,
+        None => argument,
+    };
+    if !id.is_empty() && id.chars().all(|c| c.is_ascii_hexdigit()) {
+        Some(id.to_lowercase())
+    } else {
+        None
+    }
+}
+
+pub fn parse(text: &str) -> Command {
+    let mut words = text.split_whitespace();
+    let mode = match words.next() {
+        Some("delete") => RedactMode::Delete,
+        Some("redact") => RedactMode::Redact,
+        _ => return Command::Help,
+    };
+    match words.next().and_then(email_id) {
+        Some(id) => Command::Redact { id, mode },
+        None => Command::Help,
+    }
+}
@@ -0,0 +1,190 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+
+use serde::Deserialize;
+
+use crate::addresses::ReplyToRule;
+use crate::alerts::AlertRule;
+use crate::apilimit::ApiLimitConfig;
+use crate::auth::Scope;
+use crate::authresults::AuthResultsConfig;
+use crate::budget::SendBudgetConfig;
+use crate::bounces::BounceConfig;
+use crate::canned::CannedReplies;
+use crate::chaos::ChaosConfig;
+use crate::captures::CaptureConfig;
+use crate::commands::SlashCommandConfig;
+use crate::copies::CopyConfig;
+use crate::defang::LinkSafety;
+use crate::domains::SendingDomainsConfig;
+use crate::delays::ReplyDelay;
+use crate::digest::DigestConfig;
+use crate::drift::DriftConfig;
+use crate::earlyack::EarlyAckConfig;
+use crate::echo::EchoConfig;
+use crate::encryption::EncryptionConfig;
+use crate::feedback::FeedbackConfig;
+use crate::flags::RouteFlags;
+use crate::footers::FooterConfig;
+use crate::formatting::Formatting;
+use crate::forwards::EmailForward;
+use crate::handling::HandlingConfig;
+use crate::links::LinkConfig;
+use crate::listener::HttpConfig;
+use crate::loops::LoopConfig;
+use crate::metrics::MetricsConfig;
+use crate::notices::NoticeConfig;
+use crate::pacing::PacingConfig;
+use crate::pgp::PgpConfig;
+use crate::pipeline::DeadlineConfig;
+use crate::policy::ResponsePolicy;
+use crate::publish::PublishConfig;
+use crate::quarantine::QuarantineConfig;
+use crate::queue::QueueConfig;
+use crate::reputation::ReputationConfig;
+use crate::sandbox::SandboxConfig;
+use crate::scrub::ScrubRule;
+use crate::signatures::RouteSignature;
+use crate::slack::{SlackConfig, SlackIdentity};
+use crate::smime::SmimeConfig;
+use crate::takeover::TakeoverConfig;
+use crate::templatecheck::TemplateCheckConfig;
+use crate::threats::ThreatIntelConfig;
+use crate::trace::TraceConfig;
+use crate::unrouted::UnroutedConfig;
+use crate::unsubscribe::UnsubscribeConfig;
+use crate::variants::VariantConfig;
+use crate::weekly::WeeklyReportConfig;
+
+// Everything that doesn't fit comfortably in an environment variable lives
+// in the optional TOML file pointed at by LIMAIL_CONFIG.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct Config {
+    pub admin: AdminConfig,
+    pub alerts: Vec<AlertRule>,
+    pub archive_encryption: Option<EncryptionConfig>,
+    pub auth_results: AuthResultsConfig,
+    pub bounces: Option<BounceConfig>,
+    pub canned_replies: Vec<CannedReplies>,
+    pub captures: Option<CaptureConfig>,
+    pub chaos: ChaosConfig,
+    pub deadlines: DeadlineConfig,
+    pub digests: Vec<DigestConfig>,
+    pub drift: Option<DriftConfig>,
+    pub early_ack: Option<EarlyAckConfig>,
+    pub echo: Option<EchoConfig>,
+    pub email_forwards: Vec<EmailForward>,
+    // Environment variables for what isn't in this file, the Mailgun domain
+    // say. Those given to limail itself win.
+    pub env: BTreeMap<String, String>,
+    pub feedback: Option<FeedbackConfig>,
+    pub footer: Option<FooterConfig>,
+    pub formatting: Vec<Formatting>,
+    pub handling: Option<HandlingConfig>,
+    pub http: HttpConfig,
+    pub identities: Vec<SlackIdentity>,
+    pub link_safety: Vec<LinkSafety>,
+    pub links: Option<LinkConfig>,
+    pub loops: Option<LoopConfig>,
+    pub metrics: MetricsConfig,
+    pub notices: NoticeConfig,
+    pub pacing: Option<PacingConfig>,
+    pub pgp: Option<PgpConfig>,
+    pub publish: Option<PublishConfig>,
+    pub quarantine: Option<QuarantineConfig>,
+    pub queue: Option<QueueConfig>,
+    pub reply_delays: Vec<ReplyDelay>,
+    pub reply_to: Vec<ReplyToRule>,
+    pub route_flags: Vec<RouteFlags>,
+    pub reputation: Option<ReputationConfig>,
+    pub responses: ResponsePolicy,
+    pub sandbox: Option<SandboxConfig>,
+    pub send_budget: Option<SendBudgetConfig>,
+    pub sending_domains: Option<SendingDomainsConfig>,
+    pub sent_copies: Option<CopyConfig>,
+    pub scrub: Vec<ScrubRule>,
+    pub signatures: Vec<RouteSignature>,
+    pub slack: SlackConfig,
+    pub slash_command: Option<SlashCommandConfig>,
+    pub smime: SmimeConfig,
+    pub takeover: Option<TakeoverConfig>,
+    pub template_check: TemplateCheckConfig,
+    pub threat_intel: Option<ThreatIntelConfig>,
+    pub trace: Option<TraceConfig>,
+    pub unrouted: UnroutedConfig,
+    pub unsubscribe: Option<UnsubscribeConfig>,
+    pub variants: Vec<VariantConfig>,
+    pub weekly_report: Option<WeeklyReportConfig>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct AdminConfig {
+    pub rate_limit: Option<ApiLimitConfig>,
+    pub tokens: Vec<TokenConfig>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct TokenConfig {
+    pub name: String,
+    pub token: String,
+    pub scopes: Vec<Scope>,
+}
+
+impl Config {
+    pub fn load() -> Config {
+        let path = match env::var("LIMAIL_CONFIG") {
+            Ok(path) => path,
+            Err(_) => return Config::default(),
+        };
+        Config::from_file(&path).unwrap_or_else(|e| panic!("LIMAIL_CONFIG: {}", e))
+    }
+
+    pub fn from_file(path: &str) -> Result<Config, String> {
+        let contents = fs::read_to_string(path).map_err(|e| format!("Unable to read {}: {}", path, e))?;
+        let mut value: toml::Value = toml::from_str(&contents).map_err(|e| format!("Invalid config {}: {}", path, e))?;
+        apply_profile(&mut value, env::var("LIMAIL_ENV").ok().as_ref().map(|name| &name[..]))
+            .map_err(|e| format!("{}: {}", path, e))?;
+        value.try_into().map_err(|e| format!("Invalid config {}: {}", path, e))
+    }
+}
+
+// [profiles.<name>] tables (staging, say) hold what's different when
+// LIMAIL_ENV=<name>, over the rest of the file: tables are merged, while
+// anything else, arrays included, is replaced. Without LIMAIL_ENV it's
+// the rest of the file as is.
+fn apply_profile(config: &mut toml::Value, name: Option<&str>) -> Result<(), String> {
+    let profiles = match config.as_table_mut().and_then(|table| table.remove("profiles")) {
+        Some(profiles) => profiles,
+        None => toml::Value::Table(toml::value::Table::new()),
+    };
+    let name = match name {
+        Some(name) if !name.trim().is_empty() => name.trim(),
+        _ => return Ok(()),
+    };
+    match profiles.get(name) {
+        Some(profile) if profile.is_table() => {
+            merge(config, profile.clone());
+            Ok(())
+        },
+        _ => Err(format!("LIMAIL_ENV is {}, but there's no [profiles.{}]", name, name)),
+    }
+}
+
+fn merge(base: &mut toml::Value, overrides: toml::Value) {
+    match (base, overrides) {
+        (toml::Value::Table(base), toml::Value::Table(overrides)) => {
+            for (key, value) in overrides {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    },
+                }
+            }
+        },
+        (base, overrides) => *base = overrides,
+    }
+}
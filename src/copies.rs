@@ -0,0 +1,173 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::clock::Clock;
+use crate::mailgun::Mailgun;
+use crate::outbox::OutboxEntry;
+use crate::store::{self, StoreError};
+
+fn default_dir() -> String {
+    String::from("sent")
+}
+
+fn default_retention_days() -> u64 {
+    90
+}
+
+fn default_delay_seconds() -> u64 {
+    60
+}
+
+// Copies of the emails we sent as the recipient got them, with the Mailgun
+// template filled in, so a dispute about what limail told someone can be
+// settled. Mailgun only keeps them for a few days, so each is fetched from
+// its stored messages delay_seconds after sending (once its events have
+// caught up), kept in dir (relative to DATA_DIR) and deleted after
+// retention_days.
+#[derive(Deserialize, Clone)]
+pub struct CopyConfig {
+    #[serde(default = "default_dir")]
+    pub dir: String,
+    #[serde(default = "default_retention_days")]
+    pub retention_days: u64,
+    #[serde(default = "default_delay_seconds")]
+    pub delay_seconds: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SentCopy {
+    pub at: DateTime<Utc>,
+    pub message_id: String,
+    pub recipient: String,
+    pub template: String,
+    pub subject: String,
+    pub body_plain: String,
+    pub body_html: Option<String>,
+    #[serde(default)]
+    pub sent_by: Option<String>,
+    #[serde(default)]
+    pub job_id: Option<String>,
+}
+
+// Mailgun may not have indexed the message yet on the first try.
+const ATTEMPTS: u32 = 3;
+
+#[derive(Clone)]
+pub struct Copies {
+    config: CopyConfig,
+    dir: PathBuf,
+    mailgun: Mailgun,
+    clock: Clock,
+}
+
+// Message-IDs have <, > and @ in them, which make poor file names.
+fn file_name(message_id: &str) -> String {
+    format!("{}.json", hex::encode(&Sha256::digest(message_id.trim().as_bytes())[..12]))
+}
+
+impl Copies {
+    pub fn new(config: CopyConfig, data_dir: &Path, mailgun: Mailgun) -> Copies {
+        let dir = data_dir.join(&config.dir);
+        Copies { config, dir, mailgun, clock: Clock::system() }
+    }
+
+    pub fn with_clock(self, clock: Clock) -> Copies {
+        Copies {
+            clock,
+            ..self
+        }
+    }
+
+    // In the background, the email having been sent already.
+    pub fn keep(&self, entry: &OutboxEntry) {
+        let (copies, entry) = (self.clone(), entry.clone());
+        thread::spawn(move || {
+            for attempt in 1..=ATTEMPTS {
+                thread::sleep(Duration::from_secs(copies.config.delay_seconds * u64::from(attempt)));
+                match copies.fetch(&entry) {
+                    Ok(Some(copy)) => {
+                        if let Err(e) = store::write_json(&copies.dir.join(file_name(&copy.message_id)), &copy) {
+                            error!("Unable to keep a copy of the email to {}: {}", copy.recipient, e);
+                        }
+                        break;
+                    },
+                    Ok(None) if attempt < ATTEMPTS => (),
+                    Ok(None) => warn!("Mailgun has no stored copy of {} to {}", entry.message_id, entry.recipient),
+                    Err(e) => {
+                        error!("Unable to fetch the copy of {} to {}: {}", entry.message_id, entry.recipient, e);
+                        break;
+                    },
+                }
+            }
+            if let Err(e) = copies.expire() {
+                error!("Unable to delete old copies of sent emails: {}", e);
+            }
+        });
+    }
+
+    pub fn get(&self, message_id: &str) -> Result<Option<SentCopy>, StoreError> {
+        store::read_json(&self.dir.join(file_name(message_id)))
+    }
+
+    // The accepted event has where Mailgun stored the message. Left to the
+    // sending domain, which is in the Message-ID, when there are several.
+    fn fetch(&self, entry: &OutboxEntry) -> Result<Option<SentCopy>, String> {
+        let id = entry.message_id.trim().trim_start_matches('<').trim_end_matches('>');
+        let domain = id.rsplit('@').next().filter(|domain| domain.contains('.')).unwrap_or(&self.mailgun.domain);
+        let client = reqwest::Client::builder()
+            .timeout(self.mailgun.timeout)
+            .build()
+            .map_err(|e| e.to_string())?;
+        let redact = |e: reqwest::Error| self.mailgun.api_key.redact(&e.to_string());
+        let events: Value = client.get(&format!("{}/{}/events", self.mailgun.api_url.trim_end_matches('/'), domain))
+            .query(&[("message-id", id), ("event", "accepted")])
+            .basic_auth("api", Some(self.mailgun.api_key.expose()))
+            .send()
+            .and_then(|response| response.error_for_status())
+            .and_then(|mut response| response.json())
+            .map_err(redact)?;
+        let url = match events["items"][0]["storage"]["url"].as_str() {
+            Some(url) => url,
+            None => return Ok(None),
+        };
+        let stored: Value = client.get(url)
+            .header("Accept", "application/json")
+            .basic_auth("api", Some(self.mailgun.api_key.expose()))
+            .send()
+            .and_then(|response| response.error_for_status())
+            .and_then(|mut response| response.json())
+            .map_err(redact)?;
+        Ok(Some(SentCopy {
+            at: entry.at,
+            message_id: entry.message_id.clone(),
+            recipient: entry.recipient.clone(),
+            template: entry.template.clone(),
+            subject: stored["subject"].as_str().unwrap_or(&entry.subject).to_string(),
+            body_plain: stored["body-plain"].as_str().unwrap_or_default().to_string(),
+            body_html: stored["body-html"].as_str().map(String::from),
+            sent_by: entry.sent_by.clone(),
+            job_id: entry.job_id.clone(),
+        }))
+    }
+
+    // By when each was sent, not the files' mtimes, which know nothing of
+    // the clock.
+    fn expire(&self) -> Result<(), StoreError> {
+        let cutoff = self.clock.now() - chrono::Duration::days(self.config.retention_days as i64);
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            match store::read_json::<SentCopy>(&path) {
+                Ok(Some(copy)) if copy.at < cutoff => fs::remove_file(path)?,
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+}
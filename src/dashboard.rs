@@ -0,0 +1,292 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+
+use crate::flags::RouteFlags;
+use crate::metrics::Metrics;
+
+pub struct RateLimitState {
+    pub window_minutes: i64,
+    pub last_responses: Vec<(String, DateTime<Utc>)>,
+}
+
+pub fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn format_time(dt: &DateTime<Utc>) -> String {
+    dt.format("%Y-%m-%d %H:%M:%S UTC").to_string()
+}
+
+fn counter_rows<'a>(counters: impl Iterator<Item = (&'a String, &'a u64)>) -> String {
+    let rows: Vec<String> = counters
+        .map(|(name, value)| format!("<tr><td>{}</td><td>{}</td></tr>", escape(name), value))
+        .collect();
+    if rows.is_empty() {
+        String::from("<tr><td colspan=\"2\">Nothing yet</td></tr>")
+    } else {
+        rows.join("\n")
+    }
+}
+
+fn blocklist_rows(blocklist: &[String]) -> String {
+    blocklist.iter()
+        .map(|entry| format!(
+            r#"<tr><td>{entry}</td><td><form method="post" action="/dashboard/blocklist">
+<input type="hidden" name="action" value="remove">
+<input type="hidden" name="entry" value="{entry}">
+<button type="submit">Remove</button>
+</form></td></tr>"#,
+            entry = escape(entry)
+        ))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+// A form can't span a table row's cells, so the inputs name theirs.
+fn checkbox(form: &str, name: &str, checked: bool) -> String {
+    format!(
+        r#"<input type="checkbox" form="{}" name="{}"{}>"#,
+        form,
+        name,
+        if checked { " checked" } else { "" }
+    )
+}
+
+fn route_flag_rows(routes: &[RouteFlags]) -> String {
+    routes.iter()
+        .enumerate()
+        .map(|(i, rule)| {
+            let form = format!("route-{}", i);
+            format!(
+                r#"<tr><td>{route}</td>
+<td>{verify_signature}</td><td>{archive}</td><td>{enrich}</td><td>{auto_reply}</td>
+<td><form id="{form}" method="post" action="/dashboard/routes">
+<input type="hidden" name="route" value="{route}">
+<button type="submit" name="action" value="set">Save</button>
+<button type="submit" name="action" value="remove">Remove</button>
+</form></td></tr>"#,
+                route = escape(&rule.route),
+                form = form,
+                verify_signature = checkbox(&form, "verify_signature", rule.verify_signature),
+                archive = checkbox(&form, "archive", rule.archive),
+                enrich = checkbox(&form, "enrich", rule.enrich),
+                auto_reply = checkbox(&form, "auto_reply", rule.auto_reply),
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+fn queue_summary(queue_depth: Option<Result<u64, String>>) -> String {
+    match queue_depth {
+        None => String::from("Jobs are delivered inline, there is no queue."),
+        Some(Ok(depth)) => format!("{} jobs waiting or in progress.", depth),
+        Some(Err(e)) => format!("Unable to read the queue: {}", escape(&e)),
+    }
+}
+
+pub fn render(
+    metrics: &Metrics,
+    rate_limit: &RateLimitState,
+    blocklist: &[String],
+    queue_depth: Option<Result<u64, String>>,
+    unhandled: Option<usize>,
+    routes: &[RouteFlags],
+) -> String {
+    // The labeled breakdowns are for the stats API, too many for a table.
+    let counters: BTreeMap<String, u64> = metrics.counters().into_iter()
+        .filter(|(name, _)| !name.contains('{'))
+        .collect();
+    let errors = counter_rows(counters.iter().filter(|(name, _)| name.starts_with("errors_")));
+    let activity = counter_rows(counters.iter().filter(|(name, _)| !name.starts_with("errors_")));
+
+    let mut last_responses = rate_limit.last_responses.clone();
+    last_responses.sort_by(|a, b| b.1.cmp(&a.1));
+    let last_responses: String = last_responses.iter()
+        .map(|(email, dt)| format!(
+            "<tr><td>{}</td><td>{}</td></tr>",
+            escape(email),
+            format_time(dt)
+        ))
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    let recent_emails: String = metrics.recent_emails().iter()
+        .map(|email| format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            format_time(&email.received_at),
+            escape(&email.route),
+            escape(&email.from),
+            escape(&email.subject),
+            escape(&email.outcome)
+        ))
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    format!(r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta http-equiv="refresh" content="30">
+<title>limail</title>
+<style>
+body {{ font-family: sans-serif; margin: 2em; }}
+table {{ border-collapse: collapse; margin-bottom: 2em; }}
+td, th {{ border: 1px solid #ccc; padding: 0.2em 0.6em; text-align: left; }}
+</style>
+</head>
+<body>
+<h1>limail</h1>
+<h2>Errors</h2>
+<table>
+{errors}
+</table>
+<h2>Activity</h2>
+<table>
+{activity}
+</table>
+<h2>Queue</h2>
+<p>{queue}</p>
+{unhandled}
+<h2>Rate limiter</h2>
+<p>One auto-reply per sender every {window} minutes. Currently tracking {tracked} senders.</p>
+<table>
+<tr><th>Sender</th><th>Last reply</th></tr>
+{last_responses}
+</table>
+<h2>Blocklist</h2>
+<p>Emails from these addresses (or @domains) are acknowledged but neither answered nor forwarded.</p>
+<table>
+{blocklist}
+</table>
+<form method="post" action="/dashboard/blocklist">
+<input type="hidden" name="action" value="add">
+<input type="text" name="entry" placeholder="spammer@example.com or @example.com">
+<button type="submit">Block</button>
+</form>
+<h2>Routing rules</h2>
+<p>The first rule matching a route (exactly, or a prefix ending in *) switches off what's unticked, everything is on for routes none match. Changes apply to the next webhook.</p>
+<table>
+<tr><th>Route</th><th>Verify signature</th><th>Archive</th><th>Enrich</th><th>Auto-reply</th><th></th></tr>
+{routes}
+<tr><td><input type="text" form="route-new" name="route" placeholder="responder/example or responder/*"></td>
+<td>{new_verify_signature}</td><td>{new_archive}</td><td>{new_enrich}</td><td>{new_auto_reply}</td>
+<td><form id="route-new" method="post" action="/dashboard/routes">
+<button type="submit" name="action" value="set">Add</button>
+</form></td></tr>
+</table>
+<h2>Templates</h2>
+<p><a href="/dashboard/templates">Mailgun's stored templates</a>, to create or edit.</p>
+<h2>Recent emails</h2>
+<table>
+<tr><th>Received</th><th>Route</th><th>From</th><th>Subject</th><th>Outcome</th></tr>
+{recent_emails}
+</table>
+</body>
+</html>
+"#,
+        errors = errors,
+        activity = activity,
+        queue = queue_summary(queue_depth),
+        unhandled = unhandled
+            .map(|count| format!("<h2>Slack</h2>\n<p>{} forwarded emails nobody has marked handled yet.</p>", count))
+            .unwrap_or_default(),
+        window = rate_limit.window_minutes,
+        tracked = rate_limit.last_responses.len(),
+        last_responses = last_responses,
+        blocklist = blocklist_rows(blocklist),
+        routes = route_flag_rows(routes),
+        new_verify_signature = checkbox("route-new", "verify_signature", true),
+        new_archive = checkbox("route-new", "archive", true),
+        new_enrich = checkbox("route-new", "enrich", true),
+        new_auto_reply = checkbox("route-new", "auto_reply", true),
+        recent_emails = recent_emails,
+    )
+}
+
+// The template pages don't refresh, it would throw away what's being typed.
+fn page(title: &str, body: &str) -> String {
+    format!(r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{title}</title>
+<style>
+body {{ font-family: sans-serif; margin: 2em; }}
+table {{ border-collapse: collapse; margin-bottom: 2em; }}
+td, th {{ border: 1px solid #ccc; padding: 0.2em 0.6em; text-align: left; }}
+textarea {{ width: 100%; height: 30em; font-family: monospace; }}
+</style>
+</head>
+<body>
+<p><a href="/dashboard">limail</a></p>
+{body}
+</body>
+</html>
+"#,
+        title = escape(title),
+        body = body,
+    )
+}
+
+// What Mailgun's list_templates returned.
+pub fn render_templates(templates: &Value) -> String {
+    let rows: Vec<String> = templates["items"].as_array().map(|items| &items[..]).unwrap_or(&[]).iter()
+        .filter_map(|template| {
+            let name = template["name"].as_str()?;
+            Some(format!(
+                r#"<tr><td><a href="/dashboard/templates/{name}">{name}</a></td><td>{description}</td><td>{created}</td></tr>"#,
+                name = escape(name),
+                description = escape(template["description"].as_str().unwrap_or("")),
+                created = escape(template["createdAt"].as_str().unwrap_or("")),
+            ))
+        })
+        .collect();
+    page("limail templates", &format!(r#"<h1>Templates</h1>
+<p>Mailgun's stored templates (Handlebars), which replies are sent with.</p>
+<table>
+<tr><th>Name</th><th>Description</th><th>Created</th></tr>
+{rows}
+</table>
+<h2>New template</h2>
+<form method="post" action="/dashboard/templates">
+<p><input type="text" name="name" placeholder="name"> <input type="text" name="description" placeholder="description"></p>
+<p><textarea name="template"></textarea></p>
+<p><input type="text" name="tag" placeholder="version tag (optional)"> <input type="text" name="comment" placeholder="comment (optional)"></p>
+<button type="submit">Create</button>
+</form>"#,
+        rows = if rows.is_empty() { String::from("<tr><td colspan=\"3\">None yet</td></tr>") } else { rows.join("\n") },
+    ))
+}
+
+// What Mailgun's get_template returned, with its active version.
+pub fn render_template(name: &str, template: &Value) -> String {
+    let version = &template["template"]["version"];
+    page(&format!("limail template {}", name), &format!(r#"<h1>{name}</h1>
+<p>{description}</p>
+<p>Active version {tag}, {comment}. Saving adds a new version and makes it the active one, the old ones stay in Mailgun.</p>
+<form method="post" action="/dashboard/templates/{name}">
+<p><textarea name="template">{content}</textarea></p>
+<p><input type="text" name="tag" placeholder="version tag (optional)"> <input type="text" name="comment" placeholder="comment (optional)"></p>
+<button type="submit">Save</button>
+</form>"#,
+        name = escape(name),
+        description = escape(template["template"]["description"].as_str().unwrap_or("")),
+        tag = escape(version["tag"].as_str().unwrap_or("untagged")),
+        comment = escape(version["comment"].as_str().unwrap_or("no comment")),
+        content = escape(version["template"].as_str().unwrap_or("")),
+    ))
+}
@@ -0,0 +1,76 @@
+use regex::{Captures, Regex};
+use serde::Deserialize;
+
+use crate::pipeline::route_matches;
+use crate::slack::SlackMessage;
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UrlMode {
+    Keep,
+    // hxxps://example[.]com, which nothing turns back into a link.
+    Defang,
+    // In `backticks`, so they're shown but not linked. The body is in a
+    // code block already, so this is for the subject.
+    Code,
+}
+
+fn default_url_mode() -> UrlMode {
+    UrlMode::Defang
+}
+
+// What's done about the links in emails forwarded to channels (exact ids,
+// or a prefix ending in *), so a phishing email can't be clicked through
+// from Slack, or fill the channel with previews. The first matching entry
+// wins, other channels get the links as they are, unfurled as Slack
+// pleases. The link to the archived copy is left alone.
+#[derive(Deserialize, Clone)]
+pub struct LinkSafety {
+    pub channel: String,
+    #[serde(default = "default_url_mode")]
+    pub urls: UrlMode,
+    #[serde(default)]
+    pub unfurl_links: bool,
+    #[serde(default)]
+    pub unfurl_media: bool,
+}
+
+// http(s) links, with the host on its own.
+pub fn url_pattern() -> Regex {
+    Regex::new(r"(?i)\b(https?)://([^\s/<>|`]+)([^\s<>|`]*)").unwrap()
+}
+
+impl LinkSafety {
+    pub fn for_channel<'a>(rules: &'a [LinkSafety], channel: &str) -> Option<&'a LinkSafety> {
+        rules.iter().find(|rule| route_matches(&rule.channel, channel))
+    }
+
+    // The body is posted in a code block, where backticks would show.
+    pub fn body(&self, text: &str) -> String {
+        match self.urls {
+            UrlMode::Code => String::from(text),
+            _ => self.apply(text),
+        }
+    }
+
+    pub fn apply(&self, text: &str) -> String {
+        match self.urls {
+            UrlMode::Keep => String::from(text),
+            UrlMode::Defang => url_pattern().replace_all(text, |captures: &Captures| format!(
+                "{}://{}{}",
+                captures[1].replacen('t', "x", 2).replacen('T', "X", 2),
+                captures[2].replace('.', "[.]"),
+                &captures[3],
+            )).into_owned(),
+            UrlMode::Code => url_pattern().replace_all(text, "`$0`").into_owned(),
+        }
+    }
+
+    pub fn unfurl(&self, message: SlackMessage) -> SlackMessage {
+        SlackMessage {
+            unfurl_links: Some(self.unfurl_links),
+            unfurl_media: Some(self.unfurl_media),
+            ..message
+        }
+    }
+}
@@ -0,0 +1,236 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Serialize, Deserialize};
+use serde_json::{json, Value};
+
+use crate::clock::Clock;
+use crate::pipeline::route_matches;
+use crate::store::{self, StoreError};
+
+pub const ACTION_ID: &str = "cancel_reply";
+
+// The auto-replies of respond-and-forward routes (exact, or a prefix ending
+// in *) wait `seconds` after the forward is posted, and its Cancel reply
+// button stops them in the meantime, for a mod who'd rather answer
+// personally. Pressing it takes the same Slack users as the canned
+// replies, see [slash_command]. Replies still waiting when limail stops
+// are sent once it starts again. With a [queue] the worker that sent the
+// forward sends the reply, but any process can get the button pressed, so
+// the replies waiting are kept in redis.
+#[derive(Deserialize, Clone)]
+pub struct ReplyDelay {
+    pub route: String,
+    pub seconds: i64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DelayedReply {
+    pub job_id: String,
+    pub due_at: DateTime<Utc>,
+    #[serde(default)]
+    pub cancelled_by: Option<String>,
+}
+
+fn shared_key(job_id: &str) -> String {
+    format!("limail:delay:{}", job_id)
+}
+
+fn shared_error(e: redis::RedisError) -> StoreError {
+    StoreError::IoError(format!("Unable to reach the shared delayed replies: {}", e))
+}
+
+// The replies waiting, by job, in delayed.json, or in redis when workers
+// share a queue. Never some in each: with redis down the reply can't be
+// scheduled, cancelled or taken, rather than the other workers not seeing
+// it.
+#[derive(Clone)]
+pub struct Delays {
+    rules: Arc<Vec<ReplyDelay>>,
+    path: PathBuf,
+    pending: Arc<Mutex<BTreeMap<String, DelayedReply>>>,
+    shared: Option<redis::Client>,
+    clock: Clock,
+}
+
+impl Delays {
+    pub fn load(rules: Vec<ReplyDelay>, path: PathBuf) -> Result<Delays, StoreError> {
+        let pending: BTreeMap<String, DelayedReply> = store::read_json(&path)?.unwrap_or_default();
+        Ok(Delays {
+            rules: Arc::new(rules),
+            path,
+            pending: Arc::new(Mutex::new(pending)),
+            shared: None,
+            clock: Clock::system(),
+        })
+    }
+
+    pub fn shared(self, client: redis::Client) -> Delays {
+        Delays {
+            shared: Some(client),
+            ..self
+        }
+    }
+
+    pub fn with_clock(self, clock: Clock) -> Delays {
+        Delays {
+            clock,
+            ..self
+        }
+    }
+
+    pub fn delay_for(&self, route: &str) -> Option<i64> {
+        self.rules.iter()
+            .find(|rule| route_matches(&rule.route, route))
+            .map(|rule| rule.seconds)
+            .filter(|seconds| *seconds > 0)
+    }
+
+    // A retried job keeps the reply it already has waiting.
+    pub fn schedule(&self, job_id: &str, seconds: i64) -> Result<DelayedReply, StoreError> {
+        let delayed = DelayedReply {
+            job_id: String::from(job_id),
+            due_at: self.clock.now() + Duration::seconds(seconds),
+            cancelled_by: None,
+        };
+        if let Some(client) = &self.shared {
+            let value = serde_json::to_string(&delayed)?;
+            let scheduled: String = client.get_connection()
+                .and_then(|mut connection| {
+                    redis::cmd("SET").arg(shared_key(job_id)).arg(value).arg("NX").query::<Option<String>>(&mut connection)?;
+                    redis::cmd("GET").arg(shared_key(job_id)).query(&mut connection)
+                })
+                .map_err(shared_error)?;
+            return Ok(serde_json::from_str(&scheduled)?);
+        }
+        let mut pending = self.pending.lock().unwrap();
+        let delayed = pending.entry(String::from(job_id)).or_insert(delayed).clone();
+        store::write_json(&self.path, &*pending)?;
+        Ok(delayed)
+    }
+
+    pub fn wait_until_due(&self, delayed: &DelayedReply) {
+        self.clock.sleep_until(delayed.due_at);
+    }
+
+    // Whether there was a reply still waiting.
+    pub fn cancel(&self, job_id: &str, by: &str) -> Result<bool, StoreError> {
+        if let Some(client) = &self.shared {
+            let key = shared_key(job_id);
+            // Watched, so it's either cancelled before it's taken or too late.
+            return client.get_connection()
+                .and_then(|mut connection| redis::transaction(&mut connection, &[&key], |connection, pipe| {
+                    let delayed: Option<String> = redis::cmd("GET").arg(&key).query(connection)?;
+                    match delayed.and_then(|delayed| serde_json::from_str::<DelayedReply>(&delayed).ok()) {
+                        Some(delayed) if delayed.cancelled_by.is_none() => {
+                            let cancelled = DelayedReply { cancelled_by: Some(String::from(by)), ..delayed };
+                            pipe.cmd("SET")
+                                .arg(&key)
+                                .arg(serde_json::to_string(&cancelled).unwrap_or_default())
+                                .ignore()
+                                .query::<Option<()>>(connection)
+                                .map(|set| set.map(|()| true))
+                        },
+                        _ => Ok(Some(false)),
+                    }
+                }))
+                .map_err(shared_error);
+        }
+        let mut pending = self.pending.lock().unwrap();
+        let cancelled = match pending.get_mut(job_id) {
+            Some(delayed) if delayed.cancelled_by.is_none() => {
+                delayed.cancelled_by = Some(String::from(by));
+                true
+            },
+            _ => false,
+        };
+        if cancelled {
+            store::write_json(&self.path, &*pending)?;
+        }
+        Ok(cancelled)
+    }
+
+    // Once due, for sending it (unless it's been cancelled) exactly once.
+    pub fn take(&self, job_id: &str) -> Result<Option<DelayedReply>, StoreError> {
+        if let Some(client) = &self.shared {
+            let (taken,): (Option<String>,) = client.get_connection()
+                .and_then(|mut connection| redis::pipe()
+                    .atomic()
+                    .cmd("GET").arg(shared_key(job_id))
+                    .cmd("DEL").arg(shared_key(job_id)).ignore()
+                    .query(&mut connection))
+                .map_err(shared_error)?;
+            return match taken {
+                Some(taken) => Ok(Some(serde_json::from_str(&taken)?)),
+                None => Ok(None),
+            };
+        }
+        let mut pending = self.pending.lock().unwrap();
+        let delayed = pending.remove(job_id);
+        if delayed.is_some() {
+            store::write_json(&self.path, &*pending)?;
+        }
+        Ok(delayed)
+    }
+
+    pub fn pending(&self) -> Vec<DelayedReply> {
+        if let Some(client) = &self.shared {
+            let pending: redis::RedisResult<Vec<String>> = client.get_connection()
+                .and_then(|mut connection| {
+                    let keys: Vec<String> = redis::cmd("SCAN")
+                        .cursor_arg(0)
+                        .arg("MATCH")
+                        .arg(shared_key("*"))
+                        .iter(&mut connection)?
+                        .collect();
+                    keys.iter()
+                        .map(|key| redis::cmd("GET").arg(key).query::<Option<String>>(&mut connection))
+                        .filter_map(|delayed| delayed.transpose())
+                        .collect()
+                });
+            return match pending {
+                Ok(pending) => pending.iter().filter_map(|delayed| serde_json::from_str(delayed).ok()).collect(),
+                Err(e) => {
+                    error!("Unable to reach the shared delayed replies, none are resumed: {}", e);
+                    Vec::new()
+                },
+            };
+        }
+        self.pending.lock().unwrap().values().cloned().collect()
+    }
+}
+
+pub fn block_id(job_id: &str) -> String {
+    format!("{}:{}", ACTION_ID, job_id)
+}
+
+pub fn job_id(block_id: &str) -> Option<&str> {
+    let prefix = format!("{}:", ACTION_ID);
+    if block_id.starts_with(&prefix) {
+        Some(&block_id[prefix.len()..])
+    } else {
+        None
+    }
+}
+
+// At the end of the forward of an email whose reply waits.
+pub fn button(job_id: &str, seconds: i64) -> Value {
+    json!({
+        "type": "actions",
+        "block_id": block_id(job_id),
+        "elements": [{
+            "type": "button",
+            "action_id": ACTION_ID,
+            "style": "danger",
+            "text": { "type": "plain_text", "text": format!("Cancel reply ({}s)", seconds) },
+            "confirm": {
+                "title": { "type": "plain_text", "text": "Cancel the auto-reply?" },
+                "text": { "type": "plain_text", "text": "The sender gets no automated answer, so someone should reply personally." },
+                "confirm": { "type": "plain_text", "text": "Cancel it" },
+                "deny": { "type": "plain_text", "text": "Keep it" },
+            },
+        }],
+    })
+}
@@ -0,0 +1,147 @@
+use std::collections::BTreeMap;
+use std::thread;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+
+use crate::archive::{Archive, ArchivedEmail};
+use crate::clock::Clock;
+use crate::dashboard::escape;
+use crate::links::ArchiveLinks;
+use crate::mailgun::{EmailTemplate, Mailgun};
+use crate::maintenance::Maintenance;
+use crate::store::StoreError;
+
+fn default_template() -> String {
+    String::from("digest")
+}
+
+fn default_hour() -> u32 {
+    8
+}
+
+// How much of each email's text goes in the digest.
+const EXCERPT_CHARS: usize = 200;
+
+// Emails routed to /emails/digest/<name> are only collected, and once a
+// day at `hour` (UTC) those of the past day go to `to` as one email: the
+// Mailgun `template` with the digest and digest_html variables (one line
+// per email, with a link to it when there are [links]), count and since.
+// For people who aren't on Slack. Only the frontend (or all-in-one)
+// process sends it, and not when there's nothing in it.
+#[derive(Deserialize, Clone)]
+pub struct DigestConfig {
+    pub name: String,
+    pub to: String,
+    #[serde(default = "default_template")]
+    pub template: String,
+    #[serde(default = "default_hour")]
+    pub hour: u32,
+}
+
+impl DigestConfig {
+    pub fn route(&self) -> String {
+        format!("digest/{}", self.name)
+    }
+}
+
+fn excerpt(text: &str) -> String {
+    let text = text.split_whitespace().collect::<Vec<&str>>().join(" ");
+    match text.char_indices().nth(EXCERPT_CHARS) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text,
+    }
+}
+
+pub fn variables(
+    emails: &[ArchivedEmail],
+    since: DateTime<Utc>,
+    links: Option<&ArchiveLinks>,
+) -> BTreeMap<String, String> {
+    let mut text = Vec::new();
+    let mut html = Vec::new();
+    for archived in emails {
+        let email = &archived.job.email;
+        let link = links.map(|links| links.url(&archived.job.id));
+        text.push(format!(
+            "{} {}: {}\n  {}{}",
+            archived.job.received_at.format("%Y-%m-%d %H:%M"),
+            email.from,
+            email.subject,
+            excerpt(&email.body_plain),
+            link.as_ref().map(|link| format!("\n  {}", link)).unwrap_or_default()
+        ));
+        let subject = match &link {
+            Some(link) => format!(r#"<a href="{}">{}</a>"#, escape(link), escape(&email.subject)),
+            None => escape(&email.subject),
+        };
+        html.push(format!(
+            "<li>{} <b>{}</b>: {}<br>{}</li>",
+            archived.job.received_at.format("%Y-%m-%d %H:%M"),
+            escape(&email.from),
+            subject,
+            escape(&excerpt(&email.body_plain))
+        ));
+    }
+    let mut variables = BTreeMap::new();
+    variables.insert(String::from("digest"), text.join("\n\n"));
+    variables.insert(String::from("digest_html"), format!("<ul>{}</ul>", html.join("")));
+    variables.insert(String::from("count"), emails.len().to_string());
+    variables.insert(String::from("since"), since.to_rfc3339());
+    variables
+}
+
+pub fn collected(archive: &Archive, config: &DigestConfig, since: DateTime<Utc>) -> Result<Vec<ArchivedEmail>, StoreError> {
+    let route = config.route();
+    let mut emails: Vec<ArchivedEmail> = archive.received_since(since)?.into_iter()
+        .filter(|archived| archived.job.action.route() == route && archived.redacted.is_none())
+        .collect();
+    emails.sort_by_key(|archived| archived.job.received_at);
+    Ok(emails)
+}
+
+fn send(config: &DigestConfig, archive: &Archive, mailgun: &Mailgun, links: Option<&ArchiveLinks>, since: DateTime<Utc>) -> Result<usize, String> {
+    let emails = collected(archive, config, since).map_err(|e| e.to_string())?;
+    if emails.is_empty() {
+        return Ok(0);
+    }
+    mailgun.send_email(&EmailTemplate {
+        recipient: config.to.clone(),
+        subject: format!("{} emails for {} since {}", emails.len(), config.name, since.format("%Y-%m-%d %H:%M UTC")),
+        template: config.template.clone(),
+        in_reply_to: String::new(),
+        references: String::new(),
+        variables: variables(&emails, since, links),
+        idempotency_key: None,
+        route: Some(config.route()),
+        list_unsubscribe: None,
+    }).map_err(|e| e.to_string())?;
+    Ok(emails.len())
+}
+
+fn next_run(hour: u32, now: DateTime<Utc>) -> DateTime<Utc> {
+    let today = now.date().and_hms(hour.min(23), 0, 0);
+    if today > now {
+        today
+    } else {
+        today + Duration::days(1)
+    }
+}
+
+// A digest due in maintenance is sent once it is lifted, still covering
+// the day up to when it was due.
+pub fn start(config: DigestConfig, archive: Archive, mailgun: Mailgun, links: Option<ArchiveLinks>, maintenance: Maintenance, clock: Clock) {
+    thread::spawn(move || loop {
+        let due = next_run(config.hour, clock.now());
+        info!("Next {} digest at {}", config.name, due.to_rfc3339());
+        clock.sleep_until(due);
+        if maintenance.wait_until_lifted() {
+            info!("Sending the {} digest due at {}, maintenance is over", config.name, due.to_rfc3339());
+        }
+        match send(&config, &archive, &mailgun, links.as_ref(), due - Duration::days(1)) {
+            Ok(0) => info!("Nothing for the {} digest", config.name),
+            Ok(count) => info!("Sent the {} digest of {} emails to {}", config.name, count, config.to),
+            Err(e) => error!("Unable to send the {} digest to {}: {}", config.name, config.to, e),
+        }
+    });
+}
@@ -0,0 +1,132 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Duration, Timelike, Utc};
+use serde::{Serialize, Deserialize};
+
+use crate::clock::Clock;
+
+fn default_cooldown_minutes() -> i64 {
+    30
+}
+
+#[derive(Deserialize, Clone)]
+pub struct SendingDomain {
+    pub domain: String,
+    // Defaults to MAILGUN_FROM.
+    #[serde(default)]
+    pub from: Option<String>,
+    // Raise it as a new domain warms up. No cap when unset.
+    #[serde(default)]
+    pub hourly_cap: Option<u64>,
+}
+
+// Replies go out through the first domain that is under its hourly cap and
+// not cooling down. A domain Mailgun throttles (429) or fails on (5xx)
+// cools down for cooldown_minutes and the next one takes over. Caps are
+// counted per process.
+#[derive(Deserialize, Clone)]
+pub struct SendingDomainsConfig {
+    pub domains: Vec<SendingDomain>,
+    #[serde(default = "default_cooldown_minutes")]
+    pub cooldown_minutes: i64,
+}
+
+#[derive(Serialize, Clone, Default)]
+pub struct DomainState {
+    pub sent_this_hour: u64,
+    pub cooling_down_until: Option<DateTime<Utc>>,
+    pub last_failure: Option<String>,
+    // Taken out of rotation by an admin, e.g. while its reputation recovers.
+    pub paused: bool,
+    #[serde(skip)]
+    hour: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone)]
+pub struct SendingDomains {
+    config: Arc<SendingDomainsConfig>,
+    state: Arc<Mutex<BTreeMap<String, DomainState>>>,
+    clock: Clock,
+}
+
+// The count starts over every hour.
+fn roll_over(state: &mut DomainState, now: DateTime<Utc>) {
+    let hour = now.date().and_hms(now.hour(), 0, 0);
+    if state.hour != Some(hour) {
+        state.hour = Some(hour);
+        state.sent_this_hour = 0;
+    }
+}
+
+impl SendingDomains {
+    pub fn new(config: SendingDomainsConfig) -> SendingDomains {
+        SendingDomains {
+            config: Arc::new(config),
+            state: Arc::new(Mutex::new(BTreeMap::new())),
+            clock: Clock::system(),
+        }
+    }
+
+    pub fn with_clock(self, clock: Clock) -> SendingDomains {
+        SendingDomains {
+            clock,
+            ..self
+        }
+    }
+
+    pub fn is_configured(&self, domain: &str) -> bool {
+        self.config.domains.iter().any(|d| d.domain == domain)
+    }
+
+    // The domain to send the next email through, if any is available.
+    pub fn next(&self) -> Option<SendingDomain> {
+        let now = self.clock.now();
+        let mut state = self.state.lock().unwrap();
+        self.config.domains.iter()
+            .find(|domain| {
+                let current = state.entry(domain.domain.clone()).or_default();
+                roll_over(current, now);
+                !current.paused
+                    && current.cooling_down_until.map_or(true, |until| until <= now)
+                    && domain.hourly_cap.map_or(true, |cap| current.sent_this_hour < cap)
+            })
+            .cloned()
+    }
+
+    pub fn record_sent(&self, domain: &str) {
+        let mut state = self.state.lock().unwrap();
+        let current = state.entry(String::from(domain)).or_default();
+        roll_over(current, self.clock.now());
+        current.sent_this_hour += 1;
+    }
+
+    pub fn cool_down(&self, domain: &str, failure: &str) {
+        warn!("Sending domain {} is cooling down for {} minutes: {}", domain, self.config.cooldown_minutes, failure);
+        let mut state = self.state.lock().unwrap();
+        let current = state.entry(String::from(domain)).or_default();
+        current.cooling_down_until = Some(self.clock.now() + Duration::minutes(self.config.cooldown_minutes));
+        current.last_failure = Some(String::from(failure));
+    }
+
+    // Returns false for a domain that isn't configured.
+    pub fn set_paused(&self, domain: &str, paused: bool) -> bool {
+        if !self.is_configured(domain) {
+            return false;
+        }
+        self.state.lock().unwrap().entry(String::from(domain)).or_default().paused = paused;
+        true
+    }
+
+    pub fn states(&self) -> BTreeMap<String, DomainState> {
+        let now = self.clock.now();
+        let mut state = self.state.lock().unwrap();
+        self.config.domains.iter()
+            .map(|domain| {
+                let current = state.entry(domain.domain.clone()).or_default();
+                roll_over(current, now);
+                (domain.domain.clone(), current.clone())
+            })
+            .collect()
+    }
+}
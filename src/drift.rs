@@ -0,0 +1,128 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::thread;
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::config::Config;
+use crate::mailgun::Mailgun;
+use crate::maintenance::Maintenance;
+use crate::notices::Notices;
+use crate::slack::Slack;
+use crate::templatecheck;
+
+fn default_interval_minutes() -> u64 {
+    60
+}
+
+// Checks every interval_minutes that the Slack channels, Mailgun templates
+// and sending domains the config relies on are still usable, so someone
+// hears about an archived channel or a deleted template before the next
+// email fails on it. channels and templates are checked on top of those
+// the config names, for the ones only Mailgun's routes know about.
+#[derive(Deserialize, Clone)]
+pub struct DriftConfig {
+    pub channel: String,
+    #[serde(default = "default_interval_minutes")]
+    pub interval_minutes: u64,
+    #[serde(default)]
+    pub channels: Vec<String>,
+    #[serde(default)]
+    pub templates: Vec<String>,
+}
+
+// Everything the config names.
+pub struct Watched {
+    pub channels: BTreeSet<String>,
+    pub templates: BTreeSet<String>,
+    pub domains: BTreeSet<String>,
+}
+
+impl Watched {
+    pub fn from_config(config: &Config, drift: &DriftConfig, mailgun: &Mailgun) -> Watched {
+        let notices = &config.notices;
+        let channels = drift.channels.iter().cloned()
+            .chain(Some(drift.channel.clone()))
+            .chain(notices.suppressed_channel.clone())
+            .chain(notices.signature_failure_channel.clone())
+            .chain(notices.started_channel.clone())
+            .chain(config.alerts.iter().filter_map(|alert| alert.channel.clone()))
+            .chain(config.quarantine.as_ref().map(|quarantine| quarantine.channel.clone()))
+            .chain(config.slack.fallback_channel.clone())
+            .chain(config.send_budget.as_ref().and_then(|budget| budget.channel.clone()))
+            .chain(config.handling.iter().flat_map(|handling| handling.sla.iter().filter_map(|sla| sla.escalation_channel.clone())))
+            .chain(config.weekly_report.as_ref().map(|report| report.channel.clone()))
+            .collect();
+        let templates = drift.templates.iter().cloned()
+            .chain(templatecheck::referenced(config))
+            .collect();
+        let domains = Some(mailgun.domain.clone()).into_iter()
+            .chain(config.sending_domains.iter().flat_map(|domains| domains.domains.iter().map(|domain| domain.domain.clone())))
+            .collect();
+        Watched { channels, templates, domains }
+    }
+}
+
+// What's wrong, by what it's wrong with. A check that couldn't be made
+// (Slack being down, say) leaves what was known about it as it was.
+fn check(watched: &Watched, mailgun: &Mailgun, slack: &Slack, known: &BTreeMap<String, String>) -> BTreeMap<String, String> {
+    let mut found = BTreeMap::new();
+    let mut note = |key: String, result: Result<Option<String>, String>| match result {
+        Ok(Some(problem)) => {
+            found.insert(key, problem);
+        },
+        Ok(None) => (),
+        Err(e) => {
+            warn!("Unable to check {}: {}", key, e);
+            if let Some(problem) = known.get(&key) {
+                found.insert(key, problem.clone());
+            }
+        },
+    };
+    for channel in &watched.channels {
+        let problem = slack.channel_problem(channel)
+            .map(|problem| problem.map(|problem| format!("The Slack channel <#{}> {}", channel, problem)))
+            .map_err(|e| e.to_string());
+        note(format!("channel {}", channel), problem);
+    }
+    for template in &watched.templates {
+        let problem = mailgun.template_exists(template)
+            .map(|exists| if exists { None } else { Some(format!("The Mailgun template {} is missing from {}", template, mailgun.domain)) })
+            .map_err(|e| e.to_string());
+        note(format!("template {}", template), problem);
+    }
+    for domain in &watched.domains {
+        let problem = mailgun.domain_state(domain)
+            .map(|state| if state == "active" { None } else { Some(format!("The Mailgun domain {} is {}", domain, state)) })
+            .map_err(|e| e.to_string());
+        note(format!("domain {}", domain), problem);
+    }
+    found
+}
+
+// Checks are put off in maintenance, rather than posting (or failing to
+// reach Slack and Mailgun at all).
+pub fn start(config: DriftConfig, watched: Watched, mailgun: Mailgun, slack: Slack, notices: Notices, maintenance: Maintenance) {
+    thread::spawn(move || {
+        let mut known: BTreeMap<String, String> = BTreeMap::new();
+        loop {
+            maintenance.wait_until_lifted();
+            let found = check(&watched, &mailgun, &slack, &known);
+            for (key, problem) in &found {
+                if known.get(key) != Some(problem) {
+                    warn!("{}", problem);
+                    notices.post(config.channel.clone(), notices.render("drift", &json!({ "problem": problem })));
+                }
+            }
+            for (key, problem) in &known {
+                if !found.contains_key(key) {
+                    info!("Fixed: {}", problem);
+                    notices.post(config.channel.clone(), notices.render("drift_resolved", &json!({ "problem": problem })));
+                }
+            }
+            known = found;
+            thread::sleep(Duration::from_secs(config.interval_minutes.max(1) * 60));
+        }
+    });
+}
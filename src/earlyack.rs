@@ -0,0 +1,128 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::archive::Archive;
+use crate::pipeline::{route_matches, Job, Pipeline};
+use crate::store::{self, StoreError};
+
+fn default_attempts() -> u32 {
+    3
+}
+
+fn default_retry_seconds() -> u64 {
+    30
+}
+
+// Webhooks to routes (exact, or a prefix ending in *) are answered as soon
+// as they're verified and archived, and delivered in the background, so a
+// slow Mailgun or Slack can't push them past Mailgun's webhook timeout.
+// Failed deliveries are tried attempts times, retry_seconds apart (and
+// then doubling), since Mailgun won't retry them any more. Jobs still
+// pending when limail stops are delivered when it starts again. Without a
+// [queue], that is: with one, every webhook is answered once it's queued.
+#[derive(Deserialize, Clone)]
+pub struct EarlyAckConfig {
+    pub routes: Vec<String>,
+    #[serde(default = "default_attempts")]
+    pub attempts: u32,
+    #[serde(default = "default_retry_seconds")]
+    pub retry_seconds: u64,
+}
+
+// The ids of the jobs answered but not yet delivered, in pending.json.
+#[derive(Clone)]
+pub struct EarlyAck {
+    config: Arc<EarlyAckConfig>,
+    path: PathBuf,
+    pending: Arc<Mutex<Vec<String>>>,
+}
+
+impl EarlyAck {
+    pub fn load(config: EarlyAckConfig, path: PathBuf) -> Result<EarlyAck, StoreError> {
+        let pending: Vec<String> = store::read_json(&path)?.unwrap_or_default();
+        Ok(EarlyAck {
+            config: Arc::new(config),
+            path,
+            pending: Arc::new(Mutex::new(pending)),
+        })
+    }
+
+    pub fn applies_to(&self, route: &str) -> bool {
+        self.config.routes.iter().any(|pattern| route_matches(pattern, route))
+    }
+
+    // Only returns once the job is written down as pending, so it's safe
+    // to answer the webhook. A retry of one still pending is left to the
+    // delivery already under way.
+    pub fn accept(&self, pipeline: &Pipeline, job: Job) -> Result<(), StoreError> {
+        {
+            let mut pending = self.pending.lock().unwrap();
+            if pending.contains(&job.id) {
+                info!("Job {} is already being delivered", job.id);
+                return Ok(());
+            }
+            pending.push(job.id.clone());
+            store::write_json(&self.path, &*pending)?;
+        }
+        let (early_ack, pipeline) = (self.clone(), pipeline.clone());
+        thread::spawn(move || early_ack.deliver(&pipeline, &job));
+        Ok(())
+    }
+
+    // Picks up where the last run left off.
+    pub fn resume(&self, pipeline: &Pipeline, archive: &Archive) {
+        let pending = self.pending.lock().unwrap().clone();
+        if !pending.is_empty() {
+            info!("Delivering {} jobs answered before the last shutdown", pending.len());
+        }
+        for id in pending {
+            match archive.get(&id) {
+                Ok(Some(archived)) => {
+                    let (early_ack, pipeline) = (self.clone(), pipeline.clone());
+                    thread::spawn(move || early_ack.deliver(&pipeline, &archived.job));
+                },
+                Ok(None) => {
+                    error!("Pending job {} is missing from the archive", id);
+                    self.done(&id);
+                },
+                Err(e) => error!("Unable to read pending job {}: {}", id, e),
+            }
+        }
+    }
+
+    // Attempts wait for maintenance to be lifted, rather than failing on
+    // it.
+    fn deliver(&self, pipeline: &Pipeline, job: &Job) {
+        let mut wait = Duration::from_secs(self.config.retry_seconds);
+        for attempt in 1..=self.config.attempts.max(1) {
+            if pipeline.maintenance.wait_until_lifted() {
+                info!("Maintenance is over, delivering job {}", job.id);
+            }
+            match pipeline.process(job) {
+                Ok(_) => break,
+                Err(e) if attempt < self.config.attempts => {
+                    warn!("Job {} failed (attempt {}), retrying in {}s: {}", job.id, attempt, wait.as_secs(), e);
+                    thread::sleep(wait);
+                    wait *= 2;
+                },
+                Err(e) => {
+                    error!("Job {} failed after {} attempts, giving up: {}", job.id, attempt, e);
+                    pipeline.metrics.incr("errors_early_ack");
+                },
+            }
+        }
+        self.done(&job.id);
+    }
+
+    fn done(&self, id: &str) {
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|pending| pending != id);
+        if let Err(e) = store::write_json(&self.path, &*pending) {
+            error!("Unable to update pending.json: {}", e);
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+
+use crate::pipeline::route_matches;
+use crate::store;
+
+// Routes (exact, or a prefix ending in *) whose outbound messages are written
+// out instead of being sent, to check formatting in staging.
+#[derive(Deserialize, Clone)]
+pub struct EchoConfig {
+    pub routes: Vec<String>,
+    // Relative to DATA_DIR. Messages are only logged when missing.
+    #[serde(default)]
+    pub path: Option<String>,
+}
+
+#[derive(Serialize)]
+struct EchoEntry<'a> {
+    at: DateTime<Utc>,
+    route: &'a str,
+    destination: &'a str,
+    message: &'a Value,
+}
+
+pub struct EchoedMessage {
+    pub route: String,
+    pub destination: String,
+    pub message: Value,
+}
+
+#[derive(Clone)]
+pub struct Echo {
+    routes: Arc<Vec<String>>,
+    path: Option<PathBuf>,
+    write_lock: Arc<Mutex<()>>,
+    // Kept in memory instead, see simulate.rs.
+    captured: Option<Arc<Mutex<Vec<EchoedMessage>>>>,
+}
+
+impl Echo {
+    pub fn new(config: Option<EchoConfig>, data_dir: &Path) -> Echo {
+        let config = config.unwrap_or(EchoConfig { routes: Vec::new(), path: None });
+        Echo {
+            routes: Arc::new(config.routes),
+            path: config.path.map(|path| data_dir.join(path)),
+            write_lock: Arc::new(Mutex::new(())),
+            captured: None,
+        }
+    }
+
+    // Every route, nothing logged.
+    pub fn capturing() -> Echo {
+        Echo {
+            routes: Arc::new(vec![String::from("*")]),
+            path: None,
+            write_lock: Arc::new(Mutex::new(())),
+            captured: Some(Arc::new(Mutex::new(Vec::new()))),
+        }
+    }
+
+    // What was echoed since the last call.
+    pub fn take(&self) -> Vec<EchoedMessage> {
+        match &self.captured {
+            Some(captured) => captured.lock().unwrap().split_off(0),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn is_echoed(&self, route: &str) -> bool {
+        self.routes.iter().any(|pattern| route_matches(pattern, route))
+    }
+
+    // Never fails the delivery, echoing is only ever for testing.
+    pub fn write(&self, route: &str, destination: &str, message: Value) {
+        if let Some(captured) = &self.captured {
+            captured.lock().unwrap().push(EchoedMessage {
+                route: String::from(route),
+                destination: String::from(destination),
+                message,
+            });
+            return;
+        }
+        info!("echo {} {}: {}", route, destination, message);
+        if let Some(path) = &self.path {
+            let _guard = self.write_lock.lock().unwrap();
+            let entry = EchoEntry {
+                at: Utc::now(),
+                route,
+                destination,
+                message: &message,
+            };
+            if let Err(e) = store::append_json_line(path, &entry) {
+                error!("Unable to write echoed message to {}: {}", path.display(), e);
+            }
+        }
+    }
+}
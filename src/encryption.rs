@@ -0,0 +1,113 @@
+use std::sync::Arc;
+
+use openssl::symm::{self, Cipher};
+use serde::Deserialize;
+
+use crate::store::StoreError;
+
+// Sealed data starts with this, then the key id, a colon, the nonce, the
+// tag and the ciphertext. Anything else is read as it is, so an archive
+// written before encryption was turned on stays readable.
+const MAGIC: &[u8] = b"limail:aes-256-gcm:";
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+#[derive(Deserialize, Clone)]
+pub struct EncryptionKey {
+    // Stored next to the ciphertext, so it must not contain a colon.
+    pub id: String,
+    // 32 random bytes, base64 encoded: `openssl rand -base64 32`.
+    pub key: String,
+}
+
+// New data is sealed with the first key, any of them opens old data. To
+// rotate, put a new key first, run `limail rekey-archive`, then drop the
+// old one.
+#[derive(Deserialize, Clone)]
+pub struct EncryptionConfig {
+    pub keys: Vec<EncryptionKey>,
+}
+
+#[derive(Clone)]
+pub struct Sealer {
+    keys: Arc<Vec<(String, Vec<u8>)>>,
+}
+
+fn crypto_error(e: openssl::error::ErrorStack) -> StoreError {
+    StoreError::IoError(format!("Encryption error: {}", e))
+}
+
+pub fn is_sealed(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+impl Sealer {
+    pub fn new(config: &EncryptionConfig) -> Result<Sealer, String> {
+        if config.keys.is_empty() {
+            return Err(String::from("Archive encryption needs at least one key"));
+        }
+        let keys = config.keys.iter()
+            .map(|key| {
+                if key.id.is_empty() || key.id.contains(':') {
+                    return Err(format!("Invalid archive key id {:?}", key.id));
+                }
+                match base64::decode(&key.key) {
+                    Ok(bytes) if bytes.len() == 32 => Ok((key.id.clone(), bytes)),
+                    _ => Err(format!("Archive key {} must be 32 base64 encoded bytes", key.id)),
+                }
+            })
+            .collect::<Result<Vec<(String, Vec<u8>)>, String>>()?;
+        Ok(Sealer {
+            keys: Arc::new(keys),
+        })
+    }
+
+    pub fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>, StoreError> {
+        let (id, key) = &self.keys[0];
+        let mut nonce = [0u8; NONCE_LEN];
+        openssl::rand::rand_bytes(&mut nonce).map_err(crypto_error)?;
+        let mut tag = [0u8; TAG_LEN];
+        let ciphertext = symm::encrypt_aead(Cipher::aes_256_gcm(), key, Some(&nonce), id.as_bytes(), plaintext, &mut tag)
+            .map_err(crypto_error)?;
+        let mut sealed = Vec::with_capacity(MAGIC.len() + id.len() + 1 + NONCE_LEN + TAG_LEN + ciphertext.len());
+        sealed.extend_from_slice(MAGIC);
+        sealed.extend_from_slice(id.as_bytes());
+        sealed.push(b':');
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&tag);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    fn key_id<'a>(&self, bytes: &'a [u8]) -> Option<(&'a [u8], &'a [u8])> {
+        let rest = &bytes[MAGIC.len()..];
+        let colon = rest.iter().position(|b| *b == b':')?;
+        Some((&rest[..colon], &rest[colon + 1..]))
+    }
+
+    // Sealed with the key new data is sealed with, nothing to rotate.
+    pub fn is_current(&self, bytes: &[u8]) -> bool {
+        is_sealed(bytes) && self.key_id(bytes).map_or(false, |(id, _)| id == self.keys[0].0.as_bytes())
+    }
+
+    pub fn open(&self, bytes: Vec<u8>) -> Result<Vec<u8>, StoreError> {
+        if !is_sealed(&bytes) {
+            return Ok(bytes);
+        }
+        let corrupt = || StoreError::IoError(String::from("Corrupt encrypted data"));
+        let (id, rest) = self.key_id(&bytes).ok_or_else(corrupt)?;
+        if rest.len() < NONCE_LEN + TAG_LEN {
+            return Err(corrupt());
+        }
+        let key = match self.keys.iter().find(|(key_id, _)| key_id.as_bytes() == id) {
+            Some((_, key)) => key,
+            None => return Err(StoreError::IoError(format!(
+                "Encrypted with archive key {}, which isn't configured",
+                String::from_utf8_lossy(id)
+            ))),
+        };
+        let (nonce, rest) = rest.split_at(NONCE_LEN);
+        let (tag, ciphertext) = rest.split_at(TAG_LEN);
+        symm::decrypt_aead(Cipher::aes_256_gcm(), key, Some(nonce), id, ciphertext, tag).map_err(crypto_error)
+    }
+}
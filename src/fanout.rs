@@ -0,0 +1,32 @@
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub type Call<T> = Box<dyn FnOnce() -> T + Send>;
+
+// Makes independent calls (to Slack, say) at the same time, so they take as
+// long as the slowest rather than all of them added up. Each result is None
+// if its call hadn't returned by the deadline: like with the handler
+// deadline, it carries on in the background, bounded by its own timeout.
+pub fn join<T: Send + 'static>(calls: Vec<Call<T>>, deadline: Duration) -> Vec<Option<T>> {
+    let count = calls.len();
+    let (sender, receiver) = mpsc::channel();
+    for (index, call) in calls.into_iter().enumerate() {
+        let sender = sender.clone();
+        thread::spawn(move || {
+            let _ = sender.send((index, call()));
+        });
+    }
+    drop(sender);
+
+    let mut results: Vec<Option<T>> = (0..count).map(|_| None).collect();
+    let until = Instant::now() + deadline;
+    for _ in 0..count {
+        let left = until.saturating_duration_since(Instant::now());
+        match receiver.recv_timeout(left) {
+            Ok((index, result)) => results[index] = Some(result),
+            Err(_) => break,
+        }
+    }
+    results
+}
@@ -0,0 +1,186 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+
+use crate::dashboard::escape;
+use crate::pipeline::route_matches;
+use crate::store::{self, StoreError};
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn default_variable() -> String {
+    String::from("feedback_url")
+}
+
+// Auto-replies using one of `templates` (exact, or a prefix ending in *)
+// get a unique feedback link, passed to the Mailgun template as the
+// `variable` template variable.
+#[derive(Deserialize, Clone)]
+pub struct FeedbackConfig {
+    // Where limail is reachable from the recipients' browsers.
+    pub base_url: String,
+    pub secret: String,
+    pub templates: Vec<String>,
+    #[serde(default = "default_variable")]
+    pub variable: String,
+}
+
+#[derive(Deserialize)]
+pub struct FeedbackQuery {
+    pub template: String,
+    pub signature: String,
+    // 1 (useless) to 5 (solved my problem). Just following the link counts
+    // as a click.
+    #[serde(default)]
+    pub rating: Option<u8>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct FeedbackEntry {
+    pub at: DateTime<Utc>,
+    pub id: String,
+    pub template: String,
+    pub rating: Option<u8>,
+}
+
+#[derive(Serialize, Default)]
+pub struct FeedbackSummary {
+    pub clicks: u64,
+    // Only the latest rating for each send counts.
+    pub ratings: BTreeMap<u8, u64>,
+    pub average_rating: Option<f64>,
+}
+
+fn signed_query(template: &str, signature: &str) -> String {
+    serde_urlencoded::to_string(&[("template", template), ("signature", signature)]).unwrap_or_default()
+}
+
+// A link is a random-looking send id plus the template it was sent with,
+// signed so ratings can't be made up for sends that never happened.
+#[derive(Clone)]
+pub struct Feedback {
+    config: FeedbackConfig,
+    path: PathBuf,
+    write_lock: Arc<Mutex<()>>,
+}
+
+impl Feedback {
+    pub fn new(config: FeedbackConfig, path: PathBuf) -> Feedback {
+        Feedback {
+            config,
+            path,
+            write_lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    pub fn variable(&self) -> &str {
+        &self.config.variable
+    }
+
+    fn signature(&self, id: &str, template: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_varkey(self.config.secret.as_bytes()).expect("HMAC takes keys of any size");
+        mac.input(format!("{}:{}", id, template).as_bytes());
+        mac
+    }
+
+    // The send id and link for a reply, if its template asks for one.
+    pub fn link(&self, template: &str, recipient: &str) -> Option<(String, String)> {
+        if !self.config.templates.iter().any(|pattern| route_matches(pattern, template)) {
+            return None;
+        }
+        let digest = Sha256::digest(format!("{}:{}:{}", recipient, template, Utc::now().to_rfc3339()).as_bytes());
+        let id = hex::encode(&digest[..8]);
+        let signature = hex::encode(self.signature(&id, template).result().code());
+        let url = format!(
+            "{}/feedback/{}?{}",
+            self.config.base_url.trim_end_matches('/'),
+            id,
+            signed_query(template, &signature)
+        );
+        Some((id, url))
+    }
+
+    pub fn verify(&self, id: &str, query: &FeedbackQuery) -> bool {
+        match hex::decode(&query.signature) {
+            Ok(signature) => self.signature(id, &query.template).verify(&signature).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    pub fn record(&self, id: &str, query: &FeedbackQuery) -> Result<(), StoreError> {
+        let _guard = self.write_lock.lock().unwrap();
+        store::append_json_line(&self.path, &FeedbackEntry {
+            at: Utc::now(),
+            id: String::from(id),
+            template: query.template.clone(),
+            rating: query.rating.filter(|rating| *rating >= 1 && *rating <= 5),
+        })
+    }
+
+    // Per template.
+    pub fn summary(&self) -> Result<BTreeMap<String, FeedbackSummary>, StoreError> {
+        let entries: Vec<FeedbackEntry> = store::read_json_lines(&self.path)?;
+        let mut latest_ratings: BTreeMap<(String, String), u8> = BTreeMap::new();
+        let mut summaries: BTreeMap<String, FeedbackSummary> = BTreeMap::new();
+        for entry in entries {
+            summaries.entry(entry.template.clone()).or_default().clicks += 1;
+            if let Some(rating) = entry.rating {
+                latest_ratings.insert((entry.template, entry.id), rating);
+            }
+        }
+        for ((template, _), rating) in latest_ratings {
+            *summaries.entry(template).or_default().ratings.entry(rating).or_insert(0) += 1;
+        }
+        for summary in summaries.values_mut() {
+            let (count, total) = summary.ratings.iter()
+                .fold((0, 0), |(count, total), (rating, n)| (count + n, total + u64::from(*rating) * n));
+            if count > 0 {
+                summary.average_rating = Some(total as f64 / count as f64);
+            }
+        }
+        Ok(summaries)
+    }
+}
+
+// What whoever followed the link sees, with the rating links for the same
+// send.
+pub fn render(id: &str, query: &FeedbackQuery) -> String {
+    let thanks = match query.rating {
+        Some(_) => "<p>Thanks, your rating was recorded.</p>",
+        None => "<p>Thanks for following the link. How useful was our reply?</p>",
+    };
+    let ratings: String = (1..=5)
+        .map(|rating| format!(
+            "<a href=\"/feedback/{}?{}&amp;rating={}\">{}</a>",
+            escape(id),
+            escape(&signed_query(&query.template, &query.signature)),
+            rating,
+            rating
+        ))
+        .collect::<Vec<String>>()
+        .join(" ");
+    format!(r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Feedback</title>
+<style>
+body {{ font-family: sans-serif; margin: 2em; }}
+a {{ font-size: 1.5em; margin-right: 0.5em; }}
+</style>
+</head>
+<body>
+{thanks}
+<p>Not useful {ratings} Solved my problem</p>
+</body>
+</html>
+"#,
+        thanks = thanks,
+        ratings = ratings,
+    )
+}
@@ -0,0 +1,116 @@
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use serde::{Serialize, Deserialize};
+
+use crate::pipeline::route_matches;
+use crate::store::{self, StoreError};
+
+fn default_true() -> bool {
+    true
+}
+
+// Switches parts of handling a webhook off for routes (exact, or a prefix
+// ending in *), the first match winning, so a route can differ without
+// code of its own. Everything is on for other routes, and for whatever a
+// matching entry leaves out.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RouteFlags {
+    pub route: String,
+    // Off only for internal test routes: anyone could post to them.
+    #[serde(default = "default_true")]
+    pub verify_signature: bool,
+    // Without it the email can't be replayed, linked to or delivered after
+    // an early ack, and retries aren't deduplicated or answered as the
+    // first delivery was.
+    #[serde(default = "default_true")]
+    pub archive: bool,
+    // The [threat_intel] lookup and the S/MIME check.
+    #[serde(default = "default_true")]
+    pub enrich: bool,
+    #[serde(default = "default_true")]
+    pub auto_reply: bool,
+}
+
+impl RouteFlags {
+    pub fn all(route: &str) -> RouteFlags {
+        RouteFlags {
+            route: String::from(route),
+            verify_signature: true,
+            archive: true,
+            enrich: true,
+            auto_reply: true,
+        }
+    }
+}
+
+// The [[route_flags]] from the config until they're changed from the
+// dashboard, then the ones in route_flags.json. Changes take effect
+// immediately.
+#[derive(Clone, Default)]
+pub struct Flags {
+    path: Option<PathBuf>,
+    rules: Arc<RwLock<Vec<RouteFlags>>>,
+}
+
+impl Flags {
+    pub fn new(flags: Vec<RouteFlags>) -> Flags {
+        Flags {
+            path: None,
+            rules: Arc::new(RwLock::new(flags)),
+        }
+    }
+
+    pub fn load(configured: Vec<RouteFlags>, path: PathBuf) -> Result<Flags, StoreError> {
+        let rules = store::read_json(&path)?.unwrap_or(configured);
+        Ok(Flags {
+            path: Some(path),
+            rules: Arc::new(RwLock::new(rules)),
+        })
+    }
+
+    pub fn for_route(&self, route: &str) -> RouteFlags {
+        self.rules.read().unwrap().iter()
+            .find(|flags| route_matches(&flags.route, route))
+            .cloned()
+            .unwrap_or_else(|| RouteFlags::all(route))
+    }
+
+    pub fn rules(&self) -> Vec<RouteFlags> {
+        self.rules.read().unwrap().clone()
+    }
+
+    // Replaces the entry for the same route where it is, or puts a new one
+    // first, ahead of the broader prefixes it would otherwise lose to.
+    // Returns the entry it replaced.
+    pub fn set(&self, flags: RouteFlags) -> Result<Option<RouteFlags>, StoreError> {
+        let mut rules = self.rules.write().unwrap();
+        let previous = match rules.iter_mut().find(|rule| rule.route == flags.route) {
+            Some(rule) => Some(std::mem::replace(rule, flags)),
+            None => {
+                rules.insert(0, flags);
+                None
+            },
+        };
+        self.save(&rules)?;
+        Ok(previous)
+    }
+
+    pub fn remove(&self, route: &str) -> Result<Option<RouteFlags>, StoreError> {
+        let mut rules = self.rules.write().unwrap();
+        let index = match rules.iter().position(|rule| rule.route == route) {
+            Some(index) => index,
+            None => return Ok(None),
+        };
+        let removed = rules.remove(index);
+        self.save(&rules)?;
+        Ok(Some(removed))
+    }
+
+    fn save(&self, rules: &[RouteFlags]) -> Result<(), StoreError> {
+        match &self.path {
+            Some(path) => store::write_json(path, &rules),
+            None => Ok(()),
+        }
+    }
+}
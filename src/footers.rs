@@ -0,0 +1,64 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use serde::Deserialize;
+
+use crate::dashboard::escape;
+use crate::pipeline::route_matches;
+
+// The footer of replies on route (exact, or a prefix ending in *), instead
+// of the usual one. An empty text leaves it out.
+#[derive(Deserialize, Clone)]
+pub struct RouteFooter {
+    pub route: String,
+    pub text: String,
+    #[serde(default)]
+    pub html: Option<String>,
+}
+
+// The legal text, "this is an automated reply" and how to unsubscribe,
+// kept here rather than copied into every Mailgun template. Every email
+// limail sends gets it as the footer and footer_html template variables,
+// which templates show with {{footer}} in the text part and {{{footer_html}}}
+// in the HTML one. html defaults to the text with its line breaks kept.
+// Manual sends get the usual footer, or that of the route they name.
+#[derive(Deserialize, Clone)]
+pub struct FooterConfig {
+    pub text: String,
+    #[serde(default)]
+    pub html: Option<String>,
+    #[serde(default)]
+    pub routes: Vec<RouteFooter>,
+}
+
+pub const TEXT_VARIABLE: &str = "footer";
+pub const HTML_VARIABLE: &str = "footer_html";
+
+#[derive(Clone)]
+pub struct Footers {
+    config: Arc<FooterConfig>,
+}
+
+fn html_of(text: &str) -> String {
+    escape(text).replace('\n', "<br>\n")
+}
+
+impl Footers {
+    pub fn new(config: FooterConfig) -> Footers {
+        Footers { config: Arc::new(config) }
+    }
+
+    // The template's own variables win, so a template can be sent without.
+    pub fn add(&self, route: Option<&str>, variables: &mut BTreeMap<String, String>) {
+        let (text, html) = match route.and_then(|route| self.config.routes.iter().find(|footer| route_matches(&footer.route, route))) {
+            Some(footer) => (&footer.text, &footer.html),
+            None => (&self.config.text, &self.config.html),
+        };
+        if text.trim().is_empty() {
+            return;
+        }
+        let html = html.clone().unwrap_or_else(|| html_of(text));
+        variables.entry(String::from(TEXT_VARIABLE)).or_insert_with(|| text.clone());
+        variables.entry(String::from(HTML_VARIABLE)).or_insert(html);
+    }
+}
@@ -0,0 +1,153 @@
+use serde::Deserialize;
+
+use crate::pipeline::route_matches;
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_max_blank_lines() -> usize {
+    1
+}
+
+// How the body of an email is tidied up before it's forwarded, for routes
+// (exact, or a prefix ending in *). The first matching rule wins, other
+// routes get the defaults: every line trimmed, and no more than one blank
+// line in a row. Trimming the start of lines is what mangles pasted tables
+// and code, which preserve_code_fences leaves alone between ``` lines.
+// Slack doesn't wrap code blocks, so long lines scroll sideways on phones
+// unless wrapped at wrap_column, and tabs are as wide as it pleases unless
+// turned into tab_width spaces.
+#[derive(Deserialize, Clone)]
+pub struct Formatting {
+    #[serde(default)]
+    pub route: String,
+    #[serde(default = "default_true")]
+    pub collapse_blank_lines: bool,
+    #[serde(default = "default_max_blank_lines")]
+    pub max_blank_lines: usize,
+    #[serde(default = "default_true")]
+    pub strip_leading_whitespace: bool,
+    #[serde(default = "default_true")]
+    pub strip_trailing_whitespace: bool,
+    #[serde(default)]
+    pub preserve_code_fences: bool,
+    #[serde(default)]
+    pub wrap_column: Option<usize>,
+    #[serde(default)]
+    pub tab_width: Option<usize>,
+}
+
+impl Default for Formatting {
+    fn default() -> Formatting {
+        Formatting {
+            route: String::new(),
+            collapse_blank_lines: true,
+            max_blank_lines: default_max_blank_lines(),
+            strip_leading_whitespace: true,
+            strip_trailing_whitespace: true,
+            preserve_code_fences: false,
+            wrap_column: None,
+            tab_width: None,
+        }
+    }
+}
+
+pub fn for_route<'a>(rules: &'a [Formatting], route: &str) -> Option<&'a Formatting> {
+    rules.iter().find(|rule| route_matches(&rule.route, route))
+}
+
+impl Formatting {
+    pub fn normalize(&self, body: &str) -> String {
+        let mut blank_lines = 0;
+        let mut in_fence = false;
+        let mut lines = Vec::new();
+        for line in body.split('\n') {
+            let line = line.trim_end_matches('\r');
+            let line = match self.tab_width {
+                Some(width) => expand_tabs(line, width),
+                None => String::from(line),
+            };
+            let is_fence = self.preserve_code_fences && line.trim_start().starts_with("```");
+            if in_fence || is_fence {
+                if is_fence {
+                    in_fence = !in_fence;
+                }
+                blank_lines = 0;
+                lines.push(line);
+                continue;
+            }
+            let line = match (self.strip_leading_whitespace, self.strip_trailing_whitespace) {
+                (true, true) => line.trim(),
+                (true, false) => line.trim_start(),
+                (false, true) => line.trim_end(),
+                (false, false) => &line[..],
+            };
+            if line.trim().is_empty() {
+                blank_lines += 1;
+                if self.collapse_blank_lines && blank_lines > self.max_blank_lines {
+                    continue;
+                }
+            } else {
+                blank_lines = 0;
+            }
+            match self.wrap_column {
+                Some(column) if column > 0 => lines.extend(wrap(line, column)),
+                _ => lines.push(String::from(line)),
+            }
+        }
+        lines.join("\n")
+    }
+}
+
+// Spaces up to the next tab stop, so columns still line up.
+fn expand_tabs(line: &str, width: usize) -> String {
+    let width = width.max(1);
+    let mut expanded = String::with_capacity(line.len());
+    let mut column = 0;
+    for c in line.chars() {
+        if c == '\t' {
+            let spaces = width - column % width;
+            expanded.extend(std::iter::repeat(' ').take(spaces));
+            column += spaces;
+        } else {
+            expanded.push(c);
+            column += 1;
+        }
+    }
+    expanded
+}
+
+// Breaks at the last space before the column, or at the column itself in
+// a word (a URL, say) longer than that. Keeps the line's indentation.
+fn wrap(line: &str, column: usize) -> Vec<String> {
+    let chars: Vec<char> = line.chars().collect();
+    if chars.len() <= column {
+        return vec![String::from(line)];
+    }
+    let leading = chars.iter().take_while(|c| c.is_whitespace()).count();
+    let indent: String = if leading * 2 < column { chars[..leading].iter().collect() } else { String::new() };
+    let mut wrapped = Vec::new();
+    let mut rest = &chars[..];
+    let mut first = true;
+    while !rest.is_empty() {
+        let (width, skip) = if first { (column, leading) } else { (column - indent.chars().count(), 0) };
+        if rest.len() <= width {
+            let last: String = rest.iter().collect();
+            wrapped.push(if first { last } else { format!("{}{}", indent, last) });
+            break;
+        }
+        let split = match rest[..=width].iter().rposition(|c| *c == ' ') {
+            Some(space) if space > skip => space,
+            _ => width,
+        };
+        let piece: String = rest[..split].iter().collect();
+        wrapped.push(if first { piece } else { format!("{}{}", indent, piece) });
+        rest = &rest[split..];
+        while rest.first() == Some(&' ') {
+            rest = &rest[1..];
+        }
+        first = false;
+    }
+    wrapped
+}
@@ -0,0 +1,58 @@
+use serde::Deserialize;
+
+use crate::mailgun::MailgunEmailReceived;
+use crate::viewer;
+
+// Emails a Mailgun route sends to /emails/forward/email/<alias> are sent
+// on to `to` through Mailgun, from MAILGUN_FROM (so they pass DMARC) with a
+// Reply-To of whoever sent them. Their headers come along as X-Original-*,
+// their attachments stay in the archive.
+#[derive(Deserialize, Clone)]
+pub struct EmailForward {
+    pub alias: String,
+    pub to: String,
+}
+
+pub struct ForwardedEmail {
+    pub to: String,
+    pub reply_to: String,
+    pub subject: String,
+    pub text: String,
+    pub html: Option<String>,
+    pub original_headers: Vec<(String, String)>,
+    // The inbound email's job, so a retried job isn't forwarded twice.
+    pub job_id: String,
+}
+
+impl ForwardedEmail {
+    pub fn new(to: &str, job_id: &str, email: &MailgunEmailReceived) -> ForwardedEmail {
+        ForwardedEmail {
+            to: String::from(to),
+            reply_to: email.from.clone(),
+            subject: email.subject.clone(),
+            text: email.body_plain.clone(),
+            html: email.body_html.clone(),
+            original_headers: viewer::headers(&email.message_headers),
+            job_id: String::from(job_id),
+        }
+    }
+
+    // The Mailgun messages API form.
+    pub fn form(&self, from: &str) -> Vec<(String, String)> {
+        let mut params = vec![
+            (String::from("from"), String::from(from)),
+            (String::from("to"), self.to.clone()),
+            (String::from("subject"), self.subject.clone()),
+            (String::from("text"), self.text.clone()),
+            (String::from("h:Reply-To"), self.reply_to.clone()),
+            (String::from("h:X-Limail-Idempotency-Key"), self.job_id.clone()),
+        ];
+        if let Some(html) = &self.html {
+            params.push((String::from("html"), html.clone()));
+        }
+        for (name, value) in &self.original_headers {
+            params.push((format!("h:X-Original-{}", name), value.clone()));
+        }
+        params
+    }
+}
@@ -0,0 +1,297 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Serialize, Deserialize};
+
+use crate::archive::ArchivedSlackMessage;
+use crate::clock::Clock;
+use crate::fanout::{self, Call};
+use crate::maintenance::Maintenance;
+use crate::pipeline::route_matches;
+use crate::slack::{Slack, SlackMessage};
+use crate::store::{self, StoreError};
+
+const SLA_CHECK_SECONDS: i64 = 60;
+
+fn default_reactions() -> Vec<String> {
+    vec![String::from("white_check_mark")]
+}
+
+fn default_stale_hours() -> i64 {
+    24
+}
+
+fn default_digest_hours() -> u64 {
+    24
+}
+
+// A reaction from `reactions` on a forwarded email's Slack messages marks it
+// handled (Slack Events API, reaction_added, pointed at /slack/events).
+// Emails nobody handled within stale_hours are listed in a digest posted to
+// each channel every digest_hours.
+#[derive(Deserialize, Clone)]
+pub struct HandlingConfig {
+    #[serde(default = "default_reactions")]
+    pub reactions: Vec<String>,
+    #[serde(default = "default_stale_hours")]
+    pub stale_hours: i64,
+    #[serde(default = "default_digest_hours")]
+    pub digest_hours: u64,
+    #[serde(default)]
+    pub sla: Vec<RouteSla>,
+}
+
+// Forwards on `route` (exact, or a prefix ending in *) still unhandled
+// after `minutes` get one reminder in their thread, and in
+// escalation_channel when set. The first matching entry wins.
+#[derive(Deserialize, Clone)]
+pub struct RouteSla {
+    pub route: String,
+    pub minutes: i64,
+    #[serde(default)]
+    pub escalation_channel: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct UnhandledEmail {
+    pub forwarded_at: DateTime<Utc>,
+    #[serde(default)]
+    pub route: String,
+    pub subject: String,
+    pub from: String,
+    pub messages: Vec<ArchivedSlackMessage>,
+    // Any reaction at all, not only the ones that mark it handled.
+    #[serde(default)]
+    pub first_reaction_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub reminded: bool,
+}
+
+// Forwarded emails nobody has reacted to yet, by job id. Kept in
+// unhandled.json.
+#[derive(Clone)]
+pub struct Handling {
+    pub config: HandlingConfig,
+    path: PathBuf,
+    unhandled: Arc<Mutex<BTreeMap<String, UnhandledEmail>>>,
+    clock: Clock,
+}
+
+fn permalink(message: &ArchivedSlackMessage) -> String {
+    format!("https://slack.com/archives/{}/p{}", message.channel, message.ts.replace('.', ""))
+}
+
+impl Handling {
+    pub fn load(config: HandlingConfig, path: PathBuf) -> Result<Handling, StoreError> {
+        let unhandled: BTreeMap<String, UnhandledEmail> = store::read_json(&path)?.unwrap_or_default();
+        Ok(Handling {
+            config,
+            path,
+            unhandled: Arc::new(Mutex::new(unhandled)),
+            clock: Clock::system(),
+        })
+    }
+
+    pub fn with_clock(self, clock: Clock) -> Handling {
+        Handling {
+            clock,
+            ..self
+        }
+    }
+
+    // For stamping forwarded_at, on the same clock the SLAs are checked on.
+    pub fn now(&self) -> DateTime<Utc> {
+        self.clock.now()
+    }
+
+    pub fn track(&self, id: &str, email: UnhandledEmail) -> Result<(), StoreError> {
+        let mut unhandled = self.unhandled.lock().unwrap();
+        unhandled.insert(String::from(id), email);
+        store::write_json(&self.path, &*unhandled)
+    }
+
+    pub fn is_handling_reaction(&self, reaction: &str) -> bool {
+        self.config.reactions.iter().any(|r| r == reaction)
+    }
+
+    // The job whose forward the message belongs to, if it is still unhandled.
+    pub fn find(&self, channel: &str, ts: &str) -> Option<String> {
+        self.unhandled.lock().unwrap().iter()
+            .find(|(_, email)| email.messages.iter().any(|m| m.channel == channel && m.ts == ts))
+            .map(|(id, _)| id.clone())
+    }
+
+    // Returns true the first time anyone reacts to the forward.
+    pub fn note_reaction(&self, id: &str) -> Result<bool, StoreError> {
+        let mut unhandled = self.unhandled.lock().unwrap();
+        match unhandled.get_mut(id) {
+            Some(email) if email.first_reaction_at.is_none() => {
+                email.first_reaction_at = Some(self.clock.now());
+                store::write_json(&self.path, &*unhandled)?;
+                Ok(true)
+            },
+            _ => Ok(false),
+        }
+    }
+
+    pub fn mark_handled(&self, id: &str) -> Result<(), StoreError> {
+        let mut unhandled = self.unhandled.lock().unwrap();
+        if unhandled.remove(id).is_some() {
+            store::write_json(&self.path, &*unhandled)?;
+        }
+        Ok(())
+    }
+
+    pub fn count(&self) -> usize {
+        self.unhandled.lock().unwrap().len()
+    }
+
+    fn stale(&self) -> BTreeMap<String, Vec<UnhandledEmail>> {
+        let cutoff = self.clock.now() - Duration::hours(self.config.stale_hours);
+        let mut by_channel: BTreeMap<String, Vec<UnhandledEmail>> = BTreeMap::new();
+        for email in self.unhandled.lock().unwrap().values() {
+            if email.forwarded_at < cutoff {
+                if let Some(first) = email.messages.first() {
+                    by_channel.entry(first.channel.clone()).or_default().push(email.clone());
+                }
+            }
+        }
+        by_channel
+    }
+
+    fn post_digest(&self, slack: &Slack) {
+        for (channel, emails) in self.stale() {
+            let lines: Vec<String> = emails.iter()
+                .map(|email| format!(
+                    "• <{}|{}> from {}, forwarded {}",
+                    permalink(&email.messages[0]),
+                    email.subject.replace(|c| c == '<' || c == '>' || c == '|', ""),
+                    email.from,
+                    email.forwarded_at.format("%Y-%m-%d %H:%M UTC")
+                ))
+                .collect();
+            let text = format!(
+                "{} forwarded emails have been waiting more than {} hours, react with :{}: once handled:\n{}",
+                emails.len(),
+                self.config.stale_hours,
+                self.config.reactions.first().map(|r| &r[..]).unwrap_or("white_check_mark"),
+                lines.join("\n")
+            );
+            let sent = slack.send_message(&SlackMessage {
+                channel: channel.clone(),
+                text,
+                thread_ts: None,
+                as_user: true,
+                username: None,
+                icon_emoji: None,
+                icon_url: None,
+                blocks: None,
+                unfurl_links: None,
+                unfurl_media: None,
+            });
+            if let Err(e) = sent {
+                error!("Unable to post the unhandled email digest to {}: {}", channel, e);
+            }
+        }
+    }
+
+    fn sla(&self, route: &str) -> Option<&RouteSla> {
+        self.config.sla.iter().find(|sla| route_matches(&sla.route, route))
+    }
+
+    // Marks what is overdue as reminded before posting, so a failing Slack
+    // doesn't turn into a reminder every minute.
+    fn remind_overdue(&self, slack: &Slack) {
+        let now = self.clock.now();
+        let overdue: Vec<(UnhandledEmail, RouteSla)> = {
+            let mut unhandled = self.unhandled.lock().unwrap();
+            let overdue: Vec<(UnhandledEmail, RouteSla)> = unhandled.values_mut()
+                .filter_map(|email| {
+                    let sla = self.sla(&email.route)?.clone();
+                    if email.reminded || now - email.forwarded_at < Duration::minutes(sla.minutes) {
+                        return None;
+                    }
+                    email.reminded = true;
+                    Some((email.clone(), sla))
+                })
+                .collect();
+            if !overdue.is_empty() {
+                if let Err(e) = store::write_json(&self.path, &*unhandled) {
+                    error!("Unable to save unhandled.json: {}", e);
+                }
+            }
+            overdue
+        };
+        for (email, sla) in overdue {
+            let header = &email.messages[0];
+            let waited = (now - email.forwarded_at).num_minutes();
+            let mut reminders = vec![(header.channel.clone(), Some(header.ts.clone()), format!(
+                "This email has been waiting {} minutes (the SLA is {}) and nobody has marked it handled.",
+                waited,
+                sla.minutes
+            ))];
+            if let Some(channel) = &sla.escalation_channel {
+                reminders.push((channel.clone(), None, format!(
+                    "Overdue by {} minutes: <{}|{}> from {}",
+                    waited - sla.minutes,
+                    permalink(header),
+                    email.subject.replace(|c| c == '<' || c == '>' || c == '|', ""),
+                    email.from
+                )));
+            }
+            // The reminder in the thread and the escalation don't wait on
+            // each other.
+            let calls: Vec<Call<()>> = reminders.into_iter()
+                .map(|(channel, thread_ts, text)| {
+                    let slack = slack.clone();
+                    Box::new(move || {
+                        let sent = slack.send_message(&SlackMessage {
+                            channel: channel.clone(),
+                            text,
+                            thread_ts,
+                            as_user: true,
+                            username: None,
+                            icon_emoji: None,
+                            icon_url: None,
+                            blocks: None,
+                            unfurl_links: None,
+                            unfurl_media: None,
+                        });
+                        if let Err(e) = sent {
+                            error!("Unable to post an SLA reminder to {}: {}", channel, e);
+                        }
+                    }) as Call<()>
+                })
+                .collect();
+            fanout::join(calls, slack.timeout + std::time::Duration::from_secs(1));
+        }
+    }
+
+    // The digest and the SLA reminders, in the background. Neither is
+    // posted in maintenance: the digest waits for its next turn, and the
+    // reminders for the first check after it is lifted.
+    pub fn start(&self, slack: Slack, maintenance: Maintenance) {
+        let handling = self.clone();
+        let digest_slack = slack.clone();
+        let digest_maintenance = maintenance.clone();
+        let interval = Duration::hours(self.config.digest_hours.max(1) as i64);
+        thread::spawn(move || loop {
+            handling.clock.sleep_until(handling.clock.now() + interval);
+            if !digest_maintenance.is_enabled() {
+                handling.post_digest(&digest_slack);
+            }
+        });
+        if !self.config.sla.is_empty() {
+            let handling = self.clone();
+            thread::spawn(move || loop {
+                handling.clock.sleep_until(handling.clock.now() + Duration::seconds(SLA_CHECK_SECONDS));
+                if !maintenance.is_enabled() {
+                    handling.remind_overdue(&slack);
+                }
+            });
+        }
+    }
+}
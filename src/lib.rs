@@ -0,0 +1,124 @@
+// Limail an email helper for lichess
+// Copyright (C) 2019  Lakin Wecker
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+#[macro_use] extern crate log;
+extern crate ammonia;
+extern crate amiquip;
+extern crate base64;
+extern crate bytes;
+extern crate chashmap;
+extern crate futures;
+extern crate handlebars;
+extern crate hex;
+extern crate hmac;
+extern crate openssl;
+extern crate redis;
+extern crate regex;
+extern crate reqwest;
+extern crate serde;
+extern crate serde_json;
+extern crate serde_urlencoded;
+extern crate sha2;
+extern crate signal_hook;
+extern crate socket2;
+extern crate tokio;
+extern crate toml;
+extern crate warp;
+
+pub mod addresses;
+pub mod admin;
+pub mod alerts;
+pub mod apilimit;
+pub mod archive;
+pub mod audit;
+pub mod auth;
+pub mod authresults;
+pub mod backup;
+pub mod blocklist;
+pub mod bounces;
+pub mod budget;
+pub mod canned;
+pub mod captures;
+pub mod chaos;
+pub mod cli;
+pub mod clock;
+pub mod commands;
+pub mod config;
+pub mod copies;
+pub mod dashboard;
+pub mod defang;
+pub mod delays;
+pub mod digest;
+pub mod domains;
+pub mod drift;
+pub mod earlyack;
+pub mod echo;
+pub mod encryption;
+pub mod fanout;
+pub mod feedback;
+pub mod flags;
+pub mod footers;
+pub mod formatting;
+pub mod forwards;
+pub mod handling;
+pub mod links;
+pub mod listener;
+pub mod loops;
+pub mod mailgun;
+pub mod maintenance;
+pub mod metrics;
+pub mod multipart;
+pub mod notices;
+pub mod outbox;
+pub mod pacing;
+pub mod pgp;
+pub mod pipeline;
+pub mod policy;
+pub mod providers;
+pub mod proxies;
+pub mod publish;
+pub mod quarantine;
+pub mod queue;
+pub mod ratelimit;
+pub mod reputation;
+pub mod sandbox;
+pub mod scrub;
+pub mod secrets;
+pub mod server;
+pub mod settings;
+pub mod signatures;
+pub mod simulate;
+pub mod slack;
+pub mod smime;
+pub mod store;
+pub mod suppressions;
+pub mod systemd;
+pub mod takeover;
+pub mod templatecheck;
+pub mod templates;
+pub mod threads;
+pub mod threats;
+pub mod trace;
+pub mod traceparent;
+pub mod transfer;
+pub mod unrouted;
+pub mod unsubscribe;
+pub mod variants;
+pub mod version;
+pub mod viewer;
+pub mod weekly;
+#[cfg(feature = "testing")]
+pub mod testing;
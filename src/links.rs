@@ -0,0 +1,72 @@
+use chrono::{Duration, Utc};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn default_ttl_hours() -> i64 {
+    72
+}
+
+fn default_preview_chars() -> usize {
+    500
+}
+
+#[derive(Deserialize, Clone)]
+pub struct LinkConfig {
+    // Where limail is reachable from the browsers of whoever reads Slack,
+    // e.g. https://limail.example.org
+    pub base_url: String,
+    pub secret: String,
+    #[serde(default = "default_ttl_hours")]
+    pub ttl_hours: i64,
+    // With a link to the full email, the body posted to Slack is cut short.
+    #[serde(default = "default_preview_chars")]
+    pub preview_chars: usize,
+}
+
+#[derive(Deserialize)]
+pub struct SignedQuery {
+    pub expires: i64,
+    pub signature: String,
+}
+
+// Time-limited links to the archive viewer, signed so they can be handed to
+// Slack without giving out an admin token.
+#[derive(Clone)]
+pub struct ArchiveLinks {
+    pub config: LinkConfig,
+}
+
+impl ArchiveLinks {
+    pub fn new(config: LinkConfig) -> ArchiveLinks {
+        ArchiveLinks { config }
+    }
+
+    fn mac(&self, id: &str, expires: i64) -> HmacSha256 {
+        let mut mac = HmacSha256::new_varkey(self.config.secret.as_bytes()).expect("HMAC takes keys of any size");
+        mac.input(format!("{}:{}", id, expires).as_bytes());
+        mac
+    }
+
+    pub fn query(&self, id: &str) -> String {
+        let expires = (Utc::now() + Duration::hours(self.config.ttl_hours)).timestamp();
+        let signature = hex::encode(self.mac(id, expires).result().code());
+        format!("expires={}&signature={}", expires, signature)
+    }
+
+    pub fn url(&self, id: &str) -> String {
+        format!("{}/archive/{}?{}", self.config.base_url.trim_end_matches('/'), id, self.query(id))
+    }
+
+    pub fn verify(&self, id: &str, query: &SignedQuery) -> bool {
+        if query.expires < Utc::now().timestamp() {
+            return false;
+        }
+        match hex::decode(&query.signature) {
+            Ok(signature) => self.mac(id, query.expires).verify(&signature).is_ok(),
+            Err(_) => false,
+        }
+    }
+}
@@ -0,0 +1,264 @@
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::sync::oneshot;
+use futures::{Async, Future, Poll, Stream};
+use hyper::service::{make_service_fn, service_fn, Service};
+use serde::Deserialize;
+use signal_hook::iterator::Signals;
+use socket2::{Domain, Socket, Type};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::timer::Delay;
+use warp::{Filter, Rejection};
+
+use crate::metrics::Metrics;
+use crate::proxies::{self, ProxyConfig, TrustedProxies};
+use crate::systemd;
+use crate::traceparent::TraceContext;
+
+// hyper won't take a smaller read buffer.
+pub const MIN_HEADER_BYTES: usize = 8192;
+
+fn default_true() -> bool {
+    true
+}
+
+// How the webhook server speaks HTTP, to match Mailgun's webhook client
+// with nothing in front of limail. http2 = false serves HTTP/1.1 only.
+// keep_alive = false closes every connection after one request, otherwise
+// keep_alive_seconds closes the ones with nothing read or written for that
+// long (keep it above the handler deadlines). max_concurrent_streams caps
+// the requests in flight on one HTTP/2 connection, and max_header_bytes
+// the size of HTTP/1 request heads (at least 8192).
+#[derive(Deserialize, Clone)]
+pub struct HttpConfig {
+    #[serde(default = "default_true")]
+    pub http2: bool,
+    #[serde(default = "default_true")]
+    pub keep_alive: bool,
+    #[serde(default)]
+    pub keep_alive_seconds: Option<u64>,
+    #[serde(default)]
+    pub max_concurrent_streams: Option<u32>,
+    #[serde(default)]
+    pub max_header_bytes: Option<usize>,
+    #[serde(default)]
+    pub proxy: Option<ProxyConfig>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> HttpConfig {
+        HttpConfig {
+            http2: true,
+            keep_alive: true,
+            keep_alive_seconds: None,
+            max_concurrent_streams: None,
+            max_header_bytes: None,
+            proxy: None,
+        }
+    }
+}
+
+// With SO_REUSEPORT several processes can listen on the same port, so a new
+// limail can start accepting before the old one stops. An IPv6 address
+// such as [::]:8080 takes IPv4 clients too, whatever the system default,
+// unless v6_only.
+pub fn bind(address: SocketAddr, reuse_port: bool, v6_only: bool) -> io::Result<TcpListener> {
+    let domain = match address {
+        SocketAddr::V4(_) => Domain::ipv4(),
+        SocketAddr::V6(_) => Domain::ipv6(),
+    };
+    let socket = Socket::new(domain, Type::stream(), None)?;
+    socket.set_reuse_address(true)?;
+    if reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    if address.is_ipv6() {
+        socket.set_only_v6(v6_only)?;
+    }
+    socket.bind(&address.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into_tcp_listener())
+}
+
+pub fn family(ip: &IpAddr) -> &'static str {
+    match ip {
+        IpAddr::V4(_) => "ipv4",
+        IpAddr::V6(_) => "ipv6",
+    }
+}
+
+// A connection that fails its next read or write once it's been idle for
+// `timeout`, which makes hyper close it.
+pub struct Idle {
+    stream: TcpStream,
+    remote: Option<SocketAddr>,
+    timeout: Option<Duration>,
+    deadline: Option<Delay>,
+}
+
+impl Idle {
+    pub fn new(stream: TcpStream, timeout: Option<Duration>) -> Idle {
+        Idle {
+            remote: stream.peer_addr().ok(),
+            deadline: timeout.map(|timeout| Delay::new(Instant::now() + timeout)),
+            stream,
+            timeout,
+        }
+    }
+
+    fn active(&mut self) {
+        if let (Some(deadline), Some(timeout)) = (&mut self.deadline, self.timeout) {
+            deadline.reset(Instant::now() + timeout);
+        }
+    }
+
+    // Also asks to be woken when the deadline passes.
+    fn check(&mut self) -> io::Result<()> {
+        match self.deadline.as_mut().map(Future::poll) {
+            Some(Ok(Async::Ready(()))) => Err(io::Error::new(io::ErrorKind::TimedOut, "idle keep-alive connection")),
+            _ => Ok(()),
+        }
+    }
+
+    fn track<T>(&mut self, result: io::Result<T>) -> io::Result<T> {
+        match result {
+            Ok(done) => {
+                self.active();
+                Ok(done)
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                self.check()?;
+                result
+            },
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Read for Idle {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.stream.read(buf);
+        self.track(read)
+    }
+}
+
+impl Write for Idle {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.stream.write(buf);
+        self.track(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl AsyncRead for Idle {}
+
+impl AsyncWrite for Idle {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        AsyncWrite::shutdown(&mut self.stream)
+    }
+}
+
+// One line per request, under limail::access, with IPv4 clients of a
+// dual-stack socket counted as IPv4, and the client a trusted proxy names
+// rather than the proxy. Ends with the trace id of the request's
+// traceparent, if it has one.
+fn log_request(metrics: &Metrics, client: Option<IpAddr>, request: &str, trace_id: Option<String>, status: u16, started: Instant) {
+    metrics.incr_labeled("http_requests", &[("family", client.as_ref().map_or("unknown", family))]);
+    info!(
+        target: "limail::access",
+        "{} \"{}\" {} {:?} {}",
+        client.map(|ip| ip.to_string()).unwrap_or_else(|| String::from("-")),
+        request,
+        status,
+        started.elapsed(),
+        trace_id.unwrap_or_else(|| String::from("-"))
+    );
+}
+
+// Runs the routes on incoming until it ends, then until the requests
+// already accepted are answered.
+pub fn serve<F, I>(routes: F, incoming: I, config: &HttpConfig, metrics: Metrics)
+where
+    F: Filter<Error = Rejection> + Clone + Send + Sync + 'static,
+    F::Extract: warp::Reply,
+    I: Stream<Item = TcpStream, Error = io::Error> + Send + 'static,
+{
+    let timeout = config.keep_alive_seconds.filter(|_| config.keep_alive).map(Duration::from_secs);
+    let incoming = incoming.map(move |stream| Idle::new(stream, timeout));
+    let proxies = config.proxy.as_ref()
+        .map(|proxy| TrustedProxies::new(proxy).expect("Unable to parse the [http.proxy] addresses"));
+    let service = warp::service(routes);
+    let make_service = make_service_fn(move |connection: &Idle| {
+        let peer = connection.remote.map(|remote| proxies::unmapped(remote.ip()));
+        let (service, metrics, proxies) = (service.clone(), metrics.clone(), proxies.clone());
+        Ok::<_, io::Error>(service_fn(move |request| {
+            let client = match (&proxies, peer) {
+                (Some(proxies), Some(peer)) => Some(proxies.client(peer, request.headers())),
+                _ => peer,
+            };
+            let line = format!("{} {} {:?}", request.method(), request.uri().path(), request.version());
+            let trace_id = TraceContext::from_headers(request.headers()).map(|trace| trace.trace_id);
+            let started = Instant::now();
+            let (mut service, metrics) = (service.clone(), metrics.clone());
+            service.call(request).map(move |response| {
+                log_request(&metrics, client, &line, trace_id, response.status().as_u16(), started);
+                response
+            })
+        }))
+    });
+    let mut builder = hyper::Server::builder(incoming)
+        .http1_keepalive(config.keep_alive)
+        .http1_only(!config.http2)
+        .http2_max_concurrent_streams(config.max_concurrent_streams);
+    if let Some(bytes) = config.max_header_bytes {
+        builder = builder.http1_max_buf_size(bytes.max(MIN_HEADER_BYTES));
+    }
+    let server = builder.serve(make_service).map_err(|e| error!("The server stopped: {}", e));
+    tokio::run(server);
+}
+
+// Ends the stream of incoming connections on SIGTERM or SIGINT. The server
+// then stops accepting but finishes the requests it's already handling.
+pub struct Draining<S> {
+    incoming: S,
+    shutdown: oneshot::Receiver<()>,
+}
+
+impl<S: Stream> Stream for Draining<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        match self.shutdown.poll() {
+            Ok(Async::NotReady) => self.incoming.poll(),
+            _ => Ok(Async::Ready(None)),
+        }
+    }
+}
+
+// Idle keep-alive connections would otherwise hold the process open, so give
+// up on them after `timeout`.
+pub fn drain_on_signal<S: Stream>(incoming: S, timeout: Duration) -> Draining<S> {
+    let (trigger, shutdown) = oneshot::channel();
+    let signals = Signals::new(&[signal_hook::SIGTERM, signal_hook::SIGINT])
+        .expect("Unable to listen for signals");
+    thread::spawn(move || {
+        if let Some(signal) = signals.forever().next() {
+            info!("Received signal {}, draining for up to {}s", signal, timeout.as_secs());
+            systemd::notify("STOPPING=1");
+            let _ = trigger.send(());
+            thread::sleep(timeout);
+            info!("Drain timeout reached, exiting");
+            process::exit(0);
+        }
+    });
+    Draining { incoming, shutdown }
+}
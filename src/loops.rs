@@ -0,0 +1,144 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+
+use crate::blocklist::address_of;
+use crate::clock::Clock;
+use crate::mailgun::MailgunEmailReceived;
+use crate::outbox::OutboxEntry;
+use crate::viewer;
+
+fn default_window_minutes() -> i64 {
+    10
+}
+
+fn default_rounds() -> usize {
+    3
+}
+
+fn default_pause_hours() -> i64 {
+    24
+}
+
+// Breaks mail loops with other auto-responders. When `rounds` emails in a
+// row from an address each answer a new auto-reply of ours within
+// window_minutes, and are automated themselves (Auto-Submitted, Precedence,
+// X-Autoreply...) or In-Reply-To it, that address gets no auto-replies for
+// pause_hours and channel hears about it once. Counted per process.
+#[derive(Deserialize, Clone)]
+pub struct LoopConfig {
+    #[serde(default)]
+    pub channel: Option<String>,
+    #[serde(default = "default_window_minutes")]
+    pub window_minutes: i64,
+    #[serde(default = "default_rounds")]
+    pub rounds: usize,
+    #[serde(default = "default_pause_hours")]
+    pub pause_hours: i64,
+}
+
+#[derive(Default)]
+struct Correspondent {
+    rounds: usize,
+    // The reply of ours the last round answered, so each round is a new one.
+    answered: Option<String>,
+    paused_until: Option<DateTime<Utc>>,
+}
+
+pub enum Verdict {
+    Reply,
+    Paused(String),
+    // Just found, for the one notice.
+    Broken(String),
+}
+
+fn automated(headers: &[(String, String)]) -> bool {
+    headers.iter().any(|(name, value)| {
+        let value = value.trim().to_lowercase();
+        match &name.to_lowercase()[..] {
+            "auto-submitted" => value != "no",
+            "precedence" => value == "auto_reply" || value == "bulk" || value == "junk",
+            "x-autoreply" | "x-autorespond" | "x-auto-response-suppress" => true,
+            _ => false,
+        }
+    })
+}
+
+fn answers(headers: &[(String, String)], message_id: &str) -> bool {
+    let message_id = message_id.trim().trim_start_matches('<').trim_end_matches('>');
+    !message_id.is_empty() && headers.iter().any(|(name, value)| {
+        (name.eq_ignore_ascii_case("In-Reply-To") || name.eq_ignore_ascii_case("References"))
+            && value.contains(message_id)
+    })
+}
+
+#[derive(Clone)]
+pub struct Loops {
+    config: Arc<LoopConfig>,
+    correspondents: Arc<Mutex<BTreeMap<String, Correspondent>>>,
+    clock: Clock,
+}
+
+impl Loops {
+    pub fn new(config: LoopConfig) -> Loops {
+        Loops {
+            config: Arc::new(config),
+            correspondents: Arc::new(Mutex::new(BTreeMap::new())),
+            clock: Clock::system(),
+        }
+    }
+
+    pub fn with_clock(self, clock: Clock) -> Loops {
+        Loops {
+            clock,
+            ..self
+        }
+    }
+
+    pub fn channel(&self) -> Option<&String> {
+        self.config.channel.as_ref()
+    }
+
+    // For an email from recipient, whose last auto-reply was last_reply.
+    pub fn check(&self, email: &MailgunEmailReceived, recipient: &str, last_reply: Option<&OutboxEntry>) -> Verdict {
+        let now = self.clock.now();
+        let address = address_of(recipient);
+        let mut correspondents = self.correspondents.lock().unwrap();
+        let correspondent = correspondents.entry(address.clone()).or_default();
+        match correspondent.paused_until {
+            Some(until) if until > now => {
+                return Verdict::Paused(format!("in a mail loop with us, no auto-replies until {}", until.to_rfc3339()));
+            },
+            Some(_) => *correspondent = Correspondent::default(),
+            None => (),
+        }
+        let headers = viewer::headers(&email.message_headers);
+        let answered = last_reply
+            .filter(|reply| now.signed_duration_since(reply.at) < Duration::minutes(self.config.window_minutes))
+            .filter(|reply| automated(&headers) || answers(&headers, &reply.message_id))
+            .map(|reply| reply.message_id.clone());
+        match answered {
+            Some(id) => if correspondent.answered.as_ref() != Some(&id) {
+                correspondent.rounds += 1;
+                correspondent.answered = Some(id);
+            },
+            None => {
+                correspondents.remove(&address);
+                return Verdict::Reply;
+            },
+        }
+        if correspondent.rounds < self.config.rounds.max(1) {
+            return Verdict::Reply;
+        }
+        correspondent.paused_until = Some(now + Duration::hours(self.config.pause_hours));
+        Verdict::Broken(format!(
+            "{} answered our last {} auto-replies within {} minutes each, looks like a mail loop, no auto-replies to it for {} hours",
+            address,
+            correspondent.rounds,
+            self.config.window_minutes,
+            self.config.pause_hours
+        ))
+    }
+}
@@ -1,19 +1,44 @@
+use std::collections::BTreeMap;
 use std::error::Error as StdError;
 use std::fmt::{self, Display};
+use std::time::Duration;
 
 use sha2::Sha256;
 use hmac::{Hmac, Mac};
 type HmacSha256 = Hmac<Sha256>;
+
+pub const MAILGUN_URL: &str = "https://api.mailgun.net/v3";
 use serde::{Serialize, Deserialize};
-use serde_json::{Value};
+use serde_json::{json, Value};
 use warp::Rejection;
 
+use crate::bounces::EnvelopeSenders;
+use crate::budget::SendBudget;
+use crate::chaos::Chaos;
+use crate::domains::SendingDomains;
+use crate::footers::Footers;
+use crate::forwards::ForwardedEmail;
+use crate::maintenance::Maintenance;
+use crate::pacing::Pacing;
+use crate::sandbox::Sandbox;
+use crate::secrets::Secret;
+use crate::traceparent;
+
 pub struct EmailTemplate {
     pub recipient: String,
     pub subject: String,
     pub template: String,
     pub in_reply_to: String,
-    pub references: String
+    pub references: String,
+    // Made available to the Mailgun template.
+    pub variables: BTreeMap<String, String>,
+    // Sent as X-Limail-Idempotency-Key, the same for every attempt at the
+    // same reply, so duplicates can be told apart from separate replies.
+    pub idempotency_key: Option<String>,
+    // Whose footer it gets, see footers.rs.
+    pub route: Option<String>,
+    // The List-Unsubscribe header, see unsubscribe.rs.
+    pub list_unsubscribe: Option<String>,
 }
 
 #[derive(Debug)]
@@ -21,6 +46,12 @@ pub enum MailgunError {
     JsonError(String),
     HmacError(String),
     MailgunError(String),
+    // Not sent, see budget.rs.
+    OverBudget(String),
+    // Not sent, see sandbox.rs.
+    Sandbox(String),
+    // Not sent, see maintenance.rs.
+    Maintenance(String),
 }
 impl std::convert::From<serde_json::Error> for MailgunError {
     fn from(_error: serde_json::Error) -> Self {
@@ -40,19 +71,28 @@ impl Display for MailgunError {
             MailgunError::JsonError(s) => s,
             MailgunError::HmacError(s) => s,
             MailgunError::MailgunError(s) => s,
+            MailgunError::OverBudget(s) => s,
+            MailgunError::Sandbox(s) => s,
+            MailgunError::Maintenance(s) => s,
         })
     }
 }
 impl StdError for MailgunError {}
 
+#[derive(Deserialize)]
+struct MailgunSendResponse {
+    id: String,
+}
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct MailgunEmailReceived {
     pub sender: String,
     pub from: String,
     pub subject: String,
     #[serde(rename = "body-plain")]
     pub body_plain: String,
+    #[serde(rename = "body-html", default, skip_serializing_if = "Option::is_none")]
+    pub body_html: Option<String>,
     pub timestamp: i64,
     pub token: String,
     pub signature: String,
@@ -94,45 +134,439 @@ impl MailgunEmailReceived {
     }
 }
 
+// Mailgun's newer webhooks post JSON: the signature on its own, and the
+// email as event-data. That's either the fields of the form format, or a
+// stored message event, which only has the headers it was sent with.
+#[derive(Deserialize)]
+pub struct MailgunJsonWebhook {
+    pub signature: MailgunJsonSignature,
+    #[serde(rename = "event-data")]
+    pub event_data: Value,
+}
+
+#[derive(Deserialize)]
+pub struct MailgunJsonSignature {
+    // A string of seconds in the JSON format.
+    pub timestamp: Value,
+    pub token: String,
+    pub signature: String,
+}
+
+impl MailgunJsonWebhook {
+    pub fn into_email(self) -> Result<MailgunEmailReceived, MailgunError> {
+        let data = &self.event_data;
+        let headers = &data["message"]["headers"];
+        let text = |field: &str| data[field].as_str().or_else(|| headers[field].as_str()).map(String::from);
+        let timestamp = match &self.signature.timestamp {
+            Value::Number(n) => n.as_i64(),
+            Value::String(s) => s.parse().ok(),
+            _ => None,
+        }.ok_or_else(|| MailgunError::JsonError(String::from("Invalid webhook timestamp")))?;
+        let from = text("from").ok_or_else(|| MailgunError::JsonError(String::from("The webhook has no from")))?;
+        let message_headers = match &data["message-headers"] {
+            Value::String(s) => s.clone(),
+            Value::Array(_) => data["message-headers"].to_string(),
+            _ => {
+                let mut pairs: Vec<Value> = headers.as_object()
+                    .map(|headers| headers.iter()
+                        .filter(|(name, _)| !name.eq_ignore_ascii_case("message-id"))
+                        .filter_map(|(name, value)| Some(json!([name, value.as_str()?]))).collect())
+                    .unwrap_or_default();
+                // Events leave the brackets off.
+                if let Some(id) = headers["message-id"].as_str() {
+                    let id = if id.starts_with('<') { String::from(id) } else { format!("<{}>", id) };
+                    pairs.push(json!(["Message-Id", id]));
+                }
+                Value::Array(pairs).to_string()
+            },
+        };
+        Ok(MailgunEmailReceived {
+            sender: text("sender")
+                .or_else(|| data["envelope"]["sender"].as_str().map(String::from))
+                .unwrap_or_else(|| from.clone()),
+            from,
+            subject: text("subject").unwrap_or_default(),
+            body_plain: text("body-plain").or_else(|| text("stripped-text")).unwrap_or_default(),
+            body_html: text("body-html"),
+            timestamp,
+            token: self.signature.token,
+            signature: self.signature.signature,
+            message_headers,
+        })
+    }
+}
+
+// A SHA-256 HMAC, hex encoded.
+const SIGNATURE_HEX_LEN: usize = 64;
+
+// How far a signature's timestamp can be from now, either way. A captured
+// webhook can't be replayed after that, and a token only needs remembering
+// for that long, see signatures.rs.
+pub const SIGNATURE_MAX_AGE_SECONDS: i64 = 15 * 60;
+
+pub fn check_timestamp(timestamp: i64, now: i64) -> Result<(), MailgunError> {
+    if (now - timestamp).abs() > SIGNATURE_MAX_AGE_SECONDS {
+        return Err(MailgunError::HmacError(format!("Stale timestamp, {} seconds off", now - timestamp)));
+    }
+    Ok(())
+}
+
+// Mailgun signs the timestamp followed by the token with the API key, see
+// https://documentation.mailgun.com/en/latest/user_manual.html#webhooks
+// Only the HMAC, check_timestamp says whether it's recent.
+pub fn verify_signature(api_key: &str, timestamp: i64, token: &str, signature: &str) -> Result<(), MailgunError> {
+    if token.is_empty() {
+        return Err(MailgunError::HmacError("Missing token".into()));
+    }
+    let signature = signature.trim();
+    if signature.len() != SIGNATURE_HEX_LEN || !signature.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(MailgunError::HmacError("Malformed signature".into()));
+    }
+    let signature_bytes = hex::decode(signature)
+        .map_err(|_| MailgunError::HmacError("Unable to decode signature".into()))?;
+
+    let mut mac = HmacSha256::new_varkey(api_key.as_bytes())
+        .map_err(|_| MailgunError::HmacError("Unable to create MAC".into()))?;
+    mac.input(timestamp.to_string().as_bytes());
+    mac.input(token.as_bytes());
+    // Compares in constant time.
+    mac.verify(&signature_bytes)
+        .map_err(|_| MailgunError::HmacError("Bad HMAC".into()))
+}
+
 #[derive(Clone)]
 pub struct Mailgun {
-    pub api_key: String,
+    pub api_key: Secret,
+    // MAILGUN_URL, unless testing against a fake.
+    pub api_url: String,
     pub domain: String,
-    pub from: String
+    pub from: String,
+    // For each call to the Mailgun API.
+    pub timeout: Duration,
+    // Sending through several domains instead of just `domain`.
+    pub domains: Option<SendingDomains>,
+    pub budget: Option<SendBudget>,
+    // Only with the chaos feature, see chaos.rs.
+    pub chaos: Option<Chaos>,
+    pub sandbox: Option<Sandbox>,
+    pub footers: Option<Footers>,
+    pub envelope_senders: Option<EnvelopeSenders>,
+    pub pacing: Option<Pacing>,
+    // Nothing is sent while it's enabled.
+    pub maintenance: Option<Maintenance>,
+}
+
+// Whether another sending domain might have better luck.
+enum SendFailure {
+    Throttled(String),
+    Refused(MailgunError),
 }
 impl Mailgun {
+    pub fn with_timeout(&self, timeout: Duration) -> Mailgun {
+        Mailgun {
+            timeout,
+            ..self.clone()
+        }
+    }
+
+    fn client(&self) -> Result<reqwest::Client, MailgunError> {
+        reqwest::Client::builder()
+            .timeout(self.timeout)
+            .default_headers(traceparent::outbound_headers())
+            .build()
+            .map_err(|e| MailgunError::MailgunError(format!("Unable to create client: {}", e)))
+    }
+
+    fn refuse_in_maintenance(&self) -> Result<(), MailgunError> {
+        match self.maintenance.as_ref().and_then(Maintenance::refusal) {
+            Some(refusal) => Err(MailgunError::Maintenance(refusal)),
+            None => Ok(()),
+        }
+    }
+
     pub fn verify_hmac(&self, email: &MailgunEmailReceived) -> Result<(), MailgunError> {
-        let mut mac = HmacSha256::new_varkey(&self.api_key.clone().into_bytes())
-            .map_err(|_| MailgunError::HmacError("Unable to create MAC".into()))?;
-
-        let msg = email.timestamp.to_string() + &email.token;
-        mac.input(&msg.into_bytes());
-
-        let signature_bytes = hex::decode(&email.signature)
-            .map_err(|_| MailgunError::HmacError("Unable to decode signature".into()))?;
-        mac.verify(&signature_bytes)
-            .map_err(|_| MailgunError::HmacError("Bad HMAC".into()))
-    }
-
-    pub fn send_email(&self, email: &EmailTemplate) -> Result<(), MailgunError> {
-        let params = [
-            ("from", &self.from),
-            ("to", &email.recipient),
-            ("subject", &email.subject),
-            ("template", &email.template),
-            ("h:X-Autoreply", &String::from("yes")),
-            ("h:In-Reply-To", &email.in_reply_to),
-            ("h:References", &email.references)
+        verify_signature(self.api_key.expose(), email.timestamp, &email.token, &email.signature)
+    }
+
+    // Checks that Mailgun is reachable and knows our domain and key.
+    pub fn check(&self) -> Result<(), MailgunError> {
+        let client = self.client()?;
+        let url = format!("{}/domains/{}", self.api_url.trim_end_matches('/'), self.domain);
+        let response = client.get(&url)
+            .basic_auth("api", Some(self.api_key.expose()))
+            .send()
+            .map_err(|e| MailgunError::MailgunError(self.api_key.redact(&format!("Unable to make request: {}", e))))?;
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(MailgunError::MailgunError(format!(
+                "Mailgun refused the domain {}: {}",
+                self.domain,
+                response.status()
+            )))
+        }
+    }
+
+    // active, unverified or disabled.
+    pub fn domain_state(&self, domain: &str) -> Result<String, MailgunError> {
+        let client = self.client()?;
+        let url = format!("{}/domains/{}", self.api_url.trim_end_matches('/'), domain);
+        let response: Value = client.get(&url)
+            .basic_auth("api", Some(self.api_key.expose()))
+            .send()
+            .and_then(|response| response.error_for_status())
+            .and_then(|mut response| response.json())
+            .map_err(|e| MailgunError::MailgunError(self.api_key.redact(&format!("Unable to look up {}: {}", domain, e))))?;
+        Ok(response["domain"]["state"].as_str().unwrap_or("unknown").to_string())
+    }
+
+    // The stored templates API, for /admin/templates. None when Mailgun has
+    // no such template.
+    fn templates_call(&self, method: reqwest::Method, path: &str, form: &[(&str, String)]) -> Result<Option<Value>, MailgunError> {
+        let client = self.client()?;
+        let url = format!("{}/{}/templates{}", self.api_url.trim_end_matches('/'), self.domain, path);
+        let request = client.request(method, &url).basic_auth("api", Some(self.api_key.expose()));
+        let request = if form.is_empty() { request } else { request.form(form) };
+        let mut response = request.send()
+            .map_err(|e| MailgunError::MailgunError(self.api_key.redact(&format!("Unable to make request: {}", e))))?;
+        let status = response.status();
+        if status.as_u16() == 404 {
+            return Ok(None);
+        }
+        if !status.is_success() {
+            return Err(MailgunError::MailgunError(format!(
+                "Mailgun refused the template request: {} {}",
+                status,
+                response.text().unwrap_or_default()
+            )));
+        }
+        response.json()
+            .map(Some)
+            .map_err(|e| MailgunError::MailgunError(format!("Unable to read Mailgun's answer: {}", e)))
+    }
+
+    pub fn list_templates(&self) -> Result<Value, MailgunError> {
+        Ok(self.templates_call(reqwest::Method::GET, "?limit=1000", &[])?.unwrap_or(Value::Null))
+    }
+
+    // With the content of its active version.
+    pub fn get_template(&self, name: &str) -> Result<Option<Value>, MailgunError> {
+        self.templates_call(reqwest::Method::GET, &format!("/{}?active=yes", name), &[])
+    }
+
+    pub fn create_template(&self, form: &[(&str, String)]) -> Result<Value, MailgunError> {
+        Ok(self.templates_call(reqwest::Method::POST, "", form)?.unwrap_or(Value::Null))
+    }
+
+    // Templates are changed by adding a version and making it the active one.
+    pub fn add_template_version(&self, name: &str, form: &[(&str, String)]) -> Result<Option<Value>, MailgunError> {
+        self.templates_call(reqwest::Method::POST, &format!("/{}/versions", name), form)
+    }
+
+    // What each of the account's routes does with the emails it matches.
+    pub fn route_actions(&self) -> Result<Vec<String>, MailgunError> {
+        let client = self.client()?;
+        let url = format!("{}/routes", self.api_url.trim_end_matches('/'));
+        let response: Value = client.get(&url)
+            .query(&[("limit", "1000")])
+            .basic_auth("api", Some(self.api_key.expose()))
+            .send()
+            .and_then(|response| response.error_for_status())
+            .and_then(|mut response| response.json())
+            .map_err(|e| MailgunError::MailgunError(self.api_key.redact(&format!("Unable to list the routes: {}", e))))?;
+        Ok(response["items"].as_array().map(|routes| routes.iter()
+            .flat_map(|route| route["actions"].as_array().cloned().unwrap_or_default())
+            .filter_map(|action| action.as_str().map(String::from))
+            .collect()).unwrap_or_default())
+    }
+
+    pub fn template_exists(&self, template: &str) -> Result<bool, MailgunError> {
+        let client = self.client()?;
+        let url = format!("{}/{}/templates/{}", self.api_url.trim_end_matches('/'), self.domain, template);
+        let response = client.get(&url)
+            .basic_auth("api", Some(self.api_key.expose()))
+            .send()
+            .map_err(|e| MailgunError::MailgunError(self.api_key.redact(&format!("Unable to make request: {}", e))))?;
+        match response.status() {
+            status if status.is_success() => Ok(true),
+            status if status.as_u16() == 404 => Ok(false),
+            status => Err(MailgunError::MailgunError(format!("Mailgun refused to look up the template {}: {}", template, status))),
+        }
+    }
+
+    // The form posted to Mailgun's messages API.
+    pub fn form(&self, email: &EmailTemplate) -> Vec<(&'static str, String)> {
+        self.form_from(&self.from, email)
+    }
+
+    fn form_from(&self, from: &str, email: &EmailTemplate) -> Vec<(&'static str, String)> {
+        let mut params = vec![
+            ("from", String::from(from)),
+            ("to", email.recipient.clone()),
+            ("subject", email.subject.clone()),
+            ("template", email.template.clone()),
+            ("h:X-Autoreply", String::from("yes")),
         ];
-        let client = reqwest::Client::new();
-        let url = format!("https://api.mailgun.net/v3/{}/messages", self.domain);
-        client.post(&url)
-            .basic_auth("api", Some(&self.api_key))
-            .form(&params)
+        // Manual sends aren't necessarily a reply to anything.
+        if !email.in_reply_to.is_empty() {
+            params.push(("h:In-Reply-To", email.in_reply_to.clone()));
+        }
+        if !email.references.is_empty() {
+            params.push(("h:References", email.references.clone()));
+        }
+        let mut variables = email.variables.clone();
+        if let Some(footers) = &self.footers {
+            footers.add(email.route.as_ref().map(|route| &route[..]), &mut variables);
+        }
+        if !variables.is_empty() {
+            params.push(("h:X-Mailgun-Variables", serde_json::to_string(&variables).unwrap_or_default()));
+        }
+        let route = email.route.as_ref().map(|route| &route[..]);
+        if let Some(sender) = self.envelope_senders.as_ref().and_then(|senders| senders.for_reply(route?, &email.recipient)) {
+            params.push(("h:Sender", sender));
+        }
+        if let Some(key) = &email.idempotency_key {
+            params.push(("h:X-Limail-Idempotency-Key", key.clone()));
+        }
+        if let Some(list_unsubscribe) = &email.list_unsubscribe {
+            params.push(("h:List-Unsubscribe", list_unsubscribe.clone()));
+            params.push(("h:List-Unsubscribe-Post", String::from("List-Unsubscribe=One-Click")));
+        }
+        params
+    }
+
+    // Returns the Message-ID Mailgun assigned to the email.
+    pub fn send_email(&self, email: &EmailTemplate) -> Result<String, MailgunError> {
+        self.refuse_in_maintenance()?;
+        match &self.sandbox {
+            Some(sandbox) => {
+                sandbox.allows(&email.recipient)?;
+                self.send_checked(email).map_err(|e| sandbox.refusal(e))
+            },
+            None => self.send_checked(email),
+        }
+    }
+
+    fn send_checked(&self, email: &EmailTemplate) -> Result<String, MailgunError> {
+        if let Some(chaos) = &self.chaos {
+            chaos.inject().map_err(MailgunError::MailgunError)?;
+        }
+        if let Some(budget) = &self.budget {
+            budget.check()?;
+        }
+        let sent = self.send_paced(email)?;
+        if let Some(budget) = &self.budget {
+            budget.spend();
+        }
+        Ok(sent)
+    }
+
+    fn send_paced(&self, email: &EmailTemplate) -> Result<String, MailgunError> {
+        let _permit = match &self.pacing {
+            Some(pacing) => Some(pacing.acquire(&email.recipient)?),
+            None => None,
+        };
+        let domains = match &self.domains {
+            Some(domains) => domains,
+            None => return self.send_from(&self.domain, &self.from, email).map_err(|failure| match failure {
+                SendFailure::Throttled(message) => MailgunError::MailgunError(message),
+                SendFailure::Refused(e) => e,
+            }),
+        };
+        // Each failure takes a domain out of rotation, so this ends.
+        while let Some(domain) = domains.next() {
+            let from = domain.from.as_ref().unwrap_or(&self.from);
+            match self.send_from(&domain.domain, from, email) {
+                Ok(id) => {
+                    domains.record_sent(&domain.domain);
+                    return Ok(id);
+                },
+                Err(SendFailure::Throttled(message)) => domains.cool_down(&domain.domain, &message),
+                Err(SendFailure::Refused(e)) => return Err(e),
+            }
+        }
+        Err(MailgunError::MailgunError(String::from("No sending domain is available right now")))
+    }
+
+    fn send_from(&self, domain: &str, from: &str, email: &EmailTemplate) -> Result<String, SendFailure> {
+        let sent = self.post_message(domain, &self.form_from(from, email))?;
+        info!("Email autoresponder sent to: {} through {} ({})", email.recipient, domain, sent.id);
+        Ok(sent.id)
+    }
+
+    fn post_message<T: Serialize + ?Sized>(&self, domain: &str, params: &T) -> Result<MailgunSendResponse, SendFailure> {
+        let client = self.client().map_err(SendFailure::Refused)?;
+        let url = format!("{}/{}/messages", self.api_url.trim_end_matches('/'), domain);
+        let mut response = client.post(&url)
+            .basic_auth("api", Some(self.api_key.expose()))
+            .form(params)
             .send()
-            .map_err(|e| MailgunError::MailgunError(format!("Unable to make request: {}", e)))?;
-        info!("Email autoresponder sent to: {}", email.recipient);
-        Ok(())
+            .map_err(|e| SendFailure::Refused(MailgunError::MailgunError(self.api_key.redact(&format!("Unable to make request: {}", e)))))?;
+        let status = response.status();
+        if !status.is_success() {
+            let message = self.api_key.redact(&format!("Mailgun refused the email: {} {}", status, response.text().unwrap_or_default()));
+            return Err(if status.as_u16() == 429 || status.is_server_error() {
+                SendFailure::Throttled(message)
+            } else {
+                SendFailure::Refused(MailgunError::MailgunError(message))
+            });
+        }
+        response.json()
+            .map_err(|e| SendFailure::Refused(MailgunError::MailgunError(format!("Unexpected response from Mailgun: {}", e))))
+    }
+
+    // Through MAILGUN_DOMAIN only, and as limited as replies are.
+    pub fn forward_email(&self, forward: &ForwardedEmail) -> Result<String, MailgunError> {
+        self.refuse_in_maintenance()?;
+        if let Some(sandbox) = &self.sandbox {
+            sandbox.allows(&forward.to)?;
+        }
+        if let Some(chaos) = &self.chaos {
+            chaos.inject().map_err(MailgunError::MailgunError)?;
+        }
+        if let Some(budget) = &self.budget {
+            budget.check()?;
+        }
+        let _permit = match &self.pacing {
+            Some(pacing) => Some(pacing.acquire(&forward.to)?),
+            None => None,
+        };
+        let sent = self.post_message(&self.domain, &forward.form(&self.from)).map_err(|failure| match failure {
+            SendFailure::Throttled(message) => MailgunError::MailgunError(message),
+            SendFailure::Refused(e) => e,
+        })?;
+        if let Some(budget) = &self.budget {
+            budget.spend();
+        }
+        info!("Email forwarded to: {} ({})", forward.to, sent.id);
+        Ok(sent.id)
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const API_KEY: &str = "key-3ax6xnjp29jd6fds4gc373sgvjxteol0";
+    const TIMESTAMP: i64 = 1529006854;
+    const TOKEN: &str = "a8ce0edb2dd8301dee6c2405235584e45aa91d1e9f979f3de0";
+    const SIGNATURE: &str = "b63c0701c4f4b614f272106a1b367c5c3369bcdca664ae73ebd52787e45eef07";
+
+    #[test]
+    fn verifies_mailguns_signature() {
+        assert!(verify_signature(API_KEY, TIMESTAMP, TOKEN, SIGNATURE).is_ok());
+        assert!(verify_signature(API_KEY, TIMESTAMP + 1, TOKEN, SIGNATURE).is_err());
+        assert!(verify_signature(API_KEY, TIMESTAMP, &TOKEN[1..], SIGNATURE).is_err());
+        assert!(verify_signature("key-other", TIMESTAMP, TOKEN, SIGNATURE).is_err());
+        assert!(verify_signature(API_KEY, TIMESTAMP, TOKEN, &SIGNATURE[1..]).is_err());
+    }
+
+    #[test]
+    fn refuses_stale_timestamps() {
+        assert!(check_timestamp(TIMESTAMP, TIMESTAMP + SIGNATURE_MAX_AGE_SECONDS).is_ok());
+        assert!(check_timestamp(TIMESTAMP, TIMESTAMP - SIGNATURE_MAX_AGE_SECONDS).is_ok());
+        assert!(check_timestamp(TIMESTAMP, TIMESTAMP + SIGNATURE_MAX_AGE_SECONDS + 1).is_err());
+        assert!(check_timestamp(TIMESTAMP, TIMESTAMP - SIGNATURE_MAX_AGE_SECONDS - 1).is_err());
+    }
+}
@@ -19,370 +19,213 @@
 #![feature(box_patterns)]
 
 #[macro_use] extern crate log;
-extern crate chashmap;
 extern crate dotenv;
-extern crate futures;
-extern crate hex;
-extern crate hmac;
+extern crate limail;
 extern crate pretty_env_logger;
-extern crate reqwest;
-extern crate serde;
-extern crate serde_json;
-extern crate sha2;
 extern crate tokio;
 extern crate warp;
 
-
-mod slack;
-use slack::{Slack, SlackMessage};
-mod mailgun;
-use mailgun::{
-    EmailTemplate,
-    Mailgun,
-    MailgunEmailReceived,
-    MailgunError,
-};
-
 use std::env;
+use std::fs;
+use std::process;
 use std::string::String;
-use std::net::SocketAddr;
-use std::error::Error as StdError;
-use std::fmt::{self, Display};
-use std::str;
-use std::sync::Arc;
-
-
-use serde::Serialize;
+use std::thread;
+use std::time::Duration;
 
 use dotenv::dotenv;
 
-use chashmap::CHashMap;
-
-use futures::stream::{Stream};
-use crate::futures::Future;
-
-use chrono::{DateTime, Utc};
-
-use warp::{
-    path,
-    Filter,
-    Rejection,
-    http::{StatusCode},
-    filters::multipart::{self, FormData, Part},
-};
-
-fn env_or_panic(k: &str) -> String {
-    match env::var(k)  {
-        Ok(val) => val,
-        Err(msg) => panic!(format!("No {} in environment: {}", k, msg))
+use limail::cli;
+use limail::config::Config;
+use limail::domains::SendingDomains;
+use limail::digest;
+use limail::drift::{self, Watched};
+use limail::listener;
+use limail::mailgun::Mailgun;
+use limail::queue;
+use limail::ratelimit::{LastResponseLog, Minutes};
+use limail::server::{self, App};
+use limail::settings::Settings;
+use limail::slack::Slack;
+use limail::systemd;
+use limail::templatecheck;
+use limail::version::Version;
+use limail::weekly;
+
+fn hostname() -> Option<String> {
+    fs::read_to_string("/etc/hostname").ok().map(|h| String::from(h.trim()))
+}
+
+// A stable name lets a restarted worker pick up the jobs it was working on.
+fn worker_name() -> String {
+    env::var("LIMAIL_WORKER_NAME")
+        .ok()
+        .or_else(hostname)
+        .unwrap_or_else(|| String::from("limail"))
+}
+
+// Refuse to start (and so to tell systemd we're ready) with credentials
+// that don't work, rather than finding out on the first email.
+fn check_connectivity(config: &Config, mailgun: &Mailgun, slack: &Slack) {
+    if let Err(e) = mailgun.check() {
+        panic!("Mailgun check failed (set LIMAIL_SKIP_STARTUP_CHECK to skip): {}", e);
     }
+    if let Err(e) = slack.check() {
+        panic!("Slack check failed (set LIMAIL_SKIP_STARTUP_CHECK to skip): {}", e);
+    }
+    info!("Mailgun and Slack are reachable");
+    templatecheck::check(config, mailgun);
 }
 
-#[derive(Clone)]
-struct Minutes(pub i64);
-
-#[derive(Clone)]
-struct LastResponseLog {
-    time_between_responses: Minutes,
-    last_response_date: Arc<CHashMap<String, DateTime<Utc>>>,
-}
-
-impl LastResponseLog {
+fn main() {
+    dotenv().ok();
+    pretty_env_logger::init();
 
-    fn is_too_old(&self, dt: &DateTime<Utc>) -> bool {
-        (Utc::now() - (*dt)).num_minutes() > self.time_between_responses.0
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.get(0).map(|a| &a[..]) {
+        Some("backup") => return cli::backup(&args[1..]),
+        Some("render") => return cli::render(&args[1..]),
+        Some("rekey-archive") => return cli::rekey_archive(&args[1..]),
+        Some("restore") => return cli::restore(&args[1..]),
+        Some("simulate") => return cli::simulate(&args[1..]),
+        Some(command) => panic!("Unknown command {}, expected backup, render, rekey-archive, restore or simulate", command),
+        None => (),
     }
 
-
-    fn can_send(&self, email: &String) -> bool {
-        match self.last_response_date.get(email) {
-            Some(v) => self.is_too_old(&v),
-            None => true
+    let settings = Settings::from_env().unwrap_or_else(|problems| {
+        eprintln!("limail can't start, fix these first:");
+        for problem in &problems {
+            eprintln!("  - {}", problem);
         }
-    }
-
-    fn log_send(&self, email: &String) {
-        self.clear_old();
-        self.last_response_date.insert(email.clone(), Utc::now());
-    }
+        process::exit(1)
+    });
+    let config = &settings.config;
 
-    fn clear_old(&self) {
-        let orig_size = self.last_response_date.len();
-        self.last_response_date.retain(|_, v| !self.is_too_old(v));
-        let new_size = self.last_response_date.len();
-        info!("Cleared {} old entries from last_response_date", orig_size-new_size);
+    let clock = settings.clock.clone();
+    if env::var("LIMAIL_CLOCK").is_ok() {
+        warn!("LIMAIL_CLOCK is set, it's {} for the rate limiter, the scheduled reports, delayed replies, SLAs and retention", clock.now().to_rfc3339());
     }
-}
-
-fn main() {
-    dotenv().ok();
-    pretty_env_logger::init();
-
-    let last_response_date: CHashMap<String, DateTime<Utc>> = CHashMap::new();
-    let last_response_log = LastResponseLog {
-        time_between_responses: Minutes(
-            env_or_panic("TIME_BETWEEN_RESPONSES_MINUTES")
-                .parse()
-                .expect("TIME_BETWEEN_RESPONSES_MINUTES must be a i64")
-        ),
-        last_response_date: Arc::new(last_response_date),
-    };
-    let last_response_log = warp::any().map(move || last_response_log.clone());
+    let last_response_log = LastResponseLog::new(Minutes(settings.time_between_responses_minutes))
+        .with_clock(clock.clone());
 
     let mailgun = Mailgun {
-        api_key: env_or_panic("MAILGUN_API_KEY"),
-        domain: env_or_panic("MAILGUN_DOMAIN"),
-        from: env_or_panic("MAILGUN_FROM")
+        api_key: settings.mailgun_api_key.clone(),
+        api_url: settings.mailgun_api_url.clone(),
+        domain: settings.mailgun_domain.clone(),
+        from: settings.mailgun_from.clone(),
+        timeout: Duration::from_secs(config.deadlines.mailgun_seconds),
+        domains: config.sending_domains.clone().map(|domains| SendingDomains::new(domains).with_clock(clock.clone())),
+        budget: None,
+        chaos: None,
+        sandbox: None,
+        footers: None,
+        envelope_senders: None,
+        pacing: None,
+        maintenance: None,
     };
-    let mailgun = warp::any().map(move || mailgun.clone());
 
     let slack = Slack {
-        api_key: env_or_panic("SLACK_API_TOKEN")
+        api_key: settings.slack_api_token.clone(),
+        api_url: settings.slack_api_url.clone(),
+        timeout: Duration::from_secs(config.deadlines.slack_seconds),
+        chaos: None,
+        maintenance: None,
     };
-    let slack = warp::any().map(move || slack.clone());
 
-    let basics = warp::post2()
-        .and(warp::body::content_length_limit(1024 * 1024 * 2)) // 2 MB right?
-        .and(mailgun.clone());
-
-    let no_reply_urlencoded = basics.clone()
-        .and(last_response_log.clone())
-        .and(path!("emails" / "responder" / String))
-        .and(warp::body::form())
-        .and_then(send_no_reply_template)
-        .recover(recover_error);
-
-    let no_reply_multipart = basics.clone()
-        .and(last_response_log.clone())
-        .and(path!("emails" / "responder" / String))
-        .and(multipart::form())
-        .and_then(send_no_reply_template_multipart)
-        .recover(recover_error);
-
-    let forward_email = basics.clone()
-        .and(slack.clone())
-        .and(path!("emails" / "forward" / "slack" / String))
-        .and(warp::body::form())
-        .and_then(forward_email_to_slack)
-        .recover(recover_error);
-
-    let forward_email_multipart = basics.clone()
-        .and(slack.clone())
-        .and(path!("emails" / "forward" / "slack" / String))
-        .and(multipart::form())
-        .and_then(forward_email_to_slack_multipart)
-        .recover(recover_error);
-
-    let socket_address: SocketAddr = env_or_panic("LISTEN_ADDRESS_PORT").parse()
-        .expect("LISTEN_ADDRESS_PORT must be a valid SocketAddr");
-
-    warp::serve(
-        no_reply_urlencoded
-        .or(no_reply_multipart)
-        .or(forward_email)
-        .or(forward_email_multipart)
-    ).run(socket_address);
-
-}
-
-
-#[derive(Serialize)]
-struct LimailErrorMessage {
-    code: u16,
-    message: String,
-}
-
-pub fn recover_error(err: Rejection) -> Result<impl warp::Reply, Rejection> {
-    if let Some(err) = err.find_cause::<MailgunError>() {
-        let (code, msg) = match err {
-            MailgunError::JsonError(s) => (StatusCode::BAD_REQUEST, s),
-            MailgunError::HmacError(s) => (StatusCode::BAD_REQUEST, s),
-            MailgunError::MailgunError(s) => (StatusCode::INTERNAL_SERVER_ERROR, s),
-        };
-
-        let json = warp::reply::json(&LimailErrorMessage {
-            code: code.as_u16(),
-            message: msg.clone(),
-        });
-        Ok(warp::reply::with_status(json, code))
-    } else {
-        // Could be a NOT_FOUND, or any other internal error... here we just
-        // let warp use its default rendering.
-        Err(err)
+    if !settings.skip_startup_check {
+        check_connectivity(config, &mailgun, &slack);
     }
-}
 
-#[derive(Debug)]
-pub enum MultipartError {
-    MissingFields(),
-}
+    let app = App::new(config, mailgun, slack, last_response_log, &settings.data_dir, &clock);
 
-impl StdError for MultipartError {}
-impl Display for MultipartError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.write_str(match self {
-            MultipartError::MissingFields() => "MultipartError::MissingFields",
-        })
-    }
-}
-impl std::convert::From<MultipartError> for Rejection {
-    fn from(err: MultipartError) -> Rejection {
-        warp::reject::custom(err)
-    }
-}
+    app.pipeline.notices.started(
+        &Version::current(),
+        &hostname().unwrap_or_else(|| String::from("unknown host")),
+        &settings.mode,
+    );
 
-
-fn part_to_string(part: Part) -> Option<String> {
-    let bytes = part.concat2().wait().ok()?;
-    match str::from_utf8(&bytes) {
-        Ok(s) => Some(String::from(s)),
-        Err(_) => None
+    if let Some(handling) = &app.pipeline.handling {
+        handling.start(app.pipeline.slack.clone(), app.pipeline.maintenance.clone());
     }
-}
 
-// Gross - Surely there is some way to do this that's easier . :(
-fn multipart_to_mailgun(form_data: FormData) -> Result<MailgunEmailReceived, MultipartError> {
-    let mut sender: Option<String> = None;
-    let mut from: Option<String> = None;
-    let mut subject: Option<String> = None;
-    let mut body_plain: Option<String> = None;
-    let mut timestamp: Option<i64> = None;
-    let mut token: Option<String> = None;
-    let mut signature: Option<String> = None;
-    let mut message_headers: Option<String> = None;
-    form_data.wait().for_each(|part| {
-        match part {
-            Ok(part) => {
-                let name = String::from(part.name());
-                match (&name[..], part_to_string(part)) {
-                    ("sender", val) => sender = val,
-                    ("from", val) => from = val,
-                    ("subject", val) => subject = val,
-                    ("body-plain", val) => body_plain = val,
-                    ("timestamp", Some(val)) => timestamp = val.parse().ok(),
-                    ("token", val) => token = val,
-                    ("signature", val) => signature = val,
-                    ("message-headers", val) => message_headers = val,
-                    _ => ()
-                }
-            },
-            _ => return
+    // all: handle webhooks and deliver, through the queue if there is one.
+    // frontend: only verify and queue webhooks. worker: only deliver queued jobs.
+    let mode = &settings.mode;
+    if let (Some(report), false) = (&config.weekly_report, mode == "worker") {
+        let pipeline = &app.pipeline;
+        weekly::start(
+            report.clone(),
+            pipeline.archive.clone(),
+            pipeline.outbox.clone(),
+            pipeline.slack.clone(),
+            pipeline.maintenance.clone(),
+            clock.clone(),
+        );
+    }
+    if mode != "worker" {
+        for digest in &config.digests {
+            let pipeline = &app.pipeline;
+            digest::start(
+                digest.clone(),
+                pipeline.archive.clone(),
+                pipeline.mailgun.clone(),
+                pipeline.links.clone(),
+                pipeline.maintenance.clone(),
+                clock.clone(),
+            );
         }
-    });
-    match (sender, from, subject, body_plain, timestamp, token, signature, message_headers) {
-        (Some(sender), Some(from), Some(subject), Some(body_plain),
-         Some(timestamp), Some(token), Some(signature), Some(message_headers)) => Ok(MailgunEmailReceived {
-            sender,
-            from,
-            subject,
-            body_plain,
-            timestamp,
-            token,
-            signature,
-            message_headers,
-        }),
-        _ => Err(MultipartError::MissingFields())
     }
-}
-
-fn send_no_reply_template_multipart(
-    mailgun: Mailgun,
-    last_response_log: LastResponseLog,
-    template: String,
-    form_data: FormData
-) -> Result<impl warp::Reply, Rejection>
-{
-    let mailgun_received = multipart_to_mailgun(form_data)?;
-    send_no_reply_template(mailgun, last_response_log, template, mailgun_received)
-}
-
-
-fn send_no_reply_template(
-    mailgun: Mailgun,
-    last_response_log: LastResponseLog,
-    template: String,
-    email: MailgunEmailReceived
-) -> Result<impl warp::Reply, Rejection>
-{
-    mailgun.verify_hmac(&email)?;
-    let message_id = email.get_message_id()?;
-    if last_response_log.can_send(&email.from) {
-        last_response_log.log_send(&email.from);
-        mailgun.send_email(&EmailTemplate {
-            recipient: email.from,
-            subject: format!("Re: {}", email.subject),
-            template: template,
-            in_reply_to: message_id.clone(),
-            references: message_id
-
-        })?;
-    } else {
-        info!(
-            "Already responded to {} within the past {} minutes. Skipping.",
-            email.from,
-            last_response_log.time_between_responses.0
+    if let (Some(drift), false) = (&config.drift, mode == "worker") {
+        let pipeline = &app.pipeline;
+        let watched = Watched::from_config(&config, drift, &pipeline.mailgun);
+        drift::start(
+            drift.clone(),
+            watched,
+            pipeline.mailgun.clone(),
+            pipeline.slack.clone(),
+            pipeline.notices.clone(),
+            pipeline.maintenance.clone(),
         );
     }
-    Ok("Message Processed")
-}
-
-fn forward_email_to_slack_multipart(
-    mailgun: Mailgun,
-    slack_client: Slack,
-    channel_id: String,
-    form_data: FormData,
-) ->  Result<impl warp::Reply, Rejection> {
-    let mailgun_received = multipart_to_mailgun(form_data)?;
-    forward_email_to_slack(mailgun, slack_client, channel_id, mailgun_received)
-}
-
-fn unify_new_lines(value: &String) -> String {
-    let mut count = 0;
-    value.split("\n")
-        .map(|s| s.trim())
-        .filter(|s| {
-            if s.len() == 0 {
-                count += 1;
-            } else {
-                count = 0;
-            }
-            s.len() > 0 || count <= 1
-        })
-        .collect::<Vec<&str>>()
-        .join("\n")
-}
+    if mode != "frontend" {
+        app.pipeline.resume_delayed();
+    }
+    if let (Some(early_ack), None, false) = (&app.early_ack, &app.queue, mode == "worker") {
+        early_ack.resume(&app.pipeline, &app.pipeline.archive);
+    }
+    match (&mode[..], &app.queue) {
+        ("all", Some(queue)) => {
+            let (queue, pipeline) = (queue.clone(), app.pipeline.clone());
+            thread::spawn(move || queue::run_worker(queue, pipeline, worker_name()));
+        },
+        ("all", None) => (),
+        ("frontend", Some(_)) => (),
+        ("worker", Some(queue)) => {
+            systemd::notify("READY=1");
+            queue::run_worker(queue.clone(), app.pipeline.clone(), worker_name());
+            return;
+        },
+        ("frontend", None) | ("worker", None) => {
+            panic!("LIMAIL_MODE={} needs a [queue] section in LIMAIL_CONFIG", mode)
+        },
+        _ => panic!("Unknown LIMAIL_MODE {}, expected all, frontend or worker", mode),
+    }
 
-fn forward_email_to_slack(
-    mailgun: Mailgun,
-    slack_client: Slack,
-    channel_id: String,
-    email: MailgunEmailReceived
-) ->  Result<impl warp::Reply, Rejection> {
-    mailgun.verify_hmac(&email)?;
+    let listener = systemd::listener().unwrap_or_else(|| {
+        let socket_address = settings.listen_address
+            .expect("LISTEN_FDS is set but systemd didn't pass a socket, set LISTEN_ADDRESS_PORT");
+        listener::bind(socket_address, settings.reuse_port, settings.v6_only)
+            .unwrap_or_else(|e| panic!("Unable to listen on {}: {}", socket_address, e))
+    });
+    listener.set_nonblocking(true).expect("Unable to make the listening socket non-blocking");
+    let listener = tokio::net::TcpListener::from_std(listener, &tokio::reactor::Handle::default())
+        .expect("Unable to register the listening socket");
+    info!("Listening on {}", listener.local_addr().map(|a| a.to_string()).unwrap_or_default());
+    systemd::notify("READY=1");
 
-    let text = format!("Email Received: {}", email.subject.clone());
-    slack_client
-        .send_message(&SlackMessage{ 
-            channel: channel_id.clone(),
-            text: text.clone(),
-            thread_ts: None,
-            as_user: true
-        })
-        .and_then(|msg_response| {
-            let slack_message = format!(
-                "```{}```\n(from: {})",
-                unify_new_lines(&email.body_plain),
-                email.sender.clone()
-            );
-            slack_client
-                .send_message(&SlackMessage{ 
-                    channel: channel_id.clone(),
-                    text: slack_message.clone(),
-                    thread_ts: Some(msg_response.ts.clone()),
-                    as_user: true
-                })
-        })?;
-    Ok(String::from("Sent"))
+    let drain_timeout = Duration::from_secs(settings.drain_seconds);
+    let incoming = listener::drain_on_signal(listener.incoming(), drain_timeout);
 
+    let metrics = app.pipeline.metrics.clone();
+    listener::serve(server::routes(app), incoming, &config.http, metrics);
+    info!("Drained, exiting");
 }
-
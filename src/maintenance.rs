@@ -0,0 +1,176 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+
+use crate::clock::Clock;
+use crate::store::{self, StoreError};
+
+const SHARED_KEY: &str = "limail:maintenance";
+const POLL_SECONDS: u64 = 5;
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct MaintenanceState {
+    pub enabled: bool,
+    pub since: Option<DateTime<Utc>>,
+    pub by: Option<String>,
+    // Jobs accepted inline while enabled, delivered once it is lifted. With
+    // a queue they simply wait there instead.
+    #[serde(default)]
+    pub held: Vec<String>,
+}
+
+// While enabled, webhooks are still verified and archived (or queued), but
+// nothing is sent to Mailgun or Slack until it is lifted again: their
+// clients refuse to, see Maintenance::refusal. Kept in
+// maintenance.json, or in redis when workers share a queue so all of them
+// pause.
+#[derive(Clone)]
+pub struct Maintenance {
+    path: PathBuf,
+    state: Arc<Mutex<MaintenanceState>>,
+    shared: Option<redis::Client>,
+    // Kept between checks, is_enabled being called before everything sent.
+    connection: Arc<Mutex<Option<redis::Connection>>>,
+    clock: Clock,
+}
+
+impl Maintenance {
+    pub fn load(path: PathBuf) -> Result<Maintenance, StoreError> {
+        let state: MaintenanceState = store::read_json(&path)?.unwrap_or_default();
+        Ok(Maintenance {
+            path,
+            state: Arc::new(Mutex::new(state)),
+            shared: None,
+            connection: Arc::new(Mutex::new(None)),
+            clock: Clock::system(),
+        })
+    }
+
+    pub fn shared(self, client: redis::Client) -> Maintenance {
+        Maintenance {
+            shared: Some(client),
+            ..self
+        }
+    }
+
+    pub fn with_clock(self, clock: Clock) -> Maintenance {
+        Maintenance {
+            clock,
+            ..self
+        }
+    }
+
+    // Connects again after an error.
+    fn query<T: redis::FromRedisValue>(&self, client: &redis::Client, cmd: &redis::Cmd) -> redis::RedisResult<T> {
+        let mut connection = self.connection.lock().unwrap();
+        let result = match &mut *connection {
+            Some(connection) => cmd.query(connection),
+            None => client.get_connection().and_then(|mut connected| {
+                let result = cmd.query(&mut connected);
+                *connection = Some(connected);
+                result
+            }),
+        };
+        if result.is_err() {
+            *connection = None;
+        }
+        result
+    }
+
+    fn shared_state(&self) -> Option<MaintenanceState> {
+        let client = self.shared.as_ref()?;
+        let state: redis::RedisResult<Option<String>> = self.query(client, redis::cmd("GET").arg(SHARED_KEY));
+        match state {
+            Ok(Some(state)) => serde_json::from_str(&state).ok(),
+            Ok(None) => Some(MaintenanceState::default()),
+            Err(e) => {
+                error!("Unable to reach the shared maintenance flag, falling back to disk: {}", e);
+                None
+            },
+        }
+    }
+
+    pub fn state(&self) -> MaintenanceState {
+        let local = self.state.lock().unwrap().clone();
+        match self.shared_state() {
+            Some(shared) => MaintenanceState {
+                held: local.held,
+                ..shared
+            },
+            None => local,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.state().enabled
+    }
+
+    // For what's sent in the background to wait for. Returns whether it
+    // had to.
+    pub fn wait_until_lifted(&self) -> bool {
+        if !self.is_enabled() {
+            return false;
+        }
+        while self.is_enabled() {
+            thread::sleep(Duration::from_secs(POLL_SECONDS));
+        }
+        true
+    }
+
+    // Why Mailgun and Slack won't send anything right now, if they won't.
+    pub fn refusal(&self) -> Option<String> {
+        if self.is_enabled() {
+            Some(String::from("limail is in maintenance, nothing is being sent"))
+        } else {
+            None
+        }
+    }
+
+    // Returns false if it was already enabled.
+    pub fn enable(&self, by: &str) -> Result<bool, StoreError> {
+        if self.is_enabled() {
+            return Ok(false);
+        }
+        let mut state = self.state.lock().unwrap();
+        state.enabled = true;
+        state.since = Some(self.clock.now());
+        state.by = Some(String::from(by));
+        self.save(&state)?;
+        Ok(true)
+    }
+
+    // Returns the ids of the jobs held back in the meantime.
+    pub fn disable(&self) -> Result<Vec<String>, StoreError> {
+        let mut state = self.state.lock().unwrap();
+        let held = state.held.split_off(0);
+        *state = MaintenanceState::default();
+        self.save(&state)?;
+        Ok(held)
+    }
+
+    pub fn hold(&self, id: &str) -> Result<(), StoreError> {
+        let mut state = self.state.lock().unwrap();
+        if !state.held.iter().any(|held| held == id) {
+            state.held.push(String::from(id));
+        }
+        store::write_json(&self.path, &*state)
+    }
+
+    fn save(&self, state: &MaintenanceState) -> Result<(), StoreError> {
+        if let Some(client) = &self.shared {
+            let shared = serde_json::to_string(&MaintenanceState {
+                held: Vec::new(),
+                ..state.clone()
+            })?;
+            let saved: redis::RedisResult<()> = self.query(client, redis::cmd("SET").arg(SHARED_KEY).arg(shared));
+            if let Err(e) = saved {
+                return Err(StoreError::IoError(format!("Unable to change the shared maintenance flag: {}", e)));
+            }
+        }
+        store::write_json(&self.path, state)
+    }
+}
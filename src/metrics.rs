@@ -0,0 +1,126 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+use crate::pipeline::route_matches;
+
+// How many processed emails the dashboard keeps around.
+const RECENT_EMAIL_LIMIT: usize = 50;
+
+const OTHER: &str = "other";
+
+// The label values (exact, or a prefix ending in *) counters are broken
+// down by. Anything else is counted as "other", so spam to made up routes
+// can't add a counter per path.
+#[derive(Deserialize, Clone, Default)]
+pub struct MetricsConfig {
+    #[serde(default)]
+    pub routes: Vec<String>,
+    #[serde(default)]
+    pub templates: Vec<String>,
+    #[serde(default)]
+    pub channels: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct RecentEmail {
+    pub received_at: DateTime<Utc>,
+    pub route: String,
+    pub from: String,
+    pub subject: String,
+    pub outcome: String,
+}
+
+#[derive(Clone, Default)]
+pub struct Metrics {
+    counters: Arc<Mutex<BTreeMap<String, u64>>>,
+    recent_emails: Arc<Mutex<VecDeque<RecentEmail>>>,
+    labels: Arc<MetricsConfig>,
+}
+
+impl Metrics {
+    pub fn new(config: MetricsConfig) -> Metrics {
+        Metrics {
+            labels: Arc::new(config),
+            ..Metrics::default()
+        }
+    }
+
+    pub fn incr(&self, name: &str) {
+        self.add(name, 1);
+    }
+
+    // For totals, like bytes received.
+    pub fn add(&self, name: &str, amount: u64) {
+        let mut counters = self.counters.lock().unwrap();
+        *counters.entry(String::from(name)).or_insert(0) += amount;
+    }
+
+    // Counted as name{label="value",…}. Route, template and channel values
+    // are kept only if [metrics] allows them, other labels must only ever
+    // take a handful of values.
+    pub fn incr_labeled(&self, name: &str, labels: &[(&str, &str)]) {
+        self.add_labeled(name, labels, 1);
+    }
+
+    pub fn add_labeled(&self, name: &str, labels: &[(&str, &str)], amount: u64) {
+        let labels: Vec<String> = labels.iter()
+            .map(|&(label, value)| {
+                let allowed = match label {
+                    "route" => Some(&self.labels.routes),
+                    "template" => Some(&self.labels.templates),
+                    "channel" => Some(&self.labels.channels),
+                    _ => None,
+                };
+                let value = match allowed {
+                    Some(allowed) if !allowed.iter().any(|pattern| route_matches(pattern, value)) => OTHER,
+                    _ => value,
+                };
+                format!("{}=\"{}\"", label, value.replace('\\', "\\\\").replace('"', "\\\""))
+            })
+            .collect();
+        self.add(&format!("{}{{{}}}", name, labels.join(",")), amount);
+    }
+
+    // How big the webhooks for route are, and what they carry, to tune the
+    // body limit by. Sizes are bucketed so the counters stay few.
+    pub fn record_payload(&self, route: &str, bytes: usize, attachment_sizes: &[usize]) {
+        let size = match bytes {
+            0..=102_400 => "<=100K",
+            102_401..=524_288 => "<=500K",
+            524_289..=1_048_576 => "<=1M",
+            _ => ">1M",
+        };
+        self.incr_labeled("payloads", &[("route", route), ("size", size)]);
+        self.add_labeled("payload_bytes", &[("route", route)], bytes as u64);
+        self.add_labeled("attachments", &[("route", route)], attachment_sizes.len() as u64);
+        self.add_labeled("attachment_bytes", &[("route", route)], attachment_sizes.iter().sum::<usize>() as u64);
+        self.add("payload_bytes", bytes as u64);
+        self.add("attachment_bytes", attachment_sizes.iter().sum::<usize>() as u64);
+    }
+
+    pub fn counters(&self) -> BTreeMap<String, u64> {
+        self.counters.lock().unwrap().clone()
+    }
+
+    pub fn record_email(&self, route: &str, from: &str, subject: &str, outcome: &str) {
+        let mut recent_emails = self.recent_emails.lock().unwrap();
+        if recent_emails.len() >= RECENT_EMAIL_LIMIT {
+            recent_emails.pop_back();
+        }
+        recent_emails.push_front(RecentEmail {
+            received_at: Utc::now(),
+            route: String::from(route),
+            from: String::from(from),
+            subject: String::from(subject),
+            outcome: String::from(outcome),
+        });
+    }
+
+    // Newest first.
+    pub fn recent_emails(&self) -> Vec<RecentEmail> {
+        self.recent_emails.lock().unwrap().iter().cloned().collect()
+    }
+}
@@ -0,0 +1,225 @@
+use std::error::Error as StdError;
+use std::fmt::{self, Display};
+use std::str;
+
+use warp::Rejection;
+
+use crate::mailgun::MailgunEmailReceived;
+
+// RFC 2046 boundaries are at most 70 characters, which also keeps the
+// search for them linear in the size of the body.
+const MAX_BOUNDARY: usize = 70;
+const MAX_PARTS: usize = 1000;
+
+#[derive(Debug)]
+pub enum MultipartError {
+    MissingFields(),
+    Malformed(String),
+    // And the id of the capture of the body, see captures.rs.
+    Captured(Box<MultipartError>, String),
+}
+
+impl StdError for MultipartError {}
+impl Display for MultipartError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MultipartError::MissingFields() => f.write_str("MultipartError::MissingFields"),
+            MultipartError::Malformed(s) => write!(f, "MultipartError::Malformed: {}", s),
+            MultipartError::Captured(e, id) => write!(f, "{} (body captured as {})", e, id),
+        }
+    }
+}
+impl std::convert::From<MultipartError> for Rejection {
+    fn from(err: MultipartError) -> Rejection {
+        warp::reject::custom(err)
+    }
+}
+
+fn malformed(message: &str) -> MultipartError {
+    MultipartError::Malformed(String::from(message))
+}
+
+#[derive(Debug, Clone)]
+pub struct Part {
+    pub name: String,
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    pub data: Vec<u8>,
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    if needle.is_empty() || needle.len() > haystack.len() {
+        return None;
+    }
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+// The boundary parameter of a multipart/form-data Content-Type.
+pub fn boundary_from_content_type(content_type: &str) -> Option<String> {
+    let mut params = content_type.split(';');
+    if !params.next()?.trim().eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    params
+        .filter_map(|param| {
+            let mut pair = param.splitn(2, '=');
+            match (pair.next(), pair.next()) {
+                (Some(key), Some(value)) if key.trim().eq_ignore_ascii_case("boundary") => {
+                    Some(String::from(value.trim().trim_matches('"')))
+                },
+                _ => None,
+            }
+        })
+        .next()
+}
+
+// `name="value"` out of a Content-Disposition header.
+fn disposition_param(disposition: &str, param: &str) -> Option<String> {
+    disposition.split(';').skip(1).filter_map(|p| {
+        let mut pair = p.splitn(2, '=');
+        match (pair.next(), pair.next()) {
+            (Some(key), Some(value)) if key.trim().eq_ignore_ascii_case(param) => {
+                Some(String::from(value.trim().trim_matches('"')))
+            },
+            _ => None,
+        }
+    }).next()
+}
+
+fn parse_part(raw: &[u8]) -> Result<Part, MultipartError> {
+    let (head, data) = match find(raw, b"\r\n\r\n") {
+        Some(end) => (&raw[..end], &raw[end + 4..]),
+        None => return Err(malformed("part without a blank line after its headers")),
+    };
+    let head = str::from_utf8(head).map_err(|_| malformed("part headers aren't UTF-8"))?;
+    let mut name = None;
+    let mut filename = None;
+    let mut content_type = None;
+    for line in head.split("\r\n") {
+        let mut header = line.splitn(2, ':');
+        match (header.next(), header.next()) {
+            (Some(key), Some(value)) if key.trim().eq_ignore_ascii_case("content-disposition") => {
+                name = disposition_param(value, "name");
+                filename = disposition_param(value, "filename");
+            },
+            (Some(key), Some(value)) if key.trim().eq_ignore_ascii_case("content-type") => {
+                content_type = Some(String::from(value.trim()));
+            },
+            _ => (),
+        }
+    }
+    Ok(Part {
+        name: name.ok_or_else(|| malformed("part without a name"))?,
+        filename,
+        content_type,
+        data: data.to_vec(),
+    })
+}
+
+pub fn parse_parts(body: &[u8], boundary: &str) -> Result<Vec<Part>, MultipartError> {
+    if boundary.is_empty() || boundary.len() > MAX_BOUNDARY {
+        return Err(malformed("boundary must be 1 to 70 characters"));
+    }
+    let delimiter = format!("--{}", boundary).into_bytes();
+    let separator = format!("\r\n--{}", boundary).into_bytes();
+
+    let mut rest = match find(body, &delimiter) {
+        Some(start) => &body[start + delimiter.len()..],
+        None => return Err(malformed("no boundary in the body")),
+    };
+    let mut parts = Vec::new();
+    loop {
+        if rest.starts_with(b"--") {
+            return Ok(parts);
+        }
+        if !rest.starts_with(b"\r\n") {
+            return Err(malformed("boundary not followed by a line break"));
+        }
+        rest = &rest[2..];
+        let end = find(rest, &separator).ok_or_else(|| malformed("unterminated part"))?;
+        parts.push(parse_part(&rest[..end])?);
+        if parts.len() > MAX_PARTS {
+            return Err(malformed("too many parts"));
+        }
+        rest = &rest[end + separator.len()..];
+    }
+}
+
+// The fields of a Mailgun inbound webhook, pulled out of its multipart body.
+// A pure function of the bytes (the boundary is taken from the first line)
+// so it can be fuzzed, see fuzz/.
+pub fn parse_inbound_multipart(body: &[u8]) -> Result<MailgunEmailReceived, MultipartError> {
+    let first_line = match find(body, b"\r\n") {
+        Some(end) => &body[..end],
+        None => return Err(malformed("no boundary line")),
+    };
+    if !first_line.starts_with(b"--") {
+        return Err(malformed("body doesn't start with a boundary"));
+    }
+    let boundary = str::from_utf8(&first_line[2..]).map_err(|_| malformed("boundary isn't UTF-8"))?;
+    parse_inbound_multipart_with_boundary(body, boundary)
+}
+
+pub fn parse_inbound_multipart_with_boundary(
+    body: &[u8],
+    boundary: &str,
+) -> Result<MailgunEmailReceived, MultipartError> {
+    email_from_parts(&parse_parts(body, boundary)?)
+}
+
+// The raw email, when Mailgun posts it as body-mime, for checking
+// signatures over it.
+pub fn raw_mime(parts: &[Part]) -> Option<Vec<u8>> {
+    parts.iter().find(|part| part.filename.is_none() && part.name == "body-mime").map(|part| part.data.clone())
+}
+
+// Parts with a filename, which Mailgun sends as attachment-1, attachment-2...
+pub fn attachments(parts: Vec<Part>) -> Vec<Part> {
+    parts.into_iter().filter(|part| part.filename.is_some()).collect()
+}
+
+pub fn email_from_parts(parts: &[Part]) -> Result<MailgunEmailReceived, MultipartError> {
+    let mut sender: Option<String> = None;
+    let mut from: Option<String> = None;
+    let mut subject: Option<String> = None;
+    let mut body_plain: Option<String> = None;
+    let mut body_html: Option<String> = None;
+    let mut timestamp: Option<i64> = None;
+    let mut token: Option<String> = None;
+    let mut signature: Option<String> = None;
+    let mut message_headers: Option<String> = None;
+    for part in parts {
+        // Attachments aren't part of the email we act on.
+        if part.filename.is_some() {
+            continue;
+        }
+        let value = String::from_utf8(part.data.clone()).ok();
+        match (&part.name[..], value) {
+            ("sender", val) => sender = val,
+            ("from", val) => from = val,
+            ("subject", val) => subject = val,
+            ("body-plain", val) => body_plain = val,
+            ("body-html", val) => body_html = val,
+            ("timestamp", Some(val)) => timestamp = val.parse().ok(),
+            ("token", val) => token = val,
+            ("signature", val) => signature = val,
+            ("message-headers", val) => message_headers = val,
+            _ => ()
+        }
+    }
+    match (sender, from, subject, body_plain, timestamp, token, signature, message_headers) {
+        (Some(sender), Some(from), Some(subject), Some(body_plain),
+         Some(timestamp), Some(token), Some(signature), Some(message_headers)) => Ok(MailgunEmailReceived {
+            sender,
+            from,
+            subject,
+            body_plain,
+            body_html,
+            timestamp,
+            token,
+            signature,
+            message_headers,
+        }),
+        _ => Err(MultipartError::MissingFields())
+    }
+}
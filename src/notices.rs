@@ -0,0 +1,207 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use chrono::{DateTime, Duration, Utc};
+use handlebars::Handlebars;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::clock::Clock;
+use crate::slack::{Slack, SlackMessage};
+use crate::version::Version;
+
+const EMAIL: &str = "{{#if threats}}:warning: *Suspicious:* {{threats}}\n{{/if}}\
+    Email Received: {{subject}}\n{{auth}}\
+    {{#if recipients}}\n{{recipients}}{{/if}}\
+    {{#if reply_to}}\nReply-To: {{reply_to}} (not From){{/if}}\
+    {{#if smime}}\n{{smime}}{{/if}}\
+    {{#if sender}}\n{{sender}}{{/if}}\
+    {{#if link}}\n<{{link}}|View the full email>{{/if}}";
+const ALERT_FIRED: &str = ":rotating_light: {{name}}: {{count}} {{outcome}} emails in the last {{window_minutes}} minutes";
+const ALERT_RESOLVED: &str = ":white_check_mark: {{name}} resolved";
+const SUPPRESSED: &str = ":mute: Didn't reply to {{from}} ({{subject}}) on {{route}}: {{reason}}";
+const STARTED: &str = ":rocket: limail v{{version}} ({{commit}}) started on {{host}} ({{mode}})";
+const SIGNATURE_FAILURE: &str = ":warning: Rejected a webhook for {{route}} with a bad signature: {{error}}";
+const DRIFT: &str = ":warning: {{problem}}, emails that need it will fail until it's fixed";
+const DRIFT_RESOLVED: &str = ":white_check_mark: Fixed: {{problem}}";
+const MAIL_LOOP: &str = ":repeat: Stopped auto-replying to {{correspondent}} on {{route}}: {{reason}}";
+
+// However many bad signatures arrive, one notice per route per this long.
+const SIGNATURE_FAILURE_QUIET_MINUTES: i64 = 10;
+
+// Handlebars templates for what limail posts to Slack, one per kind of
+// event, each falling back to the built in text when missing. Suppression,
+// signature failure and startup notices are only posted once they have a
+// channel.
+#[derive(Deserialize, Clone, Default)]
+pub struct NoticeConfig {
+    // The first message of a forwarded email: subject, from, route, auth,
+    // smime, sender, link, to, cc, recipients (to and cc, when it went to
+    // more than one address), reply_to (when it isn't from) and threats
+    // (what [threat_intel] found).
+    #[serde(default)]
+    pub email: Option<String>,
+    // An alert rule firing (name, count, outcome, window_minutes) or
+    // resolving (name).
+    #[serde(default)]
+    pub alert_fired: Option<String>,
+    #[serde(default)]
+    pub alert_resolved: Option<String>,
+    // A reply that wasn't sent: from, subject, route and reason.
+    #[serde(default)]
+    pub suppressed: Option<String>,
+    #[serde(default)]
+    pub suppressed_channel: Option<String>,
+    // A webhook rejected for its signature: route and error.
+    #[serde(default)]
+    pub signature_failure: Option<String>,
+    #[serde(default)]
+    pub signature_failure_channel: Option<String>,
+    // Each time limail starts, once Mailgun and Slack are known to work:
+    // version, commit, built_at, features, host and mode.
+    #[serde(default)]
+    pub started: Option<String>,
+    #[serde(default)]
+    pub started_channel: Option<String>,
+    // Something [drift] found wrong with what the config relies on, or found
+    // fixed: problem.
+    #[serde(default)]
+    pub drift: Option<String>,
+    #[serde(default)]
+    pub drift_resolved: Option<String>,
+    // A mail loop [loops] broke, posted to its channel: correspondent,
+    // route and reason.
+    #[serde(default)]
+    pub mail_loop: Option<String>,
+}
+
+fn compile(config: &NoticeConfig) -> Result<Handlebars, String> {
+    let mut templates = Handlebars::new();
+    // Slack isn't HTML, &lt; would show up as is.
+    templates.register_escape_fn(handlebars::no_escape);
+    let kinds = [
+        ("email", &config.email, EMAIL),
+        ("alert_fired", &config.alert_fired, ALERT_FIRED),
+        ("alert_resolved", &config.alert_resolved, ALERT_RESOLVED),
+        ("suppressed", &config.suppressed, SUPPRESSED),
+        ("signature_failure", &config.signature_failure, SIGNATURE_FAILURE),
+        ("started", &config.started, STARTED),
+        ("drift", &config.drift, DRIFT),
+        ("drift_resolved", &config.drift_resolved, DRIFT_RESOLVED),
+        ("mail_loop", &config.mail_loop, MAIL_LOOP),
+    ];
+    for &(kind, template, default) in kinds.iter() {
+        templates.register_template_string(kind, template.as_ref().map(|t| &t[..]).unwrap_or(default))
+            .map_err(|e| format!("Invalid [notices] {} template: {}", kind, e))?;
+    }
+    Ok(templates)
+}
+
+// For the startup checks, without a Slack client.
+pub fn check(config: &NoticeConfig) -> Result<(), String> {
+    compile(config).map(|_| ())
+}
+
+#[derive(Clone)]
+pub struct Notices {
+    templates: Arc<Handlebars>,
+    suppressed_channel: Option<String>,
+    signature_failure_channel: Option<String>,
+    started_channel: Option<String>,
+    slack: Slack,
+    signature_failures: Arc<Mutex<BTreeMap<String, DateTime<Utc>>>>,
+    clock: Clock,
+}
+
+impl Notices {
+    pub fn new(config: &NoticeConfig, slack: Slack) -> Result<Notices, String> {
+        let templates = compile(config)?;
+        Ok(Notices {
+            templates: Arc::new(templates),
+            suppressed_channel: config.suppressed_channel.clone(),
+            signature_failure_channel: config.signature_failure_channel.clone(),
+            started_channel: config.started_channel.clone(),
+            slack,
+            signature_failures: Arc::new(Mutex::new(BTreeMap::new())),
+            clock: Clock::system(),
+        })
+    }
+
+    pub fn with_clock(self, clock: Clock) -> Notices {
+        Notices {
+            clock,
+            ..self
+        }
+    }
+
+    // Only fails on a template that uses a helper wrongly, and then the
+    // notice goes out as the raw values rather than not at all.
+    pub fn render(&self, kind: &str, values: &Value) -> String {
+        self.templates.render(kind, values).unwrap_or_else(|e| {
+            error!("Unable to render the {} notice: {}", kind, e);
+            format!("{} {}", kind, values)
+        })
+    }
+
+    pub fn suppressed(&self, route: &str, from: &str, subject: &str, reason: &str) {
+        if let Some(channel) = &self.suppressed_channel {
+            let text = self.render("suppressed", &json!({
+                "route": route,
+                "from": from,
+                "subject": subject,
+                "reason": reason,
+            }));
+            self.post(channel.clone(), text);
+        }
+    }
+
+    pub fn signature_failure(&self, route: &str, error: &str) {
+        let channel = match &self.signature_failure_channel {
+            Some(channel) => channel.clone(),
+            None => return,
+        };
+        {
+            let now = self.clock.now();
+            let mut last = self.signature_failures.lock().unwrap();
+            if last.get(route).map_or(false, |at| now - *at < Duration::minutes(SIGNATURE_FAILURE_QUIET_MINUTES)) {
+                return;
+            }
+            last.insert(String::from(route), now);
+        }
+        let text = self.render("signature_failure", &json!({ "route": route, "error": error }));
+        self.post(channel, text);
+    }
+
+    pub fn started(&self, version: &Version, host: &str, mode: &str) {
+        if let Some(channel) = &self.started_channel {
+            let mut values = serde_json::to_value(version).unwrap_or_default();
+            values["host"] = json!(host);
+            values["mode"] = json!(mode);
+            let text = self.render("started", &values);
+            self.post(channel.clone(), text);
+        }
+    }
+
+    // Posting to Slack can be slow (or be what's failing), never wait for it.
+    pub fn post(&self, channel: String, text: String) {
+        let slack = self.slack.clone();
+        thread::spawn(move || {
+            let sent = slack.send_message(&SlackMessage {
+                channel,
+                text,
+                thread_ts: None,
+                as_user: true,
+                username: None,
+                icon_emoji: None,
+                icon_url: None,
+                blocks: None,
+                unfurl_links: None,
+                unfurl_media: None,
+            });
+            if let Err(e) = sent {
+                error!("Unable to post a notice to Slack: {}", e);
+            }
+        });
+    }
+}
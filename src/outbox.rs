@@ -0,0 +1,123 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+
+use crate::blocklist::address_of;
+use crate::copies::{Copies, SentCopy};
+use crate::store::{self, StoreError};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OutboxEntry {
+    pub at: DateTime<Utc>,
+    pub recipient: String,
+    pub template: String,
+    pub subject: String,
+    pub message_id: String,
+    // Sent through /admin/send rather than by the auto-responder.
+    #[serde(default)]
+    pub sent_by: Option<String>,
+    // The send id in the reply's feedback link, see feedback.rs.
+    #[serde(default)]
+    pub feedback_id: Option<String>,
+    // The inbound email answered, so a retried job isn't answered twice.
+    #[serde(default)]
+    pub job_id: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+pub struct OutboxQuery {
+    pub recipient: Option<String>,
+    pub template: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub limit: Option<usize>,
+}
+
+impl OutboxQuery {
+    fn matches(&self, entry: &OutboxEntry) -> bool {
+        self.recipient.as_ref().map_or(true, |recipient| {
+            entry.recipient.to_lowercase().contains(&recipient.to_lowercase())
+        })
+            && self.template.as_ref().map_or(true, |template| template == &entry.template)
+            && self.since.map_or(true, |since| entry.at >= since)
+    }
+}
+
+// Every email we've sent, so support can confirm whether someone was answered.
+#[derive(Clone)]
+pub struct Outbox {
+    path: PathBuf,
+    write_lock: Arc<Mutex<()>>,
+    copies: Option<Copies>,
+}
+
+impl Outbox {
+    pub fn new(path: PathBuf) -> Outbox {
+        Outbox {
+            path,
+            write_lock: Arc::new(Mutex::new(())),
+            copies: None,
+        }
+    }
+
+    // Keeps a copy of what each email said too, see copies.rs.
+    pub fn keeping(self, copies: Copies) -> Outbox {
+        Outbox { copies: Some(copies), ..self }
+    }
+
+    pub fn record(&self, entry: &OutboxEntry) -> Result<(), StoreError> {
+        {
+            let _guard = self.write_lock.lock().unwrap();
+            store::append_json_line(&self.path, entry)?;
+        }
+        if let Some(copies) = &self.copies {
+            copies.keep(entry);
+        }
+        Ok(())
+    }
+
+    pub fn copy(&self, message_id: &str) -> Result<Option<SentCopy>, StoreError> {
+        match &self.copies {
+            Some(copies) => copies.get(message_id),
+            None => Ok(None),
+        }
+    }
+
+    // Replies to a job can't be older than the job.
+    pub fn sent_for_job(&self, job_id: &str, received_at: DateTime<Utc>) -> Result<Option<OutboxEntry>, StoreError> {
+        let entries: Vec<OutboxEntry> = store::read_json_lines(&self.path)?;
+        Ok(entries.into_iter()
+            .rev()
+            .take_while(|entry| entry.at >= received_at)
+            .find(|entry| entry.job_id.as_ref().map(|id| &id[..]) == Some(job_id)))
+    }
+
+    // The last `limit` auto-replies to recipient, newest first.
+    pub fn auto_replies_to(&self, recipient: &str, limit: usize) -> Result<Vec<OutboxEntry>, StoreError> {
+        let entries: Vec<OutboxEntry> = store::read_json_lines(&self.path)?;
+        Ok(entries.into_iter()
+            .rev()
+            .filter(|entry| entry.sent_by.is_none() && address_of(&entry.recipient) == address_of(recipient))
+            .take(limit)
+            .collect())
+    }
+
+    // Oldest first.
+    pub fn auto_replies_since(&self, since: Option<DateTime<Utc>>) -> Result<Vec<OutboxEntry>, StoreError> {
+        let entries: Vec<OutboxEntry> = store::read_json_lines(&self.path)?;
+        Ok(entries.into_iter()
+            .filter(|entry| entry.sent_by.is_none() && since.map_or(true, |since| entry.at >= since))
+            .collect())
+    }
+
+    // Newest first.
+    pub fn query(&self, query: &OutboxQuery) -> Result<Vec<OutboxEntry>, StoreError> {
+        let entries: Vec<OutboxEntry> = store::read_json_lines(&self.path)?;
+        Ok(entries.into_iter()
+            .rev()
+            .filter(|entry| query.matches(entry))
+            .take(query.limit.unwrap_or(100))
+            .collect())
+    }
+}
@@ -0,0 +1,142 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::blocklist::address_of;
+use crate::mailgun::MailgunError;
+
+const MINUTE: Duration = Duration::from_secs(60);
+
+fn default_max_wait_seconds() -> u64 {
+    30
+}
+
+// Domains one provider receives mail for, paced together.
+#[derive(Deserialize, Clone)]
+pub struct ProviderPace {
+    pub domains: Vec<String>,
+    #[serde(default)]
+    pub per_minute: Option<usize>,
+    #[serde(default)]
+    pub concurrent: Option<usize>,
+}
+
+// Paces sends per recipient domain, so a bulk or digest send doesn't trip
+// a provider's rate limits. Each domain gets per_minute sends a minute and
+// concurrent at once, unless one of the providers it's listed in says
+// otherwise. A send over the pace waits for up to max_wait_seconds, then
+// fails the way Mailgun being down would, to be retried. Counted per
+// process.
+#[derive(Deserialize, Clone)]
+pub struct PacingConfig {
+    #[serde(default)]
+    pub per_minute: Option<usize>,
+    #[serde(default)]
+    pub concurrent: Option<usize>,
+    #[serde(default = "default_max_wait_seconds")]
+    pub max_wait_seconds: u64,
+    #[serde(default)]
+    pub providers: Vec<ProviderPace>,
+}
+
+#[derive(Default)]
+struct Lane {
+    // Sends of the last minute, oldest first.
+    sent: VecDeque<Instant>,
+    in_flight: usize,
+}
+
+#[derive(Clone)]
+pub struct Pacing {
+    config: Arc<PacingConfig>,
+    lanes: Arc<(Mutex<BTreeMap<String, Lane>>, Condvar)>,
+}
+
+// A send in flight, until dropped.
+pub struct Permit {
+    lanes: Option<(Arc<(Mutex<BTreeMap<String, Lane>>, Condvar)>, String)>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some((lanes, key)) = self.lanes.take() {
+            let (lock, condvar) = &*lanes;
+            if let Some(lane) = lock.lock().unwrap().get_mut(&key) {
+                lane.in_flight = lane.in_flight.saturating_sub(1);
+            }
+            condvar.notify_all();
+        }
+    }
+}
+
+fn domain_of(recipient: &str) -> String {
+    let address = address_of(recipient);
+    match address.rfind('@') {
+        Some(at) => String::from(&address[at + 1..]),
+        None => address,
+    }
+}
+
+impl Pacing {
+    pub fn new(config: PacingConfig) -> Pacing {
+        Pacing {
+            config: Arc::new(config),
+            lanes: Arc::new((Mutex::new(BTreeMap::new()), Condvar::new())),
+        }
+    }
+
+    // The lane a domain is counted in, with its limits.
+    fn limits(&self, domain: &str) -> (String, Option<usize>, Option<usize>) {
+        let provider = self.config.providers.iter()
+            .find(|provider| provider.domains.iter().any(|listed| listed.eq_ignore_ascii_case(domain)));
+        match provider {
+            Some(provider) => (
+                provider.domains.join(","),
+                provider.per_minute.or(self.config.per_minute),
+                provider.concurrent.or(self.config.concurrent),
+            ),
+            None => (String::from(domain), self.config.per_minute, self.config.concurrent),
+        }
+    }
+
+    // Waits for a turn to send to recipient.
+    pub fn acquire(&self, recipient: &str) -> Result<Permit, MailgunError> {
+        let (key, per_minute, concurrent) = self.limits(&domain_of(recipient));
+        if per_minute.is_none() && concurrent.is_none() {
+            return Ok(Permit { lanes: None });
+        }
+        let deadline = Instant::now() + Duration::from_secs(self.config.max_wait_seconds);
+        let (lock, condvar) = &*self.lanes;
+        let mut lanes = lock.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            lanes.retain(|_, lane| {
+                while lane.sent.front().map_or(false, |at| now.duration_since(*at) >= MINUTE) {
+                    lane.sent.pop_front();
+                }
+                lane.in_flight > 0 || !lane.sent.is_empty()
+            });
+            let lane = lanes.entry(key.clone()).or_default();
+            let busy = concurrent.map_or(false, |concurrent| lane.in_flight >= concurrent.max(1));
+            let next_slot = per_minute
+                .filter(|per_minute| lane.sent.len() >= (*per_minute).max(1))
+                .and_then(|_| lane.sent.front())
+                .map(|oldest| MINUTE - now.duration_since(*oldest));
+            if !busy && next_slot.is_none() {
+                lane.sent.push_back(now);
+                lane.in_flight += 1;
+                return Ok(Permit { lanes: Some((self.lanes.clone(), key)) });
+            }
+            if now >= deadline {
+                return Err(MailgunError::MailgunError(format!(
+                    "Sending to {} is paced, still no turn after {} seconds",
+                    key, self.config.max_wait_seconds
+                )));
+            }
+            let wait = next_slot.map_or(deadline - now, |slot| slot.min(deadline - now));
+            lanes = condvar.wait_timeout(lanes, wait).unwrap().0;
+        }
+    }
+}
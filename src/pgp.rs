@@ -0,0 +1,191 @@
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::mailgun::MailgunEmailReceived;
+use crate::multipart::Part;
+use crate::viewer;
+
+const ARMOR_BEGIN: &str = "-----BEGIN PGP MESSAGE-----";
+const ARMOR_END: &str = "-----END PGP MESSAGE-----";
+
+fn default_gpg() -> String {
+    String::from("gpg")
+}
+
+fn default_timeout_seconds() -> u64 {
+    30
+}
+
+// Decrypts PGP encrypted emails with gpg, using the private key in homedir
+// (which must not need a passphrase).
+#[derive(Deserialize, Clone)]
+pub struct PgpConfig {
+    #[serde(default = "default_gpg")]
+    pub gpg: String,
+    pub homedir: String,
+    #[serde(default = "default_timeout_seconds")]
+    pub timeout_seconds: u64,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Decryption {
+    NotEncrypted,
+    Decrypted,
+    Failed,
+}
+
+// The armored message, inline in the body or as the encrypted part of a
+// PGP/MIME email.
+fn armored_message(email: &MailgunEmailReceived, attachments: &[Part]) -> Option<String> {
+    let armored = |text: &str| -> Option<String> {
+        let start = text.find(ARMOR_BEGIN)?;
+        let end = text[start..].find(ARMOR_END)? + start + ARMOR_END.len();
+        Some(String::from(&text[start..end]))
+    };
+    armored(&email.body_plain).or_else(|| {
+        attachments.iter()
+            .filter_map(|part| std::str::from_utf8(&part.data).ok())
+            .find_map(armored)
+    })
+}
+
+fn is_pgp_mime(email: &MailgunEmailReceived) -> bool {
+    viewer::headers(&email.message_headers).iter().any(|(name, value)| {
+        name.eq_ignore_ascii_case("content-type") && value.to_lowercase().contains("application/pgp-encrypted")
+    })
+}
+
+// gpg's output for a PGP/MIME email is a MIME entity itself. Keeps the
+// body of the first text part, which covers what people actually send.
+fn plain_text(decrypted: &str) -> String {
+    let unified = decrypted.replace("\r\n", "\n");
+    let looks_like_mime = unified.lines().next()
+        .map_or(false, |line| line.to_lowercase().starts_with("content-"));
+    if !looks_like_mime {
+        return unified;
+    }
+    let boundary = unified.lines()
+        .take_while(|line| !line.is_empty())
+        .find_map(|line| {
+            // Unlike to_lowercase, keeps every character's byte offset.
+            let lower = line.to_ascii_lowercase();
+            let start = lower.find("boundary=")? + "boundary=".len();
+            Some(String::from(line[start..].trim_matches(|c| c == '"' || c == ';' || c == ' ')))
+        });
+    let body = match unified.find("\n\n") {
+        Some(end) => &unified[end + 2..],
+        None => return unified,
+    };
+    match boundary {
+        Some(boundary) => body.split(&format!("--{}", boundary)[..])
+            .filter_map(|part| {
+                let part = part.trim_start_matches('\n');
+                let end = part.find("\n\n")?;
+                if part[..end].to_lowercase().contains("text/plain") {
+                    Some(String::from(part[end + 2..].trim_end()))
+                } else {
+                    None
+                }
+            })
+            .next()
+            .unwrap_or_else(|| String::from(body)),
+        None => String::from(body),
+    }
+}
+
+// Read on its own thread, so a full pipe never stops gpg.
+fn read_all<R: Read + Send + 'static>(pipe: Option<R>) -> JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut output = Vec::new();
+        if let Some(mut pipe) = pipe {
+            if let Err(e) = pipe.read_to_end(&mut output) {
+                warn!("Unable to read gpg's output: {}", e);
+            }
+        }
+        output
+    })
+}
+
+pub struct Pgp {
+    config: Option<PgpConfig>,
+}
+
+impl Pgp {
+    pub fn new(config: Option<PgpConfig>) -> Pgp {
+        Pgp { config }
+    }
+
+    fn decrypt(&self, config: &PgpConfig, armored: &str) -> Result<String, String> {
+        let mut child = Command::new(&config.gpg)
+            .args(&["--homedir", &config.homedir, "--batch", "--quiet", "--decrypt"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Unable to run {}: {}", config.gpg, e))?;
+        // gpg writes as it reads, so the message goes in while its output
+        // comes out, or both sides wait on a full pipe.
+        let (stdin, message) = (child.stdin.take(), armored.as_bytes().to_vec());
+        let writer = thread::spawn(move || match stdin {
+            Some(mut stdin) => stdin.write_all(&message),
+            None => Ok(()),
+        });
+        let (stdout, stderr) = (read_all(child.stdout.take()), read_all(child.stderr.take()));
+        let deadline = Instant::now() + Duration::from_secs(config.timeout_seconds);
+        let status = loop {
+            match child.try_wait().map_err(|e| format!("gpg failed: {}", e))? {
+                Some(status) => break status,
+                None if Instant::now() >= deadline => {
+                    if let Err(e) = child.kill().and_then(|_| child.wait()) {
+                        error!("Unable to kill gpg: {}", e);
+                    }
+                    return Err(format!("gpg took longer than {} seconds", config.timeout_seconds));
+                },
+                None => thread::sleep(Duration::from_millis(20)),
+            }
+        };
+        let (stdout, stderr) = (stdout.join().unwrap_or_default(), stderr.join().unwrap_or_default());
+        if !status.success() {
+            return Err(String::from_utf8_lossy(&stderr).trim().to_string());
+        }
+        // Only worth mentioning when gpg thought all was well.
+        if let Ok(Err(e)) = writer.join() {
+            return Err(format!("Unable to write to gpg: {}", e));
+        }
+        Ok(plain_text(&String::from_utf8_lossy(&stdout)))
+    }
+
+    // Replaces an encrypted body with the decrypted text, or with a notice
+    // saying it couldn't be read, so Slack never gets a wall of armor.
+    pub fn process(&self, email: &mut MailgunEmailReceived, attachments: &[Part]) -> Decryption {
+        let armored = match armored_message(email, attachments) {
+            Some(armored) => armored,
+            None if is_pgp_mime(email) => String::new(),
+            None => return Decryption::NotEncrypted,
+        };
+        let decrypted = match &self.config {
+            Some(_) if armored.is_empty() => Err(String::from("no PGP message found in it")),
+            Some(config) => self.decrypt(config, &armored),
+            None => Err(String::from("no private key is configured")),
+        };
+        email.body_html = None;
+        match decrypted {
+            Ok(text) => {
+                email.body_plain = text;
+                Decryption::Decrypted
+            },
+            Err(reason) => {
+                warn!("Unable to decrypt a PGP encrypted email from {}: {}", email.from, reason);
+                email.body_plain = format!(
+                    "[This email is PGP encrypted and couldn't be decrypted: {}]",
+                    reason
+                );
+                Decryption::Failed
+            },
+        }
+    }
+}
@@ -0,0 +1,1138 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error as StdError;
+use std::fmt::{self, Display};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+use serde_json::{json, Value};
+use warp::Rejection;
+
+use crate::addresses::{self, ReplyToChoice, ReplyToRule};
+use crate::alerts::Alerts;
+use crate::archive::{Archive, ArchivedSlackMessage};
+use crate::authresults::AuthResults;
+use crate::blocklist::{address_of, Blocklist};
+use crate::bounces::Bounces;
+use crate::canned::{self, CannedReplies};
+use crate::clock::Clock;
+use crate::defang::LinkSafety;
+use crate::delays::{self, DelayedReply, Delays};
+use crate::digest::DigestConfig;
+use crate::echo::Echo;
+use crate::fanout::{self, Call};
+use crate::feedback::Feedback;
+use crate::flags::Flags;
+use crate::formatting::{self, Formatting};
+use crate::forwards::{EmailForward, ForwardedEmail};
+use crate::handling::{Handling, UnhandledEmail};
+use crate::links::ArchiveLinks;
+use crate::loops::{Loops, Verdict};
+use crate::mailgun::{EmailTemplate, Mailgun, MailgunEmailReceived, MailgunError};
+use crate::maintenance::Maintenance;
+use crate::metrics::Metrics;
+use crate::notices::Notices;
+use crate::outbox::{Outbox, OutboxEntry};
+use crate::quarantine::{self, QuarantineConfig};
+use crate::ratelimit::LastResponseLog;
+use crate::reputation::{Reputation, SenderHistory};
+use crate::slack::{Slack, SlackError, SlackIdentity, SlackMessage};
+use crate::smime::SmimeSignature;
+use crate::store::StoreError;
+use crate::suppressions::Suppressions;
+use crate::takeover::Takeover;
+use crate::threads::ThreadMap;
+use crate::threats::{Finding, ThreatIntel};
+use crate::trace::Tracer;
+use crate::traceparent::{self, TraceContext};
+use crate::unsubscribe::{UnsubscribeLinks, Unsubscribed};
+use crate::variants::Variants;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Action {
+    Respond { template: String },
+    ForwardToSlack { channel: String },
+    ForwardToEmail { alias: String },
+    // Both, each leg as on its own route.
+    RespondAndForward { template: String, channel: String },
+    // Collected for a daily email, see digest.rs.
+    Digest { name: String },
+    // A copy of what a human sent, see takeover.rs.
+    Takeover,
+}
+
+// An exact route like forward/slack/C0123, or a prefix ending in *.
+pub fn route_matches(pattern: &str, route: &str) -> bool {
+    if pattern.ends_with('*') {
+        route.starts_with(&pattern[..pattern.len() - 1])
+    } else {
+        pattern == route
+    }
+}
+
+fn default_handler_seconds() -> u64 {
+    30
+}
+
+fn default_call_seconds() -> u64 {
+    10
+}
+
+#[derive(Deserialize, Clone)]
+pub struct RouteDeadlines {
+    pub route: String,
+    pub handler_seconds: Option<u64>,
+    pub mailgun_seconds: Option<u64>,
+    pub slack_seconds: Option<u64>,
+}
+
+// How long a webhook may take in total, and each call to Mailgun or Slack
+// within it. The first matching entry in `routes` overrides the defaults.
+#[derive(Deserialize, Clone)]
+pub struct DeadlineConfig {
+    #[serde(default = "default_handler_seconds")]
+    pub handler_seconds: u64,
+    #[serde(default = "default_call_seconds")]
+    pub mailgun_seconds: u64,
+    #[serde(default = "default_call_seconds")]
+    pub slack_seconds: u64,
+    #[serde(default)]
+    pub routes: Vec<RouteDeadlines>,
+}
+
+impl Default for DeadlineConfig {
+    fn default() -> DeadlineConfig {
+        DeadlineConfig {
+            handler_seconds: default_handler_seconds(),
+            mailgun_seconds: default_call_seconds(),
+            slack_seconds: default_call_seconds(),
+            routes: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Deadlines {
+    pub handler: Duration,
+    pub mailgun: Duration,
+    pub slack: Duration,
+}
+
+impl DeadlineConfig {
+    pub fn for_route(&self, route: &str) -> Deadlines {
+        let overrides = self.routes.iter().find(|r| route_matches(&r.route, route));
+        let pick = |default: u64, f: fn(&RouteDeadlines) -> Option<u64>| {
+            Duration::from_secs(overrides.and_then(f).unwrap_or(default))
+        };
+        Deadlines {
+            handler: pick(self.handler_seconds, |r| r.handler_seconds),
+            mailgun: pick(self.mailgun_seconds, |r| r.mailgun_seconds),
+            slack: pick(self.slack_seconds, |r| r.slack_seconds),
+        }
+    }
+}
+
+impl Action {
+    pub fn route(&self) -> String {
+        match self {
+            Action::Respond { template } => format!("responder/{}", template),
+            Action::ForwardToSlack { channel } => format!("forward/slack/{}", channel),
+            Action::ForwardToEmail { alias } => format!("forward/email/{}", alias),
+            Action::RespondAndForward { template, channel } => format!("respond-and-forward/{}/slack/{}", template, channel),
+            Action::Digest { name } => format!("digest/{}", name),
+            Action::Takeover => String::from("takeover"),
+        }
+    }
+}
+
+// A verified inbound email and what to do with it. This is what goes on the
+// queue when the frontend and the workers run as separate processes.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Job {
+    pub id: String,
+    pub received_at: DateTime<Utc>,
+    pub action: Action,
+    pub email: MailgunEmailReceived,
+    // Set when an admin re-runs an archived email.
+    #[serde(default)]
+    pub replayed_by: Option<String>,
+    // Only for signed emails, see smime.rs.
+    #[serde(default)]
+    pub smime: Option<SmimeSignature>,
+    // Set when a mod lets it out of quarantine, see quarantine.rs.
+    #[serde(default)]
+    pub released_by: Option<String>,
+    // See traceparent.rs, replays aren't traced.
+    #[serde(default)]
+    pub trace: Option<TraceContext>,
+}
+
+impl Job {
+    // `id` is the same for every retry of a delivery, see
+    // SignatureScheme::delivery_id.
+    pub fn new(id: String, action: Action, email: MailgunEmailReceived, received_at: DateTime<Utc>) -> Job {
+        Job {
+            id,
+            received_at,
+            action,
+            email,
+            replayed_by: None,
+            smime: None,
+            released_by: None,
+            trace: None,
+        }
+    }
+
+    pub fn replay(&self, action: Action, replayed_by: &str) -> Job {
+        Job {
+            id: self.id.clone(),
+            received_at: self.received_at,
+            action,
+            email: self.email.clone(),
+            replayed_by: Some(String::from(replayed_by)),
+            smime: self.smime.clone(),
+            released_by: None,
+            trace: None,
+        }
+    }
+
+    // Posted where it was going after all.
+    pub fn release(&self, released_by: &str) -> Job {
+        Job {
+            released_by: Some(String::from(released_by)),
+            ..self.replay(self.action.clone(), released_by)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Replied,
+    Suppressed,
+    Blocked,
+    Forwarded,
+    Echoed,
+    Collected,
+    TakenOver,
+}
+
+impl Outcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Replied => "replied",
+            Outcome::Suppressed => "suppressed",
+            Outcome::Blocked => "blocked",
+            Outcome::Forwarded => "forwarded",
+            Outcome::Echoed => "echoed",
+            Outcome::Collected => "collected",
+            Outcome::TakenOver => "taken_over",
+        }
+    }
+
+    fn counter(&self) -> &'static str {
+        match self {
+            Outcome::Replied => "replies_sent",
+            Outcome::Suppressed => "replies_suppressed",
+            Outcome::Blocked => "emails_blocked",
+            Outcome::Forwarded => "forwards_sent",
+            Outcome::Echoed => "emails_echoed",
+            Outcome::Collected => "emails_collected",
+            Outcome::TakenOver => "takeovers_seen",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DeliveryError {
+    Mailgun(MailgunError),
+    Slack(SlackError),
+    DeadlineExceeded(String),
+    Storage(StoreError),
+}
+impl std::convert::From<MailgunError> for DeliveryError {
+    fn from(error: MailgunError) -> Self {
+        DeliveryError::Mailgun(error)
+    }
+}
+impl std::convert::From<SlackError> for DeliveryError {
+    fn from(error: SlackError) -> Self {
+        DeliveryError::Slack(error)
+    }
+}
+impl std::convert::From<StoreError> for DeliveryError {
+    fn from(error: StoreError) -> Self {
+        DeliveryError::Storage(error)
+    }
+}
+impl std::convert::From<DeliveryError> for Rejection {
+    fn from(err: DeliveryError) -> Rejection {
+        warp::reject::custom(err)
+    }
+}
+
+impl Display for DeliveryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeliveryError::Mailgun(e) => e.fmt(f),
+            DeliveryError::Slack(e) => e.fmt(f),
+            DeliveryError::DeadlineExceeded(s) => f.write_str(s),
+            DeliveryError::Storage(e) => e.fmt(f),
+        }
+    }
+}
+impl StdError for DeliveryError {}
+
+// Slack takes at most 3000 characters per section, and 50 blocks.
+const SECTION_CHARS: usize = 2900;
+const BODY_BLOCKS: usize = 40;
+
+fn body_blocks(body_plain: &str) -> Vec<Value> {
+    let chars: Vec<char> = body_plain.chars().collect();
+    let mut chunks: Vec<String> = chars.chunks(SECTION_CHARS)
+        .map(|chunk| chunk.iter().collect())
+        .collect();
+    if chunks.len() > BODY_BLOCKS {
+        chunks.truncate(BODY_BLOCKS);
+        chunks[BODY_BLOCKS - 1].push('…');
+    }
+    chunks.into_iter()
+        .filter(|chunk| !chunk.trim().is_empty())
+        .map(|chunk| json!({
+            "type": "section",
+            "text": { "type": "mrkdwn", "text": format!("```{}```", chunk) },
+        }))
+        .collect()
+}
+
+// The forward, posted to the fallback channel instead, saying where it was
+// meant to go and why it didn't.
+fn rerouted(message: SlackMessage, fallback: &str, reason: &str) -> SlackMessage {
+    let note = format!(":warning: Meant for <#{}>, but {}", message.channel, reason);
+    let mut blocks = vec![json!({
+        "type": "context",
+        "elements": [{ "type": "mrkdwn", "text": note }],
+    })];
+    if let Some(Value::Array(existing)) = message.blocks {
+        blocks.extend(existing);
+    }
+    SlackMessage {
+        channel: String::from(fallback),
+        text: format!("(meant for <#{}>) {}", message.channel, message.text),
+        thread_ts: None,
+        blocks: Some(Value::Array(blocks)),
+        ..message
+    }
+}
+
+// Everything needed to act on a job, shared by the webhook handlers (when
+// delivering inline) and the queue workers.
+#[derive(Clone)]
+pub struct Pipeline {
+    pub mailgun: Mailgun,
+    pub slack: Slack,
+    pub metrics: Metrics,
+    pub blocklist: Blocklist,
+    pub last_response_log: LastResponseLog,
+    pub archive: Archive,
+    pub outbox: Outbox,
+    pub bounces: Option<Bounces>,
+    pub loops: Option<Loops>,
+    pub alerts: Alerts,
+    pub deadlines: Arc<DeadlineConfig>,
+    pub echo: Echo,
+    pub links: Option<ArchiveLinks>,
+    pub threads: ThreadMap,
+    pub identities: Arc<Vec<SlackIdentity>>,
+    pub formatting: Arc<Vec<Formatting>>,
+    pub digests: Arc<Vec<DigestConfig>>,
+    pub email_forwards: Arc<Vec<EmailForward>>,
+    pub link_safety: Arc<Vec<LinkSafety>>,
+    pub threat_intel: Option<ThreatIntel>,
+    pub quarantine: Option<QuarantineConfig>,
+    pub trusted_authserv_ids: Arc<Vec<String>>,
+    pub fallback_channel: Option<String>,
+    pub flags: Flags,
+    pub reply_to: Arc<Vec<ReplyToRule>>,
+    pub maintenance: Maintenance,
+    pub variants: Variants,
+    pub feedback: Option<Feedback>,
+    pub unsubscribe_links: Option<UnsubscribeLinks>,
+    pub unsubscribed: Unsubscribed,
+    pub canned_replies: Arc<Vec<CannedReplies>>,
+    pub delays: Delays,
+    pub takeover: Option<Takeover>,
+    pub suppressions: Suppressions,
+    pub handling: Option<Handling>,
+    pub reputation: Option<Reputation>,
+    pub notices: Notices,
+    pub tracer: Tracer,
+    // The ids of the jobs process_within_deadline is still running.
+    pub in_flight: Arc<Mutex<BTreeSet<String>>>,
+    pub clock: Clock,
+}
+
+// Takes a job off the in-flight ones when it finishes, however it does.
+struct InFlight {
+    jobs: Arc<Mutex<BTreeSet<String>>>,
+    id: String,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.jobs.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl Pipeline {
+    // Gives up waiting (so the webhook can answer 503 and Mailgun retries)
+    // once the route's handler deadline has passed. The job itself carries
+    // on in the background, bounded by the Mailgun and Slack timeouts. A
+    // retry that comes while it's still running fails the same way, rather
+    // than running it again alongside: a later one finds it done, or does
+    // the steps it didn't get to.
+    pub fn process_within_deadline(&self, job: &Job) -> Result<Outcome, DeliveryError> {
+        let in_flight = {
+            let mut jobs = self.in_flight.lock().unwrap();
+            if !jobs.insert(job.id.clone()) {
+                info!("Job {} is still running, not running it again", job.id);
+                self.metrics.incr("retries_in_flight");
+                return Err(DeliveryError::DeadlineExceeded(format!("Job {} is still running", job.id)));
+            }
+            InFlight { jobs: self.in_flight.clone(), id: job.id.clone() }
+        };
+        let budget = self.deadlines.for_route(&job.action.route()).handler;
+        let (sender, receiver) = mpsc::channel();
+        let (pipeline, background_job) = (self.clone(), job.clone());
+        thread::spawn(move || {
+            let _in_flight = in_flight;
+            let _ = sender.send(pipeline.process(&background_job));
+        });
+        receiver.recv_timeout(budget).unwrap_or_else(|_| {
+            self.metrics.incr("errors_deadline");
+            Err(DeliveryError::DeadlineExceeded(format!(
+                "Job {} ({}) did not finish within {}s",
+                job.id,
+                job.action.route(),
+                budget.as_secs()
+            )))
+        })
+    }
+
+    pub fn process(&self, job: &Job) -> Result<Outcome, DeliveryError> {
+        let email = &job.email;
+        let route = job.action.route();
+        let deadlines = self.deadlines.for_route(&route);
+        let _trace = traceparent::enter(job.trace.as_ref());
+        self.tracer.begin(job);
+        // A replay is the same email again, it doesn't add to the history.
+        let auth = AuthResults::from_email(email, &self.trusted_authserv_ids);
+        let sender = self.reputation.as_ref().and_then(|reputation| match &job.replayed_by {
+            Some(_) => reputation.get(&email.from),
+            None => reputation.observe(&job.id, email, &auth).map_err(|e| {
+                error!("Unable to update the sender history of {}: {}", email.from, e);
+            }).ok(),
+        });
+        if let Some(sender) = &sender {
+            self.tracer.note(job, "sender", &sender.summary());
+        }
+        let blocked = self.blocklist.is_blocked(&email.from);
+        self.tracer.note(job, "blocklist", &json!({ "blocked": blocked }));
+        let result = if blocked {
+            info!("{} is blocklisted. Ignoring.", email.from);
+            self.count_against_sender(job, Outcome::Blocked);
+            Ok(Outcome::Blocked)
+        } else {
+            match &job.action {
+                Action::Respond { template } => self.respond(&route, &deadlines, template, job, sender.as_ref()),
+                Action::ForwardToSlack { channel } => {
+                    self.forward_to_slack(&route, &deadlines, channel, job, sender.as_ref())
+                },
+                Action::ForwardToEmail { alias } => self.forward_to_email(&deadlines, alias, job),
+                Action::RespondAndForward { template, channel } => {
+                    self.respond_and_forward(template, channel, job, sender.as_ref())
+                },
+                Action::Digest { name } => Ok(self.collect(name, job)),
+                Action::Takeover => self.take_over(job),
+            }
+        };
+
+        match &result {
+            Ok(outcome) => {
+                self.metrics.record_email(&job.action.route(), &email.from, &email.subject, outcome.as_str());
+                self.metrics.incr(outcome.counter());
+                let target = match &job.action {
+                    Action::Respond { template } => ("template", &template[..]),
+                    Action::ForwardToSlack { channel } => ("channel", &channel[..]),
+                    Action::ForwardToEmail { alias } => ("alias", &alias[..]),
+                    Action::RespondAndForward { channel, .. } => ("channel", &channel[..]),
+                    Action::Digest { name } => ("digest", &name[..]),
+                    Action::Takeover => ("takeover", "takeover"),
+                };
+                self.metrics.incr_labeled("emails", &[("route", &route), target, ("outcome", outcome.as_str())]);
+            },
+            Err(e) => {
+                self.metrics.record_email(&job.action.route(), &email.from, &email.subject, "failed");
+                self.metrics.incr_labeled("emails", &[("route", &route), ("outcome", "failed")]);
+                self.metrics.incr(match e {
+                    DeliveryError::Mailgun(MailgunError::JsonError(_)) => "errors_json",
+                    DeliveryError::Mailgun(MailgunError::HmacError(_)) => "errors_hmac",
+                    DeliveryError::Mailgun(MailgunError::MailgunError(_)) => "errors_mailgun",
+                    DeliveryError::Mailgun(MailgunError::OverBudget(_)) => "errors_budget",
+                    DeliveryError::Mailgun(MailgunError::Sandbox(_)) => "errors_sandbox",
+                    DeliveryError::Mailgun(MailgunError::Maintenance(_)) => "errors_maintenance",
+                    DeliveryError::Slack(_) => "errors_slack",
+                    DeliveryError::DeadlineExceeded(_) => "errors_deadline",
+                    DeliveryError::Storage(_) => "errors_storage",
+                });
+            },
+        }
+
+        self.alerts.observe(&job.action.route(), match &result {
+            Ok(outcome) => outcome.as_str(),
+            Err(_) => "failed",
+        });
+
+        let archived_outcome = match &result {
+            Ok(outcome) => String::from(outcome.as_str()),
+            Err(e) => format!("failed: {}", e),
+        };
+        if let Err(e) = self.archive.record_outcome(job, &archived_outcome) {
+            error!("Unable to archive the outcome of job {}: {}", job.id, e);
+        }
+        self.tracer.finish(job, &archived_outcome);
+        result
+    }
+
+    // The legs run at the same time, each within its own route's deadlines.
+    // One leg failing doesn't stop the other, and fails the job so the retry
+    // does it again. The retry skips whichever leg was done, as they each
+    // skip what's done. The outcome is the forward's, the reply's is only
+    // counted. A delayed reply (see delays.rs) waits for the forward, with
+    // its Cancel button, to be posted.
+    fn respond_and_forward(
+        &self,
+        template: &str,
+        channel: &str,
+        job: &Job,
+        sender: Option<&SenderHistory>,
+    ) -> Result<Outcome, DeliveryError> {
+        let reply_route = Action::Respond { template: String::from(template) }.route();
+        let forward_route = Action::ForwardToSlack { channel: String::from(channel) }.route();
+        if let (Some(seconds), None) = (self.delays.delay_for(&job.action.route()), &job.replayed_by) {
+            let forwarded = self.forward_to_slack(&forward_route, &self.deadlines.for_route(&forward_route), channel, job, sender)?;
+            let delayed = self.delays.schedule(&job.id, seconds)?;
+            self.tracer.note(job, "reply_delayed", &delayed);
+            self.reply_when_due(job.clone(), delayed);
+            return Ok(forwarded);
+        }
+        let reply_deadlines = self.deadlines.for_route(&reply_route);
+        let forward_deadlines = self.deadlines.for_route(&forward_route);
+        let (pipeline, reply_job, reply_sender, template) = (self.clone(), job.clone(), sender.cloned(), String::from(template));
+        let reply: Call<Result<Outcome, DeliveryError>> = Box::new(move || {
+            let _trace = traceparent::enter(reply_job.trace.as_ref());
+            pipeline.respond(&reply_route, &reply_deadlines, &template, &reply_job, reply_sender.as_ref())
+        });
+        let (pipeline, forward_job, forward_sender, channel_id) = (self.clone(), job.clone(), sender.cloned(), String::from(channel));
+        let forward: Call<Result<Outcome, DeliveryError>> = Box::new(move || {
+            let _trace = traceparent::enter(forward_job.trace.as_ref());
+            pipeline.forward_to_slack(&forward_route, &forward_deadlines, &channel_id, &forward_job, forward_sender.as_ref())
+        });
+        // Whichever hasn't finished by the handler deadline carries on in
+        // the background, and the retry finds it done or does it again.
+        let handler = self.deadlines.for_route(&job.action.route()).handler;
+        let timed_out = |leg: &str| Err(DeliveryError::DeadlineExceeded(format!(
+            "The {} of job {} did not finish within {}s",
+            leg,
+            job.id,
+            handler.as_secs()
+        )));
+        let mut legs = fanout::join(vec![reply, forward], handler);
+        let forwarded = legs.pop().and_then(|leg| leg).unwrap_or_else(|| timed_out("forward"));
+        let replied = legs.pop().and_then(|leg| leg).unwrap_or_else(|| timed_out("reply"));
+        self.tracer.note(job, "legs", &json!({
+            "reply": replied.as_ref().map(|outcome| outcome.as_str()).map_err(|e| e.to_string()),
+            "forward": forwarded.as_ref().map(|outcome| outcome.as_str()).map_err(|e| e.to_string()),
+        }));
+        match (replied, forwarded) {
+            (Ok(replied), forwarded) => {
+                self.metrics.incr(replied.counter());
+                forwarded
+            },
+            (Err(e), Ok(_)) => Err(e),
+            (Err(e), Err(forward_error)) => {
+                error!("Unable to forward job {} to {}: {}", job.id, channel, forward_error);
+                Err(e)
+            },
+        }
+    }
+
+    // One coming due in maintenance waits for it to be lifted, and can
+    // still be cancelled in the meantime.
+    pub fn reply_when_due(&self, job: Job, delayed: DelayedReply) {
+        let pipeline = self.clone();
+        thread::spawn(move || {
+            pipeline.delays.wait_until_due(&delayed);
+            if pipeline.maintenance.wait_until_lifted() {
+                info!("Maintenance is over, sending the delayed reply to job {}", job.id);
+            }
+            pipeline.send_delayed(&job);
+        });
+    }
+
+    // Those still waiting when limail last stopped.
+    pub fn resume_delayed(&self) {
+        for delayed in self.delays.pending() {
+            match self.archive.get(&delayed.job_id) {
+                Ok(Some(archived)) => self.reply_when_due(archived.job, delayed),
+                Ok(None) => {
+                    error!("The job {} of a delayed reply is missing from the archive", delayed.job_id);
+                    let _ = self.delays.take(&delayed.job_id);
+                },
+                Err(e) => error!("Unable to read the job {} of a delayed reply: {}", delayed.job_id, e),
+            }
+        }
+    }
+
+    // Not retried when it fails, the forward is there for someone to answer.
+    fn send_delayed(&self, job: &Job) {
+        let _trace = traceparent::enter(job.trace.as_ref());
+        let template = match &job.action {
+            Action::RespondAndForward { template, .. } => template,
+            _ => return,
+        };
+        match self.delays.take(&job.id) {
+            Ok(Some(DelayedReply { cancelled_by: Some(by), .. })) => {
+                info!("{} cancelled the reply to job {}", by, job.id);
+                self.metrics.incr("replies_cancelled");
+            },
+            Ok(Some(_)) => {
+                let route = Action::Respond { template: template.clone() }.route();
+                let sender = self.reputation.as_ref().and_then(|reputation| reputation.get(&job.email.from));
+                match self.respond(&route, &self.deadlines.for_route(&route), template, job, sender.as_ref()) {
+                    Ok(outcome) => self.metrics.incr(outcome.counter()),
+                    Err(e) => {
+                        error!("Unable to send the delayed reply to job {}: {}", job.id, e);
+                        self.metrics.incr("errors_delayed_reply");
+                    },
+                }
+            },
+            Ok(None) => (),
+            Err(e) => error!("Unable to take the delayed reply to job {}: {}", job.id, e),
+        }
+    }
+
+    fn forward_to_email(&self, deadlines: &Deadlines, alias: &str, job: &Job) -> Result<Outcome, DeliveryError> {
+        let forward = match self.email_forwards.iter().find(|forward| forward.alias == alias) {
+            Some(forward) => forward,
+            None => return Err(MailgunError::MailgunError(format!("There is no [[email_forwards]] alias {}", alias)).into()),
+        };
+        let done = job.replayed_by.is_none() && self.archive.get(&job.id).ok()
+            .and_then(|archived| archived)
+            .map_or(false, |archived| archived.completed_steps.iter().any(|done| done == "forward_email"));
+        if done {
+            info!("Job {} was already forwarded to {}, not sending it again", job.id, forward.to);
+            self.metrics.incr("deliveries_deduplicated");
+            return Ok(Outcome::Forwarded);
+        }
+        let forwarded = ForwardedEmail::new(&forward.to, &job.id, &job.email);
+        self.tracer.note(job, "forward_email", &json!({ "to": forward.to }));
+        match self.mailgun.with_timeout(deadlines.mailgun).forward_email(&forwarded) {
+            Ok(_) => (),
+            // As for replies, retrying wouldn't help.
+            Err(MailgunError::OverBudget(message)) => {
+                warn!("Not forwarding job {} to {}: {}", job.id, forward.to, message);
+                return Ok(Outcome::Suppressed);
+            },
+            Err(e) => return Err(e.into()),
+        }
+        // Already sent, failing now would only get it sent again.
+        if let Err(e) = self.archive.record_step(job, "forward_email") {
+            error!("Unable to archive that job {} was forwarded: {}", job.id, e);
+        }
+        Ok(Outcome::Forwarded)
+    }
+
+    fn take_over(&self, job: &Job) -> Result<Outcome, DeliveryError> {
+        let email = &job.email;
+        let takeover = match &self.takeover {
+            Some(takeover) if takeover.is_human(&email.from) => takeover,
+            _ => {
+                warn!("{} isn't one of the [takeover] mailboxes, ignoring job {}", email.from, job.id);
+                return Ok(Outcome::Suppressed);
+            },
+        };
+        let recipients: Vec<String> = addresses::header(email, "To").into_iter()
+            .chain(addresses::header(email, "Cc"))
+            .map(|mailbox| mailbox.address)
+            .filter(|address| !takeover.is_human(address))
+            .collect();
+        self.humans_wrote(&email.from, &recipients)?;
+        Ok(Outcome::TakenOver)
+    }
+
+    // Stops the auto-replies to recipients, including those already waiting.
+    pub fn humans_wrote(&self, human: &str, recipients: &[String]) -> Result<(), StoreError> {
+        let takeover = match &self.takeover {
+            Some(takeover) => takeover,
+            None => return Ok(()),
+        };
+        for recipient in recipients {
+            takeover.record(recipient)?;
+            info!("{} wrote to {}, no auto-replies to them for now", human, recipient);
+        }
+        for delayed in self.delays.pending() {
+            let archived = match self.archive.get(&delayed.job_id) {
+                Ok(Some(archived)) => archived,
+                _ => continue,
+            };
+            let sender = address_of(&archived.job.email.from);
+            if recipients.iter().any(|recipient| address_of(recipient) == sender)
+                && self.delays.cancel(&delayed.job_id, &format!("takeover:{}", address_of(human)))?
+            {
+                info!("Cancelled the delayed reply to job {}, {} wrote to {} first", delayed.job_id, human, sender);
+                self.metrics.incr("replies_taken_over");
+            }
+        }
+        Ok(())
+    }
+
+    // Already archived, which is all the digest needs.
+    fn collect(&self, name: &str, job: &Job) -> Outcome {
+        if !self.digests.iter().any(|digest| digest.name == name) {
+            warn!("There is no [[digests]] named {}, {} is only archived", name, job.id);
+        }
+        Outcome::Collected
+    }
+
+    fn count_against_sender(&self, job: &Job, outcome: Outcome) {
+        if let (Some(reputation), None) = (&self.reputation, &job.replayed_by) {
+            if let Err(e) = reputation.record_outcome(&job.email.from, outcome) {
+                error!("Unable to update the sender history of {}: {}", job.email.from, e);
+            }
+        }
+    }
+
+    // Who a reply to `email` goes to, or why nobody should get one. The
+    // canned replies picked in Slack go through here too, so they never
+    // reach anyone an auto-reply wouldn't.
+    pub fn reply_recipient(&self, route: &str, email: &MailgunEmailReceived) -> Result<String, String> {
+        // Replies only ever go to whoever sent the email, never to everyone
+        // else it was sent to, or to a From that lists several people.
+        let from = addresses::parse_list(&email.from);
+        let prefer = self.reply_to.iter()
+            .find(|rule| route_matches(&rule.route, route))
+            .map_or(ReplyToChoice::From, |rule| rule.prefer);
+        let mailboxes = match (addresses::differing_reply_to(email, &from), prefer) {
+            (Some(reply_to), ReplyToChoice::ReplyTo) => reply_to,
+            (Some(reply_to), ReplyToChoice::Neither) => {
+                return Err(format!("Reply-To {} isn't From", addresses::join(&reply_to)));
+            },
+            _ => from,
+        };
+        let recipient = match &mailboxes[..] {
+            [sender] => sender.address.clone(),
+            mailboxes => {
+                return Err(format!("the reply would go to {} addresses", mailboxes.len()));
+            },
+        };
+        if self.unsubscribed.contains(&recipient) {
+            return Err(String::from("unsubscribed from auto-replies"));
+        }
+        if let Some(takeover) = &self.takeover {
+            match takeover.answered(&recipient) {
+                Ok(Some(at)) => {
+                    self.metrics.incr("replies_taken_over");
+                    return Err(format!("a human wrote to them at {}", at.to_rfc3339()));
+                },
+                Ok(None) => (),
+                Err(e) => error!("Unable to check whether anyone wrote to {}: {}", recipient, e),
+            }
+        }
+        if let Some(bounces) = &self.bounces {
+            match bounces.undeliverable(&self.outbox, &recipient) {
+                Ok(Some(reason)) => {
+                    self.metrics.incr("replies_undeliverable");
+                    return Err(reason);
+                },
+                Ok(None) => (),
+                Err(e) => error!("Unable to check the bounces of {}: {}", recipient, e),
+            }
+        }
+        Ok(recipient)
+    }
+
+    pub fn list_unsubscribe(&self, recipient: &str) -> Option<String> {
+        self.unsubscribe_links.as_ref().map(|links| links.header(recipient))
+    }
+
+    fn respond(
+        &self,
+        route: &str,
+        deadlines: &Deadlines,
+        template: &str,
+        job: &Job,
+        sender: Option<&SenderHistory>,
+    ) -> Result<Outcome, DeliveryError> {
+        let email = &job.email;
+        if !self.flags.for_route(route).auto_reply {
+            info!("Auto-replies are off for {}, not replying to {}", route, email.from);
+            self.notices.suppressed(route, &email.from, &email.subject, "auto-replies are off for this route");
+            return Ok(Outcome::Suppressed);
+        }
+        let message_id = email.get_message_id()?;
+        // Sent before, and Mailgun (or the queue) is retrying after a crash.
+        if job.replayed_by.is_none() {
+            match self.outbox.sent_for_job(&job.id, job.received_at) {
+                Ok(Some(sent)) => {
+                    info!("Job {} was already answered ({}), not replying again", job.id, sent.message_id);
+                    self.tracer.note(job, "already_replied", &sent);
+                    self.metrics.incr("deliveries_deduplicated");
+                    return Ok(Outcome::Replied);
+                },
+                Ok(None) => (),
+                Err(e) => error!("Unable to check the outbox for job {}: {}", job.id, e),
+            }
+        }
+        if let Err(e) = self.variants.observe(route, &email.from) {
+            error!("Unable to count a repeat email from {}: {}", email.from, e);
+        }
+        if let (Some(reputation), Some(sender)) = (&self.reputation, sender) {
+            let allowed = reputation.allows_reply(sender);
+            self.tracer.note(job, "reputation", &json!({ "score": sender.score(), "allows_reply": allowed }));
+            if !allowed {
+                info!("{} has a sender score of {}. Not replying.", email.from, sender.score());
+                self.notices.suppressed(route, &email.from, &email.subject, &format!("sender score {}", sender.score()));
+                return Ok(Outcome::Suppressed);
+            }
+        }
+        let recipient = match self.reply_recipient(route, email) {
+            Ok(recipient) => recipient,
+            Err(reason) => {
+                info!("Not replying to {}: {}", email.from, reason);
+                self.notices.suppressed(route, &email.from, &email.subject, &reason);
+                return Ok(Outcome::Suppressed);
+            },
+        };
+        let sender_mailbox = addresses::sender(&email.from);
+        if let Some(loops) = &self.loops {
+            let last_reply = self.outbox.auto_replies_to(&recipient, 1).unwrap_or_else(|e| {
+                error!("Unable to check the outbox for replies to {}: {}", recipient, e);
+                Vec::new()
+            });
+            match loops.check(email, &recipient, last_reply.first()) {
+                Verdict::Reply => (),
+                Verdict::Paused(reason) => {
+                    info!("Not replying to {}: {}", email.from, reason);
+                    self.notices.suppressed(route, &email.from, &email.subject, &reason);
+                    return Ok(Outcome::Suppressed);
+                },
+                Verdict::Broken(reason) => {
+                    warn!("Not replying to {}: {}", email.from, reason);
+                    self.metrics.incr("mail_loops");
+                    if let Some(channel) = loops.channel() {
+                        self.notices.post(channel.clone(), self.notices.render("mail_loop", &json!({
+                            "correspondent": recipient,
+                            "route": route,
+                            "reason": reason,
+                        })));
+                    }
+                    self.notices.suppressed(route, &email.from, &email.subject, &reason);
+                    return Ok(Outcome::Suppressed);
+                },
+            }
+        }
+        // Keyed on the address the reply goes to, which is the Reply-To when
+        // that's preferred, however the name is spelt this time.
+        let claimed = self.last_response_log.claim(&recipient);
+        self.tracer.note(job, "rate_limit", &json!({ "claimed": claimed }));
+        if claimed {
+            let template = sender
+                .and_then(|sender| self.reputation.as_ref()?.first_time_template(route, sender))
+                .or_else(|| self.variants.pick(route, &email.from))
+                .unwrap_or_else(|| String::from(template));
+            let mut variables = BTreeMap::new();
+            // For templates to greet them by name.
+            if let Some(name) = &sender_mailbox.name {
+                variables.insert(String::from("sender_name"), name.clone());
+            }
+            let feedback_link = self.feedback.as_ref().and_then(|feedback| {
+                let (id, url) = feedback.link(&template, &email.from)?;
+                variables.insert(String::from(feedback.variable()), url);
+                Some(id)
+            });
+            let list_unsubscribe = self.list_unsubscribe(&recipient);
+            let reply = EmailTemplate {
+                recipient,
+                subject: format!("Re: {}", email.subject),
+                template,
+                in_reply_to: message_id.clone(),
+                references: message_id,
+                variables,
+                idempotency_key: Some(job.id.clone()),
+                route: Some(String::from(route)),
+                list_unsubscribe,
+            };
+            let form: serde_json::Map<String, Value> = self.mailgun.form(&reply).into_iter()
+                .map(|(name, value)| (String::from(name), Value::String(value)))
+                .collect();
+            self.tracer.note(job, "mailgun", &form);
+            if self.echo.is_echoed(route) {
+                self.echo.write(route, "mailgun", Value::Object(form));
+                return Ok(Outcome::Echoed);
+            }
+            let sent_id = match self.mailgun.with_timeout(deadlines.mailgun).send_email(&reply) {
+                Ok(sent_id) => sent_id,
+                // Retrying wouldn't help, and the budget is there to drop these.
+                Err(MailgunError::OverBudget(message)) => {
+                    info!("Not replying to {}: {}", email.from, message);
+                    self.notices.suppressed(route, &email.from, &email.subject, &message);
+                    return Ok(Outcome::Suppressed);
+                },
+                // Only on staging, where it's expected.
+                Err(MailgunError::Sandbox(message)) => {
+                    warn!("Not replying to {}: {}", email.from, message);
+                    self.notices.suppressed(route, &email.from, &email.subject, &message);
+                    return Ok(Outcome::Suppressed);
+                },
+                Err(e) => return Err(e.into()),
+            };
+            if let Err(e) = self.variants.record_sent(route, &reply.recipient, &reply.template) {
+                error!("Unable to count the {} variant sent to {}: {}", reply.template, reply.recipient, e);
+            }
+            // Already sent, failing now would only get it sent again.
+            if let Err(e) = self.outbox.record(&OutboxEntry {
+                at: self.clock.now(),
+                recipient: reply.recipient,
+                template: reply.template,
+                subject: reply.subject,
+                message_id: sent_id,
+                sent_by: None,
+                feedback_id: feedback_link,
+                job_id: Some(job.id.clone()),
+            }) {
+                error!("Unable to record the reply to {} in the outbox: {}", email.from, e);
+            }
+            Ok(Outcome::Replied)
+        } else {
+            info!(
+                "Already responded to {} within the past {} minutes. Skipping.",
+                email.from,
+                self.last_response_log.time_between_responses.0
+            );
+            let last_reply = self.outbox.auto_replies_to(&recipient, 1).unwrap_or_else(|e| {
+                error!("Unable to check the outbox for replies to {}: {}", recipient, e);
+                Vec::new()
+            });
+            if let Err(e) = self.suppressions.record(&recipient, route, template, last_reply.first().map(|entry| entry.at)) {
+                error!("Unable to record the suppressed reply to {}: {}", recipient, e);
+            }
+            self.notices.suppressed(
+                route,
+                &email.from,
+                &email.subject,
+                &format!("already replied within {} minutes", self.last_response_log.time_between_responses.0),
+            );
+            self.count_against_sender(job, Outcome::Suppressed);
+            Ok(Outcome::Suppressed)
+        }
+    }
+
+    fn forward_to_slack(
+        &self,
+        route: &str,
+        deadlines: &Deadlines,
+        channel_id: &str,
+        job: &Job,
+        sender: Option<&SenderHistory>,
+    ) -> Result<Outcome, DeliveryError> {
+        let email = &job.email;
+        let auth = AuthResults::from_email(email, &self.trusted_authserv_ids);
+        let quarantine = self.quarantine.as_ref()
+            .filter(|quarantine| job.released_by.is_none() && quarantine.holds(route, &auth));
+        let (channel_id, meant_for) = match quarantine {
+            Some(quarantine) => (&quarantine.channel[..], Some(channel_id)),
+            None => (channel_id, None),
+        };
+        if let Some(meant_for) = meant_for {
+            info!("Job {} passed none of SPF, DKIM and DMARC, quarantining it rather than posting to {}", job.id, meant_for);
+            self.tracer.note(job, "quarantine", &json!({ "channel": channel_id, "meant_for": meant_for }));
+            self.metrics.incr("emails_quarantined");
+        }
+        let default_formatting = Formatting::default();
+        let formatting = formatting::for_route(&self.formatting, route).unwrap_or(&default_formatting);
+        let mut body_plain = formatting.normalize(&email.body_plain);
+        // Keep the Slack message short, the link has everything.
+        if let Some(links) = &self.links {
+            if body_plain.chars().count() > links.config.preview_chars {
+                body_plain = body_plain.chars().take(links.config.preview_chars).collect();
+                body_plain.push('…');
+            }
+        }
+        // Who else it went to, when it wasn't only the route's address.
+        let (to, cc) = (addresses::header(email, "To"), addresses::header(email, "Cc"));
+        let recipients = if cc.is_empty() && to.len() <= 1 {
+            None
+        } else if cc.is_empty() {
+            Some(format!("To: {}", addresses::join(&to)))
+        } else {
+            Some(format!("To: {}, Cc: {}", addresses::join(&to), addresses::join(&cc)))
+        };
+        // Before the links are defanged.
+        let threats = self.threat_intel.as_ref()
+            .filter(|_| self.flags.for_route(route).enrich)
+            .map(|threat_intel| threat_intel.check(email))
+            .unwrap_or_default();
+        self.tracer.note(job, "threats", &threats);
+        if !threats.is_empty() {
+            self.metrics.incr("emails_flagged");
+        }
+        let link_safety = LinkSafety::for_channel(&self.link_safety, channel_id);
+        let subject = match link_safety {
+            Some(link_safety) => {
+                body_plain = link_safety.body(&body_plain);
+                link_safety.apply(&email.subject)
+            },
+            None => email.subject.clone(),
+        };
+        let text = self.notices.render("email", &json!({
+            "subject": subject,
+            "from": email.from,
+            "route": route,
+            "auth": auth.summary(),
+            "smime": job.smime.as_ref().map(|smime| smime.summary()),
+            "sender": sender.map(|sender| sender.summary()),
+            "link": self.links.as_ref().map(|links| links.url(&job.id)),
+            "to": addresses::join(&to),
+            "cc": addresses::join(&cc),
+            "recipients": recipients,
+            "threats": if threats.is_empty() { None } else { Some(Finding::summary(&threats)) },
+            "reply_to": addresses::differing_reply_to(email, &addresses::parse_list(&email.from))
+                .map(|reply_to| addresses::join(&reply_to)),
+        }));
+        // Replies to an email we already forwarded go into its thread.
+        let existing_thread = self.threads.find(channel_id, email);
+        let identity = self.identities.iter().find(|identity| route_matches(&identity.route, route));
+        let mut blocks = match meant_for {
+            Some(meant_for) => quarantine::blocks(&job.id, meant_for),
+            None => Vec::new(),
+        };
+        blocks.push(json!({
+            "type": "section",
+            "text": { "type": "mrkdwn", "text": text },
+        }));
+        blocks.extend(body_blocks(&body_plain));
+        blocks.push(json!({
+            "type": "context",
+            "elements": [{
+                "type": "mrkdwn",
+                "text": format!("from: {}, limail id: {}", addresses::sender(&email.from).display(), job.id),
+            }],
+        }));
+        // Not before someone has vouched for it.
+        let canned = self.canned_replies.iter().find(|canned| route_matches(&canned.route, route));
+        if let (Some(canned), None) = (canned, meant_for) {
+            blocks.push(canned::menu(&job.id, &canned.replies));
+        }
+        if let (Action::RespondAndForward { .. }, Some(seconds)) = (&job.action, self.delays.delay_for(&job.action.route())) {
+            blocks.push(delays::button(&job.id, seconds));
+        }
+        // One post, so there's never a header without its body. `text` is
+        // what notifications show.
+        let message = SlackMessage{
+            channel: String::from(channel_id),
+            text,
+            thread_ts: existing_thread.clone(),
+            as_user: true,
+            username: None,
+            icon_emoji: None,
+            icon_url: None,
+            blocks: Some(Value::Array(blocks)),
+            unfurl_links: None,
+            unfurl_media: None,
+        }.with_identity(identity);
+        let message = match link_safety {
+            Some(link_safety) => link_safety.unfurl(message),
+            None => message,
+        };
+
+        self.tracer.note(job, "slack", &message);
+        if self.echo.is_echoed(route) {
+            self.echo.write(route, "slack", serde_json::to_value(&message).unwrap_or_default());
+            return Ok(Outcome::Echoed);
+        }
+
+        // A retry of a job that got some of the way, after a crash or a
+        // failed step say. Whatever was done then is in the archive, and
+        // isn't done again. Replays are meant to do it all again.
+        let archived = match &job.replayed_by {
+            Some(_) => None,
+            None => self.archive.get(&job.id).ok().and_then(|archived| archived),
+        };
+        let is_done = |step: &str| archived.as_ref()
+            .map_or(false, |archived| archived.completed_steps.iter().any(|done| done == step));
+        let fallback = self.fallback_channel.as_ref().map(|channel| &channel[..]).filter(|channel| *channel != channel_id);
+        let already_posted = archived.as_ref()
+            .and_then(|archived| archived.slack_messages.iter()
+                .find(|message| message.channel == channel_id || Some(&message.channel[..]) == fallback)
+                .cloned());
+
+        let posted = match already_posted {
+            Some(posted) => {
+                info!("Job {} was already forwarded to {}, not posting it again", job.id, posted.channel);
+                self.metrics.incr("deliveries_deduplicated");
+                posted
+            },
+            None => {
+                let slack = self.slack.with_timeout(deadlines.slack);
+                let (posted_to, response) = match (slack.send_message(&message), fallback) {
+                    (Err(SlackError::ChannelUnavailable(reason)), Some(fallback)) => {
+                        warn!("{}, forwarding job {} to {} instead", reason, job.id, fallback);
+                        self.metrics.incr("emails_rerouted");
+                        (fallback, slack.send_message(&rerouted(message, fallback, &reason))?)
+                    },
+                    (result, _) => (channel_id, result?),
+                };
+                let posted = ArchivedSlackMessage { channel: String::from(posted_to), ts: response.ts };
+                // Already posted, failing now would only get it posted again.
+                if let Err(e) = self.archive.record_slack_messages(job, vec![posted.clone()]) {
+                    error!("Unable to archive the Slack messages for job {}: {}", job.id, e);
+                }
+                posted
+            },
+        };
+        // The thread in the channel it was meant for is no use elsewhere.
+        let thread_ts = match existing_thread {
+            Some(thread_ts) if posted.channel == channel_id => thread_ts,
+            _ => posted.ts.clone(),
+        };
+        let thread_channel = posted.channel.clone();
+
+        // The rest fail the job, so that the retry finishes them.
+        if let (Some(handling), false) = (&self.handling, is_done("track")) {
+            handling.track(&job.id, UnhandledEmail {
+                forwarded_at: handling.now(),
+                route: String::from(route),
+                subject: email.subject.clone(),
+                from: email.from.clone(),
+                messages: vec![posted],
+                first_reaction_at: None,
+                reminded: false,
+            }).map_err(|e| {
+                error!("Unable to track job {} as unhandled: {}", job.id, e);
+                e
+            })?;
+            self.archive.record_step(job, "track")?;
+        }
+        if !is_done("thread") {
+            self.threads.record(&thread_channel, email, &thread_ts).map_err(|e| {
+                error!("Unable to remember the Slack thread for job {}: {}", job.id, e);
+                e
+            })?;
+            self.archive.record_step(job, "thread")?;
+        }
+        Ok(Outcome::Forwarded)
+    }
+}
@@ -0,0 +1,162 @@
+use serde::Deserialize;
+use warp::http::StatusCode;
+use warp::Rejection;
+
+use crate::mailgun::MailgunError;
+use crate::multipart::MultipartError;
+use crate::pipeline::{route_matches, DeliveryError};
+use crate::queue::QueueError;
+use crate::store::StoreError;
+
+// What Mailgun should do about a webhook we couldn't handle. It retries
+// anything but a 200 or a 406, for up to 8 hours.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Disposition {
+    // 200, as if it had been handled.
+    Accept,
+    // 406, never retry.
+    Reject,
+    // 503, retry later.
+    Retry,
+}
+
+impl Disposition {
+    pub fn status(self) -> StatusCode {
+        match self {
+            Disposition::Accept => StatusCode::OK,
+            Disposition::Reject => StatusCode::NOT_ACCEPTABLE,
+            Disposition::Retry => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+// The body of the 200 for a handled webhook. Mailgun ignores it, but keeps
+// it in its webhook logs: json says what was done, text is the bare
+// message ("Message Processed" and so on) answered before.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SuccessFormat {
+    Text,
+    Json,
+}
+
+impl Default for SuccessFormat {
+    fn default() -> SuccessFormat {
+        SuccessFormat::Json
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    // Bad signature, or a body we can't make sense of. Retrying won't help.
+    Invalid,
+    // Mailgun or Slack refused or couldn't be reached.
+    Delivery,
+    // The route's handler deadline passed.
+    Deadline,
+    // Archiving or queueing failed on our side.
+    Storage,
+}
+
+fn default_invalid() -> Disposition {
+    Disposition::Reject
+}
+
+fn default_retry() -> Disposition {
+    Disposition::Retry
+}
+
+#[derive(Deserialize, Clone)]
+pub struct RouteResponses {
+    pub route: String,
+    pub invalid: Option<Disposition>,
+    pub delivery: Option<Disposition>,
+    pub deadline: Option<Disposition>,
+    pub storage: Option<Disposition>,
+    pub success: Option<SuccessFormat>,
+}
+
+// How each kind of webhook failure is answered. The first matching entry
+// in `routes` overrides the defaults. Handled emails always get a 200, in
+// the success format.
+#[derive(Deserialize, Clone)]
+pub struct ResponsePolicy {
+    #[serde(default = "default_invalid")]
+    pub invalid: Disposition,
+    #[serde(default = "default_retry")]
+    pub delivery: Disposition,
+    #[serde(default = "default_retry")]
+    pub deadline: Disposition,
+    #[serde(default = "default_retry")]
+    pub storage: Disposition,
+    #[serde(default)]
+    pub success: SuccessFormat,
+    #[serde(default)]
+    pub routes: Vec<RouteResponses>,
+}
+
+impl Default for ResponsePolicy {
+    fn default() -> ResponsePolicy {
+        ResponsePolicy {
+            invalid: default_invalid(),
+            delivery: default_retry(),
+            deadline: default_retry(),
+            storage: default_retry(),
+            success: SuccessFormat::default(),
+            routes: Vec::new(),
+        }
+    }
+}
+
+// The failures a webhook handler can run into, and what went wrong. Anything
+// else is left for the usual rejection handling.
+pub fn classify(err: &Rejection) -> Option<(Failure, String)> {
+    if let Some(err) = err.find_cause::<MailgunError>() {
+        Some(match err {
+            MailgunError::MailgunError(_) | MailgunError::Maintenance(_) => (Failure::Delivery, err.to_string()),
+            _ => (Failure::Invalid, err.to_string()),
+        })
+    } else if let Some(err) = err.find_cause::<DeliveryError>() {
+        Some(match err {
+            DeliveryError::Mailgun(MailgunError::MailgunError(_))
+            | DeliveryError::Mailgun(MailgunError::Maintenance(_))
+            | DeliveryError::Slack(_) => {
+                (Failure::Delivery, err.to_string())
+            },
+            DeliveryError::Mailgun(_) => (Failure::Invalid, err.to_string()),
+            DeliveryError::DeadlineExceeded(_) => (Failure::Deadline, err.to_string()),
+            DeliveryError::Storage(_) => (Failure::Storage, err.to_string()),
+        })
+    } else if let Some(err) = err.find_cause::<MultipartError>() {
+        Some((Failure::Invalid, err.to_string()))
+    } else if let Some(err) = err.find_cause::<StoreError>() {
+        Some((Failure::Storage, err.to_string()))
+    } else if let Some(err) = err.find_cause::<QueueError>() {
+        Some((Failure::Storage, err.to_string()))
+    } else {
+        None
+    }
+}
+
+impl ResponsePolicy {
+    pub fn disposition(&self, route: &str, failure: Failure) -> Disposition {
+        let overrides = self.routes.iter().find(|r| route_matches(&r.route, route));
+        let pick = |default: Disposition, f: fn(&RouteResponses) -> Option<Disposition>| {
+            overrides.and_then(f).unwrap_or(default)
+        };
+        match failure {
+            Failure::Invalid => pick(self.invalid, |r| r.invalid),
+            Failure::Delivery => pick(self.delivery, |r| r.delivery),
+            Failure::Deadline => pick(self.deadline, |r| r.deadline),
+            Failure::Storage => pick(self.storage, |r| r.storage),
+        }
+    }
+
+    pub fn success_format(&self, route: &str) -> SuccessFormat {
+        self.routes.iter()
+            .find(|r| route_matches(&r.route, route))
+            .and_then(|r| r.success)
+            .unwrap_or(self.success)
+    }
+}
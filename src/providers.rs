@@ -0,0 +1,91 @@
+use serde_json::{json, Value};
+
+use crate::mailgun::{MailgunEmailReceived, MailgunError};
+use crate::multipart::Part;
+
+fn invalid(message: &str) -> MailgunError {
+    MailgunError::JsonError(String::from(message))
+}
+
+// "Name: value" lines, continued on lines starting with whitespace, as the
+// [name, value] pairs of Mailgun's message-headers.
+fn header_pairs(block: &str) -> String {
+    let mut pairs: Vec<(String, String)> = Vec::new();
+    for line in block.lines() {
+        if line.starts_with(' ') || line.starts_with('\t') {
+            if let Some((_, value)) = pairs.last_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+            continue;
+        }
+        let mut header = line.splitn(2, ':');
+        if let (Some(name), Some(value)) = (header.next(), header.next()) {
+            pairs.push((String::from(name.trim()), String::from(value.trim())));
+        }
+    }
+    Value::Array(pairs.into_iter().map(|(name, value)| json!([name, value])).collect()).to_string()
+}
+
+// The fields SendGrid's Inbound Parse posts: from, subject, text, html,
+// headers (as they came) and envelope (JSON, with the envelope sender).
+// SendGrid signs the body rather than sending a token, see signatures.rs.
+pub fn sendgrid_email(parts: &[Part], received_at: i64) -> Result<MailgunEmailReceived, MailgunError> {
+    let field = |name: &str| parts.iter()
+        .find(|part| part.filename.is_none() && part.name == name)
+        .and_then(|part| String::from_utf8(part.data.clone()).ok());
+    let from = field("from").ok_or_else(|| invalid("The SendGrid webhook has no from"))?;
+    let envelope: Value = field("envelope").and_then(|envelope| serde_json::from_str(&envelope).ok()).unwrap_or_default();
+    Ok(MailgunEmailReceived {
+        sender: envelope["from"].as_str().map(String::from).unwrap_or_else(|| from.clone()),
+        from,
+        subject: field("subject").unwrap_or_default(),
+        body_plain: field("text").unwrap_or_default(),
+        body_html: field("html"),
+        timestamp: received_at,
+        token: String::new(),
+        signature: String::new(),
+        message_headers: header_pairs(&field("headers").unwrap_or_default()),
+    })
+}
+
+// An Amazon SES receipt published to SNS: the notification's Message is
+// itself JSON, with the headers in mail and the raw email in content (when
+// the SNS action includes it).
+pub fn sns_email(notification: &Value, received_at: i64) -> Result<MailgunEmailReceived, MailgunError> {
+    match notification["Type"].as_str() {
+        Some("Notification") => (),
+        Some("SubscriptionConfirmation") => return Err(invalid(&format!(
+            "This is an SNS subscription confirmation, confirm it at {}",
+            notification["SubscribeURL"].as_str().unwrap_or("its SubscribeURL")
+        ))),
+        _ => return Err(invalid("Not an SNS notification")),
+    }
+    let message: Value = notification["Message"].as_str()
+        .and_then(|message| serde_json::from_str(message).ok())
+        .ok_or_else(|| invalid("The SNS notification has no SES message"))?;
+    let mail = &message["mail"];
+    let common = &mail["commonHeaders"];
+    let from = common["from"][0].as_str().ok_or_else(|| invalid("The SES message has no from"))?;
+    let pairs: Vec<Value> = mail["headers"].as_array()
+        .map(|headers| headers.iter()
+            .filter_map(|header| Some(json!([header["name"].as_str()?, header["value"].as_str()?])))
+            .collect())
+        .unwrap_or_default();
+    // What's after the headers, which transfer.rs decodes if need be.
+    let content = message["content"].as_str().unwrap_or("");
+    let body = content.splitn(2, "\r\n\r\n").nth(1)
+        .or_else(|| content.splitn(2, "\n\n").nth(1))
+        .unwrap_or("");
+    Ok(MailgunEmailReceived {
+        sender: mail["source"].as_str().map(String::from).unwrap_or_else(|| String::from(from)),
+        from: String::from(from),
+        subject: common["subject"].as_str().map(String::from).unwrap_or_default(),
+        body_plain: String::from(body),
+        body_html: None,
+        timestamp: received_at,
+        token: String::new(),
+        signature: String::new(),
+        message_headers: Value::Array(pairs).to_string(),
+    })
+}
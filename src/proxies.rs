@@ -0,0 +1,158 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use hyper::HeaderMap;
+use serde::Deserialize;
+
+// The header a proxy names the client in.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClientHeader {
+    XForwardedFor,
+    Forwarded,
+    CfConnectingIp,
+}
+
+impl ClientHeader {
+    fn name(self) -> &'static str {
+        match self {
+            ClientHeader::XForwardedFor => "x-forwarded-for",
+            ClientHeader::Forwarded => "forwarded",
+            ClientHeader::CfConnectingIp => "cf-connecting-ip",
+        }
+    }
+}
+
+// Connections from these addresses (exact, or networks like 10.0.0.0/8 or
+// 2001:db8::/32) are a proxy's, and the client is whoever header names:
+// the last address in X-Forwarded-For or Forwarded that isn't one of the
+// proxies, or CF-Connecting-IP. Anyone else's header is ignored, so it
+// can't be spoofed by connecting directly.
+#[derive(Deserialize, Clone)]
+pub struct ProxyConfig {
+    pub addresses: Vec<String>,
+    pub header: ClientHeader,
+}
+
+#[derive(Clone, Copy)]
+struct Network {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    fn parse(network: &str) -> Result<Network, String> {
+        let invalid = || format!("{:?} isn't an IP address or a network like 10.0.0.0/8", network);
+        let (address, prefix) = match network.find('/') {
+            Some(slash) => (&network[..slash], Some(&network[slash + 1..])),
+            None => (network, None),
+        };
+        let address: IpAddr = address.trim().parse().map_err(|_| invalid())?;
+        let bits = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse::<u8>().ok().filter(|prefix| *prefix <= bits).ok_or_else(invalid)?,
+            None => bits,
+        };
+        Ok(Network { address: unmapped(address), prefix })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.address, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::max_value().checked_shl(32 - u32::from(self.prefix)).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            },
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::max_value().checked_shl(128 - u32::from(self.prefix)).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            },
+            _ => false,
+        }
+    }
+}
+
+// IPv4 addresses written as ::ffff:a.b.c.d, e.g. by a dual-stack socket,
+// as the IPv4 addresses they are.
+pub fn unmapped(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ip) => match ip.segments() {
+            [0, 0, 0, 0, 0, 0xffff, high, low] => {
+                IpAddr::V4(Ipv4Addr::new((high >> 8) as u8, high as u8, (low >> 8) as u8, low as u8))
+            },
+            _ => IpAddr::V6(ip),
+        },
+        ip => ip,
+    }
+}
+
+// A node as X-Forwarded-For or Forwarded's for= write it: bare, quoted,
+// with a port, IPv6 in brackets. None for "unknown" or an obfuscated name.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Ok(ip) = node.parse::<IpAddr>() {
+        return Some(unmapped(ip));
+    }
+    if node.starts_with('[') {
+        let end = node.find(']')?;
+        return node[1..end].parse().ok().map(unmapped);
+    }
+    node.parse::<SocketAddr>().ok().map(|address| unmapped(address.ip()))
+}
+
+#[derive(Clone)]
+pub struct TrustedProxies {
+    networks: Vec<Network>,
+    header: ClientHeader,
+}
+
+impl TrustedProxies {
+    pub fn new(config: &ProxyConfig) -> Result<TrustedProxies, String> {
+        Ok(TrustedProxies {
+            networks: config.addresses.iter().map(|network| Network::parse(network)).collect::<Result<_, _>>()?,
+            header: config.header,
+        })
+    }
+
+    fn trusted(&self, ip: IpAddr) -> bool {
+        self.networks.iter().any(|network| network.contains(ip))
+    }
+
+    // The nodes the request went through, client first, with None for the
+    // ones that can't be told.
+    fn chain(&self, headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+        let values = headers.get_all(self.header.name()).iter().filter_map(|value| value.to_str().ok());
+        match self.header {
+            ClientHeader::XForwardedFor => values.flat_map(|value| value.split(',')).map(parse_node).collect(),
+            ClientHeader::Forwarded => values
+                .flat_map(|value| value.split(','))
+                .filter_map(|element| element.split(';').find_map(|pair| {
+                    let pair = pair.trim();
+                    match pair.find('=') {
+                        Some(equals) if pair[..equals].eq_ignore_ascii_case("for") => Some(parse_node(&pair[equals + 1..])),
+                        _ => None,
+                    }
+                }))
+                .collect(),
+            ClientHeader::CfConnectingIp => values.take(1).map(parse_node).collect(),
+        }
+    }
+
+    // Who's on the other end of a connection from peer.
+    pub fn client(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let mut client = peer;
+        if !self.trusted(peer) {
+            return client;
+        }
+        for node in self.chain(headers).into_iter().rev() {
+            match node {
+                Some(ip) => {
+                    client = ip;
+                    if !self.trusted(ip) {
+                        break;
+                    }
+                },
+                None => break,
+            }
+        }
+        client
+    }
+}
@@ -0,0 +1,196 @@
+use std::error::Error as StdError;
+use std::fmt::{self, Display};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use amiquip::{Connection, Exchange, Publish};
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+
+use crate::mailgun::MailgunEmailReceived;
+use crate::metrics::Metrics;
+
+const NATS_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Publishes verified inbound emails to a message broker so other services
+// can consume them. Either a nats:// or an amqp(s):// url.
+#[derive(Deserialize, Clone)]
+pub struct PublishConfig {
+    pub url: String,
+    // NATS subject, or AMQP routing key.
+    pub subject: String,
+    // AMQP only, the default exchange when empty.
+    #[serde(default)]
+    pub exchange: String,
+}
+
+#[derive(Debug)]
+pub enum PublishError {
+    NatsError(String),
+    AmqpError(String),
+}
+impl std::convert::From<amiquip::Error> for PublishError {
+    fn from(error: amiquip::Error) -> Self {
+        PublishError::AmqpError(format!("AMQP error: {}", error))
+    }
+}
+
+impl Display for PublishError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            PublishError::NatsError(s) => s,
+            PublishError::AmqpError(s) => s,
+        })
+    }
+}
+impl StdError for PublishError {}
+
+#[derive(Serialize, Clone)]
+pub struct InboundEvent {
+    pub route: String,
+    pub received_at: DateTime<Utc>,
+    pub sender: String,
+    pub from: String,
+    pub subject: String,
+    pub body_plain: String,
+    pub message_id: Option<String>,
+}
+
+impl InboundEvent {
+    pub fn new(route: &str, email: &MailgunEmailReceived) -> InboundEvent {
+        InboundEvent {
+            route: String::from(route),
+            received_at: Utc::now(),
+            sender: email.sender.clone(),
+            from: email.from.clone(),
+            subject: email.subject.clone(),
+            body_plain: email.body_plain.clone(),
+            message_id: email.get_message_id().ok(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Publisher {
+    config: Option<Arc<PublishConfig>>,
+}
+
+fn nats_error(error: io::Error) -> PublishError {
+    PublishError::NatsError(format!("NATS error: {}", error))
+}
+
+// Just enough of the NATS text protocol to publish a single message:
+// read INFO, CONNECT, PUB, then PING and wait for the PONG so we know the
+// server has processed everything before we hang up.
+fn publish_nats(url: &str, subject: &str, payload: &[u8]) -> Result<(), PublishError> {
+    let without_scheme = url.trim_start_matches("nats://");
+    let (credentials, host) = match without_scheme.rfind('@') {
+        Some(at) => (Some(&without_scheme[..at]), &without_scheme[at + 1..]),
+        None => (None, without_scheme),
+    };
+    let host = if host.contains(':') { String::from(host) } else { format!("{}:4222", host) };
+    let address = host.to_socket_addrs().map_err(nats_error)?
+        .next()
+        .ok_or_else(|| PublishError::NatsError(format!("Unable to resolve {}", host)))?;
+
+    let stream = TcpStream::connect_timeout(&address, NATS_TIMEOUT).map_err(nats_error)?;
+    stream.set_read_timeout(Some(NATS_TIMEOUT)).map_err(nats_error)?;
+    stream.set_write_timeout(Some(NATS_TIMEOUT)).map_err(nats_error)?;
+    let mut reader = BufReader::new(stream.try_clone().map_err(nats_error)?);
+    let mut writer = stream;
+
+    let mut line = String::new();
+    reader.read_line(&mut line).map_err(nats_error)?;
+    if !line.starts_with("INFO") {
+        return Err(PublishError::NatsError(format!("Unexpected NATS greeting: {}", line.trim())));
+    }
+
+    let connect = match credentials.map(|c| c.splitn(2, ':').collect::<Vec<&str>>()) {
+        Some(ref parts) if parts.len() == 2 => serde_json::json!({
+            "verbose": false, "pedantic": false, "name": "limail",
+            "user": parts[0], "pass": parts[1],
+        }),
+        Some(parts) => serde_json::json!({
+            "verbose": false, "pedantic": false, "name": "limail",
+            "auth_token": parts[0],
+        }),
+        None => serde_json::json!({ "verbose": false, "pedantic": false, "name": "limail" }),
+    };
+    let mut message = format!("CONNECT {}\r\nPUB {} {}\r\n", connect, subject, payload.len()).into_bytes();
+    message.extend_from_slice(payload);
+    message.extend_from_slice(b"\r\nPING\r\n");
+    writer.write_all(&message).map_err(nats_error)?;
+
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).map_err(nats_error)? == 0 {
+            return Err(PublishError::NatsError(String::from("NATS server closed the connection")));
+        }
+        if line.starts_with("PONG") {
+            return Ok(());
+        }
+        if line.starts_with("-ERR") {
+            return Err(PublishError::NatsError(format!("NATS server replied: {}", line.trim())));
+        }
+    }
+}
+
+fn publish_amqp(config: &PublishConfig, payload: &[u8]) -> Result<(), PublishError> {
+    let mut connection = if config.url.starts_with("amqps://") {
+        Connection::open(&config.url)?
+    } else {
+        Connection::insecure_open(&config.url)?
+    };
+    let channel = connection.open_channel(None)?;
+    let exchange = if config.exchange.is_empty() {
+        Exchange::direct(&channel)
+    } else {
+        channel.exchange_declare_passive(config.exchange.clone())?
+    };
+    exchange.publish(Publish::new(payload, config.subject.clone()))?;
+    connection.close()?;
+    Ok(())
+}
+
+impl Publisher {
+    pub fn new(config: Option<PublishConfig>) -> Publisher {
+        Publisher {
+            config: config.map(Arc::new),
+        }
+    }
+
+    pub fn publish(&self, event: &InboundEvent) -> Result<(), PublishError> {
+        let config = match &self.config {
+            Some(config) => config,
+            None => return Ok(()),
+        };
+        let payload = serde_json::to_vec(event)
+            .map_err(|e| PublishError::NatsError(format!("Unable to serialize event: {}", e)))?;
+        if config.url.starts_with("nats://") {
+            publish_nats(&config.url, &config.subject, &payload)
+        } else {
+            publish_amqp(config, &payload)
+        }
+    }
+
+    // Consumers of these events are best effort, a broker outage must never
+    // hold up (or fail) the webhook.
+    pub fn publish_in_background(&self, event: InboundEvent, metrics: Metrics) {
+        if self.config.is_none() {
+            return;
+        }
+        let publisher = self.clone();
+        thread::spawn(move || {
+            match publisher.publish(&event) {
+                Ok(()) => metrics.incr("events_published"),
+                Err(e) => {
+                    error!("Unable to publish inbound email event: {}", e);
+                    metrics.incr("errors_publish");
+                },
+            }
+        });
+    }
+}
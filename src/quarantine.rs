@@ -0,0 +1,71 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::authresults::{AuthResults, Verdict};
+use crate::pipeline::route_matches;
+
+pub const ACTION_ID: &str = "quarantine_release";
+
+// Forwards on routes (exact, or a prefix ending in *, all of them when
+// empty) of emails passing none of SPF, DKIM and DMARC go to channel
+// instead of their own, with a Release button that posts them where they were
+// going once a mod vouches for them. Pressing it takes the same Slack
+// users as the canned replies, see [slash_command].
+#[derive(Deserialize, Clone)]
+pub struct QuarantineConfig {
+    pub channel: String,
+    #[serde(default)]
+    pub routes: Vec<String>,
+}
+
+impl QuarantineConfig {
+    pub fn holds(&self, route: &str, auth: &AuthResults) -> bool {
+        // Results that are missing, or not from a trusted server, are as
+        // good as failures.
+        let passed = auth.trusted && [auth.spf, auth.dkim, auth.dmarc].iter().any(|verdict| *verdict == Verdict::Pass);
+        !passed && (self.routes.is_empty() || self.routes.iter().any(|pattern| route_matches(pattern, route)))
+    }
+}
+
+// Like the canned replies, the block id carries the job.
+pub fn block_id(job_id: &str) -> String {
+    format!("{}:{}", ACTION_ID, job_id)
+}
+
+pub fn job_id(block_id: &str) -> Option<&str> {
+    let prefix = format!("{}:", ACTION_ID);
+    if block_id.starts_with(&prefix) {
+        Some(&block_id[prefix.len()..])
+    } else {
+        None
+    }
+}
+
+// What heads a quarantined forward, ahead of the email itself.
+pub fn blocks(job_id: &str, channel: &str) -> Vec<Value> {
+    vec![
+        json!({
+            "type": "section",
+            "text": {
+                "type": "mrkdwn",
+                "text": format!(":biohazard_sign: Quarantined: this email passed none of SPF, DKIM and DMARC. It was for <#{}>.", channel),
+            },
+        }),
+        json!({
+            "type": "actions",
+            "block_id": block_id(job_id),
+            "elements": [{
+                "type": "button",
+                "action_id": ACTION_ID,
+                "text": { "type": "plain_text", "text": "Release" },
+                "value": channel,
+                "confirm": {
+                    "title": { "type": "plain_text", "text": "Release this email?" },
+                    "text": { "type": "plain_text", "text": "It's posted to the channel it was for, as if it had passed." },
+                    "confirm": { "type": "plain_text", "text": "Release" },
+                    "deny": { "type": "plain_text", "text": "Cancel" },
+                },
+            }],
+        }),
+    ]
+}
@@ -0,0 +1,411 @@
+use std::error::Error as StdError;
+use std::fmt::{self, Display};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use redis::Value;
+use serde::Deserialize;
+use warp::Rejection;
+
+use crate::pipeline::{Job, Pipeline};
+
+const READ_BLOCK_MS: usize = 5000;
+const READ_BATCH: usize = 10;
+
+fn default_stream() -> String {
+    String::from("limail:jobs")
+}
+
+fn default_group() -> String {
+    String::from("limail-workers")
+}
+
+fn default_claim_after_minutes() -> u64 {
+    5
+}
+
+fn default_max_deliveries() -> u64 {
+    10
+}
+
+fn default_dead_letter_stream() -> String {
+    String::from("limail:jobs:dead")
+}
+
+#[derive(Deserialize, Clone)]
+pub struct QueueConfig {
+    pub redis_url: String,
+    #[serde(default = "default_stream")]
+    pub stream: String,
+    #[serde(default = "default_group")]
+    pub group: String,
+    // Jobs a worker hasn't acknowledged after this long (it crashed, or the
+    // delivery failed) are picked up again by another worker.
+    #[serde(default = "default_claim_after_minutes")]
+    pub claim_after_minutes: u64,
+    // After that many deliveries a job is moved to dead_letter_stream, with
+    // why, for someone to look at and replay rather than being lost.
+    #[serde(default = "default_max_deliveries")]
+    pub max_deliveries: u64,
+    #[serde(default = "default_dead_letter_stream")]
+    pub dead_letter_stream: String,
+}
+
+#[derive(Debug)]
+pub enum QueueError {
+    RedisError(String),
+    JsonError(String),
+}
+impl std::convert::From<redis::RedisError> for QueueError {
+    fn from(error: redis::RedisError) -> Self {
+        QueueError::RedisError(format!("Queue unavailable: {}", error))
+    }
+}
+impl std::convert::From<serde_json::Error> for QueueError {
+    fn from(error: serde_json::Error) -> Self {
+        QueueError::JsonError(format!("Unable to serialize job: {}", error))
+    }
+}
+impl std::convert::From<QueueError> for Rejection {
+    fn from(err: QueueError) -> Rejection {
+        warp::reject::custom(err)
+    }
+}
+
+impl Display for QueueError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            QueueError::RedisError(s) => s,
+            QueueError::JsonError(s) => s,
+        })
+    }
+}
+impl StdError for QueueError {}
+
+struct Entry {
+    id: String,
+    payload: Option<String>,
+}
+
+fn as_string(value: &Value) -> Option<String> {
+    match value {
+        Value::Data(bytes) => String::from_utf8(bytes.clone()).ok(),
+        Value::Status(s) => Some(s.clone()),
+        _ => None
+    }
+}
+
+// [[id, [field, value, ...]], ...] as returned by XRANGE and XCLAIM.
+fn parse_entries(value: &Value) -> Vec<Entry> {
+    let entries = match value {
+        Value::Bulk(entries) => entries,
+        _ => return Vec::new(),
+    };
+    entries.iter().filter_map(|entry| match entry {
+        Value::Bulk(parts) => {
+            let id = parts.get(0).and_then(as_string)?;
+            let payload = match parts.get(1) {
+                Some(Value::Bulk(fields)) => fields.chunks(2)
+                    .find(|pair| pair.len() == 2 && as_string(&pair[0]).as_ref().map(|f| &f[..]) == Some("job"))
+                    .and_then(|pair| as_string(&pair[1])),
+                _ => None
+            };
+            Some(Entry { id, payload })
+        },
+        _ => None
+    }).collect()
+}
+
+// [[stream, entries]] as returned by XREADGROUP, or nil on timeout.
+fn parse_read_reply(value: &Value) -> Vec<Entry> {
+    match value {
+        Value::Bulk(streams) => streams.iter().flat_map(|stream| match stream {
+            Value::Bulk(parts) if parts.len() == 2 => parse_entries(&parts[1]),
+            _ => Vec::new(),
+        }).collect(),
+        _ => Vec::new()
+    }
+}
+
+// Jobs live in a redis stream, consumed by a consumer group so every job is
+// handled by exactly one worker and stays pending until it is acknowledged.
+#[derive(Clone)]
+pub struct RedisQueue {
+    client: redis::Client,
+    config: Arc<QueueConfig>,
+}
+
+impl RedisQueue {
+    pub fn connect(config: QueueConfig) -> Result<RedisQueue, QueueError> {
+        let queue = RedisQueue {
+            client: redis::Client::open(&config.redis_url[..])?,
+            config: Arc::new(config),
+        };
+        queue.ensure_group()?;
+        Ok(queue)
+    }
+
+    pub fn client(&self) -> redis::Client {
+        self.client.clone()
+    }
+
+    fn ensure_group(&self) -> Result<(), QueueError> {
+        let mut connection = self.client.get_connection()?;
+        let created: redis::RedisResult<()> = redis::cmd("XGROUP")
+            .arg("CREATE")
+            .arg(&self.config.stream)
+            .arg(&self.config.group)
+            .arg("0")
+            .arg("MKSTREAM")
+            .query(&mut connection);
+        match created {
+            Err(ref e) if e.to_string().contains("BUSYGROUP") => Ok(()),
+            other => Ok(other?),
+        }
+    }
+
+    pub fn push(&self, job: &Job) -> Result<String, QueueError> {
+        let payload = serde_json::to_string(job)?;
+        let mut connection = self.client.get_connection()?;
+        let id: String = redis::cmd("XADD")
+            .arg(&self.config.stream)
+            .arg("*")
+            .arg("job")
+            .arg(payload)
+            .query(&mut connection)?;
+        Ok(id)
+    }
+
+    // Acknowledged jobs are deleted, so this is the number of jobs still waiting
+    // or being worked on.
+    pub fn depth(&self) -> Result<u64, QueueError> {
+        let mut connection = self.client.get_connection()?;
+        Ok(redis::cmd("XLEN").arg(&self.config.stream).query(&mut connection)?)
+    }
+
+    fn ack(&self, connection: &mut redis::Connection, id: &str) -> Result<(), QueueError> {
+        redis::cmd("XACK").arg(&self.config.stream).arg(&self.config.group).arg(id).query::<()>(connection)?;
+        redis::cmd("XDEL").arg(&self.config.stream).arg(id).query::<()>(connection)?;
+        Ok(())
+    }
+
+    // `start` is `>` for new jobs, or an id to re-read this consumer's own
+    // unacknowledged jobs after it.
+    fn read(&self, connection: &mut redis::Connection, consumer: &str, start: &str) -> Result<Vec<Entry>, QueueError> {
+        let mut cmd = redis::cmd("XREADGROUP");
+        cmd.arg("GROUP").arg(&self.config.group).arg(consumer)
+            .arg("COUNT").arg(READ_BATCH);
+        if start == ">" {
+            cmd.arg("BLOCK").arg(READ_BLOCK_MS);
+        }
+        cmd.arg("STREAMS").arg(&self.config.stream).arg(start);
+        let reply: Value = cmd.query(connection)?;
+        Ok(parse_read_reply(&reply))
+    }
+
+    fn entry(&self, connection: &mut redis::Connection, id: &str) -> Result<Option<Entry>, QueueError> {
+        let entries: Value = redis::cmd("XRANGE").arg(&self.config.stream).arg(id).arg(id).query(connection)?;
+        Ok(parse_entries(&entries).into_iter().next())
+    }
+
+    // How many times the entry was read or claimed, so far.
+    fn deliveries(&self, connection: &mut redis::Connection, id: &str) -> Result<u64, QueueError> {
+        let pending: Value = redis::cmd("XPENDING")
+            .arg(&self.config.stream)
+            .arg(&self.config.group)
+            .arg(id)
+            .arg(id)
+            .arg(1)
+            .query(connection)?;
+        Ok(match pending {
+            Value::Bulk(pending) => match pending.first() {
+                Some(Value::Bulk(parts)) => match parts.get(3) {
+                    Some(Value::Int(deliveries)) => *deliveries as u64,
+                    _ => 0,
+                },
+                _ => 0,
+            },
+            _ => 0,
+        })
+    }
+
+    fn dead_letter(&self, connection: &mut redis::Connection, entry: &Entry, reason: &str) -> Result<(), QueueError> {
+        error!("Moving job {} to {}: {}", entry.id, self.config.dead_letter_stream, reason);
+        let mut cmd = redis::cmd("XADD");
+        cmd.arg(&self.config.dead_letter_stream).arg("*")
+            .arg("entry").arg(&entry.id)
+            .arg("reason").arg(reason);
+        if let Some(payload) = &entry.payload {
+            cmd.arg("job").arg(payload);
+        }
+        cmd.query::<String>(connection)?;
+        self.ack(connection, &entry.id)
+    }
+
+    // Takes over jobs other (probably dead) workers left pending for too long,
+    // and the ones that failed here. Jobs that have already been tried too
+    // many times are dead-lettered instead.
+    fn claim_stale(&self, connection: &mut redis::Connection, consumer: &str) -> Result<Vec<Entry>, QueueError> {
+        let min_idle_ms = self.config.claim_after_minutes * 60 * 1000;
+        let pending: Value = redis::cmd("XPENDING")
+            .arg(&self.config.stream)
+            .arg(&self.config.group)
+            .arg("-")
+            .arg("+")
+            .arg(100)
+            .query(connection)?;
+        let pending = match pending {
+            Value::Bulk(pending) => pending,
+            _ => return Ok(Vec::new()),
+        };
+
+        let mut stale = Vec::new();
+        for entry in pending {
+            let parts = match entry {
+                Value::Bulk(parts) if parts.len() == 4 => parts,
+                _ => continue,
+            };
+            let id = match as_string(&parts[0]) {
+                Some(id) => id,
+                None => continue,
+            };
+            let (idle_ms, deliveries) = match (&parts[2], &parts[3]) {
+                (Value::Int(idle_ms), Value::Int(deliveries)) => (*idle_ms as u64, *deliveries as u64),
+                _ => continue,
+            };
+            if idle_ms < min_idle_ms {
+                continue;
+            }
+            if deliveries >= self.config.max_deliveries {
+                let entry = self.entry(connection, &id)?.unwrap_or(Entry { id, payload: None });
+                self.dead_letter(connection, &entry, &format!("gave up after {} deliveries", deliveries))?;
+                continue;
+            }
+            stale.push(id);
+        }
+        if stale.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut cmd = redis::cmd("XCLAIM");
+        cmd.arg(&self.config.stream).arg(&self.config.group).arg(consumer).arg(min_idle_ms);
+        for id in &stale {
+            cmd.arg(id);
+        }
+        let claimed: Value = cmd.query(connection)?;
+        Ok(parse_entries(&claimed))
+    }
+
+    // Returns true for an entry left for after maintenance, which may have
+    // started after it was read.
+    fn handle(&self, connection: &mut redis::Connection, pipeline: &Pipeline, entry: Entry) -> Result<bool, QueueError> {
+        if pipeline.maintenance.is_enabled() {
+            info!("In maintenance, leaving {} for later", entry.id);
+            return Ok(true);
+        }
+        let job = match entry.payload.as_ref().map(|p| serde_json::from_str::<Job>(p)) {
+            Some(Ok(job)) => job,
+            _ => return self.dead_letter(connection, &entry, "unreadable job").map(|()| false),
+        };
+        match pipeline.process(&job) {
+            Ok(outcome) => {
+                info!("Job {} ({}) {}", job.id, job.action.route(), outcome.as_str());
+                self.ack(connection, &entry.id).map(|()| false)
+            },
+            Err(e) => {
+                let deliveries = self.deliveries(connection, &entry.id)?;
+                if deliveries >= self.config.max_deliveries {
+                    pipeline.metrics.incr("jobs_dead_lettered");
+                    let reason = format!("failed {} times, last with: {}", deliveries, e);
+                    return self.dead_letter(connection, &entry, &reason).map(|()| false);
+                }
+                // Left pending, it'll be claimed again once it has been idle long enough.
+                error!("Job {} ({}) failed, will retry: {}", job.id, job.action.route(), e);
+                Ok(false)
+            }
+        }
+    }
+
+    // Adds the entry's id to held if it's left for after maintenance.
+    fn handle_or_hold(&self, connection: &mut redis::Connection, pipeline: &Pipeline, entry: Entry, held: &mut Vec<String>) -> Result<(), QueueError> {
+        let id = entry.id.clone();
+        if self.handle(connection, pipeline, entry)? {
+            held.push(id);
+        }
+        Ok(())
+    }
+
+    // Whatever this consumer read but didn't finish before it was restarted,
+    // read again once. Failed jobs come back through claim_stale.
+    fn handle_pending(&self, connection: &mut redis::Connection, pipeline: &Pipeline, consumer: &str, held: &mut Vec<String>) -> Result<(), QueueError> {
+        let mut last_id = String::from("0");
+        loop {
+            let entries = self.read(connection, consumer, &last_id)?;
+            if entries.is_empty() {
+                return Ok(());
+            }
+            for entry in entries {
+                last_id = entry.id.clone();
+                self.handle_or_hold(connection, pipeline, entry, held)?;
+            }
+        }
+    }
+
+    // Just the jobs left for after maintenance, by id.
+    fn handle_held(&self, connection: &mut redis::Connection, pipeline: &Pipeline, held: &mut Vec<String>) -> Result<(), QueueError> {
+        for id in held.split_off(0) {
+            match self.entry(connection, &id)? {
+                Some(entry) => self.handle_or_hold(connection, pipeline, entry, held)?,
+                None => info!("Held job {} is no longer queued", id),
+            }
+        }
+        Ok(())
+    }
+
+    fn work(&self, pipeline: &Pipeline, consumer: &str) -> Result<(), QueueError> {
+        let mut connection = self.client.get_connection()?;
+        let mut held = Vec::new();
+        wait_for_maintenance(pipeline);
+        self.handle_pending(&mut connection, pipeline, consumer, &mut held)?;
+
+        let claim_interval = Duration::from_secs(self.config.claim_after_minutes * 60);
+        let mut last_claim = Instant::now();
+        loop {
+            if wait_for_maintenance(pipeline) || (!held.is_empty() && !pipeline.maintenance.is_enabled()) {
+                self.handle_held(&mut connection, pipeline, &mut held)?;
+            }
+            for entry in self.read(&mut connection, consumer, ">")? {
+                self.handle_or_hold(&mut connection, pipeline, entry, &mut held)?;
+            }
+            if last_claim.elapsed() >= claim_interval {
+                last_claim = Instant::now();
+                for entry in self.claim_stale(&mut connection, consumer)? {
+                    self.handle_or_hold(&mut connection, pipeline, entry, &mut held)?;
+                }
+            }
+        }
+    }
+}
+
+// Jobs wait in the queue until maintenance is lifted. Returns whether it
+// had to wait.
+fn wait_for_maintenance(pipeline: &Pipeline) -> bool {
+    if !pipeline.maintenance.is_enabled() {
+        return false;
+    }
+    info!("In maintenance, pausing deliveries");
+    pipeline.maintenance.wait_until_lifted();
+    info!("Maintenance lifted, resuming deliveries");
+    true
+}
+
+pub fn run_worker(queue: RedisQueue, pipeline: Pipeline, consumer: String) {
+    info!("Worker {} consuming {}", consumer, queue.config.stream);
+    loop {
+        if let Err(e) = queue.work(&pipeline, &consumer) {
+            error!("Worker {} lost the queue, retrying: {}", consumer, e);
+            thread::sleep(Duration::from_secs(5));
+        }
+    }
+}
@@ -0,0 +1,102 @@
+use std::sync::Arc;
+
+use chashmap::CHashMap;
+use chrono::{DateTime, Utc};
+
+use crate::clock::Clock;
+use crate::dashboard::RateLimitState;
+
+#[derive(Clone)]
+pub struct Minutes(pub i64);
+
+#[derive(Clone)]
+pub struct LastResponseLog {
+    pub time_between_responses: Minutes,
+    last_response_date: Arc<CHashMap<String, DateTime<Utc>>>,
+    // When several workers deliver from the same queue they have to agree on
+    // who was answered recently, so the log lives in redis instead.
+    shared: Option<redis::Client>,
+    clock: Clock,
+}
+
+impl LastResponseLog {
+    pub fn new(time_between_responses: Minutes) -> LastResponseLog {
+        LastResponseLog {
+            time_between_responses,
+            last_response_date: Arc::new(CHashMap::new()),
+            shared: None,
+            clock: Clock::system(),
+        }
+    }
+
+    pub fn shared(self, client: redis::Client) -> LastResponseLog {
+        LastResponseLog {
+            shared: Some(client),
+            ..self
+        }
+    }
+
+    pub fn with_clock(self, clock: Clock) -> LastResponseLog {
+        LastResponseLog {
+            clock,
+            ..self
+        }
+    }
+
+    fn is_too_old(&self, dt: &DateTime<Utc>) -> bool {
+        (self.clock.now() - (*dt)).num_minutes() > self.time_between_responses.0
+    }
+
+
+    fn can_send(&self, email: &String) -> bool {
+        match self.last_response_date.get(email) {
+            Some(v) => self.is_too_old(&v),
+            None => true
+        }
+    }
+
+    fn log_send(&self, email: &String) {
+        self.clear_old();
+        self.last_response_date.insert(email.clone(), self.clock.now());
+    }
+
+    // Checks and records a response in one go, returns false if we already
+    // responded to this address within the window.
+    pub fn claim(&self, email: &String) -> bool {
+        if let Some(client) = &self.shared {
+            let key = format!("limail:last-response:{}", email.to_lowercase());
+            let claimed: redis::RedisResult<Option<String>> = client.get_connection()
+                .and_then(|mut connection| redis::cmd("SET")
+                    .arg(&key)
+                    .arg(self.clock.now().to_rfc3339())
+                    .arg("NX")
+                    .arg("EX")
+                    .arg(self.time_between_responses.0 * 60)
+                    .query(&mut connection));
+            match claimed {
+                Ok(claimed) => return claimed.is_some(),
+                Err(e) => error!("Unable to reach the shared rate limiter, falling back to memory: {}", e),
+            }
+        }
+        if self.can_send(email) {
+            self.log_send(email);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn snapshot(&self) -> RateLimitState {
+        RateLimitState {
+            window_minutes: self.time_between_responses.0,
+            last_responses: self.last_response_date.clone().into_iter().collect(),
+        }
+    }
+
+    fn clear_old(&self) {
+        let orig_size = self.last_response_date.len();
+        self.last_response_date.retain(|_, v| !self.is_too_old(v));
+        let new_size = self.last_response_date.len();
+        info!("Cleared {} old entries from last_response_date", orig_size-new_size);
+    }
+}
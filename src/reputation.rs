@@ -0,0 +1,192 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+
+use crate::authresults::{AuthResults, Verdict};
+use crate::blocklist::address_of;
+use crate::clock::Clock;
+use crate::mailgun::MailgunEmailReceived;
+use crate::pipeline::{route_matches, Outcome};
+use crate::store::{self, StoreError};
+use crate::viewer;
+
+// Emails beyond this many don't make a sender any more trusted.
+const MAX_HISTORY_SCORE: i64 = 10;
+
+#[derive(Deserialize, Clone)]
+pub struct FirstTimeTemplate {
+    pub route: String,
+    pub template: String,
+}
+
+// Keeps a history of every sender and scores it, see SenderHistory::score.
+// The score is shown on Slack forwards. Auto-replies to senders scoring
+// below min_reply_score are suppressed, and senders we've never heard from
+// get the first matching first_time template instead of the usual one.
+#[derive(Deserialize, Clone, Default)]
+pub struct ReputationConfig {
+    #[serde(default)]
+    pub min_reply_score: Option<i64>,
+    #[serde(default)]
+    pub first_time: Vec<FirstTimeTemplate>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SenderHistory {
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub emails: u64,
+    // Mailgun's X-Mailgun-Sflag.
+    #[serde(default)]
+    pub spam_flags: u64,
+    // DMARC failures.
+    #[serde(default)]
+    pub auth_failures: u64,
+    // Emails that came too soon after an auto-reply to get another one.
+    #[serde(default)]
+    pub suppressed: u64,
+    #[serde(default)]
+    pub blocked: u64,
+    // So a Mailgun retry isn't counted as another email.
+    #[serde(default)]
+    last_job: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Standing {
+    FirstTime,
+    Known,
+    Suspicious,
+}
+
+impl SenderHistory {
+    pub fn score(&self) -> i64 {
+        (self.emails as i64).min(MAX_HISTORY_SCORE)
+            - 3 * self.spam_flags as i64
+            - 2 * self.auth_failures as i64
+            - self.suppressed as i64
+            - 5 * self.blocked as i64
+    }
+
+    pub fn standing(&self) -> Standing {
+        if self.emails <= 1 {
+            Standing::FirstTime
+        } else if self.score() < 0 {
+            Standing::Suspicious
+        } else {
+            Standing::Known
+        }
+    }
+
+    // The line on a Slack forward.
+    pub fn summary(&self) -> String {
+        match self.standing() {
+            Standing::FirstTime => String::from("Sender: first email"),
+            standing => format!(
+                "Sender: {}{} emails since {}, score {}",
+                if standing == Standing::Suspicious { ":warning: " } else { "" },
+                self.emails,
+                self.first_seen.format("%Y-%m-%d"),
+                self.score()
+            ),
+        }
+    }
+}
+
+fn is_spam_flagged(email: &MailgunEmailReceived) -> bool {
+    viewer::headers(&email.message_headers).iter()
+        .any(|(name, value)| name.eq_ignore_ascii_case("x-mailgun-sflag") && value.trim().eq_ignore_ascii_case("yes"))
+}
+
+// Every sender's history, by address. Kept in reputation.json.
+#[derive(Clone)]
+pub struct Reputation {
+    pub config: ReputationConfig,
+    path: PathBuf,
+    senders: Arc<Mutex<BTreeMap<String, SenderHistory>>>,
+    clock: Clock,
+}
+
+impl Reputation {
+    pub fn load(config: ReputationConfig, path: PathBuf) -> Result<Reputation, StoreError> {
+        let senders: BTreeMap<String, SenderHistory> = store::read_json(&path)?.unwrap_or_default();
+        Ok(Reputation {
+            config,
+            path,
+            senders: Arc::new(Mutex::new(senders)),
+            clock: Clock::system(),
+        })
+    }
+
+    pub fn with_clock(self, clock: Clock) -> Reputation {
+        Reputation {
+            clock,
+            ..self
+        }
+    }
+
+    pub fn get(&self, from: &str) -> Option<SenderHistory> {
+        self.senders.lock().unwrap().get(&address_of(from)).cloned()
+    }
+
+    // Counts the email against its sender, returning the history including it.
+    pub fn observe(&self, job_id: &str, email: &MailgunEmailReceived, auth: &AuthResults) -> Result<SenderHistory, StoreError> {
+        let now = self.clock.now();
+        let mut senders = self.senders.lock().unwrap();
+        let history = senders.entry(address_of(&email.from)).or_insert_with(|| SenderHistory {
+            first_seen: now,
+            last_seen: now,
+            emails: 0,
+            spam_flags: 0,
+            auth_failures: 0,
+            suppressed: 0,
+            blocked: 0,
+            last_job: None,
+        });
+        if history.last_job.as_ref().map(|id| &id[..]) == Some(job_id) {
+            return Ok(history.clone());
+        }
+        history.last_seen = now;
+        history.emails += 1;
+        history.last_job = Some(String::from(job_id));
+        if is_spam_flagged(email) {
+            history.spam_flags += 1;
+        }
+        if auth.dmarc == Verdict::Fail {
+            history.auth_failures += 1;
+        }
+        let history = history.clone();
+        store::write_json(&self.path, &*senders)?;
+        Ok(history)
+    }
+
+    pub fn record_outcome(&self, from: &str, outcome: Outcome) -> Result<(), StoreError> {
+        let mut senders = self.senders.lock().unwrap();
+        let history = match senders.get_mut(&address_of(from)) {
+            Some(history) => history,
+            None => return Ok(()),
+        };
+        match outcome {
+            Outcome::Suppressed => history.suppressed += 1,
+            Outcome::Blocked => history.blocked += 1,
+            _ => return Ok(()),
+        }
+        store::write_json(&self.path, &*senders)
+    }
+
+    pub fn allows_reply(&self, history: &SenderHistory) -> bool {
+        self.config.min_reply_score.map_or(true, |min| history.score() >= min)
+    }
+
+    pub fn first_time_template(&self, route: &str, history: &SenderHistory) -> Option<String> {
+        if history.standing() != Standing::FirstTime {
+            return None;
+        }
+        self.config.first_time.iter()
+            .find(|first_time| route_matches(&first_time.route, route))
+            .map(|first_time| first_time.template.clone())
+    }
+}
@@ -0,0 +1,112 @@
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::addresses;
+use crate::mailgun::{Mailgun, MailgunError};
+
+fn default_refresh_minutes() -> i64 {
+    10
+}
+
+// Mailgun sandbox domains only deliver to the recipients authorized for
+// them, and refuse everyone else, which on staging looks just like Mailgun
+// failing. Sending through one (or with a [sandbox] section), replies to
+// anyone not authorized aren't attempted, and are logged as suppressed
+// rather than failed. The recipients Mailgun lists are fetched every
+// refresh_minutes, and authorized adds to them.
+#[derive(Deserialize, Clone, Default)]
+pub struct SandboxConfig {
+    #[serde(default)]
+    pub authorized: Vec<String>,
+    #[serde(default = "default_refresh_minutes")]
+    pub refresh_minutes: i64,
+}
+
+pub fn is_sandbox_domain(domain: &str) -> bool {
+    domain.starts_with("sandbox") && domain.ends_with(".mailgun.org")
+}
+
+#[derive(Clone)]
+pub struct Sandbox {
+    config: Arc<SandboxConfig>,
+    mailgun: Mailgun,
+    listed: Arc<Mutex<Option<(DateTime<Utc>, BTreeSet<String>)>>>,
+}
+
+impl Sandbox {
+    pub fn for_mailgun(config: Option<SandboxConfig>, mailgun: &Mailgun) -> Option<Sandbox> {
+        if config.is_none() && !is_sandbox_domain(&mailgun.domain) {
+            return None;
+        }
+        info!("{} is a Mailgun sandbox, only replying to its authorized recipients", mailgun.domain);
+        Some(Sandbox {
+            config: Arc::new(config.unwrap_or(SandboxConfig { refresh_minutes: default_refresh_minutes(), ..SandboxConfig::default() })),
+            mailgun: Mailgun { sandbox: None, ..mailgun.clone() },
+            listed: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    pub fn allows(&self, recipient: &str) -> Result<(), MailgunError> {
+        let recipient = addresses::sender(recipient).address.to_lowercase();
+        if self.config.authorized.iter().any(|authorized| authorized.trim().to_lowercase() == recipient) {
+            return Ok(());
+        }
+        let mut listed = self.listed.lock().unwrap();
+        let stale = listed.as_ref()
+            .map_or(true, |(at, _)| Utc::now() - *at > Duration::minutes(self.config.refresh_minutes));
+        if stale {
+            match self.fetch() {
+                Ok(recipients) => *listed = Some((Utc::now(), recipients)),
+                // Mailgun has the last word then.
+                Err(e) => {
+                    warn!("Unable to list the authorized recipients of {}: {}", self.mailgun.domain, e);
+                    return Ok(());
+                },
+            }
+        }
+        match listed.as_ref() {
+            Some((_, recipients)) if !recipients.contains(&recipient) => Err(MailgunError::Sandbox(format!(
+                "{} isn't an authorized recipient of the Mailgun sandbox {}",
+                recipient,
+                self.mailgun.domain
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    // The v5 API, next to the v3 one in MAILGUN_URL.
+    fn fetch(&self) -> Result<BTreeSet<String>, String> {
+        let base = self.mailgun.api_url.trim_end_matches('/');
+        let base = if base.ends_with("/v3") { &base[..base.len() - 3] } else { base };
+        let response: Value = reqwest::Client::builder()
+            .timeout(self.mailgun.timeout)
+            .build()
+            .and_then(|client| client.get(&format!("{}/v5/sandbox/auth_recipients", base))
+                .query(&[("sandbox", &self.mailgun.domain)])
+                .basic_auth("api", Some(self.mailgun.api_key.expose()))
+                .send()?
+                .error_for_status()?
+                .json())
+            .map_err(|e| self.mailgun.api_key.redact(&e.to_string()))?;
+        Ok(response["recipients"].as_array().map(|recipients| recipients.iter()
+            .filter(|recipient| recipient["activated"].as_bool().unwrap_or(true))
+            .filter_map(|recipient| recipient["email"].as_str())
+            .map(|email| email.trim().to_lowercase())
+            .collect()).unwrap_or_default())
+    }
+
+    // What Mailgun says when it refuses someone anyway, the list having
+    // changed since it was fetched, say.
+    pub fn refusal(&self, error: MailgunError) -> MailgunError {
+        match error {
+            MailgunError::MailgunError(message) if message.to_lowercase().contains("authorized recipients") => {
+                MailgunError::Sandbox(message)
+            },
+            error => error,
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use std::sync::Arc;
+
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::mailgun::MailgunEmailReceived;
+
+// Sensitive text people paste into emails: credit card numbers, passwords,
+// API tokens. Matches of `pattern` are replaced before the email is
+// archived, queued, published or posted to Slack.
+#[derive(Deserialize, Clone)]
+pub struct ScrubRule {
+    pub name: String,
+    pub pattern: String,
+    // May refer to groups in pattern ($1). Defaults to [<name> removed].
+    #[serde(default)]
+    pub replacement: Option<String>,
+    // Only replace matches whose digits pass the Luhn check, so order
+    // numbers and phone numbers survive a card number rule.
+    #[serde(default)]
+    pub luhn: bool,
+}
+
+struct CompiledRule {
+    pattern: Regex,
+    replacement: String,
+    luhn: bool,
+}
+
+fn passes_luhn(text: &str) -> bool {
+    let digits: Vec<u32> = text.chars().filter_map(|c| c.to_digit(10)).collect();
+    if digits.len() < 13 || digits.len() > 19 {
+        return false;
+    }
+    let sum: u32 = digits.iter().rev().enumerate()
+        .map(|(i, digit)| match (i % 2, digit * 2) {
+            (0, _) => *digit,
+            (_, doubled) if doubled > 9 => doubled - 9,
+            (_, doubled) => doubled,
+        })
+        .sum();
+    sum % 10 == 0
+}
+
+#[derive(Clone, Default)]
+pub struct Scrubber {
+    rules: Arc<Vec<CompiledRule>>,
+}
+
+impl Scrubber {
+    pub fn new(rules: &[ScrubRule]) -> Result<Scrubber, String> {
+        let compiled = rules.iter()
+            .map(|rule| match Regex::new(&rule.pattern) {
+                Ok(pattern) => Ok(CompiledRule {
+                    pattern,
+                    replacement: rule.replacement.clone().unwrap_or_else(|| format!("[{} removed]", rule.name)),
+                    luhn: rule.luhn,
+                }),
+                Err(e) => Err(format!("Invalid scrub pattern for {}: {}", rule.name, e)),
+            })
+            .collect::<Result<Vec<CompiledRule>, String>>()?;
+        Ok(Scrubber {
+            rules: Arc::new(compiled),
+        })
+    }
+
+    // Returns the text and how many matches were replaced.
+    fn scrub_text(&self, text: &str) -> (String, usize) {
+        let mut count = 0;
+        let mut text = String::from(text);
+        for rule in self.rules.iter() {
+            text = rule.pattern
+                .replace_all(&text, |captures: &regex::Captures| {
+                    let matched = &captures[0];
+                    if rule.luhn && !passes_luhn(matched) {
+                        String::from(matched)
+                    } else {
+                        count += 1;
+                        let mut replacement = String::new();
+                        captures.expand(&rule.replacement, &mut replacement);
+                        replacement
+                    }
+                })
+                .into_owned();
+        }
+        (text, count)
+    }
+
+    // The subject and both bodies. Returns how many matches were replaced.
+    pub fn scrub(&self, email: &mut MailgunEmailReceived) -> usize {
+        if self.rules.is_empty() {
+            return 0;
+        }
+        let (subject, in_subject) = self.scrub_text(&email.subject);
+        let (body_plain, in_plain) = self.scrub_text(&email.body_plain);
+        let (body_html, in_html) = match &email.body_html {
+            Some(html) => {
+                let (html, count) = self.scrub_text(html);
+                (Some(html), count)
+            },
+            None => (None, 0),
+        };
+        email.subject = subject;
+        email.body_plain = body_plain;
+        email.body_html = body_html;
+        in_subject + in_plain + in_html
+    }
+}
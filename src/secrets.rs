@@ -0,0 +1,212 @@
+use std::env;
+use std::fmt::{self, Debug, Display};
+use std::fs;
+use std::time::Duration;
+
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use zeroize::Zeroize;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const TIMEOUT_SECONDS: u64 = 10;
+
+const REDACTED: &str = "[redacted]";
+
+// An API key. Prints as [redacted], and is wiped from memory when dropped.
+#[derive(Clone)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: String) -> Secret {
+        Secret(value)
+    }
+
+    // Only for handing to the API it's for.
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    // In case an error echoes the request back.
+    pub fn redact(&self, text: &str) -> String {
+        if self.0.is_empty() {
+            String::from(text)
+        } else {
+            text.replace(&self.0[..], REDACTED)
+        }
+    }
+}
+
+impl Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+// Looks up a secret given to limail as an environment variable, in order:
+//
+// * NAME_FILE, a file holding it (e.g. a mounted Kubernetes/Docker secret).
+// * NAME=vault:<path>#<field>, a field of a Vault KV secret. Needs
+//   VAULT_ADDR and VAULT_TOKEN (or VAULT_TOKEN_FILE).
+// * NAME=aws-secretsmanager:<secret id>[#<field>], an AWS Secrets Manager
+//   secret, or a field of one holding JSON. Needs AWS_REGION and
+//   AWS_ACCESS_KEY_ID/AWS_SECRET_ACCESS_KEY (and AWS_SESSION_TOKEN for
+//   temporary credentials).
+// * NAME itself.
+//
+// None when it isn't set at all.
+pub fn lookup(name: &str) -> Option<Result<Secret, String>> {
+    let file_name = format!("{}_FILE", name);
+    if let Ok(path) = env::var(&file_name) {
+        return Some(fs::read_to_string(&path)
+            .map(|mut secret| {
+                let trimmed = Secret::new(String::from(secret.trim_end_matches(|c| c == '\n' || c == '\r')));
+                secret.zeroize();
+                trimmed
+            })
+            .map_err(|e| format!("Unable to read {} {}: {}", file_name, path, e)));
+    }
+    let value = env::var(name).ok()?;
+    let resolved = if value.starts_with("vault:") {
+        vault(&value["vault:".len()..])
+    } else if value.starts_with("aws-secretsmanager:") {
+        aws_secrets_manager(&value["aws-secretsmanager:".len()..])
+    } else {
+        return Some(Ok(Secret::new(value)));
+    };
+    Some(resolved.map(Secret::new).map_err(|e| format!("Unable to look up {}: {}", name, e)))
+}
+
+fn client() -> Result<reqwest::Client, String> {
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(TIMEOUT_SECONDS))
+        .build()
+        .map_err(|e| e.to_string())
+}
+
+fn split_field(reference: &str) -> (&str, Option<&str>) {
+    match reference.rfind('#') {
+        Some(hash) => (&reference[..hash], Some(&reference[hash + 1..])),
+        None => (reference, None),
+    }
+}
+
+fn vault(reference: &str) -> Result<String, String> {
+    let (path, field) = match split_field(reference) {
+        (path, Some(field)) => (path, field),
+        (_, None) => return Err(String::from("a Vault reference needs a #field")),
+    };
+    let address = env::var("VAULT_ADDR").map_err(|_| String::from("VAULT_ADDR isn't set"))?;
+    let token = match env::var("VAULT_TOKEN_FILE") {
+        Ok(path) => fs::read_to_string(&path)
+            .map(|token| String::from(token.trim()))
+            .map_err(|e| format!("Unable to read VAULT_TOKEN_FILE {}: {}", path, e))?,
+        Err(_) => env::var("VAULT_TOKEN").map_err(|_| String::from("VAULT_TOKEN isn't set"))?,
+    };
+    let url = format!("{}/v1/{}", address.trim_end_matches('/'), path.trim_start_matches('/'));
+    let mut response = client()?
+        .get(&url)
+        .header("X-Vault-Token", token)
+        .send()
+        .map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("Vault answered {} for {}", response.status(), path));
+    }
+    let body: Value = response.json().map_err(|e| e.to_string())?;
+    // KV version 2 nests the secret one level deeper than version 1.
+    body["data"]["data"][field].as_str()
+        .or_else(|| body["data"][field].as_str())
+        .map(String::from)
+        .ok_or_else(|| format!("Vault secret {} has no field {}", path, field))
+}
+
+fn sign(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = HmacSha256::new_varkey(key).expect("HMAC takes keys of any size");
+    mac.input(data.as_bytes());
+    mac.result().code().to_vec()
+}
+
+fn aws_secrets_manager(reference: &str) -> Result<String, String> {
+    let (secret_id, field) = split_field(reference);
+    let var = |name: &str| env::var(name).map_err(|_| format!("{} isn't set", name));
+    let region = var("AWS_REGION").or_else(|_| var("AWS_DEFAULT_REGION"))?;
+    let access_key = var("AWS_ACCESS_KEY_ID")?;
+    let secret_key = var("AWS_SECRET_ACCESS_KEY")?;
+    let session_token = env::var("AWS_SESSION_TOKEN").ok();
+
+    // Signature Version 4, see
+    // https://docs.aws.amazon.com/general/latest/gr/sigv4_signing.html
+    let host = format!("secretsmanager.{}.amazonaws.com", region);
+    let body = json!({ "SecretId": secret_id }).to_string();
+    let now = Utc::now();
+    let (amz_date, date) = (now.format("%Y%m%dT%H%M%SZ").to_string(), now.format("%Y%m%d").to_string());
+    let mut headers = vec![
+        ("content-type", String::from("application/x-amz-json-1.1")),
+        ("host", host.clone()),
+        ("x-amz-date", amz_date.clone()),
+        ("x-amz-target", String::from("secretsmanager.GetSecretValue")),
+    ];
+    if let Some(token) = &session_token {
+        headers.push(("x-amz-security-token", token.clone()));
+    }
+    // Signed in order.
+    headers.sort();
+    let canonical_headers: String = headers.iter().map(|(name, value)| format!("{}:{}\n", name, value)).collect();
+    let signed_headers = headers.iter().map(|(name, _)| *name).collect::<Vec<&str>>().join(";");
+    let canonical_request = format!(
+        "POST\n/\n\n{}\n{}\n{}",
+        canonical_headers,
+        signed_headers,
+        hex::encode(Sha256::digest(body.as_bytes()))
+    );
+    let scope = format!("{}/{}/secretsmanager/aws4_request", date, region);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex::encode(Sha256::digest(canonical_request.as_bytes()))
+    );
+    let signing_key = ["secretsmanager", "aws4_request"].iter().fold(
+        sign(&sign(format!("AWS4{}", secret_key).as_bytes(), &date), &region),
+        |key, part| sign(&key, part),
+    );
+    let authorization = format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        access_key,
+        scope,
+        signed_headers,
+        hex::encode(sign(&signing_key, &string_to_sign))
+    );
+
+    let mut request = client()?.post(&format!("https://{}/", host)).header("Authorization", authorization);
+    for (name, value) in headers.into_iter().filter(|(name, _)| *name != "host") {
+        request = request.header(name, value);
+    }
+    let mut response = request.body(body).send().map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("Secrets Manager answered {} for {}", response.status(), secret_id));
+    }
+    let answer: Value = response.json().map_err(|e| e.to_string())?;
+    let secret = answer["SecretString"].as_str()
+        .ok_or_else(|| format!("Secret {} isn't a string", secret_id))?;
+    match field {
+        None => Ok(String::from(secret)),
+        Some(field) => serde_json::from_str::<Value>(secret).ok()
+            .and_then(|fields| fields[field].as_str().map(String::from))
+            .ok_or_else(|| format!("Secret {} has no field {}", secret_id, field)),
+    }
+}
@@ -0,0 +1,2147 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use bytes::Buf;
+use serde::{Serialize, Deserialize};
+use serde_json::{json, Value};
+use warp::{
+    path,
+    Filter,
+    Rejection,
+    http::{
+        HeaderMap,
+        header::{HeaderValue, CONTENT_DISPOSITION, CONTENT_TYPE, LOCATION, RETRY_AFTER, WWW_AUTHENTICATE},
+        Response,
+        StatusCode,
+    },
+};
+
+use crate::admin::{self, AdminError, CopyQuery, ExportQuery, NewTemplate, SendRequest, SenderQuery, TemplateUpdate};
+use crate::alerts::Alerts;
+use crate::apilimit::{ApiLimiter, Quota};
+use crate::archive::{Archive, ArchivedSlackMessage};
+use crate::audit::{AuditLog, AuditQuery};
+use crate::auth::{self, AuthError, Principal, Scope, Tokens};
+use crate::blocklist::Blocklist;
+use crate::bounces::{BounceReport, Bounces, DeliveryEvent, EnvelopeSenders};
+use crate::budget::SendBudget;
+use crate::canned;
+use crate::chaos::Chaos;
+use crate::captures::Captures;
+use crate::clock::Clock;
+use crate::commands::{self, Command, EventEnvelope, Interaction, InteractionForm, SlashCommand, SlashCommandConfig, SlashResponse};
+use crate::config::Config;
+use crate::copies::Copies;
+use crate::dashboard::{self, RateLimitState};
+use crate::delays::{self, Delays};
+use crate::earlyack::EarlyAck;
+use crate::echo::Echo;
+use crate::encryption::Sealer;
+use crate::feedback::{self, Feedback, FeedbackQuery};
+use crate::flags::{Flags, RouteFlags};
+use crate::footers::Footers;
+use crate::handling::Handling;
+use crate::links::{ArchiveLinks, SignedQuery};
+use crate::loops::Loops;
+use crate::mailgun::{Mailgun, MailgunEmailReceived, MailgunError, MailgunJsonWebhook};
+use crate::maintenance::Maintenance;
+use crate::metrics::Metrics;
+use crate::multipart::{self, MultipartError};
+use crate::notices::Notices;
+use crate::outbox::{Outbox, OutboxQuery};
+use crate::pacing::Pacing;
+use crate::pgp::{Decryption, Pgp};
+use crate::pipeline::{Action, DeliveryError, Job, Outcome, Pipeline};
+use crate::policy::{self, ResponsePolicy, SuccessFormat};
+use crate::providers;
+use crate::publish::{InboundEvent, Publisher};
+use crate::quarantine;
+use crate::queue::{QueueError, RedisQueue};
+use crate::ratelimit::LastResponseLog;
+use crate::reputation::Reputation;
+use crate::sandbox::Sandbox;
+use crate::scrub::Scrubber;
+use crate::signatures::{MailgunToken, Provider, SignedRequest, Signatures};
+use crate::slack::{Slack, SlackError, SlackMessage};
+use crate::smime::Smime;
+use crate::store::StoreError;
+use crate::suppressions::{Suppressions, SuppressionQuery};
+use crate::takeover::Takeover;
+use crate::threads::ThreadMap;
+use crate::threats::ThreatIntel;
+use crate::trace::Tracer;
+use crate::traceparent::TraceContext;
+use crate::transfer;
+use crate::unrouted::{self, Unrouted, UnroutedRequest};
+use crate::unsubscribe::{self, UnsubscribeLinks, UnsubscribeQuery, Unsubscribed};
+use crate::variants::Variants;
+use crate::version::Version;
+use crate::viewer::{self, HtmlQuery};
+
+// Everything the webhooks, dashboard and admin API need. Built from the
+// configuration by main, or around fake Mailgun and Slack servers by
+// limail::testing.
+#[derive(Clone)]
+pub struct App {
+    pub tokens: Tokens,
+    pub audit: AuditLog,
+    pub publisher: Publisher,
+    pub pipeline: Pipeline,
+    pub queue: Option<RedisQueue>,
+    pub slash_command: Option<SlashCommandConfig>,
+    pub policy: Arc<ResponsePolicy>,
+    pub scrubber: Scrubber,
+    pub pgp: Arc<Pgp>,
+    pub smime: Arc<Smime>,
+    pub unrouted: Unrouted,
+    pub captures: Captures,
+    pub signatures: Arc<Signatures>,
+    pub early_ack: Option<EarlyAck>,
+}
+
+impl App {
+    pub fn new(
+        config: &Config,
+        mailgun: Mailgun,
+        slack: Slack,
+        last_response_log: LastResponseLog,
+        data_dir: &Path,
+        clock: &Clock,
+    ) -> App {
+        let queue = config.queue.clone().map(|queue_config| {
+            RedisQueue::connect(queue_config).expect("Unable to connect to the queue")
+        });
+        let maintenance = Maintenance::load(data_dir.join("maintenance.json"))
+            .expect("Unable to load maintenance.json from DATA_DIR")
+            .with_clock(clock.clone());
+        let maintenance = match &queue {
+            Some(queue) => maintenance.shared(queue.client()),
+            None => maintenance,
+        };
+
+        // Nothing gets through these while in maintenance, whoever is
+        // sending.
+        let mailgun = Mailgun {
+            chaos: config.chaos.mailgun.clone().map(|faults| Chaos::new("Mailgun", faults)),
+            sandbox: Sandbox::for_mailgun(config.sandbox.clone(), &mailgun),
+            footers: config.footer.clone().map(Footers::new),
+            envelope_senders: config.bounces.as_ref().and_then(|bounces| EnvelopeSenders::new(bounces.envelope_senders.clone())),
+            pacing: config.pacing.clone().map(Pacing::new),
+            maintenance: Some(maintenance.clone()),
+            ..mailgun
+        };
+        let slack = Slack {
+            chaos: config.chaos.slack.clone().map(|faults| Chaos::new("Slack", faults)),
+            maintenance: Some(maintenance.clone()),
+            ..slack
+        };
+
+        let signatures = Signatures::new(&config.signatures, mailgun.api_key.clone()).unwrap_or_else(|e| panic!("{}", e));
+        let signatures = match &queue {
+            Some(queue) => signatures.shared(queue.client()),
+            None => signatures,
+        };
+        let flags = Flags::load(config.route_flags.clone(), data_dir.join("route_flags.json"))
+            .expect("Unable to load route_flags.json from DATA_DIR");
+        for rule in flags.rules().iter().filter(|rule| !rule.verify_signature) {
+            warn!("Webhooks for {} aren't verified, anyone can post them", rule.route);
+        }
+        if config.quarantine.is_some() && config.auth_results.trusted_authserv_ids.is_empty() {
+            warn!("[auth_results] has no trusted_authserv_ids, every email is quarantined");
+        }
+
+        let blocklist = Blocklist::load(data_dir.join("blocklist.json"))
+            .expect("Unable to load blocklist.json from DATA_DIR");
+
+        let metrics = Metrics::new(config.metrics.clone());
+
+        let tracer = Tracer::new(config.trace.clone(), data_dir, vec![mailgun.api_key.clone(), slack.api_key.clone()])
+            .with_clock(clock.clone());
+
+        let notices = Notices::new(&config.notices, slack.clone())
+            .unwrap_or_else(|e| panic!("{}", e))
+            .with_clock(clock.clone());
+
+        let alerts = Alerts::new(config.alerts.clone(), notices.clone(), metrics.clone())
+            .with_clock(clock.clone());
+
+        let last_response_log = match &queue {
+            Some(queue) => last_response_log.shared(queue.client()),
+            None => last_response_log,
+        };
+        let budget = config.send_budget.clone()
+            .map(|budget| SendBudget::new(budget, slack.clone(), metrics.clone()).with_clock(clock.clone()));
+        let mailgun = Mailgun {
+            budget: match (&queue, budget) {
+                (Some(queue), Some(budget)) => Some(budget.shared(queue.client())),
+                (_, budget) => budget,
+            },
+            ..mailgun
+        };
+        let threads = ThreadMap::load(data_dir.join("threads.json"))
+            .expect("Unable to load threads.json from DATA_DIR")
+            .with_clock(clock.clone());
+        let threads = match &queue {
+            Some(queue) => threads.shared(queue.client()),
+            None => threads,
+        };
+
+        let delays = Delays::load(config.reply_delays.clone(), data_dir.join("delayed.json"))
+            .expect("Unable to load delayed.json from DATA_DIR")
+            .with_clock(clock.clone());
+        let delays = match &queue {
+            Some(queue) => delays.shared(queue.client()),
+            None => delays,
+        };
+
+        let archive = Archive::new(data_dir.join("archive"));
+        let archive = match &config.archive_encryption {
+            Some(keys) => archive.encrypted(Sealer::new(keys).unwrap_or_else(|e| panic!("{}", e))),
+            None => archive,
+        };
+
+        let outbox = Outbox::new(data_dir.join("outbox.log"));
+        let outbox = match &config.sent_copies {
+            Some(copies) => outbox.keeping(Copies::new(copies.clone(), data_dir, mailgun.clone()).with_clock(clock.clone())),
+            None => outbox,
+        };
+
+        let pipeline = Pipeline {
+            mailgun,
+            slack,
+            metrics,
+            blocklist,
+            last_response_log,
+            archive,
+            outbox,
+            bounces: config.bounces.clone().map(|bounces| Bounces::new(bounces, data_dir.join("bounces.log"))),
+            loops: config.loops.clone().map(|loops| Loops::new(loops).with_clock(clock.clone())),
+            alerts,
+            deadlines: Arc::new(config.deadlines.clone()),
+            echo: Echo::new(config.echo.clone(), data_dir),
+            links: config.links.clone().map(ArchiveLinks::new),
+            threads,
+            identities: Arc::new(config.identities.clone()),
+            formatting: Arc::new(config.formatting.clone()),
+            digests: Arc::new(config.digests.clone()),
+            email_forwards: Arc::new(config.email_forwards.clone()),
+            link_safety: Arc::new(config.link_safety.clone()),
+            quarantine: config.quarantine.clone(),
+            trusted_authserv_ids: Arc::new(config.auth_results.trusted_authserv_ids.clone()),
+            fallback_channel: config.slack.fallback_channel.clone(),
+            flags,
+            threat_intel: config.threat_intel.as_ref()
+                .map(|threat_intel| ThreatIntel::new(threat_intel).unwrap_or_else(|e| panic!("{}", e))),
+            reply_to: Arc::new(config.reply_to.clone()),
+            maintenance,
+            variants: Variants::load(config.variants.clone(), data_dir.join("variants.json"))
+                .expect("Unable to load variants.json from DATA_DIR"),
+            feedback: config.feedback.clone().map(|feedback| Feedback::new(feedback, data_dir.join("feedback.log"))),
+            unsubscribe_links: config.unsubscribe.clone().map(UnsubscribeLinks::new),
+            unsubscribed: Unsubscribed::load(data_dir.join("unsubscribed.json"))
+                .expect("Unable to load unsubscribed.json from DATA_DIR"),
+            canned_replies: Arc::new(config.canned_replies.clone()),
+            delays,
+            takeover: config.takeover.clone()
+                .map(|takeover| Takeover::new(takeover, data_dir.join("takeover.json")).with_clock(clock.clone())),
+            suppressions: Suppressions::new(data_dir.join("suppressions.log")).with_clock(clock.clone()),
+            handling: config.handling.clone().map(|handling| {
+                Handling::load(handling, data_dir.join("unhandled.json"))
+                    .expect("Unable to load unhandled.json from DATA_DIR")
+                    .with_clock(clock.clone())
+            }),
+            reputation: config.reputation.clone().map(|reputation| {
+                Reputation::load(reputation, data_dir.join("reputation.json"))
+                    .expect("Unable to load reputation.json from DATA_DIR")
+                    .with_clock(clock.clone())
+            }),
+            notices,
+            tracer,
+            in_flight: Arc::new(Mutex::new(BTreeSet::new())),
+            clock: clock.clone(),
+        };
+
+        App {
+            tokens: match &config.admin.rate_limit {
+                Some(limit) => Tokens::new(config.admin.tokens.clone()).limited(ApiLimiter::new(limit.clone())),
+                None => Tokens::new(config.admin.tokens.clone()),
+            },
+            audit: AuditLog::new(data_dir.join("audit.log")),
+            publisher: Publisher::new(config.publish.clone()),
+            pipeline,
+            queue,
+            slash_command: config.slash_command.clone(),
+            policy: Arc::new(config.responses.clone()),
+            scrubber: Scrubber::new(&config.scrub).unwrap_or_else(|e| panic!("{}", e)),
+            pgp: Arc::new(Pgp::new(config.pgp.clone())),
+            smime: Arc::new(Smime::new(&config.smime).unwrap_or_else(|e| panic!("{}", e))),
+            unrouted: Unrouted::new(config.unrouted.clone(), data_dir),
+            captures: Captures::new(config.captures.clone(), data_dir).with_clock(clock.clone()),
+            signatures: Arc::new(signatures),
+            early_ack: config.early_ack.clone().map(|early_ack| {
+                EarlyAck::load(early_ack, data_dir.join("pending.json"))
+                    .expect("Unable to load pending.json from DATA_DIR")
+            }),
+        }
+    }
+}
+
+// Checked before the body is read, which can only happen once, so the
+// other formats' routes still get to read it.
+fn content_type(expected: &'static str) -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+    warp::header::<String>("content-type").and_then(move |content_type: String| {
+        if content_type.to_lowercase().starts_with(expected) {
+            Ok(content_type)
+        } else {
+            Err(warp::reject::not_found())
+        }
+    })
+}
+
+pub fn routes(app: App) -> impl Filter<Extract = (impl warp::Reply,), Error = Rejection> + Clone + Send + Sync + 'static {
+    let App { tokens, audit, publisher, pipeline, queue, slash_command, policy, scrubber, pgp, smime, unrouted, captures, signatures, early_ack } = app;
+    let mailgun = pipeline.mailgun.clone();
+    let metrics = pipeline.metrics.clone();
+    let blocklist = pipeline.blocklist.clone();
+    let last_response_log = pipeline.last_response_log.clone();
+    let archive = pipeline.archive.clone();
+    let outbox = pipeline.outbox.clone();
+    let alerts = pipeline.alerts.clone();
+    let links = pipeline.links.clone();
+    let slack = pipeline.slack.clone();
+    let maintenance = pipeline.maintenance.clone();
+    let handling = pipeline.handling.clone();
+    let flags = pipeline.flags.clone();
+
+    let rate_limit_state = warp::any().map(move || last_response_log.snapshot());
+
+    let intake = Intake {
+        mailgun: mailgun.clone(),
+        metrics: metrics.clone(),
+        publisher,
+        pipeline: pipeline.clone(),
+        queue: queue.clone(),
+        archive: archive.clone(),
+        policy,
+        scrubber,
+        pgp,
+        smime,
+        captures,
+        signatures,
+        early_ack,
+    };
+    let intake = warp::any().map(move || intake.clone());
+
+    let unsubscribed = pipeline.unsubscribed.clone();
+    let unsubscribed = warp::any().map(move || unsubscribed.clone());
+
+    let pipeline = warp::any().map(move || pipeline.clone());
+
+    let archive = warp::any().map(move || archive.clone());
+
+    let links = warp::any().map(move || links.clone());
+
+    let mailgun = warp::any().map(move || mailgun.clone());
+
+    let slack = warp::any().map(move || slack.clone());
+
+    let maintenance = warp::any().map(move || maintenance.clone());
+
+    let handling = warp::any().map(move || handling.clone());
+
+    let slash_command = warp::any().map(move || slash_command.clone());
+
+    let outbox = warp::any().map(move || outbox.clone());
+
+    let queue = warp::any().map(move || queue.clone());
+
+    let blocklist = warp::any().map(move || blocklist.clone());
+
+    let flags = warp::any().map(move || flags.clone());
+
+    let audit = warp::any().map(move || audit.clone());
+
+    let unrouted = warp::any().map(move || unrouted.clone());
+
+    let recover = {
+        let metrics = metrics.clone();
+        move |err: Rejection| {
+            count_error(&metrics, &err);
+            recover_error(err)
+        }
+    };
+    let metrics = warp::any().map(move || metrics.clone());
+
+    let basics = warp::post2()
+        .and(warp::body::content_length_limit(1024 * 1024 * 2)) // 2 MB right?
+        .and(intake.clone());
+
+    let no_reply_urlencoded = basics.clone()
+        .and(path!("emails" / "responder" / String).map(|template| Action::Respond { template }))
+        .and(content_type("application/x-www-form-urlencoded"))
+        .and(warp::header::headers_cloned())
+        .and(warp::body::concat())
+        .and_then(receive)
+        .recover(recover.clone());
+
+    let no_reply_multipart = basics.clone()
+        .and(path!("emails" / "responder" / String).map(|template| Action::Respond { template }))
+        .and(content_type("multipart/form-data"))
+        .and(warp::header::headers_cloned())
+        .and(warp::body::concat())
+        .and_then(receive_multipart)
+        .recover(recover.clone());
+
+    let no_reply_json = basics.clone()
+        .and(path!("emails" / "responder" / String).map(|template| Action::Respond { template }))
+        .and(content_type("application/json"))
+        .and(warp::header::headers_cloned())
+        .and(warp::body::concat())
+        .and_then(receive_json)
+        .recover(recover.clone());
+
+    let forward_email = basics.clone()
+        .and(path!("emails" / "forward" / "slack" / String).map(|channel| Action::ForwardToSlack { channel }))
+        .and(content_type("application/x-www-form-urlencoded"))
+        .and(warp::header::headers_cloned())
+        .and(warp::body::concat())
+        .and_then(receive)
+        .recover(recover.clone());
+
+    let forward_email_multipart = basics.clone()
+        .and(path!("emails" / "forward" / "slack" / String).map(|channel| Action::ForwardToSlack { channel }))
+        .and(content_type("multipart/form-data"))
+        .and(warp::header::headers_cloned())
+        .and(warp::body::concat())
+        .and_then(receive_multipart)
+        .recover(recover.clone());
+
+    let forward_email_json = basics.clone()
+        .and(path!("emails" / "forward" / "slack" / String).map(|channel| Action::ForwardToSlack { channel }))
+        .and(content_type("application/json"))
+        .and(warp::header::headers_cloned())
+        .and(warp::body::concat())
+        .and_then(receive_json)
+        .recover(recover.clone());
+
+    // One Mailgun route for both an auto-reply and a Slack forward.
+    let respond_and_forward = basics.clone()
+        .and(path!("emails" / "respond-and-forward" / String / "slack" / String)
+            .map(|template, channel| Action::RespondAndForward { template, channel }))
+        .and(content_type("application/x-www-form-urlencoded"))
+        .and(warp::header::headers_cloned())
+        .and(warp::body::concat())
+        .and_then(receive)
+        .recover(recover.clone());
+
+    let respond_and_forward_multipart = basics.clone()
+        .and(path!("emails" / "respond-and-forward" / String / "slack" / String)
+            .map(|template, channel| Action::RespondAndForward { template, channel }))
+        .and(content_type("multipart/form-data"))
+        .and(warp::header::headers_cloned())
+        .and(warp::body::concat())
+        .and_then(receive_multipart)
+        .recover(recover.clone());
+
+    let respond_and_forward_json = basics.clone()
+        .and(path!("emails" / "respond-and-forward" / String / "slack" / String)
+            .map(|template, channel| Action::RespondAndForward { template, channel }))
+        .and(content_type("application/json"))
+        .and(warp::header::headers_cloned())
+        .and(warp::body::concat())
+        .and_then(receive_json)
+        .recover(recover.clone());
+
+    let forward_to_email = basics.clone()
+        .and(path!("emails" / "forward" / "email" / String).map(|alias| Action::ForwardToEmail { alias }))
+        .and(content_type("application/x-www-form-urlencoded"))
+        .and(warp::header::headers_cloned())
+        .and(warp::body::concat())
+        .and_then(receive)
+        .recover(recover.clone());
+
+    let forward_to_email_multipart = basics.clone()
+        .and(path!("emails" / "forward" / "email" / String).map(|alias| Action::ForwardToEmail { alias }))
+        .and(content_type("multipart/form-data"))
+        .and(warp::header::headers_cloned())
+        .and(warp::body::concat())
+        .and_then(receive_multipart)
+        .recover(recover.clone());
+
+    let forward_to_email_json = basics.clone()
+        .and(path!("emails" / "forward" / "email" / String).map(|alias| Action::ForwardToEmail { alias }))
+        .and(content_type("application/json"))
+        .and(warp::header::headers_cloned())
+        .and(warp::body::concat())
+        .and_then(receive_json)
+        .recover(recover.clone());
+
+    // Copies of what the humans send, see takeover.rs.
+    let takeover_email = basics.clone()
+        .and(path!("emails" / "takeover").map(|| Action::Takeover))
+        .and(content_type("application/x-www-form-urlencoded"))
+        .and(warp::header::headers_cloned())
+        .and(warp::body::concat())
+        .and_then(receive)
+        .recover(recover.clone());
+
+    let takeover_email_multipart = basics.clone()
+        .and(path!("emails" / "takeover").map(|| Action::Takeover))
+        .and(content_type("multipart/form-data"))
+        .and(warp::header::headers_cloned())
+        .and(warp::body::concat())
+        .and_then(receive_multipart)
+        .recover(recover.clone());
+
+    let takeover_email_json = basics.clone()
+        .and(path!("emails" / "takeover").map(|| Action::Takeover))
+        .and(content_type("application/json"))
+        .and(warp::header::headers_cloned())
+        .and(warp::body::concat())
+        .and_then(receive_json)
+        .recover(recover.clone());
+
+    let digest_email = basics.clone()
+        .and(path!("emails" / "digest" / String).map(|name| Action::Digest { name }))
+        .and(content_type("application/x-www-form-urlencoded"))
+        .and(warp::header::headers_cloned())
+        .and(warp::body::concat())
+        .and_then(receive)
+        .recover(recover.clone());
+
+    let digest_email_multipart = basics.clone()
+        .and(path!("emails" / "digest" / String).map(|name| Action::Digest { name }))
+        .and(content_type("multipart/form-data"))
+        .and(warp::header::headers_cloned())
+        .and(warp::body::concat())
+        .and_then(receive_multipart)
+        .recover(recover.clone());
+
+    let digest_email_json = basics.clone()
+        .and(path!("emails" / "digest" / String).map(|name| Action::Digest { name }))
+        .and(content_type("application/json"))
+        .and(warp::header::headers_cloned())
+        .and(warp::body::concat())
+        .and_then(receive_json)
+        .recover(recover.clone());
+
+    // Last of the webhooks, for whatever none of them took.
+    let unrouted_webhook = basics.clone()
+        .and(warp::path("emails"))
+        .and(warp::path::tail())
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::body::concat())
+        .and(unrouted)
+        .map(unrouted_webhook);
+
+    let dashboard = warp::get2()
+        .and(path!("dashboard"))
+        .and(warp::path::end())
+        .and(auth::require(tokens.clone(), Scope::ReadStats))
+        .and(metrics.clone())
+        .and(rate_limit_state.clone())
+        .and(blocklist.clone())
+        .and(queue.clone())
+        .and(handling.clone())
+        .and(flags.clone())
+        .map(show_dashboard)
+        .recover(recover.clone());
+
+    let dashboard_blocklist = warp::post2()
+        .and(path!("dashboard" / "blocklist"))
+        .and(warp::path::end())
+        .and(auth::require(tokens.clone(), Scope::ManageBlocklist))
+        .and(warp::header::optional::<String>("origin"))
+        .and(warp::header::optional::<String>("host"))
+        .and(audit.clone())
+        .and(blocklist.clone())
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::form())
+        .and_then(change_blocklist)
+        .recover(recover.clone());
+
+    let dashboard_routes = warp::post2()
+        .and(path!("dashboard" / "routes"))
+        .and(warp::path::end())
+        .and(auth::require(tokens.clone(), Scope::ManageRoutes))
+        .and(warp::header::optional::<String>("origin"))
+        .and(warp::header::optional::<String>("host"))
+        .and(audit.clone())
+        .and(flags.clone())
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::form())
+        .and_then(change_route_flags)
+        .recover(recover.clone());
+
+    let dashboard_templates = warp::get2()
+        .and(path!("dashboard" / "templates"))
+        .and(warp::path::end())
+        .and(auth::require(tokens.clone(), Scope::ReadStats))
+        .and(mailgun.clone())
+        .and_then(show_templates)
+        .recover(recover.clone());
+
+    let dashboard_template = warp::get2()
+        .and(path!("dashboard" / "templates" / String))
+        .and(warp::path::end())
+        .and(auth::require(tokens.clone(), Scope::ReadStats))
+        .and(mailgun.clone())
+        .and_then(show_template)
+        .recover(recover.clone());
+
+    let dashboard_create_template = warp::post2()
+        .and(path!("dashboard" / "templates"))
+        .and(warp::path::end())
+        .and(auth::require(tokens.clone(), Scope::ManageTemplates))
+        .and(warp::header::optional::<String>("origin"))
+        .and(warp::header::optional::<String>("host"))
+        .and(audit.clone())
+        .and(mailgun.clone())
+        .and(warp::body::content_length_limit(1024 * 256))
+        .and(warp::body::form())
+        .and_then(create_template)
+        .recover(recover.clone());
+
+    let dashboard_update_template = warp::post2()
+        .and(path!("dashboard" / "templates" / String))
+        .and(warp::path::end())
+        .and(auth::require(tokens.clone(), Scope::ManageTemplates))
+        .and(warp::header::optional::<String>("origin"))
+        .and(warp::header::optional::<String>("host"))
+        .and(audit.clone())
+        .and(mailgun.clone())
+        .and(warp::body::content_length_limit(1024 * 256))
+        .and(warp::body::form())
+        .and_then(update_template)
+        .recover(recover.clone());
+
+    let admin_stats = warp::get2()
+        .and(path!("admin" / "stats"))
+        .and(warp::path::end())
+        .and(auth::require(tokens.clone(), Scope::ReadStats))
+        .and(metrics.clone())
+        .map(admin::stats)
+        .recover(recover.clone());
+
+    let admin_list_blocklist = warp::get2()
+        .and(path!("admin" / "blocklist"))
+        .and(warp::path::end())
+        .and(auth::require(tokens.clone(), Scope::ReadStats))
+        .and(blocklist.clone())
+        .map(admin::list_blocklist)
+        .recover(recover.clone());
+
+    let admin_list_unsubscribed = warp::get2()
+        .and(path!("admin" / "unsubscribed"))
+        .and(warp::path::end())
+        .and(auth::require(tokens.clone(), Scope::ReadStats))
+        .and(unsubscribed.clone())
+        .and(warp::query::<ExportQuery>())
+        .and_then(admin::list_unsubscribed)
+        .recover(recover.clone());
+
+    let admin_unsubscribe = warp::put2()
+        .and(path!("admin" / "unsubscribed" / String))
+        .and(warp::path::end())
+        .and(auth::require(tokens.clone(), Scope::ManageBlocklist))
+        .and(audit.clone())
+        .and(unsubscribed.clone())
+        .and_then(admin::unsubscribe)
+        .recover(recover.clone());
+
+    let admin_resubscribe = warp::delete2()
+        .and(path!("admin" / "unsubscribed" / String))
+        .and(warp::path::end())
+        .and(auth::require(tokens.clone(), Scope::ManageBlocklist))
+        .and(audit.clone())
+        .and(unsubscribed.clone())
+        .and_then(admin::resubscribe)
+        .recover(recover.clone());
+
+    let admin_block = warp::put2()
+        .and(path!("admin" / "blocklist" / String))
+        .and(warp::path::end())
+        .and(auth::require(tokens.clone(), Scope::ManageBlocklist))
+        .and(audit.clone())
+        .and(blocklist.clone())
+        .and_then(admin::block)
+        .recover(recover.clone());
+
+    let admin_unblock = warp::delete2()
+        .and(path!("admin" / "blocklist" / String))
+        .and(warp::path::end())
+        .and(auth::require(tokens.clone(), Scope::ManageBlocklist))
+        .and(audit.clone())
+        .and(blocklist.clone())
+        .and_then(admin::unblock)
+        .recover(recover.clone());
+
+    let admin_audit = warp::get2()
+        .and(path!("admin" / "audit"))
+        .and(warp::path::end())
+        .and(auth::require(tokens.clone(), Scope::ReadStats))
+        .and(audit.clone())
+        .and(warp::query::<AuditQuery>())
+        .and_then(admin::audit_log)
+        .recover(recover.clone());
+
+    let admin_archived_email = warp::get2()
+        .and(path!("admin" / "emails" / String))
+        .and(warp::path::end())
+        .and(auth::require(tokens.clone(), Scope::ReadStats))
+        .and(archive.clone())
+        .and_then(admin::archived_email)
+        .recover(recover.clone());
+
+    let admin_archived_email_html = warp::get2()
+        .and(path!("admin" / "emails" / String / "html"))
+        .and(warp::path::end())
+        .and(auth::require(tokens.clone(), Scope::ReadStats))
+        .and(archive.clone())
+        .and(warp::query::<HtmlQuery>())
+        .and_then(admin::archived_email_html)
+        .recover(recover.clone());
+
+    let admin_replay = warp::post2()
+        .and(path!("admin" / "emails" / String / "replay"))
+        .and(warp::path::end())
+        .and(auth::require(tokens.clone(), Scope::SendEmail))
+        .and(audit.clone())
+        .and(archive.clone())
+        .and(pipeline.clone())
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::json())
+        .and_then(admin::replay)
+        .recover(recover.clone());
+
+    let admin_send = warp::post2()
+        .and(path!("admin" / "send"))
+        .and(warp::path::end())
+        .and(auth::require(tokens.clone(), Scope::SendEmail))
+        .and(audit.clone())
+        .and(mailgun.clone())
+        .and(metrics.clone())
+        .and(outbox.clone())
+        .and(maintenance.clone())
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::json())
+        .and_then(admin::send)
+        .recover(recover.clone());
+
+    let admin_templates = warp::get2()
+        .and(path!("admin" / "templates"))
+        .and(warp::path::end())
+        .and(auth::require(tokens.clone(), Scope::ReadStats))
+        .and(mailgun.clone())
+        .and_then(admin::templates)
+        .recover(recover.clone());
+
+    let admin_template = warp::get2()
+        .and(path!("admin" / "templates" / String))
+        .and(warp::path::end())
+        .and(auth::require(tokens.clone(), Scope::ReadStats))
+        .and(mailgun.clone())
+        .and_then(admin::template)
+        .recover(recover.clone());
+
+    let admin_create_template = warp::post2()
+        .and(path!("admin" / "templates"))
+        .and(warp::path::end())
+        .and(auth::require(tokens.clone(), Scope::ManageTemplates))
+        .and(audit.clone())
+        .and(mailgun.clone())
+        .and(warp::body::content_length_limit(1024 * 256))
+        .and(warp::body::json())
+        .and_then(admin::create_template)
+        .recover(recover.clone());
+
+    let admin_update_template = warp::put2()
+        .and(path!("admin" / "templates" / String))
+        .and(warp::path::end())
+        .and(auth::require(tokens.clone(), Scope::ManageTemplates))
+        .and(audit.clone())
+        .and(mailgun.clone())
+        .and(warp::body::content_length_limit(1024 * 256))
+        .and(warp::body::json())
+        .and_then(admin::update_template)
+        .recover(recover.clone());
+
+    let admin_maintenance = warp::get2()
+        .and(path!("admin" / "maintenance"))
+        .and(warp::path::end())
+        .and(auth::require(tokens.clone(), Scope::ReadStats))
+        .and(maintenance.clone())
+        .map(admin::maintenance_status)
+        .recover(recover.clone());
+
+    let admin_start_maintenance = warp::put2()
+        .and(path!("admin" / "maintenance"))
+        .and(warp::path::end())
+        .and(auth::require(tokens.clone(), Scope::Maintenance))
+        .and(audit.clone())
+        .and(maintenance.clone())
+        .and_then(admin::start_maintenance)
+        .recover(recover.clone());
+
+    let admin_end_maintenance = warp::delete2()
+        .and(path!("admin" / "maintenance"))
+        .and(warp::path::end())
+        .and(auth::require(tokens.clone(), Scope::Maintenance))
+        .and(audit.clone())
+        .and(archive.clone())
+        .and(pipeline.clone())
+        .and_then(admin::end_maintenance)
+        .recover(recover.clone());
+
+    let admin_domains = warp::get2()
+        .and(path!("admin" / "domains"))
+        .and(warp::path::end())
+        .and(auth::require(tokens.clone(), Scope::ReadStats))
+        .and(mailgun.clone())
+        .and_then(admin::domains)
+        .recover(recover.clone());
+
+    let admin_pause_domain = warp::put2()
+        .and(path!("admin" / "domains" / String / "pause"))
+        .and(warp::path::end())
+        .and(auth::require(tokens.clone(), Scope::SendEmail))
+        .and(audit.clone())
+        .and(mailgun.clone())
+        .and_then(|domain, principal, audit, mailgun| admin::pause_domain(domain, true, principal, audit, mailgun))
+        .recover(recover.clone());
+
+    let admin_resume_domain = warp::delete2()
+        .and(path!("admin" / "domains" / String / "pause"))
+        .and(warp::path::end())
+        .and(auth::require(tokens.clone(), Scope::SendEmail))
+        .and(audit.clone())
+        .and(mailgun.clone())
+        .and_then(|domain, principal, audit, mailgun| admin::pause_domain(domain, false, principal, audit, mailgun))
+        .recover(recover.clone());
+
+    let admin_variants = warp::get2()
+        .and(path!("admin" / "variants"))
+        .and(warp::path::end())
+        .and(auth::require(tokens.clone(), Scope::ReadStats))
+        .and(pipeline.clone())
+        .map(admin::variants)
+        .recover(recover.clone());
+
+    let admin_sender = warp::get2()
+        .and(path!("admin" / "senders"))
+        .and(warp::path::end())
+        .and(auth::require(tokens.clone(), Scope::ReadStats))
+        .and(warp::query::<SenderQuery>())
+        .and(pipeline.clone())
+        .and_then(admin::sender)
+        .recover(recover.clone());
+
+    let admin_redact = warp::post2()
+        .and(path!("admin" / "emails" / String / "redact"))
+        .and(warp::path::end())
+        .and(auth::require(tokens.clone(), Scope::Redact))
+        .and(audit.clone())
+        .and(archive.clone())
+        .and(slack.clone())
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::json())
+        .and_then(admin::redact)
+        .recover(recover.clone());
+
+    // Authenticated by Slack's request signature, see commands.rs.
+    let slack_command = warp::post2()
+        .and(path!("slack" / "commands"))
+        .and(warp::path::end())
+        .and(slash_command.clone())
+        .and(audit.clone())
+        .and(archive.clone())
+        .and(slack.clone())
+        .and(warp::header::<String>("x-slack-request-timestamp"))
+        .and(warp::header::<String>("x-slack-signature"))
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::concat())
+        .and_then(run_slash_command)
+        .recover(recover.clone());
+
+    let slack_interaction = warp::post2()
+        .and(path!("slack" / "interactions"))
+        .and(warp::path::end())
+        .and(slash_command.clone())
+        .and(warp::header::<String>("x-slack-request-timestamp"))
+        .and(warp::header::<String>("x-slack-signature"))
+        .and(warp::body::content_length_limit(1024 * 64))
+        .and(warp::body::concat())
+        .and(audit.clone())
+        .and(pipeline.clone())
+        .and_then(run_interaction)
+        .recover(recover.clone());
+
+    let slack_event = warp::post2()
+        .and(path!("slack" / "events"))
+        .and(warp::path::end())
+        .and(slash_command.clone())
+        .and(warp::header::<String>("x-slack-request-timestamp"))
+        .and(warp::header::<String>("x-slack-signature"))
+        .and(warp::body::content_length_limit(1024 * 64))
+        .and(warp::body::concat())
+        .and(pipeline.clone())
+        .and_then(run_event)
+        .recover(recover.clone());
+
+    let admin_outbox = warp::get2()
+        .and(path!("admin" / "outbox"))
+        .and(warp::path::end())
+        .and(auth::require(tokens.clone(), Scope::ReadStats))
+        .and(outbox.clone())
+        .and(warp::query::<OutboxQuery>())
+        .and_then(admin::outbox)
+        .recover(recover.clone());
+
+    let admin_outbox_copy = warp::get2()
+        .and(path!("admin" / "outbox" / "copy"))
+        .and(warp::path::end())
+        .and(auth::require(tokens.clone(), Scope::ReadStats))
+        .and(outbox.clone())
+        .and(warp::query::<CopyQuery>())
+        .and_then(admin::outbox_copy)
+        .recover(recover.clone());
+
+    let admin_outbox_copy_html = warp::get2()
+        .and(path!("admin" / "outbox" / "copy" / "html"))
+        .and(warp::path::end())
+        .and(auth::require(tokens.clone(), Scope::ReadStats))
+        .and(outbox.clone())
+        .and(warp::query::<CopyQuery>())
+        .and_then(admin::outbox_copy_html)
+        .recover(recover.clone());
+
+    // Signed links rather than tokens, see links.rs.
+    let archive_view = warp::get2()
+        .and(path!("archive" / String))
+        .and(warp::path::end())
+        .and(links.clone())
+        .and(archive.clone())
+        .and(warp::query::<SignedQuery>())
+        .and_then(show_archived_email)
+        .recover(recover.clone());
+
+    let archive_attachment = warp::get2()
+        .and(path!("archive" / String / "attachments" / usize))
+        .and(warp::path::end())
+        .and(links.clone())
+        .and(archive.clone())
+        .and(warp::query::<SignedQuery>())
+        .and_then(show_archived_attachment)
+        .recover(recover.clone());
+
+    // Signed links in the auto-replies themselves, see feedback.rs.
+    let feedback_link = warp::get2()
+        .and(path!("feedback" / String))
+        .and(warp::path::end())
+        .and(pipeline.clone())
+        .and(warp::query::<FeedbackQuery>())
+        .and_then(record_feedback)
+        .recover(recover.clone());
+
+    // Signed links in the List-Unsubscribe header, see unsubscribe.rs.
+    let unsubscribe_page = warp::get2()
+        .and(path!("unsubscribe"))
+        .and(warp::path::end())
+        .and(pipeline.clone())
+        .and(warp::query::<UnsubscribeQuery>())
+        .and_then(show_unsubscribe)
+        .recover(recover.clone());
+
+    let unsubscribe = warp::post2()
+        .and(path!("unsubscribe"))
+        .and(warp::path::end())
+        .and(pipeline.clone())
+        .and(warp::query::<UnsubscribeQuery>())
+        .and_then(record_unsubscribe)
+        .recover(recover.clone());
+
+    let admin_feedback = warp::get2()
+        .and(path!("admin" / "feedback"))
+        .and(warp::path::end())
+        .and(auth::require(tokens.clone(), Scope::ReadStats))
+        .and(pipeline.clone())
+        .and_then(admin::feedback)
+        .recover(recover.clone());
+
+    // The rate limiter's suppressions, see suppressions.rs.
+    let admin_suppressions = warp::get2()
+        .and(path!("admin" / "suppressions"))
+        .and(warp::path::end())
+        .and(auth::require(tokens.clone(), Scope::ReadStats))
+        .and(pipeline.clone())
+        .and(warp::query::<SuppressionQuery>())
+        .and_then(admin::suppressions)
+        .recover(recover.clone());
+
+    let admin_suppressions_summary = warp::get2()
+        .and(path!("admin" / "suppressions" / "summary"))
+        .and(warp::path::end())
+        .and(auth::require(tokens.clone(), Scope::ReadStats))
+        .and(pipeline.clone())
+        .and(warp::query::<SuppressionQuery>())
+        .and_then(admin::suppressions_summary)
+        .recover(recover.clone());
+
+    // Mailgun's webhooks about the emails it sent, see bounces.rs.
+    let delivery_events = warp::post2()
+        .and(path!("mailgun" / "events"))
+        .and(warp::path::end())
+        .and(warp::body::content_length_limit(1024 * 64))
+        .and(warp::body::concat())
+        .and(pipeline.clone())
+        .and_then(record_delivery_event)
+        .recover(recover.clone());
+
+    // Bounces sent to an envelope sender, forwarded by a Mailgun route.
+    let bounce_reports = warp::post2()
+        .and(path!("emails" / "bounces"))
+        .and(warp::path::end())
+        .and(warp::body::content_length_limit(1024 * 1024 * 2))
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::body::concat())
+        .and(pipeline.clone())
+        .and_then(record_bounce_report)
+        .recover(recover.clone());
+
+    let ready = warp::get2()
+        .and(path!("ready"))
+        .and(warp::path::end())
+        .map(move || show_readiness(&alerts));
+
+    let version = warp::get2()
+        .and(path!("version"))
+        .and(warp::path::end())
+        .map(|| warp::reply::json(&Version::current()));
+
+    let quota = auth::quota(tokens.clone());
+
+    no_reply_urlencoded
+        .or(no_reply_multipart)
+        .or(no_reply_json)
+        .or(forward_email)
+        .or(forward_email_multipart)
+        .or(forward_email_json)
+        .or(respond_and_forward)
+        .or(respond_and_forward_multipart)
+        .or(respond_and_forward_json)
+        .or(forward_to_email)
+        .or(forward_to_email_multipart)
+        .or(forward_to_email_json)
+        .or(takeover_email)
+        .or(takeover_email_multipart)
+        .or(takeover_email_json)
+        .or(digest_email)
+        .or(digest_email_multipart)
+        .or(digest_email_json)
+        .or(bounce_reports)
+        .or(unrouted_webhook)
+        .or(dashboard)
+        .or(dashboard_blocklist)
+        .or(dashboard_routes)
+        .or(dashboard_templates)
+        .or(dashboard_template)
+        .or(dashboard_create_template)
+        .or(dashboard_update_template)
+        .or(admin_stats)
+        .or(admin_list_blocklist)
+        .or(admin_block)
+        .or(admin_list_unsubscribed)
+        .or(admin_unsubscribe)
+        .or(admin_resubscribe)
+        .or(admin_unblock)
+        .or(admin_audit)
+        .or(admin_archived_email)
+        .or(admin_archived_email_html)
+        .or(admin_replay)
+        .or(admin_send)
+        .or(admin_templates)
+        .or(admin_template)
+        .or(admin_create_template)
+        .or(admin_update_template)
+        .or(admin_outbox)
+        .or(admin_outbox_copy)
+        .or(admin_outbox_copy_html)
+        .or(admin_maintenance)
+        .or(admin_start_maintenance)
+        .or(admin_end_maintenance)
+        .or(admin_variants)
+        .or(admin_sender)
+        .or(admin_domains)
+        .or(admin_pause_domain)
+        .or(admin_resume_domain)
+        .or(admin_redact)
+        .or(slack_command)
+        .or(slack_interaction)
+        .or(slack_event)
+        .or(archive_view)
+        .or(archive_attachment)
+        .or(feedback_link)
+        .or(unsubscribe_page)
+        .or(unsubscribe)
+        .or(delivery_events)
+        .or(admin_feedback)
+        .or(admin_suppressions)
+        .or(admin_suppressions_summary)
+        .or(ready)
+        .or(version)
+        .and(quota)
+        .map(with_rate_limit_headers)
+}
+
+// See https://datatracker.ietf.org/doc/draft-ietf-httpapi-ratelimit-headers/
+fn set_rate_limit_headers<B>(response: &mut Response<B>, quota: &Quota) {
+    let headers = response.headers_mut();
+    headers.insert("ratelimit-limit", HeaderValue::from(quota.limit));
+    headers.insert("ratelimit-remaining", HeaderValue::from(quota.remaining));
+    headers.insert("ratelimit-reset", HeaderValue::from(quota.reset_seconds));
+}
+
+fn with_rate_limit_headers(reply: impl warp::Reply, quota: Option<Quota>) -> warp::reply::Response {
+    let mut response = reply.into_response();
+    if let Some(quota) = quota {
+        set_rate_limit_headers(&mut response, &quota);
+    }
+    response
+}
+
+#[derive(Serialize)]
+struct LimailErrorMessage {
+    code: u16,
+    message: String,
+}
+
+fn error_response(code: StatusCode, message: &str) -> Response<String> {
+    let body = serde_json::to_string(&LimailErrorMessage {
+        code: code.as_u16(),
+        message: String::from(message),
+    }).unwrap_or_default();
+    let mut response = Response::builder();
+    response.status(code).header(CONTENT_TYPE, "application/json");
+    if code == StatusCode::UNAUTHORIZED {
+        response.header(WWW_AUTHENTICATE, "Basic realm=\"limail\"");
+    }
+    response.body(body).unwrap()
+}
+
+fn count_error(metrics: &Metrics, err: &Rejection) {
+    // Delivery errors are counted by the pipeline itself.
+    if let Some(err) = err.find_cause::<MailgunError>() {
+        metrics.incr(match err {
+            MailgunError::JsonError(_) => "errors_json",
+            MailgunError::HmacError(_) => "errors_hmac",
+            MailgunError::MailgunError(_) => "errors_mailgun",
+            MailgunError::OverBudget(_) => "errors_budget",
+            MailgunError::Sandbox(_) => "errors_sandbox",
+            MailgunError::Maintenance(_) => "errors_maintenance",
+        });
+    } else if err.find_cause::<QueueError>().is_some() {
+        metrics.incr("errors_queue");
+    } else if err.find_cause::<MultipartError>().is_some() {
+        metrics.incr("errors_multipart");
+    } else if err.find_cause::<AuthError>().is_some() {
+        metrics.incr("errors_auth");
+    } else if err.find_cause::<StoreError>().is_some() {
+        metrics.incr("errors_store");
+    }
+}
+
+fn mailgun_error_status(err: &MailgunError) -> (StatusCode, &String) {
+    match err {
+        MailgunError::JsonError(s) => (StatusCode::BAD_REQUEST, s),
+        MailgunError::HmacError(s) => (StatusCode::BAD_REQUEST, s),
+        MailgunError::MailgunError(s) => (StatusCode::INTERNAL_SERVER_ERROR, s),
+        MailgunError::OverBudget(s) => (StatusCode::TOO_MANY_REQUESTS, s),
+        MailgunError::Sandbox(s) => (StatusCode::UNPROCESSABLE_ENTITY, s),
+        MailgunError::Maintenance(s) => (StatusCode::SERVICE_UNAVAILABLE, s),
+    }
+}
+
+pub fn recover_error(err: Rejection) -> Result<impl warp::Reply, Rejection> {
+    if let Some(err) = err.find_cause::<MailgunError>() {
+        let (code, msg) = mailgun_error_status(err);
+        Ok(error_response(code, msg))
+    } else if let Some(err) = err.find_cause::<DeliveryError>() {
+        let (code, msg) = match err {
+            DeliveryError::Mailgun(err) => mailgun_error_status(err),
+            DeliveryError::Slack(SlackError::HttpError(s)) | DeliveryError::Slack(SlackError::ChannelUnavailable(s)) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, s)
+            },
+            DeliveryError::Slack(SlackError::Maintenance(s)) => (StatusCode::SERVICE_UNAVAILABLE, s),
+            DeliveryError::DeadlineExceeded(s) => (StatusCode::SERVICE_UNAVAILABLE, s),
+            DeliveryError::Storage(StoreError::IoError(s)) | DeliveryError::Storage(StoreError::JsonError(s)) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, s)
+            },
+        };
+        Ok(error_response(code, msg))
+    } else if let Some(err) = err.find_cause::<QueueError>() {
+        error!("{}", err);
+        let msg = match err {
+            QueueError::RedisError(s) => s,
+            QueueError::JsonError(s) => s,
+        };
+        Ok(error_response(StatusCode::SERVICE_UNAVAILABLE, msg))
+    } else if let Some(err) = err.find_cause::<AuthError>() {
+        let (code, msg) = match err {
+            AuthError::Unauthorized(s) => (StatusCode::UNAUTHORIZED, s),
+            AuthError::Forbidden(s) => (StatusCode::FORBIDDEN, s),
+            AuthError::RateLimited(s, quota) => {
+                let mut response = error_response(StatusCode::TOO_MANY_REQUESTS, s);
+                set_rate_limit_headers(&mut response, quota);
+                response.headers_mut().insert(RETRY_AFTER, HeaderValue::from(quota.retry_after_seconds));
+                return Ok(response);
+            },
+        };
+        Ok(error_response(code, msg))
+    } else if let Some(err) = err.find_cause::<StoreError>() {
+        error!("{}", err);
+        let msg = match err {
+            StoreError::IoError(s) => s,
+            StoreError::JsonError(s) => s,
+        };
+        Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR, msg))
+    } else if let Some(err) = err.find_cause::<MultipartError>() {
+        Ok(error_response(StatusCode::BAD_REQUEST, &err.to_string()))
+    } else if let Some(err) = err.find_cause::<AdminError>() {
+        let (code, msg) = match err {
+            AdminError::NotFound(s) => (StatusCode::NOT_FOUND, s),
+            AdminError::InMaintenance(s) => (StatusCode::SERVICE_UNAVAILABLE, s),
+        };
+        Ok(error_response(code, msg))
+    } else {
+        // Could be a NOT_FOUND, or any other internal error... here we just
+        // let warp use its default rendering.
+        Err(err)
+    }
+}
+
+fn show_dashboard(
+    _principal: Principal,
+    metrics: Metrics,
+    rate_limit: RateLimitState,
+    blocklist: Blocklist,
+    queue: Option<RedisQueue>,
+    handling: Option<Handling>,
+    flags: Flags,
+) -> impl warp::Reply {
+    let queue_depth = queue.map(|queue| queue.depth().map_err(|e| e.to_string()));
+    let unhandled = handling.map(|handling| handling.count());
+    warp::reply::html(dashboard::render(&metrics, &rate_limit, &blocklist.entries(), queue_depth, unhandled, &flags.rules()))
+}
+
+// Meant for load balancers and monitoring, so it doesn't need a token.
+fn show_readiness(alerts: &Alerts) -> Response<String> {
+    let readiness = alerts.readiness();
+    Response::builder()
+        .status(if readiness.status == "ok" { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE })
+        .header(CONTENT_TYPE, "application/json")
+        .body(serde_json::to_string(&readiness).unwrap_or_default())
+        .unwrap()
+}
+
+fn verify_link(links: &Option<ArchiveLinks>, id: &str, query: &SignedQuery) -> Result<(), Rejection> {
+    match links {
+        None => Err(warp::reject::not_found()),
+        Some(links) if links.verify(id, query) => Ok(()),
+        Some(_) => Err(AuthError::Forbidden(String::from("This link is invalid or has expired")).into()),
+    }
+}
+
+fn show_archived_email(
+    id: String,
+    links: Option<ArchiveLinks>,
+    archive: Archive,
+    query: SignedQuery,
+) -> Result<impl warp::Reply, Rejection> {
+    verify_link(&links, &id, &query)?;
+    match archive.get(&id)? {
+        Some(archived) => {
+            let signed = format!("expires={}&signature={}", query.expires, query.signature);
+            Ok(warp::reply::html(viewer::render(&archived, &signed)))
+        },
+        None => Err(AdminError::NotFound(format!("No archived email {}", id)).into()),
+    }
+}
+
+fn show_archived_attachment(
+    id: String,
+    index: usize,
+    links: Option<ArchiveLinks>,
+    archive: Archive,
+    query: SignedQuery,
+) -> Result<impl warp::Reply, Rejection> {
+    verify_link(&links, &id, &query)?;
+    match archive.attachment(&id, index)? {
+        // Always a download, never rendered in limail's origin.
+        Some((attachment, data)) => Ok(Response::builder()
+            .header(CONTENT_TYPE, "application/octet-stream")
+            .header(CONTENT_DISPOSITION, format!(
+                "attachment; filename=\"{}\"",
+                attachment.filename.replace(|c: char| c == '"' || c == '\\' || c.is_control(), "_")
+            ))
+            .body(data)
+            .unwrap()),
+        None => Err(AdminError::NotFound(format!("No attachment {} on archived email {}", index, id)).into()),
+    }
+}
+
+fn record_feedback(
+    id: String,
+    pipeline: Pipeline,
+    query: FeedbackQuery,
+) -> Result<impl warp::Reply, Rejection> {
+    let feedback = match &pipeline.feedback {
+        Some(feedback) => feedback,
+        None => return Err(warp::reject::not_found()),
+    };
+    if !feedback.verify(&id, &query) {
+        return Err(AuthError::Forbidden(String::from("This link is invalid")).into());
+    }
+    feedback.record(&id, &query)?;
+    Ok(warp::reply::html(feedback::render(&id, &query)))
+}
+
+fn verify_unsubscribe(pipeline: &Pipeline, query: &UnsubscribeQuery) -> Result<(), Rejection> {
+    let links = match &pipeline.unsubscribe_links {
+        Some(links) => links,
+        None => return Err(warp::reject::not_found()),
+    };
+    if !links.verify(query) {
+        return Err(AuthError::Forbidden(String::from("This link is invalid")).into());
+    }
+    Ok(())
+}
+
+fn show_unsubscribe(pipeline: Pipeline, query: UnsubscribeQuery) -> Result<impl warp::Reply, Rejection> {
+    verify_unsubscribe(&pipeline, &query)?;
+    let done = pipeline.unsubscribed.contains(&query.address);
+    Ok(warp::reply::html(unsubscribe::render(&query, done)))
+}
+
+// The one-click POST from the mail client (its body is always
+// List-Unsubscribe=One-Click), or the button on the page.
+fn record_unsubscribe(pipeline: Pipeline, query: UnsubscribeQuery) -> Result<impl warp::Reply, Rejection> {
+    verify_unsubscribe(&pipeline, &query)?;
+    if pipeline.unsubscribed.add(&query.address, "link")? {
+        info!("{} unsubscribed from auto-replies", query.address);
+        pipeline.metrics.incr("unsubscribed");
+    }
+    Ok(warp::reply::html(unsubscribe::render(&query, true)))
+}
+
+#[derive(Deserialize)]
+struct BlocklistChange {
+    action: String,
+    entry: String,
+}
+
+// The dashboard relies on basic auth, which the browser will happily attach
+// to a form posted from some other site, so refuse cross-origin posts.
+fn is_same_origin(origin: &Option<String>, host: &Option<String>) -> bool {
+    match (origin, host) {
+        (None, _) => true,
+        (Some(origin), Some(host)) => {
+            let origin_host = origin.splitn(2, "://").nth(1).unwrap_or("");
+            origin_host == host
+        },
+        (Some(_), None) => false,
+    }
+}
+
+fn change_blocklist(
+    principal: Principal,
+    origin: Option<String>,
+    host: Option<String>,
+    audit: AuditLog,
+    blocklist: Blocklist,
+    change: BlocklistChange,
+) -> Result<impl warp::Reply, Rejection> {
+    if !is_same_origin(&origin, &host) {
+        return Err(AuthError::Forbidden(String::from("Cross-origin request refused")).into());
+    }
+    match &change.action[..] {
+        "add" => admin::change_blocklist(&principal, &audit, &blocklist, true, &change.entry)?,
+        "remove" => admin::change_blocklist(&principal, &audit, &blocklist, false, &change.entry)?,
+        _ => false,
+    };
+    Ok(see_other("/dashboard"))
+}
+
+// Back to the dashboard if `location` (with a template's name in it) won't
+// do as a header.
+fn see_other(location: &str) -> Response<String> {
+    Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(LOCATION, location)
+        .body(String::new())
+        .unwrap_or_else(|_| see_other("/dashboard"))
+}
+
+// An unticked checkbox isn't sent at all.
+#[derive(Deserialize)]
+struct RouteFlagsChange {
+    action: String,
+    route: String,
+    verify_signature: Option<String>,
+    archive: Option<String>,
+    enrich: Option<String>,
+    auto_reply: Option<String>,
+}
+
+fn change_route_flags(
+    principal: Principal,
+    origin: Option<String>,
+    host: Option<String>,
+    audit: AuditLog,
+    flags: Flags,
+    change: RouteFlagsChange,
+) -> Result<impl warp::Reply, Rejection> {
+    if !is_same_origin(&origin, &host) {
+        return Err(AuthError::Forbidden(String::from("Cross-origin request refused")).into());
+    }
+    let route = change.route.trim();
+    if route.is_empty() {
+        return Ok(see_other("/dashboard"));
+    }
+    match &change.action[..] {
+        "set" => {
+            if change.verify_signature.is_none() {
+                warn!("{} turned off signature checks for {}, anyone can post its webhooks", principal.name, route);
+            }
+            admin::set_route_flags(&principal, &audit, &flags, RouteFlags {
+                route: String::from(route),
+                verify_signature: change.verify_signature.is_some(),
+                archive: change.archive.is_some(),
+                enrich: change.enrich.is_some(),
+                auto_reply: change.auto_reply.is_some(),
+            })?;
+        },
+        "remove" => {
+            admin::remove_route_flags(&principal, &audit, &flags, route)?;
+        },
+        _ => (),
+    }
+    Ok(see_other("/dashboard"))
+}
+
+fn show_templates(_principal: Principal, mailgun: Mailgun) -> Result<impl warp::Reply, Rejection> {
+    Ok(warp::reply::html(dashboard::render_templates(&mailgun.list_templates()?)))
+}
+
+fn show_template(name: String, _principal: Principal, mailgun: Mailgun) -> Result<impl warp::Reply, Rejection> {
+    match mailgun.get_template(&name)? {
+        Some(template) => Ok(warp::reply::html(dashboard::render_template(&name, &template))),
+        None => Err(AdminError::NotFound(format!("Mailgun has no template {}", name)).into()),
+    }
+}
+
+fn create_template(
+    principal: Principal,
+    origin: Option<String>,
+    host: Option<String>,
+    audit: AuditLog,
+    mailgun: Mailgun,
+    request: NewTemplate,
+) -> Result<impl warp::Reply, Rejection> {
+    if !is_same_origin(&origin, &host) {
+        return Err(AuthError::Forbidden(String::from("Cross-origin request refused")).into());
+    }
+    admin::save_new_template(&principal, &audit, &mailgun, &request)?;
+    Ok(see_other(&format!("/dashboard/templates/{}", request.name)))
+}
+
+fn update_template(
+    name: String,
+    principal: Principal,
+    origin: Option<String>,
+    host: Option<String>,
+    audit: AuditLog,
+    mailgun: Mailgun,
+    request: TemplateUpdate,
+) -> Result<impl warp::Reply, Rejection> {
+    if !is_same_origin(&origin, &host) {
+        return Err(AuthError::Forbidden(String::from("Cross-origin request refused")).into());
+    }
+    admin::save_template_version(&principal, &audit, &mailgun, &name, &request)?;
+    Ok(see_other(&format!("/dashboard/templates/{}", name)))
+}
+
+// Slack wants a 200 with something to show the user for anything but a
+// forged request.
+fn run_slash_command(
+    slash: Option<SlashCommandConfig>,
+    audit: AuditLog,
+    archive: Archive,
+    slack: Slack,
+    timestamp: String,
+    signature: String,
+    body: warp::body::FullBody,
+) -> Result<impl warp::Reply, Rejection> {
+    let slash = match slash {
+        Some(slash) => slash,
+        None => return Err(warp::reject::not_found()),
+    };
+    let body = body.bytes();
+    if !slash.verify(&timestamp, &signature, body) {
+        return Err(AuthError::Unauthorized(String::from("Invalid Slack signature")).into());
+    }
+    let command: SlashCommand = match serde_urlencoded::from_bytes(body) {
+        Ok(command) => command,
+        Err(_) => return Ok(warp::reply::json(&SlashResponse::ephemeral(String::from(commands::USAGE)))),
+    };
+    if !slash.allows(&command.user_id) {
+        return Ok(warp::reply::json(&SlashResponse::ephemeral(String::from(
+            "You aren't allowed to run limail commands."
+        ))));
+    }
+    let principal = Principal {
+        name: format!("slack:{}", command.user_name),
+        scopes: vec![Scope::Redact],
+    };
+    let text = match commands::parse(&command.text) {
+        Command::Help => String::from(commands::USAGE),
+        Command::Redact { id, mode } => match admin::redact_email(&principal, &audit, &archive, &slack, &id, mode)? {
+            None => format!("No archived email {}.", id),
+            Some(response) if response.slack_failures.is_empty() => format!(
+                "Scrubbed email {} and {} Slack messages.",
+                id,
+                response.slack_messages
+            ),
+            Some(response) => format!(
+                "Scrubbed email {}, but Slack refused some messages:\n{}",
+                id,
+                response.slack_failures.join("\n")
+            ),
+        },
+    };
+    Ok(warp::reply::json(&SlashResponse::ephemeral(text)))
+}
+
+// Slack only needs a 200 back, the result is posted to the forward's thread.
+fn run_interaction(
+    slash: Option<SlashCommandConfig>,
+    timestamp: String,
+    signature: String,
+    body: warp::body::FullBody,
+    audit: AuditLog,
+    pipeline: Pipeline,
+) -> Result<impl warp::Reply, Rejection> {
+    let slash = match slash {
+        Some(slash) => slash,
+        None => return Err(warp::reject::not_found()),
+    };
+    let body = body.bytes();
+    if !slash.verify(&timestamp, &signature, body) {
+        return Err(AuthError::Unauthorized(String::from("Invalid Slack signature")).into());
+    }
+    let interaction = serde_urlencoded::from_bytes::<InteractionForm>(body).ok()
+        .and_then(|form| serde_json::from_str::<Interaction>(&form.payload).ok());
+    let interaction = match interaction {
+        Some(interaction) => interaction,
+        None => return Ok(StatusCode::OK),
+    };
+    let (channel, message) = match (&interaction.channel, &interaction.message) {
+        (Some(channel), Some(message)) => (channel, message),
+        _ => return Ok(StatusCode::OK),
+    };
+    let thread_ts = message.thread_ts.clone().unwrap_or_else(|| message.ts.clone());
+    let note = |text: String| {
+        let posted = pipeline.slack.send_message(&SlackMessage {
+            channel: channel.id.clone(),
+            text,
+            thread_ts: Some(thread_ts.clone()),
+            as_user: true,
+            username: None,
+            icon_emoji: None,
+            icon_url: None,
+            blocks: None,
+            unfurl_links: None,
+            unfurl_media: None,
+        });
+        if let Err(e) = posted {
+            error!("Unable to post to Slack thread {}: {}", thread_ts, e);
+        }
+    };
+    if !slash.allows(&interaction.user.id) {
+        note(format!("<@{}> isn't allowed to act on forwards.", interaction.user.id));
+        return Ok(StatusCode::OK);
+    }
+    let principal = Principal {
+        name: format!("slack:{}", interaction.user.username),
+        scopes: vec![Scope::SendEmail],
+    };
+    for action in interaction.actions.iter().filter(|action| action.action_id == canned::ACTION_ID) {
+        let (id, template) = match (canned::job_id(&action.block_id), &action.selected_option) {
+            (Some(id), Some(option)) => (id, &option.value),
+            _ => continue,
+        };
+        let archived = match pipeline.archive.get(id)? {
+            Some(archived) => archived,
+            None => {
+                note(format!("No archived email {}, nothing was sent.", id));
+                continue;
+            },
+        };
+        let email = &archived.job.email;
+        let route = archived.job.action.route();
+        let recipient = match pipeline.reply_recipient(&route, email) {
+            Ok(recipient) => recipient,
+            Err(reason) => {
+                note(format!("Not sending the {} reply to {}: {}.", template, email.from, reason));
+                continue;
+            },
+        };
+        let request = SendRequest {
+            list_unsubscribe: pipeline.list_unsubscribe(&recipient),
+            recipient,
+            template: template.clone(),
+            subject: format!("Re: {}", email.subject),
+            in_reply_to: email.get_message_id().ok(),
+            route: Some(route),
+        };
+        let sent = admin::send_manually(
+            &principal,
+            &audit,
+            &pipeline.mailgun,
+            &pipeline.metrics,
+            &pipeline.outbox,
+            &pipeline.maintenance,
+            &request,
+        );
+        note(match sent {
+            Ok(_) => format!("<@{}> sent the {} reply to {}.", interaction.user.id, template, request.recipient),
+            Err(_) => format!("Sending the {} reply to {} failed, see limail's log.", template, request.recipient),
+        });
+    }
+    for action in interaction.actions.iter().filter(|action| action.action_id == delays::ACTION_ID) {
+        let id = match delays::job_id(&action.block_id) {
+            Some(id) => id,
+            None => continue,
+        };
+        let cancelled = pipeline.delays.cancel(id, &principal.name)?;
+        if cancelled {
+            audit.record(&principal, "reply.cancel", id, serde_json::Value::Null, json!({ "cancelled_by": principal.name }))?;
+        }
+        note(if cancelled {
+            format!("<@{}> cancelled the auto-reply, someone should answer personally.", interaction.user.id)
+        } else {
+            String::from("Too late, the auto-reply was already sent or cancelled.")
+        });
+    }
+    for action in interaction.actions.iter().filter(|action| action.action_id == quarantine::ACTION_ID) {
+        let id = match quarantine::job_id(&action.block_id) {
+            Some(id) => id,
+            None => continue,
+        };
+        if pipeline.maintenance.is_enabled() {
+            note(String::from("limail is in maintenance, nothing is being posted. Try again later."));
+            continue;
+        }
+        let archived = match pipeline.archive.get(id)? {
+            Some(archived) => archived,
+            None => {
+                note(format!("No archived email {}, nothing was released.", id));
+                continue;
+            },
+        };
+        let job = archived.job.release(&principal.name);
+        audit.record(&principal, "email.release", id, json!({ "channel": channel.id }), json!({ "action": job.action }))?;
+        note(match pipeline.process(&job) {
+            Ok(_) => format!("<@{}> released this email to {}.", interaction.user.id, job.action.route()),
+            Err(e) => format!("Releasing this email failed: {}", e),
+        });
+    }
+    Ok(StatusCode::OK)
+}
+
+fn record_delivery_event(body: warp::body::FullBody, pipeline: Pipeline) -> Result<impl warp::Reply, Rejection> {
+    if pipeline.bounces.is_none() && pipeline.takeover.is_none() {
+        return Err(warp::reject::not_found());
+    }
+    let event: DeliveryEvent = serde_json::from_slice(body.bytes())
+        .map_err(|e| MailgunError::JsonError(format!("Unable to parse the event: {}", e)))?;
+    event.verify(pipeline.mailgun.api_key.expose(), pipeline.clock.now().timestamp())?;
+    if let (Some(bounces), Some(bounce)) = (&pipeline.bounces, event.bounce()) {
+        info!("{} bounced for {}: {}", bounce.message_id, bounce.recipient, bounce.reason);
+        bounces.record(&bounce)?;
+        pipeline.metrics.incr("replies_bounced");
+    }
+    if let Some(takeover) = &pipeline.takeover {
+        let data = &event.event_data;
+        if data.event == "accepted" && takeover.is_human(&data.message.headers.from) {
+            pipeline.humans_wrote(&data.message.headers.from, &[data.recipient.clone()])?;
+        }
+    }
+    Ok(warp::reply::json(&serde_json::Value::Null))
+}
+
+fn record_bounce_report(
+    content_type: Option<String>,
+    body: warp::body::FullBody,
+    pipeline: Pipeline,
+) -> Result<impl warp::Reply, Rejection> {
+    let bounces = match &pipeline.bounces {
+        Some(bounces) => bounces,
+        None => return Err(warp::reject::not_found()),
+    };
+    let fields: BTreeMap<String, String> = match content_type.as_ref().and_then(|content_type| multipart::boundary_from_content_type(content_type)) {
+        Some(boundary) => multipart::parse_parts(body.bytes(), &boundary)?
+            .into_iter()
+            .filter(|part| part.filename.is_none())
+            .filter_map(|part| Some((part.name, String::from_utf8(part.data).ok()?)))
+            .collect(),
+        None => serde_urlencoded::from_bytes(body.bytes())
+            .map_err(|e| MailgunError::JsonError(format!("Invalid webhook form: {}", e)))?,
+    };
+    let report = BounceReport::from_fields(fields)?;
+    report.verify(pipeline.mailgun.api_key.expose(), pipeline.clock.now().timestamp())?;
+    match bounces.from_report(&pipeline.outbox, &report)? {
+        Some(bounce) => {
+            info!("{} bounced for {}: {}", bounce.message_id, bounce.recipient, bounce.reason);
+            bounces.record(&bounce)?;
+            pipeline.metrics.incr("replies_bounced");
+        },
+        None => info!("Ignored a bounce report to {}", report.recipient),
+    }
+    Ok(warp::reply::json(&serde_json::Value::Null))
+}
+
+fn run_event(
+    slash: Option<SlashCommandConfig>,
+    timestamp: String,
+    signature: String,
+    body: warp::body::FullBody,
+    pipeline: Pipeline,
+) -> Result<impl warp::Reply, Rejection> {
+    let slash = match slash {
+        Some(slash) => slash,
+        None => return Err(warp::reject::not_found()),
+    };
+    let body = body.bytes();
+    if !slash.verify(&timestamp, &signature, body) {
+        return Err(AuthError::Unauthorized(String::from("Invalid Slack signature")).into());
+    }
+    let envelope: EventEnvelope = match serde_json::from_slice(body) {
+        Ok(envelope) => envelope,
+        Err(_) => return Ok(warp::reply::json(&serde_json::Value::Null)),
+    };
+    if envelope.kind == "url_verification" {
+        return Ok(warp::reply::json(&json!({ "challenge": envelope.challenge })));
+    }
+    let (handling, event) = match (&pipeline.handling, &envelope.event) {
+        (Some(handling), Some(event)) if event.kind == "reaction_added" => (handling, event),
+        _ => return Ok(warp::reply::json(&serde_json::Value::Null)),
+    };
+    let id = match event.item.as_ref().and_then(|item| handling.find(&item.channel, &item.ts)) {
+        Some(id) => id,
+        None => return Ok(warp::reply::json(&serde_json::Value::Null)),
+    };
+    let by = format!("slack:{}", event.user);
+    if handling.note_reaction(&id)? {
+        pipeline.archive.record_first_reaction(&id, &by)?;
+        pipeline.metrics.incr("emails_reacted_to");
+    }
+    if handling.is_handling_reaction(&event.reaction) {
+        handling.mark_handled(&id)?;
+        pipeline.archive.mark_handled(&id, &by)?;
+        pipeline.metrics.incr("emails_handled");
+    }
+    Ok(warp::reply::json(&serde_json::Value::Null))
+}
+
+#[derive(Clone)]
+struct Intake {
+    mailgun: Mailgun,
+    metrics: Metrics,
+    publisher: Publisher,
+    pipeline: Pipeline,
+    queue: Option<RedisQueue>,
+    archive: Archive,
+    policy: Arc<ResponsePolicy>,
+    scrubber: Scrubber,
+    pgp: Arc<Pgp>,
+    smime: Arc<Smime>,
+    captures: Captures,
+    signatures: Arc<Signatures>,
+    early_ack: Option<EarlyAck>,
+}
+
+fn receive_multipart(
+    intake: Intake,
+    action: Action,
+    content_type: String,
+    headers: HeaderMap,
+    body: warp::body::FullBody,
+) -> Result<impl warp::Reply, Rejection>
+{
+    let boundary = match multipart::boundary_from_content_type(&content_type) {
+        Some(boundary) => boundary,
+        None => return Err(warp::reject::not_found()),
+    };
+    let route = action.route();
+    let captured = |e: MultipartError| match intake.captures.capture(&route, &content_type, body.bytes(), &e.to_string()) {
+        Some(id) => MultipartError::Captured(Box::new(e), id),
+        None => e,
+    };
+    let result = multipart::parse_parts(body.bytes(), &boundary)
+        .map_err(|e| Rejection::from(captured(e)))
+        .and_then(|parts| {
+            let request = SignedRequest {
+                headers: &headers,
+                body: body.bytes(),
+                mailgun: MailgunToken::from_parts(&parts),
+                received_at: intake.pipeline.clock.now().timestamp(),
+            };
+            let id = verify_webhook(&intake, &route, &request)?;
+            let email = match intake.signatures.provider(&route) {
+                Provider::Mailgun => multipart::email_from_parts(&parts).map_err(|e| Rejection::from(captured(e)))?,
+                Provider::Sendgrid => providers::sendgrid_email(&parts, request.received_at)?,
+                Provider::Sns => return Err(MailgunError::JsonError(String::from("SNS posts JSON, not multipart")).into()),
+            };
+            let mime = multipart::raw_mime(&parts);
+            accept(intake.clone(), action, id, email, multipart::attachments(parts), mime, &request)
+        });
+    answer(&intake, &route, result)
+}
+
+// Before anything in the body is believed. Returns the id of the delivery,
+// see SignatureScheme::delivery_id.
+fn verify_webhook(intake: &Intake, route: &str, request: &SignedRequest) -> Result<String, Rejection> {
+    if intake.pipeline.flags.for_route(route).verify_signature {
+        if let Err(e) = intake.signatures.verify(route, request) {
+            intake.pipeline.notices.signature_failure(route, &e.to_string());
+            return Err(e.into());
+        }
+    }
+    Ok(intake.signatures.delivery_id(route, request))
+}
+
+#[derive(Serialize)]
+struct UnroutedResponse {
+    code: u16,
+    message: String,
+    path: String,
+    fields: Vec<String>,
+}
+
+fn unrouted_webhook(
+    intake: Intake,
+    tail: warp::path::Tail,
+    content_type: Option<String>,
+    body: warp::body::FullBody,
+    unrouted: Unrouted,
+) -> Response<String> {
+    intake.metrics.incr("errors_unrouted");
+    let request = UnroutedRequest {
+        at: intake.pipeline.clock.now(),
+        path: String::from(tail.as_str()),
+        fields: unrouted::field_names(content_type.as_ref().map(|c| &c[..]).unwrap_or(""), body.bytes()),
+        content_type,
+        size: body.remaining(),
+    };
+    if let Err(e) = unrouted.record(&request) {
+        error!("Unable to log the request to /emails/{}: {}", request.path, e);
+    }
+    let body = serde_json::to_string(&UnroutedResponse {
+        code: StatusCode::NOT_FOUND.as_u16(),
+        message: String::from(
+            "No webhook route here, expected emails/responder/<template>, emails/forward/slack/<channel>, \
+             emails/respond-and-forward/<template>/slack/<channel>, emails/forward/email/<alias> or \
+             emails/digest/<name> or emails/takeover posted as a form, multipart or JSON"
+        ),
+        path: format!("emails/{}", request.path),
+        fields: request.fields,
+    }).unwrap_or_default();
+    Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .header(CONTENT_TYPE, "application/json")
+        .body(body)
+        .unwrap()
+}
+
+fn receive_json(
+    intake: Intake,
+    action: Action,
+    _content_type: String,
+    headers: HeaderMap,
+    body: warp::body::FullBody,
+) -> Result<impl warp::Reply, Rejection>
+{
+    let route = action.route();
+    let result = serde_json::from_slice::<Value>(body.bytes())
+        .map_err(|e| MailgunError::JsonError(format!("Invalid webhook JSON: {}", e)))
+        .map_err(Rejection::from)
+        .and_then(|webhook| {
+            let request = SignedRequest {
+                headers: &headers,
+                body: body.bytes(),
+                mailgun: MailgunToken::from_json(&webhook),
+                received_at: intake.pipeline.clock.now().timestamp(),
+            };
+            let id = verify_webhook(&intake, &route, &request)?;
+            let email = match intake.signatures.provider(&route) {
+                Provider::Mailgun => serde_json::from_value::<MailgunJsonWebhook>(webhook)
+                    .map_err(|e| MailgunError::JsonError(format!("Invalid webhook JSON: {}", e)))
+                    .and_then(MailgunJsonWebhook::into_email)?,
+                Provider::Sns => providers::sns_email(&webhook, request.received_at)?,
+                Provider::Sendgrid => return Err(MailgunError::JsonError(String::from("SendGrid posts emails as multipart, not JSON")).into()),
+            };
+            accept(intake.clone(), action, id, email, Vec::new(), None, &request)
+        });
+    answer(&intake, &route, result)
+}
+
+fn receive(
+    intake: Intake,
+    action: Action,
+    _content_type: String,
+    headers: HeaderMap,
+    body: warp::body::FullBody,
+) -> Result<impl warp::Reply, Rejection>
+{
+    let route = action.route();
+    let request = SignedRequest {
+        headers: &headers,
+        body: body.bytes(),
+        mailgun: serde_urlencoded::from_bytes(body.bytes()).ok(),
+        received_at: intake.pipeline.clock.now().timestamp(),
+    };
+    let result = verify_webhook(&intake, &route, &request).and_then(|id| {
+        let email = match intake.signatures.provider(&route) {
+            Provider::Mailgun => serde_urlencoded::from_bytes::<MailgunEmailReceived>(body.bytes())
+                .map_err(|e| MailgunError::JsonError(format!("Invalid webhook form: {}", e)))?,
+            _ => return Err(MailgunError::JsonError(String::from("Only Mailgun posts emails as a form")).into()),
+        };
+        accept(intake.clone(), action, id, email, Vec::new(), None, &request)
+    });
+    answer(&intake, &route, result)
+}
+
+// What became of a handled webhook, which Mailgun keeps in its webhook
+// logs. The message is all there is to the text format. The outcome is
+// only known for emails processed there and then, not queued or held ones.
+// Retries of those get the same answer again, without processing the email
+// again, except on Mailgun's HMAC which refuses a token already handled.
+#[derive(Serialize, Deserialize)]
+struct Accepted {
+    code: u16,
+    message: String,
+    job_id: String,
+    action: Action,
+    outcome: Option<String>,
+    suppressed: bool,
+    // The id Mailgun gave the reply.
+    mailgun_id: Option<String>,
+    slack_messages: Vec<ArchivedSlackMessage>,
+    // Answered from the archive, see [answer].
+    #[serde(skip)]
+    cached: bool,
+}
+
+impl Accepted {
+    fn new(message: &str, job: &Job) -> Accepted {
+        Accepted {
+            code: StatusCode::OK.as_u16(),
+            message: String::from(message),
+            job_id: job.id.clone(),
+            action: job.action.clone(),
+            outcome: None,
+            suppressed: false,
+            mailgun_id: None,
+            slack_messages: Vec::new(),
+            cached: false,
+        }
+    }
+
+    // For a retry of an email that was processed when it first came.
+    fn cached(archive: &Archive, action: &Action, id: &str) -> Option<Accepted> {
+        let archived = archive.get(id).unwrap_or_else(|e| {
+            error!("Unable to look up the answer to an earlier delivery of job {}: {}", id, e);
+            None
+        })?;
+        let response = archived.response.filter(|_| archived.job.action.route() == action.route())?;
+        let accepted: Accepted = serde_json::from_value(response.body).ok()?;
+        Some(Accepted { cached: true, ..accepted })
+    }
+
+    fn processed(self, outcome: Outcome, pipeline: &Pipeline, job: &Job) -> Accepted {
+        let mailgun_id = match outcome {
+            Outcome::Replied => pipeline.outbox.sent_for_job(&job.id, job.received_at).ok()
+                .and_then(|sent| sent)
+                .map(|sent| sent.message_id),
+            _ => None,
+        };
+        let slack_messages = match outcome {
+            Outcome::Forwarded => pipeline.archive.get(&job.id).ok()
+                .and_then(|archived| archived)
+                .map(|archived| archived.slack_messages)
+                .unwrap_or_default(),
+            _ => Vec::new(),
+        };
+        Accepted {
+            outcome: Some(String::from(outcome.as_str())),
+            suppressed: outcome == Outcome::Suppressed,
+            mailgun_id,
+            slack_messages,
+            ..self
+        }
+    }
+}
+
+// Answers Mailgun according to the route's response policy, which decides
+// whether (and when) it retries.
+fn answer(
+    intake: &Intake,
+    route: &str,
+    result: Result<Accepted, Rejection>,
+) -> Result<Response<String>, Rejection> {
+    let err = match result {
+        Ok(accepted) => {
+            let mut response = Response::builder();
+            if accepted.cached {
+                response.header("x-limail-cached", "true");
+            }
+            return Ok(match intake.policy.success_format(route) {
+                SuccessFormat::Text => response
+                    .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+                    .body(accepted.message)
+                    .unwrap(),
+                SuccessFormat::Json => response
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(serde_json::to_string(&accepted).unwrap_or_default())
+                    .unwrap(),
+            });
+        },
+        Err(err) => err,
+    };
+    match policy::classify(&err) {
+        Some((failure, message)) => {
+            count_error(&intake.metrics, &err);
+            let disposition = intake.policy.disposition(route, failure);
+            error!("Webhook for {} failed ({:?}, answering {:?}): {}", route, failure, disposition, message);
+            Ok(error_response(disposition.status(), &message))
+        },
+        None => Err(err),
+    }
+}
+
+// `request` has been verified, see verify_webhook.
+fn accept(
+    intake: Intake,
+    action: Action,
+    id: String,
+    email: MailgunEmailReceived,
+    attachments: Vec<multipart::Part>,
+    mime: Option<Vec<u8>>,
+    request: &SignedRequest,
+) -> Result<Accepted, Rejection>
+{
+    let (signatures, route) = (intake.signatures.clone(), action.route());
+    let accepted = accept_job(intake, action, id, email, attachments, mime, request)?;
+    signatures.remember(&route, request);
+    Ok(accepted)
+}
+
+fn accept_job(
+    intake: Intake,
+    action: Action,
+    id: String,
+    mut email: MailgunEmailReceived,
+    attachments: Vec<multipart::Part>,
+    mime: Option<Vec<u8>>,
+    request: &SignedRequest,
+) -> Result<Accepted, Rejection>
+{
+    let (headers, body) = (request.headers, request.body);
+    let flags = intake.pipeline.flags.for_route(&action.route());
+    if flags.archive {
+        if let Some(accepted) = Accepted::cached(&intake.archive, &action, &id) {
+            info!("Job {} was already processed ({}), answering its retry the same", accepted.job_id, accepted.message);
+            intake.metrics.incr("retries_cached");
+            return Ok(accepted);
+        }
+    }
+    intake.metrics.incr("emails_received");
+    let attachment_sizes: Vec<usize> = attachments.iter().map(|part| part.data.len()).collect();
+    intake.metrics.record_payload(&action.route(), body.len(), &attachment_sizes);
+    if transfer::decode_remnants(&mut email) {
+        intake.metrics.incr("emails_transfer_decoded");
+    }
+    match intake.pgp.process(&mut email, &attachments) {
+        Decryption::NotEncrypted => (),
+        Decryption::Decrypted => intake.metrics.incr("emails_decrypted"),
+        Decryption::Failed => intake.metrics.incr("errors_decryption"),
+    }
+    if intake.scrubber.scrub(&mut email) > 0 {
+        intake.metrics.incr("emails_scrubbed");
+    }
+    let processed = match action {
+        Action::Respond { .. } => "Message Processed",
+        Action::ForwardToSlack { .. } => "Sent",
+        Action::ForwardToEmail { .. } => "Sent",
+        Action::RespondAndForward { .. } => "Message Processed",
+        Action::Digest { .. } => "Collected",
+        Action::Takeover => "Noted",
+    };
+    let mut job = Job::new(id, action, email, intake.pipeline.clock.now());
+    let trace = TraceContext::from_headers(headers).unwrap_or_else(TraceContext::start);
+    info!(
+        "Job {} is span {} of trace {}{}",
+        job.id,
+        trace.span_id,
+        trace.trace_id,
+        trace.parent_id.as_ref().map(|parent| format!(", under {}", parent)).unwrap_or_default()
+    );
+    job.trace = Some(trace);
+    if flags.enrich {
+        job.smime = intake.smime.check(&job.email.from, &attachments, mime.as_ref().map(|mime| &mime[..]));
+    }
+    // Failing here makes Mailgun retry, rather than handling an email we
+    // couldn't replay later.
+    // A retry is the job that first came then, which is how its replies
+    // are told apart from ones to other jobs.
+    if flags.archive {
+        if let Some(archived) = intake.archive.store(&job, &attachments, body.len())? {
+            job.received_at = archived.job.received_at;
+        }
+    }
+    intake.publisher.publish_in_background(InboundEvent::new(&job.action.route(), &job.email), intake.metrics.clone());
+    match &intake.queue {
+        Some(queue) => {
+            queue.push(&job)?;
+            intake.metrics.incr("jobs_queued");
+            Ok(Accepted::new("Message Queued", &job))
+        },
+        None if intake.pipeline.maintenance.is_enabled() => {
+            intake.pipeline.maintenance.hold(&job.id)?;
+            intake.metrics.incr("jobs_held");
+            Ok(Accepted::new("Message Held", &job))
+        },
+        None => match &intake.early_ack {
+            Some(early_ack) if early_ack.applies_to(&job.action.route()) => {
+                let accepted = Accepted::new("Message Accepted", &job);
+                early_ack.accept(&intake.pipeline, job)?;
+                intake.metrics.incr("jobs_acked_early");
+                Ok(accepted)
+            },
+            _ => {
+                let outcome = intake.pipeline.process_within_deadline(&job)?;
+                let accepted = Accepted::new(processed, &job).processed(outcome, &intake.pipeline, &job);
+                if flags.archive {
+                    let recorded = serde_json::to_value(&accepted)
+                        .map_err(|e| StoreError::IoError(e.to_string()))
+                        .and_then(|body| intake.archive.record_response(&job, body));
+                    if let Err(e) = recorded {
+                        error!("Unable to archive the answer to job {}: {}", job.id, e);
+                    }
+                }
+                Ok(accepted)
+            },
+        },
+    }
+}
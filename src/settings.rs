@@ -0,0 +1,290 @@
+use std::env;
+use std::fmt::{self, Display};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+
+use crate::clock::Clock;
+use crate::config::Config;
+use crate::encryption::Sealer;
+use crate::listener;
+use crate::mailgun::MAILGUN_URL;
+use crate::notices;
+use crate::proxies::TrustedProxies;
+use crate::scrub::Scrubber;
+use crate::secrets::{self, Secret};
+use crate::signatures::Signatures;
+use crate::slack::SLACK_URL;
+use crate::threats::ThreatIntel;
+
+pub struct Problem {
+    pub message: String,
+    pub suggestion: Option<String>,
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.suggestion {
+            Some(suggestion) => write!(f, "{}\n    {}", self.message, suggestion),
+            None => f.write_str(&self.message),
+        }
+    }
+}
+
+// Everything the server reads from its environment and LIMAIL_CONFIG,
+// checked up front.
+pub struct Settings {
+    pub clock: Clock,
+    pub config: Config,
+    pub data_dir: PathBuf,
+    pub drain_seconds: u64,
+    // None when systemd passes us the socket.
+    pub listen_address: Option<SocketAddr>,
+    pub mailgun_api_key: Secret,
+    pub mailgun_api_url: String,
+    pub mailgun_domain: String,
+    pub mailgun_from: String,
+    pub mode: String,
+    pub reuse_port: bool,
+    pub skip_startup_check: bool,
+    pub slack_api_token: Secret,
+    pub slack_api_url: String,
+    pub time_between_responses_minutes: i64,
+    pub v6_only: bool,
+}
+
+#[derive(Default)]
+struct Problems(Vec<Problem>);
+
+impl Problems {
+    fn add(&mut self, message: String, suggestion: Option<&str>) {
+        self.0.push(Problem {
+            message,
+            suggestion: suggestion.map(String::from),
+        });
+    }
+
+    fn required(&mut self, name: &str, suggestion: &str) -> String {
+        match env::var(name) {
+            Ok(value) if !value.trim().is_empty() => value,
+            Ok(_) => {
+                self.add(format!("{} is empty", name), Some(suggestion));
+                String::new()
+            },
+            Err(_) => {
+                self.add(format!("{} isn't set", name), Some(suggestion));
+                String::new()
+            },
+        }
+    }
+
+    // Also read from NAME_FILE or a secret store, see secrets.rs.
+    fn secret(&mut self, name: &str, suggestion: &str) -> Secret {
+        match secrets::lookup(name) {
+            Some(Ok(value)) if !value.expose().trim().is_empty() => value,
+            Some(Ok(_)) => {
+                self.add(format!("{} is empty", name), Some(suggestion));
+                Secret::new(String::new())
+            },
+            Some(Err(e)) => {
+                self.add(e, Some("Check the secret exists and limail's credentials can read it"));
+                Secret::new(String::new())
+            },
+            None => {
+                self.add(format!("{} isn't set", name), Some(&format!("{}, or point {}_FILE at a file holding it", suggestion, name)));
+                Secret::new(String::new())
+            },
+        }
+    }
+
+    fn parsed<T: std::str::FromStr>(&mut self, name: &str, value: Option<String>, suggestion: &str) -> Option<T> {
+        let value = value?;
+        match value.trim().parse() {
+            Ok(parsed) => Some(parsed),
+            Err(_) => {
+                self.add(format!("{} is {:?}, which isn't valid", name, value), Some(suggestion));
+                None
+            },
+        }
+    }
+
+    fn path_exists(&mut self, what: &str, path: &Path) {
+        if !path.exists() {
+            self.add(
+                format!("{} {} doesn't exist", what, path.display()),
+                Some("Check the path, relative paths are relative to where limail is started"),
+            );
+        }
+    }
+}
+
+impl Settings {
+    // Every problem at once, rather than one per restart.
+    pub fn from_env() -> Result<Settings, Vec<Problem>> {
+        let mut problems = Problems::default();
+
+        // First, since it can set the rest.
+        let config = match env::var("LIMAIL_CONFIG") {
+            Ok(path) => Config::from_file(&path).unwrap_or_else(|e| {
+                problems.add(e, Some("See limail.example.toml for every option"));
+                Config::default()
+            }),
+            Err(_) => Config::default(),
+        };
+        for (name, value) in &config.env {
+            if env::var_os(name).is_none() {
+                env::set_var(name, value);
+            }
+        }
+
+        let mailgun_api_key = problems.secret("MAILGUN_API_KEY", "Set it to a Mailgun API key with sending rights for MAILGUN_DOMAIN");
+        let mailgun_domain = problems.required("MAILGUN_DOMAIN", "Set it to the Mailgun sending domain, e.g. MAILGUN_DOMAIN=mg.lichess.org");
+        let mailgun_from = problems.required("MAILGUN_FROM", "Set it to the From of replies, e.g. MAILGUN_FROM=\"Lichess <contact@lichess.org>\"");
+        let slack_api_token = problems.secret("SLACK_API_TOKEN", "Set it to the bot token (xoxb-…) of the Slack app");
+        let time_between_responses = problems.required("TIME_BETWEEN_RESPONSES_MINUTES", "Set it to how long to wait before replying to the same address again, e.g. 60");
+        let time_between_responses_minutes = match &time_between_responses[..] {
+            "" => 0,
+            _ => problems
+                .parsed("TIME_BETWEEN_RESPONSES_MINUTES", Some(time_between_responses), "It's a number of minutes, e.g. 60")
+                .unwrap_or(0),
+        };
+        let drain_seconds = problems
+            .parsed("LIMAIL_DRAIN_SECONDS", env::var("LIMAIL_DRAIN_SECONDS").ok(), "It's a number of seconds, e.g. 30")
+            .unwrap_or(30);
+        // Only for trying out time-dependent behaviour on staging.
+        let clock = problems
+            .parsed::<DateTime<Utc>>("LIMAIL_CLOCK", env::var("LIMAIL_CLOCK").ok(), "It's when to start the clock at, e.g. 2020-01-06T08:59:00Z")
+            .map_or_else(Clock::system, Clock::starting_at);
+
+        let mode = env::var("LIMAIL_MODE").unwrap_or_else(|_| String::from("all"));
+        if !["all", "frontend", "worker"].contains(&&mode[..]) {
+            problems.add(format!("LIMAIL_MODE is {:?}", mode), Some("Use all, frontend or worker"));
+        }
+
+        // A worker doesn't listen, and systemd may hand us the socket.
+        let socket_activated = env::var("LISTEN_FDS").is_ok();
+        let listen_address = match env::var("LISTEN_ADDRESS_PORT") {
+            Ok(address) => problems.parsed(
+                "LISTEN_ADDRESS_PORT",
+                Some(address),
+                "It's an address and a port, e.g. LISTEN_ADDRESS_PORT=127.0.0.1:8080, or [::]:8080 for IPv6 and IPv4",
+            ),
+            Err(_) if mode != "worker" && !socket_activated => {
+                problems.add(
+                    String::from("LISTEN_ADDRESS_PORT isn't set"),
+                    Some("Set it to the address to listen on, e.g. 127.0.0.1:8080, or start limail through limail.socket"),
+                );
+                None
+            },
+            Err(_) => None,
+        };
+
+        // Otherwise [::] takes IPv4 clients too.
+        let v6_only = env::var("LIMAIL_V6_ONLY").is_ok();
+        if let (true, Some(SocketAddr::V4(address))) = (v6_only, &listen_address) {
+            problems.add(
+                format!("LIMAIL_V6_ONLY is set but LISTEN_ADDRESS_PORT is the IPv4 address {}", address),
+                Some("Listen on an IPv6 address, e.g. [::]:8080, or unset LIMAIL_V6_ONLY"),
+            );
+        }
+
+        let data_dir = PathBuf::from(env::var("DATA_DIR").unwrap_or_else(|_| String::from(".")));
+        if !data_dir.is_dir() {
+            problems.add(
+                format!("DATA_DIR {} isn't a directory", data_dir.display()),
+                Some("Create it, limail keeps its blocklist, archive and outbox there"),
+            );
+        }
+        if let Ok(dir) = env::var("TEMPLATE_DIR") {
+            problems.path_exists("TEMPLATE_DIR", Path::new(&dir));
+        }
+
+        if mode != "all" && config.queue.is_none() {
+            problems.add(
+                format!("LIMAIL_MODE={} needs a [queue] section in LIMAIL_CONFIG", mode),
+                Some("Add [queue], or use LIMAIL_MODE=all"),
+            );
+        }
+        if let Some(keys) = &config.archive_encryption {
+            if let Err(e) = Sealer::new(keys) {
+                problems.add(e, Some("Generate a key with `openssl rand -base64 32`"));
+            }
+        }
+        if let Err(e) = Scrubber::new(&config.scrub) {
+            problems.add(e, None);
+        }
+        if let Err(e) = Signatures::new(&config.signatures, mailgun_api_key.clone()) {
+            problems.add(e, Some("See [[signatures]] in limail.example.toml"));
+        }
+        if let Some(Err(e)) = config.threat_intel.as_ref().map(ThreatIntel::new) {
+            problems.add(e, Some("See [threat_intel] in limail.example.toml"));
+        }
+        if let Err(e) = notices::check(&config.notices) {
+            problems.add(e, Some("The templates are Handlebars, see [notices] in limail.example.toml"));
+        }
+        if (config.chaos.mailgun.is_some() || config.chaos.slack.is_some()) && !cfg!(feature = "chaos") {
+            problems.add(
+                String::from("LIMAIL_CONFIG has a [chaos] section, but limail was built without the chaos feature"),
+                Some("Build with `cargo build --features chaos`, or remove [chaos]"),
+            );
+        }
+        for sender in config.bounces.iter().flat_map(|bounces| bounces.envelope_senders.iter()) {
+            if !sender.address.contains('@') {
+                problems.add(
+                    format!("The envelope sender {:?} for {} isn't an email address", sender.address, sender.route),
+                    Some("See [bounces] in limail.example.toml"),
+                );
+            }
+        }
+        for forward in config.email_forwards.iter().filter(|forward| !forward.to.contains('@')) {
+            problems.add(
+                format!("The email forward {} goes to {:?}, which isn't an email address", forward.alias, forward.to),
+                Some("See [[email_forwards]] in limail.example.toml"),
+            );
+        }
+        if let Some(bytes) = config.http.max_header_bytes.filter(|bytes| *bytes < listener::MIN_HEADER_BYTES) {
+            problems.add(
+                format!("[http] max_header_bytes is {}, below the least the server can do", bytes),
+                Some(&format!("Set it to at least {}", listener::MIN_HEADER_BYTES)),
+            );
+        }
+        if let Some(seconds) = config.http.keep_alive_seconds.filter(|seconds| *seconds <= config.deadlines.handler_seconds) {
+            problems.add(
+                format!("[http] keep_alive_seconds is {}, which would cut off webhooks still being handled", seconds),
+                Some(&format!("Set it above [deadlines] handler_seconds ({})", config.deadlines.handler_seconds)),
+            );
+        }
+        if let Some(Err(e)) = config.http.proxy.as_ref().map(TrustedProxies::new) {
+            problems.add(format!("[http.proxy] addresses: {}", e), Some("List IP addresses or networks, e.g. [\"10.0.0.0/8\", \"::1\"]"));
+        }
+        if let Some(pgp) = &config.pgp {
+            problems.path_exists("The [pgp] homedir", Path::new(&pgp.homedir));
+        }
+        for path in &config.smime.ca_files {
+            problems.path_exists("The S/MIME CA file", Path::new(path));
+        }
+
+        if !problems.0.is_empty() {
+            return Err(problems.0);
+        }
+        Ok(Settings {
+            clock,
+            config,
+            data_dir,
+            drain_seconds,
+            listen_address,
+            mailgun_api_key,
+            mailgun_api_url: env::var("MAILGUN_API_URL").unwrap_or_else(|_| String::from(MAILGUN_URL)),
+            mailgun_domain,
+            mailgun_from,
+            mode,
+            reuse_port: env::var("LIMAIL_REUSE_PORT").is_ok(),
+            skip_startup_check: env::var("LIMAIL_SKIP_STARTUP_CHECK").is_ok(),
+            slack_api_token,
+            slack_api_url: env::var("SLACK_API_URL").unwrap_or_else(|_| String::from(SLACK_URL)),
+            time_between_responses_minutes,
+            v6_only,
+        })
+    }
+}
@@ -0,0 +1,415 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Public};
+use openssl::sign::Verifier;
+use openssl::x509::X509;
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use warp::http::HeaderMap;
+
+use crate::mailgun::{self, MailgunError};
+use crate::multipart::Part;
+use crate::pipeline::route_matches;
+use crate::secrets::Secret;
+
+const SENDGRID_SIGNATURE: &str = "x-twilio-email-event-webhook-signature";
+const SENDGRID_TIMESTAMP: &str = "x-twilio-email-event-webhook-timestamp";
+const DEFAULT_TOKEN_HEADER: &str = "x-limail-token";
+const CERT_TIMEOUT_SECONDS: u64 = 10;
+
+// Which scheme verifies the webhooks posted to route (exact, or a prefix
+// ending in *), and so whose format they're in. Routes without one use
+// Mailgun's HMAC.
+//
+// * mailgun: the timestamp, token and signature fields, signed with
+//   MAILGUN_API_KEY, no more than 15 minutes off and with a token that
+//   wasn't already handled.
+// * sendgrid-ecdsa: SendGrid's signed webhook, Inbound Parse's multipart
+//   format, public_key is the base64 verification key from its settings.
+// * sns: an Amazon SNS notification of an SES receipt, checked against the
+//   certificate it names (which has to be Amazon's).
+// * static-token: the header request header (X-Limail-Token by default)
+//   has to be token, on webhooks in Mailgun's format.
+#[derive(Deserialize, Clone)]
+pub struct RouteSignature {
+    pub route: String,
+    pub scheme: String,
+    #[serde(default)]
+    pub public_key: Option<String>,
+    #[serde(default)]
+    pub header: Option<String>,
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+// Mailgun's signing fields, from wherever the webhook's format puts them.
+#[derive(Deserialize)]
+pub struct MailgunToken {
+    pub timestamp: i64,
+    pub token: String,
+    pub signature: String,
+}
+
+impl MailgunToken {
+    // The newer JSON webhooks have the timestamp as a string or a number.
+    pub fn from_json(webhook: &Value) -> Option<MailgunToken> {
+        let signature = &webhook["signature"];
+        Some(MailgunToken {
+            timestamp: match &signature["timestamp"] {
+                Value::Number(n) => n.as_i64()?,
+                Value::String(s) => s.parse().ok()?,
+                _ => return None,
+            },
+            token: String::from(signature["token"].as_str()?),
+            signature: String::from(signature["signature"].as_str()?),
+        })
+    }
+
+    pub fn from_parts(parts: &[Part]) -> Option<MailgunToken> {
+        let field = |name: &str| parts.iter()
+            .find(|part| part.filename.is_none() && part.name == name)
+            .and_then(|part| std::str::from_utf8(&part.data).ok());
+        Some(MailgunToken {
+            timestamp: field("timestamp")?.trim().parse().ok()?,
+            token: String::from(field("token")?),
+            signature: String::from(field("signature")?),
+        })
+    }
+}
+
+// What a webhook's signature can cover. It's verified before the email in
+// the body is read, which is up to the provider that sent it.
+pub struct SignedRequest<'a> {
+    pub headers: &'a HeaderMap,
+    pub body: &'a [u8],
+    pub mailgun: Option<MailgunToken>,
+    // When it arrived, as a Unix timestamp, for how old its signature is.
+    pub received_at: i64,
+}
+
+// Who posts a route's webhooks, in their own format: Mailgun's (whatever
+// the scheme verifying them), SendGrid's Inbound Parse or Amazon SES
+// notifications through SNS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Provider {
+    Mailgun,
+    Sendgrid,
+    Sns,
+}
+
+fn digest_id(bytes: &[u8]) -> String {
+    hex::encode(&Sha256::digest(bytes)[..8])
+}
+
+pub trait SignatureScheme: Send + Sync {
+    fn verify(&self, request: &SignedRequest) -> Result<(), MailgunError>;
+
+    fn provider(&self) -> Provider {
+        Provider::Mailgun
+    }
+
+    // The same for a retry of a delivery and different for every other
+    // one, which is what a job's id is. Unless the scheme says better,
+    // that's the body itself.
+    fn delivery_id(&self, request: &SignedRequest) -> String {
+        digest_id(request.body)
+    }
+
+    // What's only ever signed once, for schemes that have such a thing,
+    // see SeenTokens.
+    fn replay_token(&self, _request: &SignedRequest) -> Option<String> {
+        None
+    }
+}
+
+fn invalid(message: &str) -> MailgunError {
+    MailgunError::HmacError(String::from(message))
+}
+
+fn header<'a>(request: &'a SignedRequest, name: &str) -> Option<&'a str> {
+    request.headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+pub struct MailgunHmac {
+    api_key: Secret,
+}
+
+impl SignatureScheme for MailgunHmac {
+    fn verify(&self, request: &SignedRequest) -> Result<(), MailgunError> {
+        let signed = request.mailgun.as_ref().ok_or_else(|| invalid("Missing Mailgun signature"))?;
+        mailgun::verify_signature(self.api_key.expose(), signed.timestamp, &signed.token, &signed.signature)?;
+        mailgun::check_timestamp(signed.timestamp, request.received_at)
+    }
+
+    fn replay_token(&self, request: &SignedRequest) -> Option<String> {
+        request.mailgun.as_ref().map(|signed| signed.token.clone())
+    }
+
+    // Mailgun's token is new for every delivery, and the same for its
+    // retries.
+    fn delivery_id(&self, request: &SignedRequest) -> String {
+        match &request.mailgun {
+            Some(signed) => digest_id(signed.token.as_bytes()),
+            None => digest_id(request.body),
+        }
+    }
+}
+
+pub struct SendgridEcdsa {
+    public_key: PKey<Public>,
+}
+
+impl SignatureScheme for SendgridEcdsa {
+    fn provider(&self) -> Provider {
+        Provider::Sendgrid
+    }
+
+    // The timestamp header followed by the raw body, see
+    // https://docs.sendgrid.com/for-developers/tracking-events/getting-started-event-webhook-security-features
+    fn verify(&self, request: &SignedRequest) -> Result<(), MailgunError> {
+        let signature = header(request, SENDGRID_SIGNATURE).ok_or_else(|| invalid("Missing SendGrid signature"))?;
+        let timestamp = header(request, SENDGRID_TIMESTAMP).ok_or_else(|| invalid("Missing SendGrid timestamp"))?;
+        let signature = base64::decode(signature.trim()).map_err(|_| invalid("Malformed SendGrid signature"))?;
+        let verified = Verifier::new(MessageDigest::sha256(), &self.public_key).and_then(|mut verifier| {
+            verifier.update(timestamp.as_bytes())?;
+            verifier.update(request.body)?;
+            verifier.verify(&signature)
+        });
+        match verified {
+            Ok(true) => Ok(()),
+            _ => Err(invalid("Bad SendGrid signature")),
+        }
+    }
+}
+
+pub struct SnsSignature {
+    certificates: Mutex<BTreeMap<String, X509>>,
+}
+
+// Only Amazon's own certificates count, or anyone could sign with theirs.
+fn is_sns_certificate_url(url: &str) -> bool {
+    if !url.starts_with("https://") {
+        return false;
+    }
+    let host = url["https://".len()..].split('/').next().unwrap_or("");
+    host.starts_with("sns.") && host.ends_with(".amazonaws.com") && !host.contains('@') && url.ends_with(".pem")
+}
+
+impl SnsSignature {
+    fn certificate(&self, url: &str) -> Result<X509, MailgunError> {
+        if let Some(cert) = self.certificates.lock().unwrap().get(url) {
+            return Ok(cert.clone());
+        }
+        let pem = reqwest::Client::builder()
+            .timeout(Duration::from_secs(CERT_TIMEOUT_SECONDS))
+            .build()
+            .and_then(|client| client.get(url).send()?.error_for_status()?.text())
+            .map_err(|e| MailgunError::MailgunError(format!("Unable to fetch the SNS certificate {}: {}", url, e)))?;
+        let cert = X509::from_pem(pem.as_bytes()).map_err(|_| invalid("Invalid SNS certificate"))?;
+        self.certificates.lock().unwrap().insert(String::from(url), cert.clone());
+        Ok(cert)
+    }
+}
+
+impl SignatureScheme for SnsSignature {
+    fn provider(&self) -> Provider {
+        Provider::Sns
+    }
+
+    fn delivery_id(&self, request: &SignedRequest) -> String {
+        let message: Option<Value> = serde_json::from_slice(request.body).ok();
+        match message.as_ref().and_then(|message| message["MessageId"].as_str()) {
+            Some(id) => digest_id(id.as_bytes()),
+            None => digest_id(request.body),
+        }
+    }
+
+    // See https://docs.aws.amazon.com/sns/latest/dg/sns-verify-signature-of-message.html
+    fn verify(&self, request: &SignedRequest) -> Result<(), MailgunError> {
+        let message: Value = serde_json::from_slice(request.body).map_err(|_| invalid("Not an SNS message"))?;
+        let field = |name: &str| message[name].as_str();
+        let signed_fields: &[&str] = match field("Type") {
+            Some("Notification") => &["Message", "MessageId", "Subject", "Timestamp", "TopicArn", "Type"],
+            Some("SubscriptionConfirmation") | Some("UnsubscribeConfirmation") => {
+                &["Message", "MessageId", "SubscribeURL", "Timestamp", "Token", "TopicArn", "Type"]
+            },
+            _ => return Err(invalid("Unknown SNS message type")),
+        };
+        let string_to_sign: String = signed_fields.iter()
+            .filter_map(|name| field(name).map(|value| format!("{}\n{}\n", name, value)))
+            .collect();
+        let digest = match field("SignatureVersion") {
+            Some("1") => MessageDigest::sha1(),
+            Some("2") => MessageDigest::sha256(),
+            _ => return Err(invalid("Unknown SNS signature version")),
+        };
+        let url = field("SigningCertURL").filter(|url| is_sns_certificate_url(url))
+            .ok_or_else(|| invalid("Untrusted SNS certificate URL"))?;
+        let signature = field("Signature").and_then(|signature| base64::decode(signature).ok())
+            .ok_or_else(|| invalid("Malformed SNS signature"))?;
+        let cert = self.certificate(url)?;
+        let verified = cert.public_key().and_then(|key| {
+            let mut verifier = Verifier::new(digest, &key)?;
+            verifier.update(string_to_sign.as_bytes())?;
+            verifier.verify(&signature)
+        });
+        match verified {
+            Ok(true) => Ok(()),
+            _ => Err(invalid("Bad SNS signature")),
+        }
+    }
+}
+
+pub struct StaticToken {
+    header: String,
+    token: Secret,
+}
+
+impl SignatureScheme for StaticToken {
+    fn verify(&self, request: &SignedRequest) -> Result<(), MailgunError> {
+        let presented = header(request, &self.header).unwrap_or("").as_bytes();
+        let expected = self.token.expose().as_bytes();
+        if presented.len() == expected.len() && openssl::memcmp::eq(presented, expected) {
+            Ok(())
+        } else {
+            Err(invalid("Missing or invalid webhook token"))
+        }
+    }
+}
+
+// The tokens of the webhooks handled in the past two
+// SIGNATURE_MAX_AGE_SECONDS, when check_timestamp would refuse them anyway,
+// so a captured webhook can't be handled twice. Only handled ones count,
+// which lets Mailgun's retries of a delivery that failed through. In redis
+// when workers share a queue.
+#[derive(Clone, Default)]
+struct SeenTokens {
+    local: Arc<Mutex<BTreeMap<String, i64>>>,
+    shared: Option<redis::Client>,
+}
+
+const SEEN_SECONDS: i64 = 2 * mailgun::SIGNATURE_MAX_AGE_SECONDS;
+
+impl SeenTokens {
+    fn contains(&self, token: &str) -> bool {
+        let key = format!("limail:token:{}", digest_id(token.as_bytes()));
+        if let Some(client) = &self.shared {
+            let seen: redis::RedisResult<bool> = client.get_connection()
+                .and_then(|mut connection| redis::cmd("EXISTS").arg(&key).query(&mut connection));
+            match seen {
+                Ok(seen) => return seen,
+                Err(e) => error!("Unable to reach the shared webhook tokens, checking locally: {}", e),
+            }
+        }
+        self.local.lock().unwrap().contains_key(&key)
+    }
+
+    fn remember(&self, token: &str, now: i64) {
+        let key = format!("limail:token:{}", digest_id(token.as_bytes()));
+        if let Some(client) = &self.shared {
+            let remembered: redis::RedisResult<()> = client.get_connection()
+                .and_then(|mut connection| redis::cmd("SET")
+                    .arg(&key)
+                    .arg(now)
+                    .arg("EX")
+                    .arg(SEEN_SECONDS)
+                    .query(&mut connection));
+            match remembered {
+                Ok(()) => return,
+                Err(e) => error!("Unable to reach the shared webhook tokens, remembering locally: {}", e),
+            }
+        }
+        let mut local = self.local.lock().unwrap();
+        local.retain(|_, at| now - *at <= SEEN_SECONDS);
+        local.insert(key, now);
+    }
+}
+
+pub struct Signatures {
+    routes: Vec<(String, Box<dyn SignatureScheme>)>,
+    mailgun: MailgunHmac,
+    seen: SeenTokens,
+}
+
+impl Signatures {
+    pub fn new(configs: &[RouteSignature], mailgun_api_key: Secret) -> Result<Signatures, String> {
+        let routes = configs.iter()
+            .map(|config| {
+                let scheme: Box<dyn SignatureScheme> = match &config.scheme[..] {
+                    "mailgun" => Box::new(MailgunHmac { api_key: mailgun_api_key.clone() }),
+                    "sendgrid-ecdsa" => {
+                        let key = config.public_key.as_ref()
+                            .ok_or_else(|| format!("The sendgrid-ecdsa signature for {} needs a public_key", config.route))?;
+                        let der = base64::decode(key.trim())
+                            .map_err(|_| format!("The public_key for {} isn't base64", config.route))?;
+                        let public_key = PKey::public_key_from_der(&der)
+                            .map_err(|e| format!("Invalid public_key for {}: {}", config.route, e))?;
+                        Box::new(SendgridEcdsa { public_key })
+                    },
+                    "sns" => Box::new(SnsSignature { certificates: Mutex::new(BTreeMap::new()) }),
+                    "static-token" => Box::new(StaticToken {
+                        header: config.header.clone().unwrap_or_else(|| String::from(DEFAULT_TOKEN_HEADER)),
+                        token: Secret::new(config.token.clone()
+                            .ok_or_else(|| format!("The static-token signature for {} needs a token", config.route))?),
+                    }),
+                    other => return Err(format!(
+                        "Unknown signature scheme {} for {}, expected mailgun, sendgrid-ecdsa, sns or static-token",
+                        other,
+                        config.route
+                    )),
+                };
+                Ok((config.route.clone(), scheme))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(Signatures {
+            routes,
+            mailgun: MailgunHmac { api_key: mailgun_api_key },
+            seen: SeenTokens::default(),
+        })
+    }
+
+    pub fn shared(self, client: redis::Client) -> Signatures {
+        Signatures {
+            seen: SeenTokens {
+                shared: Some(client),
+                ..self.seen
+            },
+            ..self
+        }
+    }
+
+    fn scheme(&self, route: &str) -> &dyn SignatureScheme {
+        match self.routes.iter().find(|(pattern, _)| route_matches(pattern, route)) {
+            Some((_, scheme)) => scheme.as_ref(),
+            None => &self.mailgun,
+        }
+    }
+
+    pub fn verify(&self, route: &str, request: &SignedRequest) -> Result<(), MailgunError> {
+        let scheme = self.scheme(route);
+        scheme.verify(request)?;
+        match scheme.replay_token(request) {
+            Some(token) if self.seen.contains(&token) => Err(invalid("This webhook was already handled")),
+            _ => Ok(()),
+        }
+    }
+
+    // Once the webhook is handled, so it won't be again.
+    pub fn remember(&self, route: &str, request: &SignedRequest) {
+        if let Some(token) = self.scheme(route).replay_token(request) {
+            self.seen.remember(&token, request.received_at);
+        }
+    }
+
+    pub fn provider(&self, route: &str) -> Provider {
+        self.scheme(route).provider()
+    }
+
+    pub fn delivery_id(&self, route: &str, request: &SignedRequest) -> String {
+        self.scheme(route).delivery_id(request)
+    }
+}
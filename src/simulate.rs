@@ -0,0 +1,244 @@
+use std::fmt::{self, Display};
+use std::fs;
+use std::path::Path;
+use std::time::Duration as StdDuration;
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::archive::{Archive, ArchivedEmail};
+use crate::chaos::ChaosConfig;
+use crate::clock::Clock;
+use crate::config::Config;
+use crate::echo::{Echo, EchoedMessage};
+use crate::encryption::Sealer;
+use crate::mailgun::Mailgun;
+use crate::notices::NoticeConfig;
+use crate::outbox::{Outbox, OutboxQuery};
+use crate::ratelimit::{LastResponseLog, Minutes};
+use crate::secrets::Secret;
+use crate::server::App;
+use crate::slack::Slack;
+use crate::store::StoreError;
+
+// How long after an email arrived its reply can show up in the outbox.
+const REPLY_WINDOW_MINUTES: i64 = 10;
+
+// What limail did, or would do, with an email.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Decision {
+    pub outcome: String,
+    // The template replied with, or the channel forwarded to.
+    pub detail: Option<String>,
+}
+
+impl Decision {
+    // The outbox may not go back as far as the archive, so a reply whose
+    // template is unknown matches any reply.
+    fn matches(&self, other: &Decision) -> bool {
+        self.outcome == other.outcome
+            && (self.detail.is_none() || other.detail.is_none() || self.detail == other.detail)
+    }
+}
+
+impl Display for Decision {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.detail {
+            Some(detail) => write!(f, "{} ({})", self.outcome, detail),
+            None => f.write_str(&self.outcome),
+        }
+    }
+}
+
+pub struct Difference {
+    pub id: String,
+    pub received_at: DateTime<Utc>,
+    pub route: String,
+    pub from: String,
+    pub actual: Decision,
+    pub simulated: Decision,
+}
+
+#[derive(Default)]
+pub struct Simulation {
+    pub replayed: usize,
+    // Archived without an outcome, e.g. still queued.
+    pub skipped: usize,
+    pub differences: Vec<Difference>,
+}
+
+// `7d`, `12h` or `30m`.
+pub fn parse_since(since: &str) -> Result<Duration, String> {
+    let invalid = || format!("Invalid duration {}, expected e.g. 7d, 12h or 30m", since);
+    if since.len() < 2 {
+        return Err(invalid());
+    }
+    let (amount, unit) = since.split_at(since.len() - 1);
+    let amount: i64 = amount.parse().map_err(|_| invalid())?;
+    match unit {
+        "d" => Ok(Duration::days(amount)),
+        "h" => Ok(Duration::hours(amount)),
+        "m" => Ok(Duration::minutes(amount)),
+        _ => Err(invalid()),
+    }
+}
+
+// The outcome of the email as it was first delivered, replays aside.
+fn actual_decision(archived: &ArchivedEmail, outbox: &Outbox) -> Result<Option<Decision>, StoreError> {
+    let outcome = match archived.outcomes.iter().filter(|o| o.replayed_by.is_none()).last() {
+        Some(outcome) => outcome,
+        None => return Ok(None),
+    };
+    let job = &archived.job;
+    let detail = match &outcome.outcome[..] {
+        "replied" => outbox
+            .query(&OutboxQuery {
+                recipient: Some(job.email.from.clone()),
+                template: None,
+                since: Some(job.received_at),
+                limit: None,
+            })?
+            .into_iter()
+            .filter(|entry| entry.sent_by.is_none() && entry.at <= job.received_at + Duration::minutes(REPLY_WINDOW_MINUTES))
+            .last()
+            .map(|entry| entry.template),
+        "forwarded" => archived.slack_messages.first().map(|message| message.channel.clone()),
+        _ => None,
+    };
+    // Who knows what a failure would have turned into.
+    let outcome = if outcome.outcome.starts_with("failed") { "failed" } else { &outcome.outcome[..] };
+    Ok(Some(Decision {
+        outcome: String::from(outcome),
+        detail,
+    }))
+}
+
+// Echoed messages stand for what would have been sent.
+fn simulated_decision(outcome: &str, echoed: &[EchoedMessage]) -> Decision {
+    let field = |message: &EchoedMessage, name: &str| {
+        message.message.get(name).and_then(|value| value.as_str()).map(String::from)
+    };
+    match echoed.first() {
+        Some(message) if message.destination == "mailgun" => Decision {
+            outcome: String::from("replied"),
+            detail: field(message, "template"),
+        },
+        Some(message) if message.destination == "slack" => Decision {
+            outcome: String::from("forwarded"),
+            detail: field(message, "channel"),
+        },
+        _ => Decision {
+            outcome: String::from(outcome),
+            detail: None,
+        },
+    }
+}
+
+// Replays what the archive in data_dir received since `since` through the
+// candidate config, with every route echoed so nothing is sent. Runs in
+// scratch_dir starting from a copy of the blocklist. Sender history and
+// variant counts are built up from the replayed emails alone. With a
+// time_between_responses (in minutes), the rate limiter's clock is set to
+// when each email was received, so it holds back the same repeats it
+// would have then, otherwise nothing is too recent to answer.
+pub fn run(
+    current: &Config,
+    candidate: Config,
+    data_dir: &Path,
+    scratch_dir: &Path,
+    since: DateTime<Utc>,
+    time_between_responses: Option<i64>,
+) -> Result<Simulation, StoreError> {
+    let archive = Archive::new(data_dir.join("archive"));
+    let archive = match &current.archive_encryption {
+        Some(keys) => archive.encrypted(Sealer::new(keys).map_err(StoreError::IoError)?),
+        None => archive,
+    };
+    let outbox = Outbox::new(data_dir.join("outbox.log"));
+    let mut received = archive.received_since(since)?;
+    received.sort_by_key(|archived| archived.job.received_at);
+
+    let io_error = |e: std::io::Error| StoreError::IoError(format!("Unable to set up {}: {}", scratch_dir.display(), e));
+    fs::create_dir_all(scratch_dir).map_err(io_error)?;
+    let blocklist = data_dir.join("blocklist.json");
+    if blocklist.exists() {
+        fs::copy(&blocklist, scratch_dir.join("blocklist.json")).map_err(io_error)?;
+    }
+
+    let candidate = Config {
+        alerts: Vec::new(),
+        archive_encryption: None,
+        chaos: ChaosConfig::default(),
+        early_ack: None,
+        handling: None,
+        notices: NoticeConfig {
+            suppressed_channel: None,
+            signature_failure_channel: None,
+            ..candidate.notices
+        },
+        queue: None,
+        sandbox: None,
+        send_budget: None,
+        sending_domains: None,
+        threat_intel: None,
+        trace: None,
+        ..candidate
+    };
+    let mailgun = Mailgun {
+        api_key: Secret::new(String::new()),
+        api_url: String::from("http://localhost:0"),
+        domain: String::from("localhost"),
+        from: String::from("limail@localhost"),
+        timeout: StdDuration::from_secs(1),
+        domains: None,
+        budget: None,
+        chaos: None,
+        sandbox: None,
+        footers: None,
+        envelope_senders: None,
+        pacing: None,
+        maintenance: None,
+    };
+    let slack = Slack {
+        api_key: Secret::new(String::new()),
+        api_url: String::from("http://localhost:0"),
+        timeout: StdDuration::from_secs(1),
+        chaos: None,
+        maintenance: None,
+    };
+    let clock = Clock::manual(since);
+    let last_response_log = LastResponseLog::new(Minutes(time_between_responses.unwrap_or(-1)))
+        .with_clock(clock.clone());
+    let mut app = App::new(&candidate, mailgun, slack, last_response_log, scratch_dir, &clock);
+    let echo = Echo::capturing();
+    app.pipeline.echo = echo.clone();
+
+    let mut simulation = Simulation::default();
+    for archived in received {
+        let actual = match actual_decision(&archived, &outbox)? {
+            Some(actual) => actual,
+            None => {
+                simulation.skipped += 1;
+                continue;
+            },
+        };
+        let job = archived.job;
+        clock.set(job.received_at);
+        let outcome = match app.pipeline.process(&job) {
+            Ok(outcome) => String::from(outcome.as_str()),
+            Err(_) => String::from("failed"),
+        };
+        let simulated = simulated_decision(&outcome, &echo.take());
+        simulation.replayed += 1;
+        if !actual.matches(&simulated) {
+            simulation.differences.push(Difference {
+                id: job.id.clone(),
+                received_at: job.received_at,
+                route: job.action.route(),
+                from: job.email.from.clone(),
+                actual,
+                simulated,
+            });
+        }
+    }
+    Ok(simulation)
+}
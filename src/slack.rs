@@ -2,15 +2,26 @@ use std::error::Error as StdError;
 
 use reqwest::header::{CONTENT_TYPE, AUTHORIZATION};
 use serde::{Serialize, Deserialize};
+use serde_json::json;
 use std::fmt::{self, Display};
+use std::time::Duration;
 use warp::Rejection;
 use log::{error};
 
-const SLACK_URL: &str = "https://slack.com/api/";
+use crate::chaos::Chaos;
+use crate::maintenance::Maintenance;
+use crate::secrets::Secret;
+use crate::traceparent;
+
+pub const SLACK_URL: &str = "https://slack.com/api/";
 
 #[derive(Debug)]
 pub enum SlackError {
-    HttpError(String)
+    HttpError(String),
+    // channel_not_found or is_archived, which no retry will fix.
+    ChannelUnavailable(String),
+    // Not sent, see maintenance.rs.
+    Maintenance(String),
 }
 
 impl std::convert::From<reqwest::Error> for SlackError {
@@ -30,6 +41,8 @@ impl Display for SlackError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.write_str(match self {
             SlackError::HttpError(s) => s,
+            SlackError::ChannelUnavailable(s) => s,
+            SlackError::Maintenance(s) => s,
         })
     }
 }
@@ -37,32 +50,200 @@ impl StdError for SlackError {}
 
 #[derive(Clone)]
 pub struct Slack {
-    pub api_key: String
+    pub api_key: Secret,
+    // SLACK_URL, unless testing against a fake.
+    pub api_url: String,
+    // For each call to the Slack API.
+    pub timeout: Duration,
+    // Only with the chaos feature, see chaos.rs.
+    pub chaos: Option<Chaos>,
+    // No calls are made to Slack while it's enabled.
+    pub maintenance: Option<Maintenance>,
 }
 #[derive(Serialize, Deserialize, Debug)]
 pub struct MessageResponse {
     pub ok: bool,
     pub ts: String,
 }
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Deserialize, Debug)]
+struct ApiResponse {
+    ok: bool,
+    #[serde(default)]
+    error: Option<String>,
+    #[serde(default)]
+    ts: Option<String>,
+}
+
+// Where forwards go when their own channel is gone (deleted, archived, or
+// never shared with the bot), saying where they were meant for. Without
+// one, the webhook fails like any other Slack error.
+#[derive(Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct SlackConfig {
+    pub fallback_channel: Option<String>,
+}
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct SlackMessage {
     pub channel: String,
     pub text: String,
     pub thread_ts: Option<String>, // TODO: Make this better typed
-    pub as_user: bool
+    pub as_user: bool,
+    // Only used by Slack when as_user is false.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icon_emoji: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icon_url: Option<String>,
+    // Block Kit layout, `text` is then only the notification fallback.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blocks: Option<serde_json::Value>,
+    // Whether Slack previews the links, as it pleases when None.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unfurl_links: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unfurl_media: Option<bool>,
+}
+
+fn default_as_user() -> bool {
+    true
+}
+
+// Who forwarded emails appear to come from, so different mailboxes can be
+// told apart in a shared channel. The first entry matching the route wins,
+// routes without one post as the bot user.
+#[derive(Deserialize, Clone)]
+pub struct SlackIdentity {
+    pub route: String,
+    #[serde(default = "default_as_user")]
+    pub as_user: bool,
+    pub username: Option<String>,
+    pub icon_emoji: Option<String>,
+    pub icon_url: Option<String>,
+}
+
+impl SlackMessage {
+    pub fn with_identity(self, identity: Option<&SlackIdentity>) -> SlackMessage {
+        match identity {
+            Some(identity) => SlackMessage {
+                as_user: identity.as_user,
+                username: identity.username.clone(),
+                icon_emoji: identity.icon_emoji.clone(),
+                icon_url: identity.icon_url.clone(),
+                ..self
+            },
+            None => self,
+        }
+    }
 }
 impl Slack {
+    pub fn with_timeout(&self, timeout: Duration) -> Slack {
+        Slack {
+            timeout,
+            ..self.clone()
+        }
+    }
+
+    fn client(&self) -> Result<reqwest::Client, SlackError> {
+        if let Some(refusal) = self.maintenance.as_ref().and_then(Maintenance::refusal) {
+            return Err(SlackError::Maintenance(refusal));
+        }
+        if let Some(chaos) = &self.chaos {
+            chaos.inject().map_err(SlackError::HttpError)?;
+        }
+        Ok(reqwest::Client::builder()
+            .timeout(self.timeout)
+            .default_headers(traceparent::outbound_headers())
+            .build()?)
+    }
+
     pub fn send_message(&self, message: &SlackMessage) -> Result<MessageResponse, SlackError> {
-        let client = reqwest::Client::new();
-        let url = format!("{}/chat.postMessage", SLACK_URL);
-        let msg_response: MessageResponse = client.post(&url)
-            .header(AUTHORIZATION, format!("Bearer {}", &self.api_key))
+        let client = self.client()?;
+        let url = format!("{}/chat.postMessage", self.api_url.trim_end_matches('/'));
+        let response: ApiResponse = client.post(&url)
+            .header(AUTHORIZATION, format!("Bearer {}", self.api_key.expose()))
             .header(CONTENT_TYPE, "application/json")
             .json(&message)
             .send()?
             .json()?;
+        match (response.ok, response.ts, response.error) {
+            (true, Some(ts), _) => Ok(MessageResponse { ok: true, ts }),
+            (_, _, Some(ref error)) if error == "channel_not_found" || error == "is_archived" => {
+                Err(SlackError::ChannelUnavailable(format!("Slack can't post to {}: {}", message.channel, error)))
+            },
+            (_, _, error) => Err(SlackError::HttpError(format!(
+                "Slack refused chat.postMessage: {}",
+                error.unwrap_or_default()
+            ))),
+        }
+    }
+
+    fn call(&self, method: &str, body: &serde_json::Value) -> Result<(), SlackError> {
+        let client = self.client()?;
+        let url = format!("{}/{}", self.api_url.trim_end_matches('/'), method);
+        let response: ApiResponse = client.post(&url)
+            .header(AUTHORIZATION, format!("Bearer {}", self.api_key.expose()))
+            .header(CONTENT_TYPE, "application/json")
+            .json(body)
+            .send()?
+            .json()?;
+        match response.error {
+            _ if response.ok => Ok(()),
+            // Someone got there first, which is what we wanted anyway.
+            Some(ref error) if error == "message_not_found" => Ok(()),
+            error => Err(SlackError::HttpError(format!(
+                "Slack refused {}: {}",
+                method,
+                error.unwrap_or_default()
+            ))),
+        }
+    }
+
+    pub fn delete_message(&self, channel: &str, ts: &str) -> Result<(), SlackError> {
+        self.call("chat.delete", &json!({ "channel": channel, "ts": ts }))
+    }
 
-        Ok(msg_response)
+    // Replaces the blocks too, or they'd still show the old text.
+    pub fn update_message(&self, channel: &str, ts: &str, text: &str) -> Result<(), SlackError> {
+        self.call("chat.update", &json!({ "channel": channel, "ts": ts, "text": text, "blocks": [] }))
+    }
+
+    // What's stopping us posting to channel, if anything.
+    pub fn channel_problem(&self, channel: &str) -> Result<Option<String>, SlackError> {
+        let client = self.client()?;
+        let url = format!("{}/conversations.info", self.api_url.trim_end_matches('/'));
+        let response: serde_json::Value = client.get(&url)
+            .header(AUTHORIZATION, format!("Bearer {}", self.api_key.expose()))
+            .query(&[("channel", channel)])
+            .send()?
+            .json()?;
+        match response["error"].as_str() {
+            _ if response["channel"]["is_archived"].as_bool() == Some(true) => Ok(Some(String::from("is archived"))),
+            None if response["ok"].as_bool() == Some(true) => Ok(None),
+            Some(error @ "channel_not_found") => Ok(Some(String::from(error))),
+            error => Err(SlackError::HttpError(format!(
+                "Slack refused conversations.info: {}",
+                error.unwrap_or_default()
+            ))),
+        }
+    }
+
+    // Checks that Slack is reachable and accepts our token.
+    pub fn check(&self) -> Result<(), SlackError> {
+        let client = self.client()?;
+        let url = format!("{}/auth.test", self.api_url.trim_end_matches('/'));
+        let response: ApiResponse = client.post(&url)
+            .header(AUTHORIZATION, format!("Bearer {}", self.api_key.expose()))
+            .send()?
+            .json()?;
+        if response.ok {
+            Ok(())
+        } else {
+            Err(SlackError::HttpError(format!(
+                "Slack rejected the token: {}",
+                response.error.unwrap_or_default()
+            )))
+        }
     }
 }
 
@@ -0,0 +1,172 @@
+use std::fs;
+
+use openssl::error::ErrorStack;
+use openssl::nid::Nid;
+use openssl::pkcs7::{Pkcs7, Pkcs7Flags};
+use openssl::stack::Stack;
+use openssl::x509::X509;
+use openssl::x509::store::{X509Store, X509StoreBuilder};
+use serde::{Serialize, Deserialize};
+
+use crate::blocklist::address_of;
+use crate::multipart::Part;
+
+// Signer certificates are checked against the system's trusted CAs, plus
+// the PEM files in ca_files (e.g. a police force's own CA).
+#[derive(Deserialize, Clone, Default)]
+pub struct SmimeConfig {
+    #[serde(default)]
+    pub ca_files: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SmimeSignature {
+    // Only once the signature checks out, from the certificate that made it.
+    pub signer: Option<String>,
+    // The signature is over the email as it arrived.
+    #[serde(default)]
+    pub verified: bool,
+    // The signer's certificate chains up to a trusted CA.
+    pub trusted: bool,
+    pub detail: Option<String>,
+}
+
+impl SmimeSignature {
+    fn unverified(detail: &str) -> SmimeSignature {
+        SmimeSignature {
+            signer: None,
+            verified: false,
+            trusted: false,
+            detail: Some(String::from(detail)),
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        let signer = self.signer.as_ref().map(|s| &s[..]).unwrap_or("an unknown signer");
+        match (self.verified, self.trusted, &self.detail) {
+            (false, _, Some(detail)) => format!("S/MIME ❌ unverified signature: {}", detail),
+            (false, _, None) => String::from("S/MIME ❌ unverified signature"),
+            (true, true, _) => format!("S/MIME ✅ signed by {}", signer),
+            (true, false, Some(detail)) => format!("S/MIME ❌ signed by {}, not trusted: {}", signer, detail),
+            (true, false, None) => format!("S/MIME ❌ signed by {}, not trusted", signer),
+        }
+    }
+}
+
+// The certificates anywhere in a PKCS #7 structure, found by trying every
+// DER SEQUENCE long enough to be one. Which of them made the signature is
+// only known once one of them verifies it, see Smime::check.
+fn certificates(der: &[u8]) -> Vec<X509> {
+    (0..der.len().saturating_sub(4))
+        .filter(|&i| der[i] == 0x30 && der[i + 1] == 0x82)
+        .filter_map(|i| {
+            let end = i + 4 + ((der[i + 2] as usize) << 8 | der[i + 3] as usize);
+            X509::from_der(der.get(i..end)?).ok()
+        })
+        .collect()
+}
+
+// One of its email addresses, the one matching `from` if any, else its
+// common name.
+fn signer_name(cert: &X509, from: &str) -> Option<String> {
+    let entries = |nid: Nid| cert.subject_name().entries_by_nid(nid)
+        .filter_map(|entry| entry.data().as_utf8().ok().map(|text| text.to_string()))
+        .collect::<Vec<String>>();
+    let mut emails: Vec<String> = cert.subject_alt_names()
+        .map(|names| names.iter().filter_map(|name| name.email().map(String::from)).collect())
+        .unwrap_or_default();
+    emails.extend(entries(Nid::PKCS9_EMAILADDRESS));
+    emails.iter()
+        .find(|email| email.to_lowercase() == address_of(from))
+        .or_else(|| emails.first())
+        .cloned()
+        .or_else(|| entries(Nid::COMMONNAME).into_iter().next())
+}
+
+// What's between a PEM's BEGIN and END lines, decoded.
+fn pem_der(pem: &[u8]) -> Vec<u8> {
+    let encoded: String = String::from_utf8_lossy(pem).lines()
+        .filter(|line| !line.starts_with("-----"))
+        .collect();
+    base64::decode(&encoded).unwrap_or_default()
+}
+
+fn is_signature(part: &Part) -> bool {
+    let content_type = part.content_type.as_ref().map(|c| c.to_lowercase()).unwrap_or_default();
+    let filename = part.filename.as_ref().map(|f| f.to_lowercase()).unwrap_or_default();
+    content_type.contains("pkcs7-signature") || filename.ends_with(".p7s")
+}
+
+fn is_signed_mime(mime: &[u8]) -> bool {
+    String::from_utf8_lossy(mime).to_lowercase().contains("pkcs7-signature")
+}
+
+pub struct Smime {
+    store: X509Store,
+}
+
+impl Smime {
+    pub fn new(config: &SmimeConfig) -> Result<Smime, String> {
+        let openssl_error = |e: openssl::error::ErrorStack| format!("Unable to set up S/MIME verification: {}", e);
+        let mut builder = X509StoreBuilder::new().map_err(openssl_error)?;
+        builder.set_default_paths().map_err(openssl_error)?;
+        for path in &config.ca_files {
+            let pem = fs::read(path).map_err(|e| format!("Unable to read S/MIME CA file {}: {}", path, e))?;
+            for cert in X509::stack_from_pem(&pem).map_err(openssl_error)? {
+                builder.add_cert(cert).map_err(openssl_error)?;
+            }
+        }
+        Ok(Smime {
+            store: builder.build(),
+        })
+    }
+
+    // Only `cert` may have signed it, so it's known who did.
+    fn verify_by(&self, pkcs7: &Pkcs7, cert: &X509, content: &[u8], flags: Pkcs7Flags) -> Result<(), ErrorStack> {
+        let mut certs = Stack::new()?;
+        certs.push(cert.clone())?;
+        pkcs7.verify(&certs, &self.store, Some(content), None, flags | Pkcs7Flags::NOINTERN)
+    }
+
+    // A detached signature is over the MIME part next to it exactly as it
+    // was sent, so it's checked against the raw email (`mime`, Mailgun's
+    // body-mime). Mailgun's parsed fields only have the smime.p7s
+    // attachment, with nothing to check it against, so those signatures
+    // are unverified whatever certificates they carry. None when the email
+    // isn't signed.
+    pub fn check(&self, from: &str, attachments: &[Part], mime: Option<&[u8]>) -> Option<SmimeSignature> {
+        let mime = match mime {
+            Some(mime) if is_signed_mime(mime) => mime,
+            _ if attachments.iter().any(|part| is_signature(part)) => {
+                return Some(SmimeSignature::unverified("Mailgun didn't pass on the signed MIME to check it against"));
+            },
+            _ => return None,
+        };
+        let (pkcs7, content) = match Pkcs7::from_smime(mime) {
+            Ok((pkcs7, Some(content))) => (pkcs7, content),
+            Ok((_, None)) => return Some(SmimeSignature::unverified("the signature isn't detached from the content")),
+            Err(_) => return Some(SmimeSignature::unverified("the signed MIME doesn't parse")),
+        };
+        let der = pkcs7.to_pem().map(|pem| pem_der(&pem)).unwrap_or_default();
+        let signer = certificates(&der).into_iter()
+            .find(|cert| self.verify_by(&pkcs7, cert, &content, Pkcs7Flags::NOVERIFY).is_ok());
+        let signer = match signer {
+            Some(signer) => signer,
+            None => return Some(SmimeSignature::unverified("the signature doesn't match the email")),
+        };
+        Some(match self.verify_by(&pkcs7, &signer, &content, Pkcs7Flags::empty()) {
+            Ok(()) => SmimeSignature {
+                signer: signer_name(&signer, from),
+                verified: true,
+                trusted: true,
+                detail: None,
+            },
+            Err(e) => SmimeSignature {
+                signer: signer_name(&signer, from),
+                verified: true,
+                trusted: false,
+                detail: e.errors().first().and_then(|error| error.reason()).map(String::from),
+            },
+        })
+    }
+}
@@ -0,0 +1,183 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+
+use crate::blocklist::address_of;
+use crate::clock::Clock;
+use crate::outbox::OutboxEntry;
+use crate::store::{self, StoreError};
+
+// Upper bounds (in minutes) of the buckets intervals are counted in, the
+// rest go in "longer".
+const BUCKETS: [(i64, &str); 9] = [
+    (1, "under_1"),
+    (5, "1_to_5"),
+    (15, "5_to_15"),
+    (30, "15_to_30"),
+    (60, "30_to_60"),
+    (120, "60_to_120"),
+    (240, "120_to_240"),
+    (720, "240_to_720"),
+    (1440, "720_to_1440"),
+];
+
+// Rate limit windows (in minutes) to show what each would have held back.
+const WINDOWS: [i64; 9] = [5, 15, 30, 60, 120, 240, 480, 720, 1440];
+
+// One reply the rate limiter held back, with how long it had been since
+// the sender's last auto-reply (None when the outbox doesn't have it, e.g.
+// it was sent by another instance sharing the redis limiter but not the
+// data dir).
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Suppression {
+    pub at: DateTime<Utc>,
+    pub sender: String,
+    pub route: String,
+    pub template: String,
+    pub minutes_since_last: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct SuppressionQuery {
+    pub sender: Option<String>,
+    pub template: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub limit: Option<usize>,
+}
+
+impl SuppressionQuery {
+    fn matches(&self, sender: &str, template: &str, at: DateTime<Utc>) -> bool {
+        self.sender.as_ref().map_or(true, |wanted| address_of(wanted) == address_of(sender))
+            && self.template.as_ref().map_or(true, |wanted| wanted == template)
+            && self.since.map_or(true, |since| at >= since)
+    }
+}
+
+#[derive(Serialize, Default)]
+pub struct SuppressionSummary {
+    pub window_minutes: i64,
+    pub suppressed: u64,
+    pub senders: u64,
+    pub by_template: BTreeMap<String, u64>,
+    // How long after the last auto-reply the suppressed repeats came.
+    pub intervals: BTreeMap<String, u64>,
+    pub median_minutes: Option<i64>,
+    pub p90_minutes: Option<i64>,
+    // The repeats that came after the window, and so were replied to
+    // again, by how long after the previous auto-reply.
+    pub replied_again: u64,
+    pub replied_again_intervals: BTreeMap<String, u64>,
+    // Of all the repeats (suppressed or replied to again), how many a
+    // window of that many minutes would have held back.
+    pub within_window: BTreeMap<i64, u64>,
+}
+
+fn bucket(minutes: i64) -> &'static str {
+    BUCKETS.iter()
+        .find(|(below, _)| minutes < *below)
+        .map_or("longer", |(_, name)| name)
+}
+
+fn percentile(sorted: &[i64], percent: usize) -> Option<i64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    Some(sorted[(sorted.len() - 1) * percent / 100])
+}
+
+// Every reply the rate limiter suppressed, in suppressions.log, for
+// tuning TIME_BETWEEN_RESPONSES_MINUTES from the real intervals between an
+// auto-reply and the same sender writing again.
+#[derive(Clone)]
+pub struct Suppressions {
+    path: PathBuf,
+    write_lock: Arc<Mutex<()>>,
+    clock: Clock,
+}
+
+impl Suppressions {
+    pub fn new(path: PathBuf) -> Suppressions {
+        Suppressions {
+            path,
+            write_lock: Arc::new(Mutex::new(())),
+            clock: Clock::system(),
+        }
+    }
+
+    pub fn with_clock(self, clock: Clock) -> Suppressions {
+        Suppressions {
+            clock,
+            ..self
+        }
+    }
+
+    pub fn record(&self, sender: &str, route: &str, template: &str, last_reply: Option<DateTime<Utc>>) -> Result<(), StoreError> {
+        let _guard = self.write_lock.lock().unwrap();
+        let now = self.clock.now();
+        store::append_json_line(&self.path, &Suppression {
+            at: now,
+            sender: address_of(sender),
+            route: String::from(route),
+            template: String::from(template),
+            minutes_since_last: last_reply.map(|at| (now - at).num_minutes().max(0)),
+        })
+    }
+
+    // Newest first.
+    pub fn query(&self, query: &SuppressionQuery) -> Result<Vec<Suppression>, StoreError> {
+        let suppressions: Vec<Suppression> = store::read_json_lines(&self.path)?;
+        Ok(suppressions.into_iter()
+            .rev()
+            .filter(|suppression| query.matches(&suppression.sender, &suppression.template, suppression.at))
+            .take(query.limit.unwrap_or(100))
+            .collect())
+    }
+
+    // Of those matching query (its limit doesn't apply), along with the
+    // auto-replies (oldest first) that answered a repeat after the window.
+    pub fn summary(
+        &self,
+        query: &SuppressionQuery,
+        auto_replies: &[OutboxEntry],
+        window_minutes: i64,
+    ) -> Result<SuppressionSummary, StoreError> {
+        let suppressions: Vec<Suppression> = store::read_json_lines(&self.path)?;
+        let mut summary = SuppressionSummary { window_minutes, ..SuppressionSummary::default() };
+        let mut senders = BTreeSet::new();
+        let mut minutes = Vec::new();
+        let matching = suppressions.iter()
+            .filter(|suppression| query.matches(&suppression.sender, &suppression.template, suppression.at));
+        for suppression in matching {
+            summary.suppressed += 1;
+            senders.insert(suppression.sender.clone());
+            *summary.by_template.entry(suppression.template.clone()).or_insert(0) += 1;
+            if let Some(since_last) = suppression.minutes_since_last {
+                *summary.intervals.entry(String::from(bucket(since_last))).or_insert(0) += 1;
+                minutes.push(since_last);
+            }
+        }
+        summary.senders = senders.len() as u64;
+        minutes.sort();
+        summary.median_minutes = percentile(&minutes, 50);
+        summary.p90_minutes = percentile(&minutes, 90);
+        let mut last_replies: BTreeMap<String, DateTime<Utc>> = BTreeMap::new();
+        for entry in auto_replies {
+            let recipient = address_of(&entry.recipient);
+            let previous = last_replies.insert(recipient.clone(), entry.at);
+            if let Some(previous) = previous.filter(|_| query.matches(&recipient, &entry.template, entry.at)) {
+                let since_last = (entry.at - previous).num_minutes().max(0);
+                summary.replied_again += 1;
+                *summary.replied_again_intervals.entry(String::from(bucket(since_last))).or_insert(0) += 1;
+                minutes.push(since_last);
+            }
+        }
+        for window in WINDOWS.iter() {
+            let held_back = minutes.iter().filter(|since_last| **since_last < *window).count();
+            summary.within_window.insert(*window, held_back as u64);
+        }
+        Ok(summary)
+    }
+}
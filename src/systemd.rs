@@ -0,0 +1,47 @@
+use std::env;
+use std::net::TcpListener;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::net::UnixDatagram;
+use std::process;
+
+// The first file descriptor systemd passes, see sd_listen_fds(3).
+const SD_LISTEN_FDS_START: i32 = 3;
+
+// The listening socket systemd opened for us when started through a .socket
+// unit. Keeping the socket in systemd's hands means connections queue up
+// rather than being refused while limail restarts.
+pub fn listener() -> Option<TcpListener> {
+    let pid: u32 = env::var("LISTEN_PID").ok()?.parse().ok()?;
+    if pid != process::id() {
+        return None;
+    }
+    let fds: i32 = env::var("LISTEN_FDS").ok()?.parse().ok()?;
+    if fds < 1 {
+        return None;
+    }
+    if fds > 1 {
+        warn!("systemd passed {} sockets, only the first is used", fds);
+    }
+    // So anything we spawn doesn't think they're meant for it.
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    info!("Using the listening socket passed by systemd");
+    Some(unsafe { TcpListener::from_raw_fd(SD_LISTEN_FDS_START) })
+}
+
+// sd_notify(3), a no-op unless started by systemd with Type=notify.
+pub fn notify(state: &str) {
+    let path = match env::var("NOTIFY_SOCKET") {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+    if path.starts_with('@') {
+        warn!("Abstract NOTIFY_SOCKET {} isn't supported, not notifying systemd", path);
+        return;
+    }
+    let sent = UnixDatagram::unbound().and_then(|socket| socket.send_to(state.as_bytes(), &path));
+    if let Err(e) = sent {
+        error!("Unable to notify systemd ({}): {}", state, e);
+    }
+}
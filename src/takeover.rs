@@ -0,0 +1,78 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+
+use crate::blocklist::address_of;
+use crate::clock::Clock;
+use crate::store::{self, StoreError};
+
+fn default_minutes() -> i64 {
+    60
+}
+
+// Once one of mailboxes (the humans) is seen writing to someone, that
+// person gets no auto-replies for `minutes`, and the delayed ones waiting
+// for them are cancelled, so they don't get both a human and a robot
+// answer. The humans are seen in copies of what they send (Bcc'd to an
+// address whose Mailgun route forwards to /emails/takeover), or in
+// Mailgun's accepted events (at /mailgun/events) when they send through
+// Mailgun themselves.
+#[derive(Deserialize, Clone)]
+pub struct TakeoverConfig {
+    pub mailboxes: Vec<String>,
+    #[serde(default = "default_minutes")]
+    pub minutes: i64,
+}
+
+// When the humans last wrote to each address, in takeover.json so every
+// process sees it.
+#[derive(Clone)]
+pub struct Takeover {
+    config: Arc<TakeoverConfig>,
+    path: PathBuf,
+    write_lock: Arc<Mutex<()>>,
+    clock: Clock,
+}
+
+impl Takeover {
+    pub fn new(config: TakeoverConfig, path: PathBuf) -> Takeover {
+        Takeover {
+            config: Arc::new(config),
+            path,
+            write_lock: Arc::new(Mutex::new(())),
+            clock: Clock::system(),
+        }
+    }
+
+    pub fn with_clock(self, clock: Clock) -> Takeover {
+        Takeover {
+            clock,
+            ..self
+        }
+    }
+
+    pub fn is_human(&self, from: &str) -> bool {
+        let from = address_of(from);
+        self.config.mailboxes.iter().any(|mailbox| address_of(mailbox) == from)
+    }
+
+    pub fn record(&self, recipient: &str) -> Result<(), StoreError> {
+        let _guard = self.write_lock.lock().unwrap();
+        let mut answered: BTreeMap<String, DateTime<Utc>> = store::read_json(&self.path)?.unwrap_or_default();
+        let now = self.clock.now();
+        let since = now - Duration::minutes(self.config.minutes);
+        answered.retain(|_, at| *at >= since);
+        answered.insert(address_of(recipient), now);
+        store::write_json(&self.path, &answered)
+    }
+
+    // When a human last wrote to recipient, if it was recently enough.
+    pub fn answered(&self, recipient: &str) -> Result<Option<DateTime<Utc>>, StoreError> {
+        let answered: BTreeMap<String, DateTime<Utc>> = store::read_json(&self.path)?.unwrap_or_default();
+        let since = self.clock.now() - Duration::minutes(self.config.minutes);
+        Ok(answered.get(&address_of(recipient)).cloned().filter(|at| *at >= since))
+    }
+}
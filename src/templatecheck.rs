@@ -0,0 +1,99 @@
+use std::collections::BTreeSet;
+
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::config::Config;
+use crate::mailgun::{Mailgun, MailgunError};
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OnMissing {
+    Refuse,
+    Warn,
+}
+
+impl Default for OnMissing {
+    fn default() -> OnMissing {
+        OnMissing::Refuse
+    }
+}
+
+// At startup, every Mailgun template limail could send has to exist: those
+// the config names, and those in the /emails/responder/<template> (or
+// respond-and-forward/<template>) URLs of Mailgun's routes (unless routes
+// is false, for accounts whose routes point at other limails). Missing ones
+// stop limail from starting, or are only logged as errors with
+// on_missing = "warn". Skipped along with the other checks by
+// LIMAIL_SKIP_STARTUP_CHECK.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct TemplateCheckConfig {
+    pub on_missing: OnMissing,
+    pub routes: bool,
+}
+
+impl Default for TemplateCheckConfig {
+    fn default() -> TemplateCheckConfig {
+        TemplateCheckConfig {
+            on_missing: OnMissing::default(),
+            routes: true,
+        }
+    }
+}
+
+// The templates the config names, for variants, canned replies, first
+// time senders and digests.
+pub fn referenced(config: &Config) -> BTreeSet<String> {
+    config.variants.iter()
+        .flat_map(|variants| variants.templates.iter().map(|variant| variant.template.clone()))
+        .chain(config.canned_replies.iter().flat_map(|canned| canned.replies.iter().map(|reply| reply.template.clone())))
+        .chain(config.reputation.iter().flat_map(|reputation| reputation.first_time.iter().map(|first| first.template.clone())))
+        .chain(config.digests.iter().map(|digest| digest.template.clone()))
+        .collect()
+}
+
+// As forward("https://limail.example.org/emails/responder/appeal").
+pub fn in_routes(actions: &[String]) -> BTreeSet<String> {
+    let pattern = Regex::new(r#"/emails/(?:responder|respond-and-forward)/([^/"'?#\s)]+)"#).unwrap();
+    actions.iter()
+        .flat_map(|action| pattern.captures_iter(action).map(|captures| String::from(&captures[1])).collect::<Vec<String>>())
+        .collect()
+}
+
+// The templates that don't exist, each with where it's used.
+pub fn missing(config: &Config, mailgun: &Mailgun) -> Result<Vec<String>, MailgunError> {
+    let mut missing = Vec::new();
+    let from_routes = if config.template_check.routes {
+        in_routes(&mailgun.route_actions()?)
+    } else {
+        BTreeSet::new()
+    };
+    for template in referenced(config).iter() {
+        if !mailgun.template_exists(template)? {
+            missing.push(format!("{} (in LIMAIL_CONFIG)", template));
+        }
+    }
+    for template in from_routes.difference(&referenced(config)) {
+        if !mailgun.template_exists(template)? {
+            missing.push(format!("{} (in a Mailgun route)", template));
+        }
+    }
+    Ok(missing)
+}
+
+pub fn check(config: &Config, mailgun: &Mailgun) {
+    let missing = match missing(config, mailgun) {
+        Ok(missing) => missing,
+        Err(e) => panic!("Mailgun template check failed (set LIMAIL_SKIP_STARTUP_CHECK to skip): {}", e),
+    };
+    if missing.is_empty() {
+        info!("Every Mailgun template in use exists");
+        return;
+    }
+    let message = format!("Mailgun has no templates {} on {}", missing.join(", "), mailgun.domain);
+    match config.template_check.on_missing {
+        OnMissing::Refuse => panic!("{} (set on_missing = \"warn\" in [template_check] to start anyway)", message),
+        OnMissing::Warn => error!("{}, replies using them will fail", message),
+    }
+}
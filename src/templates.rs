@@ -0,0 +1,132 @@
+use std::error::Error as StdError;
+use std::fmt::{self, Display};
+use std::fs;
+use std::path::PathBuf;
+
+use handlebars::Handlebars;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::mailgun::MailgunEmailReceived;
+
+// A local copy of a Mailgun template, templates/<name>.hbs. The first line
+// may be `Subject: ...`, followed by a blank line and the body.
+const EXTENSION: &str = "hbs";
+const SUBJECT_PREFIX: &str = "Subject:";
+
+#[derive(Debug)]
+pub enum TemplateError {
+    IoError(String),
+    ParseError(String),
+    RenderError(String),
+}
+
+impl Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            TemplateError::IoError(s) => s,
+            TemplateError::ParseError(s) => s,
+            TemplateError::RenderError(s) => s,
+        })
+    }
+}
+impl StdError for TemplateError {}
+
+// What a template gets to work with, the same whether it's rendered from a
+// real webhook or a sample payload.
+#[derive(Serialize, Clone, Debug)]
+pub struct TemplateContext {
+    pub sender: String,
+    pub from: String,
+    pub subject: String,
+    pub body_plain: String,
+    pub message_id: Option<String>,
+}
+
+fn field(payload: &Value, names: &[&str]) -> String {
+    names.iter()
+        .filter_map(|name| payload.get(name).and_then(Value::as_str))
+        .next()
+        .map(String::from)
+        .unwrap_or_default()
+}
+
+impl TemplateContext {
+    pub fn from_email(email: &MailgunEmailReceived) -> TemplateContext {
+        TemplateContext {
+            sender: email.sender.clone(),
+            from: email.from.clone(),
+            subject: email.subject.clone(),
+            body_plain: email.body_plain.clone(),
+            message_id: email.get_message_id().ok(),
+        }
+    }
+
+    // Sample payloads are written by hand, so be forgiving: missing fields
+    // are empty and message-headers may be the JSON string Mailgun sends or
+    // the array it contains.
+    pub fn from_payload(payload: &Value) -> TemplateContext {
+        let message_headers = match payload.get("message-headers") {
+            Some(Value::String(s)) => s.clone(),
+            Some(other) => other.to_string(),
+            None => String::from("[]"),
+        };
+        let email = MailgunEmailReceived {
+            sender: field(payload, &["sender"]),
+            from: field(payload, &["from", "From"]),
+            subject: field(payload, &["subject", "Subject"]),
+            body_plain: field(payload, &["body-plain", "body_plain"]),
+            body_html: None,
+            timestamp: 0,
+            token: String::new(),
+            signature: String::new(),
+            message_headers,
+        };
+        TemplateContext::from_email(&email)
+    }
+}
+
+#[derive(Debug)]
+pub struct Rendered {
+    pub subject: String,
+    pub body: String,
+}
+
+pub struct Templates {
+    dir: PathBuf,
+}
+
+impl Templates {
+    pub fn new(dir: PathBuf) -> Templates {
+        Templates { dir }
+    }
+
+    pub fn render(&self, name: &str, context: &TemplateContext) -> Result<Rendered, TemplateError> {
+        let path = self.dir.join(format!("{}.{}", name, EXTENSION));
+        let source = fs::read_to_string(&path)
+            .map_err(|e| TemplateError::IoError(format!("Unable to read {}: {}", path.display(), e)))?;
+
+        // Without a subject line, the auto-responder's own subject.
+        let (subject, body) = match source.lines().next() {
+            Some(first) if first.starts_with(SUBJECT_PREFIX) => {
+                let body = source.splitn(2, '\n').nth(1).unwrap_or("");
+                let body = body.trim_start_matches(|c| c == '\r' || c == '\n');
+                (String::from(first[SUBJECT_PREFIX.len()..].trim()), String::from(body))
+            },
+            _ => (String::from("Re: {{subject}}"), source.clone()),
+        };
+
+        let mut handlebars = Handlebars::new();
+        handlebars.set_strict_mode(true);
+        handlebars.register_template_string("subject", &subject)
+            .map_err(|e| TemplateError::ParseError(format!("{}: subject: {}", path.display(), e)))?;
+        handlebars.register_template_string("body", &body)
+            .map_err(|e| TemplateError::ParseError(format!("{}: {}", path.display(), e)))?;
+        let render = |part: &str| handlebars.render(part, context)
+            .map_err(|e| TemplateError::RenderError(format!("{}: {}", path.display(), e)));
+        Ok(Rendered {
+            subject: render("subject")?,
+            body: render("body")?,
+        })
+    }
+}
@@ -0,0 +1,146 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Serialize, Deserialize};
+
+use crate::clock::Clock;
+use crate::mailgun::MailgunEmailReceived;
+use crate::store::{self, StoreError};
+use crate::viewer;
+
+// Conversations nobody has replied to in this long start a new thread.
+const THREAD_DAYS: i64 = 90;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SlackThread {
+    pub ts: String,
+    pub updated_at: DateTime<Utc>,
+}
+
+// Which Slack thread each email conversation was forwarded into, keyed by
+// channel and Message-ID, so replies keep landing in the same thread after
+// a restart. Kept in threads.json, or in redis when workers share a queue.
+#[derive(Clone)]
+pub struct ThreadMap {
+    path: PathBuf,
+    threads: Arc<RwLock<BTreeMap<String, SlackThread>>>,
+    shared: Option<redis::Client>,
+    clock: Clock,
+}
+
+fn key(channel: &str, message_id: &str) -> String {
+    format!("{}:{}", channel, message_id.trim().to_lowercase())
+}
+
+// Message-IDs this email is a reply to, oldest first, then its own.
+pub fn conversation_ids(email: &MailgunEmailReceived) -> (Vec<String>, Option<String>) {
+    let mut ancestors = Vec::new();
+    let mut own = None;
+    for (name, value) in viewer::headers(&email.message_headers) {
+        match &name.to_lowercase()[..] {
+            "references" | "in-reply-to" => {
+                for id in value.split_whitespace() {
+                    if !ancestors.iter().any(|known: &String| known == id) {
+                        ancestors.push(String::from(id));
+                    }
+                }
+            },
+            "message-id" => own = Some(value.trim().to_string()),
+            _ => (),
+        }
+    }
+    (ancestors, own)
+}
+
+impl ThreadMap {
+    pub fn load(path: PathBuf) -> Result<ThreadMap, StoreError> {
+        let threads: BTreeMap<String, SlackThread> = store::read_json(&path)?.unwrap_or_default();
+        Ok(ThreadMap {
+            path,
+            threads: Arc::new(RwLock::new(threads)),
+            shared: None,
+            clock: Clock::system(),
+        })
+    }
+
+    pub fn shared(self, client: redis::Client) -> ThreadMap {
+        ThreadMap {
+            shared: Some(client),
+            ..self
+        }
+    }
+
+    pub fn with_clock(self, clock: Clock) -> ThreadMap {
+        ThreadMap {
+            clock,
+            ..self
+        }
+    }
+
+    fn is_too_old(&self, thread: &SlackThread) -> bool {
+        self.clock.now() - thread.updated_at > Duration::days(THREAD_DAYS)
+    }
+
+    fn get(&self, key: &str) -> Option<String> {
+        if let Some(client) = &self.shared {
+            let ts: redis::RedisResult<Option<String>> = client.get_connection()
+                .and_then(|mut connection| redis::cmd("GET")
+                    .arg(format!("limail:thread:{}", key))
+                    .query(&mut connection));
+            match ts {
+                Ok(ts) => return ts,
+                Err(e) => error!("Unable to reach the shared thread map, falling back to disk: {}", e),
+            }
+        }
+        self.threads.read().unwrap().get(key)
+            .filter(|thread| !self.is_too_old(thread))
+            .map(|thread| thread.ts.clone())
+    }
+
+    // The thread the newest known ancestor of this email was forwarded into.
+    pub fn find(&self, channel: &str, email: &MailgunEmailReceived) -> Option<String> {
+        let (ancestors, own) = conversation_ids(email);
+        // A Mailgun retry of an email we already forwarded has its own id.
+        own.iter().chain(ancestors.iter().rev())
+            .find_map(|id| self.get(&key(channel, id)))
+    }
+
+    // Remembers the thread under every id in the conversation, so a reply
+    // to any of them finds it.
+    pub fn record(&self, channel: &str, email: &MailgunEmailReceived, ts: &str) -> Result<(), StoreError> {
+        let (ancestors, own) = conversation_ids(email);
+        let keys: Vec<String> = ancestors.iter().chain(own.iter())
+            .map(|id| key(channel, id))
+            .collect();
+        if let Some(client) = &self.shared {
+            let recorded: redis::RedisResult<()> = client.get_connection()
+                .and_then(|mut connection| {
+                    let mut pipe = redis::pipe();
+                    for key in &keys {
+                        pipe.cmd("SET")
+                            .arg(format!("limail:thread:{}", key))
+                            .arg(ts)
+                            .arg("EX")
+                            .arg(THREAD_DAYS * 24 * 60 * 60)
+                            .ignore();
+                    }
+                    pipe.query(&mut connection)
+                });
+            match recorded {
+                Ok(()) => return Ok(()),
+                Err(e) => error!("Unable to reach the shared thread map, falling back to disk: {}", e),
+            }
+        }
+        let mut threads = self.threads.write().unwrap();
+        threads.retain(|_, thread| !self.is_too_old(thread));
+        for key in keys {
+            threads.insert(key, SlackThread {
+                ts: String::from(ts),
+                updated_at: self.clock.now(),
+            });
+        }
+        store::write_json(&self.path, &*threads)
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::{Serialize, Deserialize};
+use serde_json::{json, Value};
+
+use crate::addresses;
+use crate::defang;
+use crate::mailgun::MailgunEmailReceived;
+use crate::secrets::Secret;
+
+const SAFE_BROWSING_URL: &str = "https://safebrowsing.googleapis.com/v4/threatMatches:find";
+// Safe Browsing takes at most this many URLs a request.
+const MAX_URLS: usize = 500;
+
+fn default_timeout_seconds() -> u64 {
+    3
+}
+
+// Looks up the sender's domain and the links in each forwarded email in a
+// list of bad domains (domains, and domains_file with one per line), and
+// with safe_browsing_key in Google Safe Browsing too. Subdomains of a bad
+// domain are bad. Whatever's found heads the Slack forward. Safe Browsing
+// is given timeout_seconds, and the email is forwarded unflagged if it
+// can't be reached.
+#[derive(Deserialize, Clone)]
+pub struct ThreatIntelConfig {
+    #[serde(default)]
+    pub domains: Vec<String>,
+    #[serde(default)]
+    pub domains_file: Option<String>,
+    #[serde(default)]
+    pub safe_browsing_key: Option<String>,
+    #[serde(default = "default_timeout_seconds")]
+    pub timeout_seconds: u64,
+}
+
+#[derive(Serialize, Clone)]
+pub struct Finding {
+    pub indicator: String,
+    pub source: String,
+}
+
+impl Finding {
+    pub fn summary(findings: &[Finding]) -> String {
+        findings.iter()
+            .map(|finding| format!("{} ({})", finding.indicator, finding.source))
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+}
+
+#[derive(Clone)]
+pub struct ThreatIntel {
+    domains: Arc<BTreeSet<String>>,
+    safe_browsing_key: Option<Secret>,
+    timeout: Duration,
+}
+
+fn normalize_domain(domain: &str) -> String {
+    domain.trim().trim_end_matches('.').to_lowercase()
+}
+
+// The host of a link, without any user or port.
+fn host_of(authority: &str) -> String {
+    let host = authority.rsplit('@').next().unwrap_or(authority);
+    normalize_domain(host.split(':').next().unwrap_or(host))
+}
+
+impl ThreatIntel {
+    pub fn new(config: &ThreatIntelConfig) -> Result<ThreatIntel, String> {
+        let mut domains: BTreeSet<String> = config.domains.iter().map(|domain| normalize_domain(domain)).collect();
+        if let Some(path) = &config.domains_file {
+            let contents = fs::read_to_string(path)
+                .map_err(|e| format!("Unable to read [threat_intel] domains_file {}: {}", path, e))?;
+            domains.extend(contents.lines()
+                .map(|line| line.trim())
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(normalize_domain));
+        }
+        Ok(ThreatIntel {
+            domains: Arc::new(domains),
+            safe_browsing_key: config.safe_browsing_key.clone().map(Secret::new),
+            timeout: Duration::from_secs(config.timeout_seconds),
+        })
+    }
+
+    fn is_listed(&self, domain: &str) -> bool {
+        let mut domain = &domain[..];
+        loop {
+            if self.domains.contains(domain) {
+                return true;
+            }
+            match domain.find('.') {
+                Some(dot) => domain = &domain[dot + 1..],
+                None => return false,
+            }
+        }
+    }
+
+    pub fn check(&self, email: &MailgunEmailReceived) -> Vec<Finding> {
+        let mut findings = Vec::new();
+        let sender = addresses::sender(&email.from).address;
+        if let Some(at) = sender.rfind('@') {
+            let domain = normalize_domain(&sender[at + 1..]);
+            if self.is_listed(&domain) {
+                findings.push(Finding { indicator: domain, source: String::from("sender domain blocklisted") });
+            }
+        }
+
+        let pattern = defang::url_pattern();
+        let text = format!("{}\n{}\n{}", email.subject, email.body_plain, email.body_html.as_ref().map_or("", |html| &html[..]));
+        let urls: BTreeSet<String> = pattern.find_iter(&text).map(|url| String::from(url.as_str())).collect();
+        let mut hosts = BTreeSet::new();
+        for captures in pattern.captures_iter(&text) {
+            hosts.insert(host_of(&captures[2]));
+        }
+        for host in hosts {
+            if self.is_listed(&host) {
+                findings.push(Finding { indicator: host, source: String::from("link blocklisted") });
+            }
+        }
+
+        if let (Some(key), false) = (&self.safe_browsing_key, urls.is_empty()) {
+            match self.safe_browsing(key, &urls) {
+                Ok(matches) => findings.extend(matches),
+                Err(e) => warn!("Unable to check the links of an email from {} with Safe Browsing: {}", email.from, e),
+            }
+        }
+        findings
+    }
+
+    fn safe_browsing(&self, key: &Secret, urls: &BTreeSet<String>) -> Result<Vec<Finding>, String> {
+        let entries: Vec<Value> = urls.iter().take(MAX_URLS).map(|url| json!({ "url": url })).collect();
+        let response: Value = reqwest::Client::builder()
+            .timeout(self.timeout)
+            .build()
+            .and_then(|client| client.post(&format!("{}?key={}", SAFE_BROWSING_URL, key.expose()))
+                .json(&json!({
+                    "client": { "clientId": "limail", "clientVersion": env!("CARGO_PKG_VERSION") },
+                    "threatInfo": {
+                        "threatTypes": ["MALWARE", "SOCIAL_ENGINEERING", "UNWANTED_SOFTWARE", "POTENTIALLY_HARMFUL_APPLICATION"],
+                        "platformTypes": ["ANY_PLATFORM"],
+                        "threatEntryTypes": ["URL"],
+                        "threatEntries": entries,
+                    },
+                }))
+                .send()?
+                .error_for_status()?
+                .json())
+            .map_err(|e| key.redact(&e.to_string()))?;
+        Ok(response["matches"].as_array().map(|matches| matches.iter()
+            .filter_map(|found| Some(Finding {
+                indicator: String::from(found["threat"]["url"].as_str()?),
+                source: format!("Safe Browsing: {}", found["threatType"].as_str().unwrap_or("threat").to_lowercase()),
+            }))
+            .collect()).unwrap_or_default())
+    }
+}
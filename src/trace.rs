@@ -0,0 +1,162 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+
+use crate::clock::Clock;
+use crate::pipeline::{route_matches, Job};
+use crate::secrets::Secret;
+use crate::store::{self, StoreError};
+
+fn default_dir() -> String {
+    String::from("traces")
+}
+
+fn default_retention_days() -> u64 {
+    3
+}
+
+// Writes everything processing a sample of emails did (the parsed email,
+// each decision and what was sent to Mailgun and Slack) to dir (relative
+// to DATA_DIR) as <job id>.json. sample_percent of the emails to routes
+// (exact, or a prefix ending in *, all of them when empty) are traced, the
+// same ones each time Mailgun retries. API keys are redacted, but traces
+// hold someone's email, so they're deleted after retention_days.
+#[derive(Deserialize, Clone)]
+pub struct TraceConfig {
+    pub sample_percent: f64,
+    #[serde(default)]
+    pub routes: Vec<String>,
+    #[serde(default = "default_dir")]
+    pub dir: String,
+    #[serde(default = "default_retention_days")]
+    pub retention_days: u64,
+}
+
+#[derive(Serialize)]
+struct TraceStep {
+    at: DateTime<Utc>,
+    step: String,
+    detail: Value,
+}
+
+#[derive(Serialize)]
+struct Trace {
+    id: String,
+    route: String,
+    started_at: DateTime<Utc>,
+    email: Value,
+    steps: Vec<TraceStep>,
+    outcome: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct Tracer {
+    config: Option<Arc<TraceConfig>>,
+    dir: PathBuf,
+    secrets: Arc<Vec<Secret>>,
+    traces: Arc<Mutex<BTreeMap<String, Trace>>>,
+    clock: Clock,
+}
+
+impl Tracer {
+    pub fn new(config: Option<TraceConfig>, data_dir: &Path, secrets: Vec<Secret>) -> Tracer {
+        let dir = data_dir.join(config.as_ref().map(|c| &c.dir[..]).unwrap_or("traces"));
+        Tracer {
+            config: config.map(Arc::new),
+            dir,
+            secrets: Arc::new(secrets),
+            traces: Arc::new(Mutex::new(BTreeMap::new())),
+            clock: Clock::system(),
+        }
+    }
+
+    pub fn with_clock(self, clock: Clock) -> Tracer {
+        Tracer {
+            clock,
+            ..self
+        }
+    }
+
+    // Job ids are hex digests, so their first bytes are as good as a coin.
+    fn is_sampled(&self, job: &Job) -> bool {
+        let config = match &self.config {
+            Some(config) => config,
+            None => return false,
+        };
+        let route = job.action.route();
+        if !config.routes.is_empty() && !config.routes.iter().any(|pattern| route_matches(pattern, &route)) {
+            return false;
+        }
+        let roll = job.id.get(..4).and_then(|prefix| u32::from_str_radix(prefix, 16).ok()).unwrap_or(0);
+        f64::from(roll) / 65536.0 * 100.0 < config.sample_percent
+    }
+
+    pub fn begin(&self, job: &Job) {
+        if !self.is_sampled(job) {
+            return;
+        }
+        // The webhook's signing material says nothing about the email.
+        let mut email = serde_json::to_value(&job.email).unwrap_or_default();
+        if let Some(fields) = email.as_object_mut() {
+            fields.remove("token");
+            fields.remove("signature");
+        }
+        self.traces.lock().unwrap().insert(job.id.clone(), Trace {
+            id: job.id.clone(),
+            route: job.action.route(),
+            started_at: self.clock.now(),
+            email,
+            steps: Vec::new(),
+            outcome: None,
+        });
+    }
+
+    // Does nothing for jobs that aren't being traced, so callers needn't check.
+    pub fn note<T: Serialize>(&self, job: &Job, step: &str, detail: &T) {
+        if let Some(trace) = self.traces.lock().unwrap().get_mut(&job.id) {
+            trace.steps.push(TraceStep {
+                at: self.clock.now(),
+                step: String::from(step),
+                detail: serde_json::to_value(detail).unwrap_or_default(),
+            });
+        }
+    }
+
+    pub fn finish(&self, job: &Job, outcome: &str) {
+        let mut trace = match self.traces.lock().unwrap().remove(&job.id) {
+            Some(trace) => trace,
+            None => return,
+        };
+        trace.outcome = Some(String::from(outcome));
+        if let Err(e) = self.write(&trace) {
+            error!("Unable to write the trace of job {}: {}", job.id, e);
+        }
+    }
+
+    fn write(&self, trace: &Trace) -> Result<(), StoreError> {
+        let mut json = serde_json::to_string_pretty(trace)?;
+        for secret in self.secrets.iter() {
+            json = secret.redact(&json);
+        }
+        store::write_bytes(&self.dir.join(format!("{}.json", trace.id)), json.as_bytes())?;
+        let retention_days = self.config.as_ref().map_or(0, |config| config.retention_days);
+        self.expire(retention_days)
+    }
+
+    fn expire(&self, retention_days: u64) -> Result<(), StoreError> {
+        let cutoff = SystemTime::from(self.clock.now()) - Duration::from_secs(retention_days * 24 * 60 * 60);
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            if entry.metadata()?.modified()? < cutoff {
+                fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+}
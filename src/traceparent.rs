@@ -0,0 +1,135 @@
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use chrono::Utc;
+use reqwest::header::{HeaderMap as OutboundHeaders, HeaderValue};
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use warp::http::HeaderMap;
+
+pub const TRACEPARENT: &str = "traceparent";
+pub const TRACESTATE: &str = "tracestate";
+
+static IDS: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static CURRENT: RefCell<Option<TraceContext>> = RefCell::new(None);
+}
+
+// Unique rather than unpredictable, which is all trace ids need.
+fn new_id(bytes: usize) -> String {
+    let seed = format!(
+        "{}:{}:{:?}",
+        Utc::now().timestamp_nanos(),
+        IDS.fetch_add(1, Ordering::Relaxed),
+        thread::current().id()
+    );
+    hex::encode(&Sha256::digest(seed.as_bytes())[..bytes])
+}
+
+fn lower_hex(value: &str, len: usize) -> bool {
+    value.len() == len && value.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+fn id(value: &str, len: usize) -> bool {
+    lower_hex(value, len) && value.bytes().any(|b| b != b'0')
+}
+
+// Where handling an email sits in a W3C trace (see
+// https://www.w3.org/TR/trace-context/): a span of its own in the trace of
+// the traceparent header our ingress sent with the webhook, or a new trace
+// when there wasn't one. Its calls to Mailgun and Slack carry it on as
+// their traceparent, and tracestate as it came.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TraceContext {
+    pub trace_id: String,
+    pub span_id: String,
+    // The ingress' span, if any.
+    #[serde(default)]
+    pub parent_id: Option<String>,
+    pub sampled: bool,
+    #[serde(default)]
+    pub state: Option<String>,
+}
+
+impl TraceContext {
+    pub fn start() -> TraceContext {
+        TraceContext {
+            trace_id: new_id(16),
+            span_id: new_id(8),
+            parent_id: None,
+            sampled: true,
+            state: None,
+        }
+    }
+
+    // A span of ours under the caller's, if traceparent is valid.
+    pub fn continued(traceparent: &str, tracestate: Option<&str>) -> Option<TraceContext> {
+        let fields: Vec<&str> = traceparent.trim().split('-').collect();
+        if fields.len() < 4 {
+            return None;
+        }
+        let (version, trace_id, parent_id, flags) = (fields[0], fields[1], fields[2], fields[3]);
+        // Later versions may append fields, version 00 has exactly these.
+        if !lower_hex(version, 2) || version == "ff" || (version == "00" && fields.len() > 4) {
+            return None;
+        }
+        if !id(trace_id, 32) || !id(parent_id, 16) || !lower_hex(flags, 2) {
+            return None;
+        }
+        let flags = u8::from_str_radix(flags, 16).ok()?;
+        Some(TraceContext {
+            trace_id: String::from(trace_id),
+            span_id: new_id(8),
+            parent_id: Some(String::from(parent_id)),
+            sampled: flags & 1 == 1,
+            state: tracestate.map(str::trim).filter(|state| !state.is_empty()).map(String::from),
+        })
+    }
+
+    pub fn from_headers(headers: &HeaderMap) -> Option<TraceContext> {
+        let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+        TraceContext::continued(header(TRACEPARENT)?, header(TRACESTATE))
+    }
+
+    pub fn traceparent(&self) -> String {
+        format!("00-{}-{}-{:02x}", self.trace_id, self.span_id, self.sampled as u8)
+    }
+}
+
+// Makes trace the current one on this thread, until dropped.
+pub struct Entered {
+    previous: Option<TraceContext>,
+}
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CURRENT.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+pub fn enter(trace: Option<&TraceContext>) -> Entered {
+    Entered {
+        previous: CURRENT.with(|current| current.replace(trace.cloned())),
+    }
+}
+
+pub fn current() -> Option<TraceContext> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+// For the Mailgun and Slack clients made while handling a traced email.
+pub fn outbound_headers() -> OutboundHeaders {
+    let mut headers = OutboundHeaders::new();
+    if let Some(trace) = current() {
+        if let Ok(value) = HeaderValue::from_str(&trace.traceparent()) {
+            headers.insert(TRACEPARENT, value);
+        }
+        if let Some(value) = trace.state.as_ref().and_then(|state| HeaderValue::from_str(state).ok()) {
+            headers.insert(TRACESTATE, value);
+        }
+    }
+    headers
+}
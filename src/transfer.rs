@@ -0,0 +1,109 @@
+use crate::mailgun::MailgunEmailReceived;
+
+// Relays that mangle the MIME structure sometimes leave the body still
+// quoted-printable or base64 encoded, which shows up in Slack as =E2=80=99
+// or a wall of letters. Only bodies that look encoded, and decode to valid
+// UTF-8, are decoded. Returns whether anything was.
+pub fn decode_remnants(email: &mut MailgunEmailReceived) -> bool {
+    let mut decoded = false;
+    if let Some(body) = decode(&email.body_plain) {
+        email.body_plain = body;
+        decoded = true;
+    }
+    if let Some(body) = email.body_html.as_ref().and_then(|html| decode(html)) {
+        email.body_html = Some(body);
+        decoded = true;
+    }
+    decoded
+}
+
+fn decode(body: &str) -> Option<String> {
+    if looks_base64(body) {
+        let compact: String = body.chars().filter(|c| !c.is_whitespace()).collect();
+        if let Some(text) = base64::decode(&compact).ok().and_then(|bytes| String::from_utf8(bytes).ok()) {
+            if text.chars().all(|c| !c.is_control() || c.is_whitespace()) {
+                return Some(text);
+            }
+        }
+    }
+    if looks_quoted_printable(body) {
+        return decode_quoted_printable(body);
+    }
+    None
+}
+
+// A single run of base64 lines, as MIME wraps them, long enough not to be
+// a word that happens to be made of the same letters.
+fn looks_base64(body: &str) -> bool {
+    let body = body.trim();
+    let lines: Vec<&str> = body.lines().map(|line| line.trim()).collect();
+    body.len() >= 24
+        && !body.contains(' ')
+        && lines.iter().all(|line| line.len() <= 76)
+        && lines.iter().rev().skip(1).all(|line| line.len() % 4 == 0)
+        && body.chars().all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '/' || c == '=' || c.is_whitespace())
+        && body.chars().any(|c| c.is_ascii_digit() || c == '+' || c == '/')
+}
+
+// Escapes of bytes outside ASCII (what was punctuation or accents) or soft
+// line breaks. A lone =20 in someone's text isn't enough.
+fn looks_quoted_printable(body: &str) -> bool {
+    let bytes = body.as_bytes();
+    let mut escapes = 0;
+    let mut soft_breaks = 0;
+    for (index, byte) in bytes.iter().enumerate() {
+        if *byte != b'=' {
+            continue;
+        }
+        match (bytes.get(index + 1), bytes.get(index + 2)) {
+            (Some(b'\n'), _) | (Some(b'\r'), Some(b'\n')) => soft_breaks += 1,
+            (Some(high), Some(low)) if is_upper_hex(*high) && is_upper_hex(*low) => {
+                if hex(*high) >= 8 {
+                    escapes += 1;
+                }
+            },
+            _ => (),
+        }
+    }
+    escapes >= 2 || (soft_breaks >= 2 && escapes >= 1)
+}
+
+fn decode_quoted_printable(body: &str) -> Option<String> {
+    let bytes = body.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'=' {
+            match (bytes.get(index + 1), bytes.get(index + 2)) {
+                (Some(b'\n'), _) => {
+                    index += 2;
+                    continue;
+                },
+                (Some(b'\r'), Some(b'\n')) => {
+                    index += 3;
+                    continue;
+                },
+                (Some(high), Some(low)) if is_upper_hex(*high) && is_upper_hex(*low) => {
+                    decoded.push(hex(*high) << 4 | hex(*low));
+                    index += 3;
+                    continue;
+                },
+                _ => (),
+            }
+        }
+        decoded.push(bytes[index]);
+        index += 1;
+    }
+    String::from_utf8(decoded).ok()
+}
+
+fn is_upper_hex(byte: u8) -> bool {
+    byte.is_ascii_digit() || (b'A'..=b'F').contains(&byte)
+}
+
+fn hex(byte: u8) -> u8 {
+    match byte {
+        b'0'..=b'9' => byte - b'0',
+        _ => byte - b'A' + 10,
+    }
+}
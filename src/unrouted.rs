@@ -0,0 +1,78 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+
+use crate::multipart;
+use crate::store::{self, StoreError};
+
+// Webhooks posted to an emails/... path no route handles (usually a typo
+// in the Mailgun route) are logged with the names of their fields, and also
+// written to log (relative to DATA_DIR) when set.
+#[derive(Deserialize, Clone, Default)]
+pub struct UnroutedConfig {
+    #[serde(default)]
+    pub log: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct UnroutedRequest {
+    pub at: DateTime<Utc>,
+    pub path: String,
+    pub content_type: Option<String>,
+    pub size: usize,
+    // Names only, the values are someone's email.
+    pub fields: Vec<String>,
+}
+
+#[derive(Clone)]
+pub struct Unrouted {
+    path: Option<PathBuf>,
+    write_lock: Arc<Mutex<()>>,
+}
+
+// The field names of a form, multipart or JSON body, whichever it is.
+pub fn field_names(content_type: &str, body: &[u8]) -> Vec<String> {
+    let lower = content_type.to_lowercase();
+    if lower.starts_with("application/json") {
+        serde_json::from_slice::<Value>(body).ok()
+            .and_then(|value| value.as_object().map(|fields| fields.keys().cloned().collect()))
+            .unwrap_or_default()
+    } else if let Some(boundary) = multipart::boundary_from_content_type(content_type) {
+        multipart::parse_parts(body, &boundary)
+            .map(|parts| parts.into_iter().map(|part| part.name).collect())
+            .unwrap_or_default()
+    } else {
+        serde_urlencoded::from_bytes::<Vec<(String, String)>>(body)
+            .map(|fields| fields.into_iter().map(|(name, _)| name).collect())
+            .unwrap_or_default()
+    }
+}
+
+impl Unrouted {
+    pub fn new(config: UnroutedConfig, data_dir: &Path) -> Unrouted {
+        Unrouted {
+            path: config.log.map(|log| data_dir.join(log)),
+            write_lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    pub fn record(&self, request: &UnroutedRequest) -> Result<(), StoreError> {
+        warn!(
+            "No route for POST /emails/{} ({}, {} bytes, fields: {})",
+            request.path,
+            request.content_type.as_ref().map(|c| &c[..]).unwrap_or("no content type"),
+            request.size,
+            request.fields.join(", ")
+        );
+        match &self.path {
+            Some(path) => {
+                let _guard = self.write_lock.lock().unwrap();
+                store::append_json_line(path, request)
+            },
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,178 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Serialize, Deserialize};
+use sha2::Sha256;
+
+use crate::blocklist::address_of;
+use crate::dashboard::escape;
+use crate::store::{self, StoreError};
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Auto-replies get List-Unsubscribe headers: a signed one-click link to
+// base_url/unsubscribe (RFC 8058), and mailto when set, which has to be an
+// address someone reads since limail doesn't. See Unsubscribed for what
+// unsubscribing does.
+#[derive(Deserialize, Clone)]
+pub struct UnsubscribeConfig {
+    // Where limail is reachable from the recipients' mail clients.
+    pub base_url: String,
+    pub secret: String,
+    #[serde(default)]
+    pub mailto: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct UnsubscribeQuery {
+    pub address: String,
+    pub signature: String,
+}
+
+#[derive(Clone)]
+pub struct UnsubscribeLinks {
+    config: UnsubscribeConfig,
+}
+
+impl UnsubscribeLinks {
+    pub fn new(config: UnsubscribeConfig) -> UnsubscribeLinks {
+        UnsubscribeLinks { config }
+    }
+
+    fn signature(&self, address: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_varkey(self.config.secret.as_bytes()).expect("HMAC takes keys of any size");
+        mac.input(address_of(address).as_bytes());
+        mac
+    }
+
+    pub fn url(&self, address: &str) -> String {
+        let address = address_of(address);
+        let signature = hex::encode(self.signature(&address).result().code());
+        format!(
+            "{}/unsubscribe?{}",
+            self.config.base_url.trim_end_matches('/'),
+            serde_urlencoded::to_string(&[("address", &address[..]), ("signature", &signature[..])]).unwrap_or_default()
+        )
+    }
+
+    // The List-Unsubscribe header for a reply to address.
+    pub fn header(&self, address: &str) -> String {
+        let link = format!("<{}>", self.url(address));
+        match &self.config.mailto {
+            Some(mailto) => format!("<mailto:{}?subject=unsubscribe>, {}", mailto, link),
+            None => link,
+        }
+    }
+
+    pub fn verify(&self, query: &UnsubscribeQuery) -> bool {
+        match hex::decode(&query.signature) {
+            Ok(signature) => self.signature(&query.address).verify(&signature).is_ok(),
+            Err(_) => false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OptOut {
+    pub address: String,
+    pub at: DateTime<Utc>,
+    // "link" for the List-Unsubscribe link, otherwise the admin token.
+    pub by: String,
+}
+
+// The addresses that asked not to be auto-replied to, which every responder
+// route honours. Forwards to Slack, and replies sent by hand, still happen.
+#[derive(Clone)]
+pub struct Unsubscribed {
+    path: PathBuf,
+    entries: Arc<RwLock<BTreeMap<String, OptOut>>>,
+}
+
+impl Unsubscribed {
+    pub fn load(path: PathBuf) -> Result<Unsubscribed, StoreError> {
+        let entries: BTreeMap<String, OptOut> = store::read_json(&path)?.unwrap_or_default();
+        Ok(Unsubscribed {
+            path,
+            entries: Arc::new(RwLock::new(entries)),
+        })
+    }
+
+    pub fn contains(&self, address: &str) -> bool {
+        self.entries.read().unwrap().contains_key(&address_of(address))
+    }
+
+    pub fn get(&self, address: &str) -> Option<OptOut> {
+        self.entries.read().unwrap().get(&address_of(address)).cloned()
+    }
+
+    pub fn entries(&self) -> Vec<OptOut> {
+        self.entries.read().unwrap().values().cloned().collect()
+    }
+
+    // Changes are persisted before returning.
+    pub fn add(&self, address: &str, by: &str) -> Result<bool, StoreError> {
+        let address = address_of(address);
+        if !address.contains('@') {
+            return Ok(false);
+        }
+        let mut entries = self.entries.write().unwrap();
+        if entries.contains_key(&address) {
+            return Ok(false);
+        }
+        entries.insert(address.clone(), OptOut { address, at: Utc::now(), by: String::from(by) });
+        store::write_json(&self.path, &*entries)?;
+        Ok(true)
+    }
+
+    pub fn remove(&self, address: &str) -> Result<bool, StoreError> {
+        let mut entries = self.entries.write().unwrap();
+        let removed = entries.remove(&address_of(address)).is_some();
+        if removed {
+            store::write_json(&self.path, &*entries)?;
+        }
+        Ok(removed)
+    }
+}
+
+// For compliance audits, one opt-out per line.
+pub fn csv(entries: &[OptOut]) -> String {
+    let quote = |field: &str| format!("\"{}\"", field.replace('"', "\"\""));
+    let mut csv = String::from("address,at,by\n");
+    for entry in entries {
+        csv.push_str(&format!("{},{},{}\n", quote(&entry.address), entry.at.to_rfc3339(), quote(&entry.by)));
+    }
+    csv
+}
+
+// Mail clients post without asking, people following the link get a
+// button, so that link scanners opening it don't unsubscribe anyone.
+pub fn render(query: &UnsubscribeQuery, done: bool) -> String {
+    let body = if done {
+        format!("<p>{} won't get any more automated replies from us.</p>", escape(&query.address))
+    } else {
+        format!(
+            r#"<form method="post" action="/unsubscribe?{}"><p>Stop automated replies to {}?</p><button type="submit">Unsubscribe</button></form>"#,
+            escape(&serde_urlencoded::to_string(&[("address", &query.address), ("signature", &query.signature)]).unwrap_or_default()),
+            escape(&query.address)
+        )
+    };
+    format!(r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Unsubscribe</title>
+<style>
+body {{ font-family: sans-serif; margin: 2em; }}
+</style>
+</head>
+<body>
+{body}
+</body>
+</html>
+"#,
+        body = body,
+    )
+}
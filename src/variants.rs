@@ -0,0 +1,119 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+
+use crate::blocklist::address_of;
+use crate::pipeline::route_matches;
+use crate::store::{self, StoreError};
+
+#[derive(Deserialize, Clone)]
+pub struct Variant {
+    pub template: String,
+    #[serde(default = "default_weight")]
+    pub weight: u64,
+}
+
+fn default_weight() -> u64 {
+    1
+}
+
+// Auto-responder routes that reply with one of several templates instead
+// of the one in the URL, picked by weight. The first matching route wins.
+#[derive(Deserialize, Clone)]
+pub struct VariantConfig {
+    pub route: String,
+    pub templates: Vec<Variant>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct VariantStats {
+    pub sent: u64,
+    // Emails received from someone who had already been sent this variant.
+    pub repeats: u64,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct VariantsState {
+    // Route pattern -> template -> counts.
+    stats: BTreeMap<String, BTreeMap<String, VariantStats>>,
+    // Route pattern -> recipient -> the template they were sent.
+    recipients: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+// Picks, and keeps count of, template variants. Kept in variants.json.
+#[derive(Clone)]
+pub struct Variants {
+    configs: Arc<Vec<VariantConfig>>,
+    path: PathBuf,
+    state: Arc<Mutex<VariantsState>>,
+}
+
+impl Variants {
+    pub fn load(configs: Vec<VariantConfig>, path: PathBuf) -> Result<Variants, StoreError> {
+        let state: VariantsState = store::read_json(&path)?.unwrap_or_default();
+        Ok(Variants {
+            configs: Arc::new(configs),
+            path,
+            state: Arc::new(Mutex::new(state)),
+        })
+    }
+
+    fn config(&self, route: &str) -> Option<&VariantConfig> {
+        self.configs.iter()
+            .find(|config| route_matches(&config.route, route) && config.templates.iter().any(|v| v.weight > 0))
+    }
+
+    // The same sender always lands on the same variant (as long as the
+    // weights don't change), so a repeat email is counted against the
+    // wording they actually got.
+    pub fn pick(&self, route: &str, recipient: &str) -> Option<String> {
+        let config = self.config(route)?;
+        let total: u64 = config.templates.iter().map(|v| v.weight).sum();
+        let digest = Sha256::digest(address_of(recipient).as_bytes());
+        let mut point = digest[..8].iter().fold(0u64, |acc, b| (acc << 8) | u64::from(*b)) % total;
+        for variant in &config.templates {
+            if point < variant.weight {
+                return Some(variant.template.clone());
+            }
+            point -= variant.weight;
+        }
+        None
+    }
+
+    // Called for every email on a variant route, before anything is sent.
+    pub fn observe(&self, route: &str, sender: &str) -> Result<(), StoreError> {
+        let pattern = match self.config(route) {
+            Some(config) => config.route.clone(),
+            None => return Ok(()),
+        };
+        let mut state = self.state.lock().unwrap();
+        let previous = state.recipients.get(&pattern)
+            .and_then(|recipients| recipients.get(&address_of(sender)))
+            .cloned();
+        match previous {
+            Some(template) => {
+                state.stats.entry(pattern).or_default().entry(template).or_default().repeats += 1;
+                store::write_json(&self.path, &*state)
+            },
+            None => Ok(()),
+        }
+    }
+
+    pub fn record_sent(&self, route: &str, recipient: &str, template: &str) -> Result<(), StoreError> {
+        let pattern = match self.config(route) {
+            Some(config) => config.route.clone(),
+            None => return Ok(()),
+        };
+        let mut state = self.state.lock().unwrap();
+        state.stats.entry(pattern.clone()).or_default().entry(String::from(template)).or_default().sent += 1;
+        state.recipients.entry(pattern).or_default().insert(address_of(recipient), String::from(template));
+        store::write_json(&self.path, &*state)
+    }
+
+    pub fn stats(&self) -> BTreeMap<String, BTreeMap<String, VariantStats>> {
+        self.state.lock().unwrap().stats.clone()
+    }
+}
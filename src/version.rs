@@ -0,0 +1,28 @@
+use chrono::{DateTime, TimeZone, Utc};
+use serde::Serialize;
+
+// Which build is handling mail, filled in by build.rs.
+#[derive(Serialize)]
+pub struct Version {
+    pub version: &'static str,
+    pub commit: &'static str,
+    pub built_at: Option<DateTime<Utc>>,
+    pub features: Vec<&'static str>,
+}
+
+impl Version {
+    pub fn current() -> Version {
+        let mut features = Vec::new();
+        if cfg!(feature = "testing") {
+            features.push("testing");
+        }
+        Version {
+            version: env!("CARGO_PKG_VERSION"),
+            commit: env!("LIMAIL_GIT_COMMIT"),
+            built_at: env!("LIMAIL_BUILT_AT").parse::<i64>().ok()
+                .filter(|&seconds| seconds > 0)
+                .map(|seconds| Utc.timestamp(seconds, 0)),
+            features,
+        }
+    }
+}
@@ -0,0 +1,183 @@
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::archive::ArchivedEmail;
+use crate::copies::SentCopy;
+use crate::dashboard::escape;
+
+// [[name, value], ...] as Mailgun sends message-headers.
+pub fn headers(message_headers: &str) -> Vec<(String, String)> {
+    match serde_json::from_str(message_headers) {
+        Ok(Value::Array(pairs)) => pairs.iter().filter_map(|pair| match pair {
+            Value::Array(pair) if pair.len() == 2 => match (&pair[0], &pair[1]) {
+                (Value::String(name), Value::String(value)) => Some((name.clone(), value.clone())),
+                _ => None,
+            },
+            _ => None,
+        }).collect(),
+        _ => Vec::new(),
+    }
+}
+
+// The full original email, for whoever followed a link from Slack.
+// `query` is the signed query string, reused for the attachment links.
+pub fn render(archived: &ArchivedEmail, query: &str) -> String {
+    let email = &archived.job.email;
+    let headers: String = headers(&email.message_headers).iter()
+        .map(|(name, value)| format!("<tr><th>{}</th><td>{}</td></tr>", escape(name), escape(value)))
+        .collect::<Vec<String>>()
+        .join("\n");
+    let attachments: String = archived.attachments.iter().enumerate()
+        .map(|(index, attachment)| format!(
+            "<li><a href=\"/archive/{}/attachments/{}?{}\">{}</a> ({}, {} bytes)</li>",
+            escape(&archived.job.id),
+            index,
+            escape(query),
+            escape(&attachment.filename),
+            escape(attachment.content_type.as_ref().map(|t| &t[..]).unwrap_or("unknown type")),
+            attachment.size
+        ))
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    format!(r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{subject}</title>
+<style>
+body {{ font-family: sans-serif; margin: 2em; }}
+table {{ border-collapse: collapse; margin-bottom: 2em; }}
+td, th {{ border: 1px solid #ccc; padding: 0.2em 0.6em; text-align: left; vertical-align: top; }}
+pre {{ white-space: pre-wrap; }}
+</style>
+</head>
+<body>
+<h1>{subject}</h1>
+<p>From {from}, received {received}{size}.</p>
+<h2>Body</h2>
+<pre>{body}</pre>
+<h2>Attachments</h2>
+<ul>
+{attachments}
+</ul>
+<h2>Headers</h2>
+<table>
+{headers}
+</table>
+</body>
+</html>
+"#,
+        subject = escape(&email.subject),
+        from = escape(&email.from),
+        received = archived.job.received_at.format("%Y-%m-%d %H:%M:%S UTC"),
+        size = archived.payload_size.map(|size| format!(" ({} bytes)", size)).unwrap_or_default(),
+        body = escape(&email.body_plain),
+        attachments = if attachments.is_empty() { String::from("<li>None</li>") } else { attachments },
+        headers = headers,
+    )
+}
+
+#[derive(Deserialize, Default)]
+pub struct HtmlQuery {
+    #[serde(default)]
+    pub remote_images: bool,
+}
+
+// What the browser may load for a sanitized email, on top of ammonia
+// stripping scripts, forms and event handlers. Remote images are how
+// senders learn an email was opened, so they're blocked unless asked for.
+pub fn content_security_policy(remote_images: bool) -> &'static str {
+    if remote_images {
+        "default-src 'none'; style-src 'unsafe-inline'; img-src data: http: https:; frame-ancestors 'none'"
+    } else {
+        "default-src 'none'; style-src 'unsafe-inline'; img-src data:; frame-ancestors 'none'"
+    }
+}
+
+pub fn sanitize(html: &str, remote_images: bool) -> String {
+    let mut builder = ammonia::Builder::default();
+    // Inline styles are most of what makes an email look like itself, and
+    // the content security policy stops them loading anything.
+    builder.add_generic_attributes(&["style"]);
+    if !remote_images {
+        builder.rm_tag_attributes("img", &["src", "srcset"]);
+    }
+    builder.clean(html).to_string()
+}
+
+// The HTML part of an archived email as the user saw it, or None when the
+// email had no HTML part.
+pub fn render_html(archived: &ArchivedEmail, remote_images: bool) -> Option<String> {
+    let email = &archived.job.email;
+    let html = email.body_html.as_ref()?;
+    let notice = if remote_images {
+        String::from("Remote images are shown.")
+    } else {
+        format!(
+            "Remote images are blocked. <a href=\"/admin/emails/{}/html?remote_images=true\">Show them</a>",
+            escape(&archived.job.id)
+        )
+    };
+    Some(format!(r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{subject}</title>
+<style>
+.limail-notice {{ font-family: sans-serif; background: #eee; padding: 0.5em 1em; margin-bottom: 1em; }}
+</style>
+</head>
+<body>
+<div class="limail-notice">From {from}: {subject}. {notice}</div>
+{html}
+</body>
+</html>
+"#,
+        subject = escape(&email.subject),
+        from = escape(&email.from),
+        notice = notice,
+        html = sanitize(html, remote_images),
+    ))
+}
+
+// A copy of an email we sent, HTML part sanitized like an inbound one, or
+// the text when there's no HTML.
+pub fn render_sent(copy: &SentCopy, remote_images: bool) -> String {
+    let notice = match (&copy.body_html, remote_images) {
+        (None, _) => String::new(),
+        (Some(_), true) => String::from(" Remote images are shown."),
+        (Some(_), false) => format!(
+            " Remote images are blocked. <a href=\"/admin/outbox/copy/html?{}\">Show them</a>",
+            escape(&serde_urlencoded::to_string(&[("message_id", &copy.message_id[..]), ("remote_images", "true")]).unwrap_or_default())
+        ),
+    };
+    let body = match &copy.body_html {
+        Some(html) => sanitize(html, remote_images),
+        None => format!("<pre>{}</pre>", escape(&copy.body_plain)),
+    };
+    format!(r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{subject}</title>
+<style>
+.limail-notice {{ font-family: sans-serif; background: #eee; padding: 0.5em 1em; margin-bottom: 1em; }}
+pre {{ white-space: pre-wrap; }}
+</style>
+</head>
+<body>
+<div class="limail-notice">To {recipient}, {at} ({template}{sent_by}): {subject}.{notice}</div>
+{body}
+</body>
+</html>
+"#,
+        subject = escape(&copy.subject),
+        recipient = escape(&copy.recipient),
+        at = copy.at.format("%Y-%m-%d %H:%M:%S UTC"),
+        template = escape(&copy.template),
+        sent_by = copy.sent_by.as_ref().map(|by| format!(", sent by {}", escape(by))).unwrap_or_default(),
+        notice = notice,
+        body = body,
+    )
+}
@@ -0,0 +1,180 @@
+use std::collections::BTreeMap;
+use std::thread;
+
+use chrono::{DateTime, Datelike, Duration, Utc, Weekday};
+use serde::Deserialize;
+
+use crate::archive::Archive;
+use crate::blocklist::address_of;
+use crate::clock::Clock;
+use crate::maintenance::Maintenance;
+use crate::outbox::{Outbox, OutboxQuery};
+use crate::slack::{Slack, SlackMessage};
+use crate::store::StoreError;
+
+fn default_weekday() -> String {
+    String::from("mon")
+}
+
+fn default_hour() -> u32 {
+    9
+}
+
+fn default_top_domains() -> usize {
+    5
+}
+
+// A summary of the past seven days, posted to `channel` every `weekday` at
+// `hour` (UTC). Built from the archive and the outbox, so it covers what
+// every process did, but only the frontend (or all-in-one) process posts it.
+#[derive(Deserialize, Clone)]
+pub struct WeeklyReportConfig {
+    pub channel: String,
+    #[serde(default = "default_weekday")]
+    pub weekday: String,
+    #[serde(default = "default_hour")]
+    pub hour: u32,
+    #[serde(default = "default_top_domains")]
+    pub top_domains: usize,
+}
+
+pub struct WeeklyReport {
+    pub since: DateTime<Utc>,
+    pub received: BTreeMap<String, u64>,
+    pub auto_replies: u64,
+    pub top_domains: Vec<(String, u64)>,
+    // From receipt to the reaction that marked it handled.
+    pub average_handling_minutes: Option<f64>,
+}
+
+fn parse_weekday(weekday: &str) -> Weekday {
+    match &weekday.to_lowercase().chars().take(3).collect::<String>()[..] {
+        "tue" => Weekday::Tue,
+        "wed" => Weekday::Wed,
+        "thu" => Weekday::Thu,
+        "fri" => Weekday::Fri,
+        "sat" => Weekday::Sat,
+        "sun" => Weekday::Sun,
+        "mon" => Weekday::Mon,
+        _ => {
+            error!("Unknown weekly report weekday {}, posting on Mondays", weekday);
+            Weekday::Mon
+        },
+    }
+}
+
+fn domain_of(from: &str) -> String {
+    let address = address_of(from);
+    match address.rfind('@') {
+        Some(at) => String::from(&address[at + 1..]),
+        None => address,
+    }
+}
+
+impl WeeklyReport {
+    // Of the week before `now`.
+    pub fn build(archive: &Archive, outbox: &Outbox, top_domains: usize, now: DateTime<Utc>) -> Result<WeeklyReport, StoreError> {
+        let since = now - Duration::days(7);
+        let mut received: BTreeMap<String, u64> = BTreeMap::new();
+        let mut domains: BTreeMap<String, u64> = BTreeMap::new();
+        let mut handling_minutes: Vec<i64> = Vec::new();
+        for archived in archive.received_since(since)? {
+            *received.entry(archived.job.action.route()).or_insert(0) += 1;
+            *domains.entry(domain_of(&archived.job.email.from)).or_insert(0) += 1;
+            if let Some(handled) = &archived.handled {
+                handling_minutes.push((handled.at - archived.job.received_at).num_minutes());
+            }
+        }
+        let auto_replies = outbox
+            .query(&OutboxQuery {
+                since: Some(since),
+                limit: Some(usize::max_value()),
+                ..OutboxQuery::default()
+            })?
+            .iter()
+            .filter(|entry| entry.sent_by.is_none())
+            .count() as u64;
+        let mut domains: Vec<(String, u64)> = domains.into_iter().collect();
+        domains.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        domains.truncate(top_domains);
+        let average_handling_minutes = if handling_minutes.is_empty() {
+            None
+        } else {
+            Some(handling_minutes.iter().sum::<i64>() as f64 / handling_minutes.len() as f64)
+        };
+        Ok(WeeklyReport {
+            since,
+            received,
+            auto_replies,
+            top_domains: domains,
+            average_handling_minutes,
+        })
+    }
+
+    pub fn text(&self) -> String {
+        let total: u64 = self.received.values().sum();
+        let mut lines = vec![format!(
+            "*Weekly report* since {}: {} emails received, {} auto-replies sent",
+            self.since.format("%Y-%m-%d"),
+            total,
+            self.auto_replies
+        )];
+        for (route, count) in &self.received {
+            lines.push(format!("• {}: {}", route, count));
+        }
+        if !self.top_domains.is_empty() {
+            let domains: Vec<String> = self.top_domains.iter()
+                .map(|(domain, count)| format!("{} ({})", domain, count))
+                .collect();
+            lines.push(format!("Top sender domains: {}", domains.join(", ")));
+        }
+        if let Some(minutes) = self.average_handling_minutes {
+            lines.push(format!("Average handling time: {:.0} minutes", minutes));
+        }
+        lines.join("\n")
+    }
+}
+
+// The next time the report is due, strictly after `now`.
+fn next_run(config: &WeeklyReportConfig, now: DateTime<Utc>) -> DateTime<Utc> {
+    let weekday = parse_weekday(&config.weekday);
+    let hour = config.hour.min(23);
+    (0..=7)
+        .map(|days| (now + Duration::days(days)).date().and_hms(hour, 0, 0))
+        .find(|at| at.weekday() == weekday && *at > now)
+        .unwrap_or_else(|| now + Duration::days(7))
+}
+
+// A report due in maintenance is posted once it is lifted.
+pub fn start(config: WeeklyReportConfig, archive: Archive, outbox: Outbox, slack: Slack, maintenance: Maintenance, clock: Clock) {
+    thread::spawn(move || loop {
+        let due = next_run(&config, clock.now());
+        info!("Next weekly report at {}", due.to_rfc3339());
+        clock.sleep_until(due);
+        if maintenance.wait_until_lifted() {
+            info!("Posting the weekly report due at {}, maintenance is over", due.to_rfc3339());
+        }
+        let report = match WeeklyReport::build(&archive, &outbox, config.top_domains, due) {
+            Ok(report) => report,
+            Err(e) => {
+                error!("Unable to build the weekly report: {}", e);
+                continue;
+            },
+        };
+        let sent = slack.send_message(&SlackMessage {
+            channel: config.channel.clone(),
+            text: report.text(),
+            thread_ts: None,
+            as_user: true,
+            username: None,
+            icon_emoji: None,
+            icon_url: None,
+            blocks: None,
+            unfurl_links: None,
+            unfurl_media: None,
+        });
+        if let Err(e) = sent {
+            error!("Unable to post the weekly report to {}: {}", config.channel, e);
+        }
+    });
+}